@@ -0,0 +1,183 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The entry point of the shim process
+//!
+//! The shim maps the keep image, installs the syscall trap and starts the
+//! payload with its arguments and environment on the keep stack.
+
+use crate::hostlib::IMAGE_FD;
+use crate::sys::{exit, random, syscall};
+use crate::{trap, Layout};
+
+use core::mem::size_of;
+use crt0stack::{Builder, Entry, Handle, OutOfSpace};
+use goblin::elf::header::{header64::Header, ELFMAG};
+use lset::Span;
+use nbytes::bytes;
+use rcrt1::_dyn_reloc;
+
+/// The size of the crt0 stack, large enough for the payload arguments
+const CRT0_SIZE: usize = bytes![128; KiB];
+
+/// Entry point
+///
+/// The kernel starts the shim like any static PIE, so the shim relocates
+/// itself before anything else.
+#[no_mangle]
+#[naked]
+pub unsafe extern "sysv64" fn _start() -> ! {
+    asm!("
+    # relocate the dynamic symbols
+    # rdi - address of _DYNAMIC section
+    # rsi - shim load offset
+    .hidden _DYNAMIC
+    lea     rdi,                    [rip + _DYNAMIC]
+    .hidden __ehdr_start
+    lea     rsi,                    [rip + __ehdr_start]
+    call    {DYN_RELOC}
+
+    xor     rbp,                    rbp
+    call    {ENTRY}
+    ud2
+    ",
+        DYN_RELOC = sym _dyn_reloc,
+        ENTRY = sym entry,
+        options(noreturn)
+    )
+}
+
+/// Maps `span` of the keep image at the same offset in the keep
+fn map(layout: &Layout, span: Span<usize>, prot: libc::c_int, flags: libc::c_int) {
+    let offset = span.start - layout.keep.start;
+    let args = [
+        span.start,
+        span.count,
+        prot as _,
+        flags as _,
+        IMAGE_FD as _,
+        offset,
+    ];
+
+    match unsafe { syscall(libc::SYS_mmap, args) } {
+        Ok(addr) if addr == span.start => (),
+        _ => exit(1),
+    }
+}
+
+fn crt0setup<'a>(
+    layout: &Layout,
+    hdr: &Header,
+    crt0: &'a mut [u8],
+) -> Result<Handle<'a>, OutOfSpace> {
+    let rand = unsafe { core::mem::transmute([random(), random()]) };
+    let phdr = layout.code.start as u64 + hdr.e_phoff;
+
+    let args = unsafe {
+        core::slice::from_raw_parts(
+            layout.args.start as *const u8,
+            layout.args.end - layout.args.start,
+        )
+    };
+    let mut strings = args
+        .split(|b| *b == 0)
+        .map(|s| core::str::from_utf8(s).unwrap_or_else(|_| exit(1)));
+
+    // Set the arguments
+    let mut builder = Builder::new(crt0);
+    builder.push("/init")?;
+    for arg in strings.by_ref().take(layout.argc) {
+        builder.push(arg)?;
+    }
+
+    // Set the environment
+    let mut builder = builder.done()?;
+    for env in strings.take(layout.envc) {
+        builder.push(env)?;
+    }
+
+    // Set the aux vector
+    let mut builder = builder.done()?;
+    builder.push(&Entry::ExecFilename("/init"))?;
+    builder.push(&Entry::Platform("x86_64"))?;
+    builder.push(&Entry::Uid(1000))?;
+    builder.push(&Entry::EUid(1000))?;
+    builder.push(&Entry::Gid(1000))?;
+    builder.push(&Entry::EGid(1000))?;
+    builder.push(&Entry::PageSize(4096))?;
+    builder.push(&Entry::Secure(false))?;
+    builder.push(&Entry::ClockTick(100))?;
+    builder.push(&Entry::Flags(0))?; // TODO: https://github.com/enarx/enarx/issues/386
+    builder.push(&Entry::HwCap(0))?; // TODO: https://github.com/enarx/enarx/issues/386
+    builder.push(&Entry::HwCap2(0))?; // TODO: https://github.com/enarx/enarx/issues/386
+    builder.push(&Entry::PHdr(phdr as _))?;
+    builder.push(&Entry::PHent(hdr.e_phentsize as _))?;
+    builder.push(&Entry::PHnum(hdr.e_phnum as _))?;
+    builder.push(&Entry::Random(rand))?;
+
+    builder.done()
+}
+
+extern "C" fn entry() -> ! {
+    // Read the layout from the start of the image.
+    let mut layout = Layout::default();
+    let size = size_of::<Layout>();
+    let args = [
+        IMAGE_FD as _,
+        &mut layout as *mut Layout as _,
+        size,
+        0,
+        0,
+        0,
+    ];
+
+    match unsafe { syscall(libc::SYS_pread64, args) } {
+        Ok(n) if n == size => (),
+        _ => exit(1),
+    }
+
+    // Map the keep, then share the block with the host.
+    let rwx = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+    let rw = libc::PROT_READ | libc::PROT_WRITE;
+    let private = libc::MAP_PRIVATE | libc::MAP_FIXED_NOREPLACE;
+    map(&layout, layout.keep.into(), rwx, private);
+    map(
+        &layout,
+        layout.block.into(),
+        rw,
+        libc::MAP_SHARED | libc::MAP_FIXED,
+    );
+
+    let _ = unsafe { syscall(libc::SYS_close, [IMAGE_FD as _, 0, 0, 0, 0, 0]) };
+    let layout = unsafe { &*(layout.prefix.start as *const Layout) };
+
+    // From here on, every syscall of the payload is trapped.
+    trap::install(layout);
+
+    // Validate the ELF header.
+    let hdr = unsafe { &*(layout.code.start as *const Header) };
+
+    if !hdr.e_ident[..ELFMAG.len()].eq(ELFMAG) {
+        exit(1);
+    }
+
+    // Prepare the crt0 stack at the top of the keep stack.
+    let stack = Span::from(layout.stack);
+    let stack = unsafe { core::slice::from_raw_parts_mut(stack.start as *mut u8, stack.count) };
+    let (_, crt0) = stack.split_at_mut(stack.len() - CRT0_SIZE);
+
+    let space = random() as usize & 0xf0;
+    let handle = match crt0setup(layout, hdr, &mut crt0[space..]) {
+        Err(OutOfSpace) => exit(1),
+        Ok(handle) => handle,
+    };
+
+    unsafe {
+        asm!(
+            "mov rsp, {SP}",
+            "jmp {START}",
+            SP = in(reg) &*handle,
+            START = in(reg) layout.code.start as u64 + hdr.e_entry,
+            options(noreturn)
+        )
+    }
+}
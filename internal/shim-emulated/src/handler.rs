@@ -0,0 +1,316 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::sys;
+use crate::Layout;
+
+use core::fmt::Write;
+use core::ptr::NonNull;
+use primordial::{Page, Register};
+
+use sallyport::{request, Block, Cursor, Request};
+use sgx_heap::Heap;
+use syscall::{
+    BaseSyscallHandler, EnarxSyscallHandler, FileSyscallHandler, MemorySyscallHandler,
+    NetworkSyscallHandler, NewThread, PageAlloc, ProcessSyscallHandler, Sealed, SyscallHandler,
+    SystemSyscallHandler, Thread, Tmpfs, ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS,
+};
+use untrusted::{AddressValidator, UntrustedRef, UntrustedRefMut};
+
+pub const TRACE: bool = false;
+
+/// The in-memory filesystem served to the payload
+static mut TMPFS: Tmpfs = Tmpfs::new();
+
+/// The open sealed files of the payload
+static mut SEALED: Sealed = Sealed::new();
+
+/// The thread of the payload
+static mut THREAD: Thread = Thread::new();
+
+/// Allocates the pages of the tmpfs from the heap
+struct TmpfsPages<'a>(&'a Layout);
+
+impl<'a> PageAlloc for TmpfsPages<'a> {
+    fn alloc(&mut self) -> Option<NonNull<Page>> {
+        let mut heap = unsafe { Heap::new(self.0.heap.into()) };
+        let page = heap.mmap::<Page>(
+            0,
+            Page::size(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+
+        NonNull::new(page.ok()?)
+    }
+
+    unsafe fn free(&mut self, page: NonNull<Page>) {
+        let mut heap = Heap::new(self.0.heap.into());
+        let _ = heap.munmap(page.as_ptr(), Page::size());
+    }
+}
+
+pub struct Handler<'a> {
+    layout: &'a Layout,
+    block: &'a mut Block,
+    argv: [usize; 6],
+    xstate: &'a mut [u8],
+}
+
+impl<'a> Write for Handler<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if s.as_bytes().is_empty() {
+            return Ok(());
+        }
+
+        let c = self.new_cursor();
+        let (_, untrusted) = c.copy_from_slice(s.as_bytes()).or(Err(core::fmt::Error))?;
+
+        let req = request!(libc::SYS_write => libc::STDERR_FILENO, untrusted, untrusted.len());
+        let res = unsafe { self.proxy(req) };
+
+        match res {
+            Ok(res) if usize::from(res[0]) > s.bytes().len() => self.attacked(),
+            Ok(res) if usize::from(res[0]) == s.bytes().len() => Ok(()),
+            _ => Err(core::fmt::Error),
+        }
+    }
+}
+
+impl<'a> Handler<'a> {
+    /// Create a new handler for a syscall with the arguments `argv`
+    ///
+    /// The payload continues with the extended state in `xstate`.
+    pub fn new(
+        layout: &'a Layout,
+        block: &'a mut Block,
+        argv: [usize; 6],
+        xstate: &'a mut [u8],
+    ) -> Self {
+        Self {
+            layout,
+            block,
+            argv,
+            xstate,
+        }
+    }
+
+    /// Whether `size` bytes at `ptr` lie within the keep
+    fn inside(&self, ptr: usize, size: usize) -> bool {
+        match ptr.checked_add(size) {
+            Some(end) => ptr >= self.layout.keep.start && end <= self.layout.keep.end,
+            None => false,
+        }
+    }
+}
+
+impl<'a> AddressValidator for Handler<'a> {
+    fn validate_const_mem_fn(&self, ptr: *const (), size: usize) -> bool {
+        self.inside(ptr as _, size)
+    }
+
+    fn validate_mut_mem_fn(&self, ptr: *mut (), size: usize) -> bool {
+        self.inside(ptr as _, size)
+    }
+}
+
+impl<'a> SyscallHandler for Handler<'a> {}
+impl<'a> SystemSyscallHandler for Handler<'a> {}
+impl<'a> NetworkSyscallHandler for Handler<'a> {}
+
+impl<'a> BaseSyscallHandler for Handler<'a> {
+    /// The host maps the block at the same address as the shim
+    fn translate_shim_to_host_addr<T>(buf: *const T) -> usize {
+        buf as _
+    }
+
+    fn new_cursor(&mut self) -> Cursor {
+        self.block.cursor()
+    }
+
+    unsafe fn proxy(&mut self, req: Request) -> sallyport::Result {
+        self.block.msg.req = req;
+
+        // prevent earlier writes from being moved beyond this point
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::Release);
+
+        sys::call();
+
+        // prevent later reads from being moved before this point
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::Acquire);
+
+        self.block.msg.rep.into()
+    }
+
+    fn attacked(&mut self) -> ! {
+        self.exit(1)
+    }
+
+    #[inline]
+    fn unknown_syscall(
+        &mut self,
+        _a: Register<usize>,
+        _b: Register<usize>,
+        _c: Register<usize>,
+        _d: Register<usize>,
+        _e: Register<usize>,
+        _f: Register<usize>,
+        nr: usize,
+    ) {
+        if !TRACE {
+            return;
+        }
+        debugln!(self, "unsupported syscall: {}", nr);
+    }
+
+    fn trace(&mut self, name: &str, argc: usize) {
+        if !TRACE {
+            return;
+        }
+
+        let argv = self.argv;
+
+        debug!(self, "{}(", name);
+        for (i, arg) in argv[..argc].iter().copied().enumerate() {
+            let prefix = if i > 0 { ", " } else { "" };
+            debug!(self, "{}0x{:x}", prefix, arg);
+        }
+
+        debugln!(self, ")");
+    }
+}
+
+impl<'a> ProcessSyscallHandler for Handler<'a> {
+    /// Do an arch_prctl() syscall
+    ///
+    /// The shim itself does not use the segment bases, so the payload
+    /// gets the ones of the process.
+    fn arch_prctl(&mut self, code: libc::c_int, addr: libc::c_ulong) -> sallyport::Result {
+        self.trace("arch_prctl", 2);
+
+        match code {
+            ARCH_SET_FS | ARCH_GET_FS | ARCH_SET_GS | ARCH_GET_GS => {
+                let args = [code as _, addr as _, 0, 0, 0, 0];
+                let ret = unsafe { sys::syscall(libc::SYS_arch_prctl, args)? };
+                Ok([ret.into(), Default::default()])
+            }
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    fn thread<T>(&mut self, f: impl FnOnce(&mut Thread) -> T) -> T {
+        f(unsafe { &mut THREAD })
+    }
+
+    fn xstate<T>(&mut self, f: impl FnOnce(&mut [u8]) -> T) -> T {
+        f(self.xstate)
+    }
+
+    /// The shim has a single thread, so it cannot start another one.
+    fn spawn(&mut self, _new: NewThread) -> Result<(), libc::c_int> {
+        Err(libc::EAGAIN)
+    }
+}
+
+impl<'a> FileSyscallHandler for Handler<'a> {
+    fn tmpfs<T>(&mut self, f: impl FnOnce(&mut Tmpfs, &mut dyn PageAlloc) -> T) -> Option<T> {
+        let tmpfs = unsafe { &mut TMPFS };
+        Some(f(
+            tmpfs.mount(&self.layout.tmpfs)?,
+            &mut TmpfsPages(self.layout),
+        ))
+    }
+
+    /// An emulated keep has no secret, so opening a sealed file fails
+    fn sealed<T>(&mut self, f: impl FnOnce(&mut Sealed) -> T) -> Option<T> {
+        let sealed = unsafe { &mut SEALED };
+        Some(f(sealed.mount(&self.layout.sealed, |_| None)?))
+    }
+}
+
+impl<'a> MemorySyscallHandler for Handler<'a> {
+    /// Do a brk() system call
+    fn brk(&mut self, addr: *const u8) -> sallyport::Result {
+        self.trace("brk", 1);
+
+        let mut heap = unsafe { Heap::new(self.layout.heap.into()) };
+        let ret = heap.brk(addr as _);
+        Ok([ret.into(), Default::default()])
+    }
+
+    /// Do a mprotect() system call
+    // Like in an SGX keep, the page permissions are fixed.
+    // Fake success.
+    fn mprotect(
+        &mut self,
+        _addr: UntrustedRef<u8>,
+        _len: libc::size_t,
+        _prot: libc::c_int,
+    ) -> sallyport::Result {
+        self.trace("mprotect", 3);
+
+        Ok(Default::default())
+    }
+
+    /// Do a mmap() system call
+    fn mmap(
+        &mut self,
+        addr: UntrustedRef<u8>,
+        length: libc::size_t,
+        prot: libc::c_int,
+        flags: libc::c_int,
+        fd: libc::c_int,
+        offset: libc::off_t,
+    ) -> sallyport::Result {
+        self.trace("mmap", 6);
+
+        let mut heap = unsafe { Heap::new(self.layout.heap.into()) };
+        let ret = heap.mmap::<libc::c_void>(
+            addr.as_ptr() as _,
+            length,
+            prot,
+            flags,
+            fd, // Allow truncation!
+            offset,
+        )?;
+
+        Ok([ret.into(), Default::default()])
+    }
+
+    /// Do a munmap() system call
+    fn munmap(&mut self, addr: UntrustedRef<u8>, length: libc::size_t) -> sallyport::Result {
+        self.trace("munmap", 2);
+
+        let mut heap = unsafe { Heap::new(self.layout.heap.into()) };
+        heap.munmap::<libc::c_void>(addr.as_ptr() as _, length)?;
+        Ok(Default::default())
+    }
+
+    // Do madvise syscall
+    // We don't actually support this. So, fake success.
+    fn madvise(
+        &mut self,
+        _addr: *const libc::c_void,
+        _length: libc::size_t,
+        _advice: libc::c_int,
+    ) -> sallyport::Result {
+        self.trace("madvise", 3);
+        Ok(Default::default())
+    }
+}
+
+impl<'a> EnarxSyscallHandler for Handler<'a> {
+    /// An emulated keep has no technology to attest
+    fn get_attestation(
+        &mut self,
+        _nonce: UntrustedRef<u8>,
+        _nonce_len: libc::size_t,
+        _buf: UntrustedRefMut<u8>,
+        _buf_len: libc::size_t,
+    ) -> sallyport::Result {
+        self.trace("get_attestation", 4);
+
+        Err(libc::ENOSYS)
+    }
+}
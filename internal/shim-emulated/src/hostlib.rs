@@ -0,0 +1,60 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// The common Layout for `enarx-keepldr` and `shim-emulated`
+
+use lset::Line;
+
+/// The file descriptor of the keep image in the shim process
+///
+/// The image holds the layout at its start. Every other byte of it is
+/// mapped at `Layout::keep.start` plus its offset.
+pub const IMAGE_FD: i32 = 3;
+
+/// The file descriptor of the socket connecting the shim to the host
+///
+/// The shim writes a byte after placing a request in the block and the
+/// host writes one back after placing the reply.
+pub const HOST_FD: i32 = 4;
+
+/// The layout of the emulated keep
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// The boundaries of the keep.
+    pub keep: Line<usize>,
+
+    /// The boundaries of the prefix, which holds this layout.
+    pub prefix: Line<usize>,
+
+    /// The boundaries of the sallyport block shared with the host.
+    pub block: Line<usize>,
+
+    /// The boundaries of the code.
+    pub code: Line<usize>,
+
+    /// The boundaries of the heap.
+    pub heap: Line<usize>,
+
+    /// The boundaries of the stack.
+    pub stack: Line<usize>,
+
+    /// The boundaries of the payload arguments and environment.
+    ///
+    /// The region contains `argc` followed by `envc` NUL terminated strings.
+    pub args: Line<usize>,
+
+    /// The number of payload arguments, not counting `argv[0]`.
+    pub argc: usize,
+
+    /// The number of payload environment variables.
+    pub envc: usize,
+
+    /// The boundaries of the keep manifest.
+    pub manifest: Line<usize>,
+
+    /// The NUL padded mount point of the tmpfs, or all zeros without one.
+    pub tmpfs: [u8; 32],
+
+    /// The NUL padded prefix of the sealed files, or all zeros without one.
+    pub sealed: [u8; 32],
+}
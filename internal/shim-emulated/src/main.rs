@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The emulated shim
+//!
+//! This crate runs the payload in an ordinary process. A seccomp filter
+//! traps every syscall of the payload, which the shim handles like the
+//! shims of the hardware backends do, proxying them to the host through a
+//! sallyport block.
+//!
+//! The payload has a single thread and the shim handles its syscalls and
+//! faults in signal handlers, which block all other signals. So only one
+//! `Handler` runs at a time and the `static mut`s of the shim need no lock.
+
+#![no_std]
+#![feature(asm)]
+#![feature(naked_functions)]
+#![deny(clippy::all)]
+#![deny(missing_docs)]
+#![no_main]
+
+extern crate compiler_builtins;
+extern crate rcrt1;
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    sys::exit(1)
+}
+
+/// _Unwind_Resume is only needed in the `debug` profile
+///
+/// even though this project has `panic=abort`
+/// it seems like the debug libc.rlib has some references
+/// with unwinding
+/// See also: https://github.com/rust-lang/rust/issues/47493
+#[cfg(debug_assertions)]
+#[no_mangle]
+extern "C" fn _Unwind_Resume() {
+    unimplemented!();
+}
+
+/// rust_eh_personality is only needed in the `debug` profile
+///
+/// even though this project has `panic=abort`
+/// it seems like the debug libc.rlib has some references
+/// with unwinding
+/// See also: https://github.com/rust-lang/rust/issues/47493
+#[cfg(debug_assertions)]
+#[no_mangle]
+pub extern "C" fn rust_eh_personality() {
+    unimplemented!();
+}
+
+// ============== REAL CODE HERE ===============
+
+macro_rules! debug {
+    ($dst:expr, $($arg:tt)*) => {
+        #[allow(unused_must_use)] {
+            use core::fmt::Write;
+            write!($dst, $($arg)*);
+        }
+    };
+}
+
+macro_rules! debugln {
+    ($dst:expr) => { debugln!($dst,) };
+    ($dst:expr, $($arg:tt)*) => {
+        #[allow(unused_must_use)] {
+            use core::fmt::Write;
+            writeln!($dst, $($arg)*);
+        }
+    };
+}
+
+mod entry;
+mod handler;
+mod hostlib;
+mod sys;
+mod trap;
+
+use hostlib::Layout;
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The syscalls of the shim itself
+//!
+//! The seccomp filter only lets syscalls issued from the shim through to
+//! the kernel, so these are the only ones the host kernel ever sees.
+
+use crate::hostlib::HOST_FD;
+
+/// Executes a syscall and returns its result or its error number
+///
+/// # Safety
+///
+/// The caller has to ensure the arguments are valid for the syscall.
+pub unsafe fn syscall(nr: libc::c_long, args: [usize; 6]) -> Result<usize, libc::c_int> {
+    let ret: isize;
+
+    asm!(
+        "syscall",
+        inlateout("rax") nr as isize => ret,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack)
+    );
+
+    match ret {
+        -4095..=-1 => Err(-ret as libc::c_int),
+        _ => Ok(ret as usize),
+    }
+}
+
+/// Ends the shim process, and with it the payload
+pub fn exit(code: libc::c_int) -> ! {
+    loop {
+        let _ = unsafe { syscall(libc::SYS_exit_group, [code as usize, 0, 0, 0, 0, 0]) };
+    }
+}
+
+/// Returns random bytes from the kernel
+pub fn random() -> u64 {
+    let mut r: u64 = 0;
+    let size = core::mem::size_of::<u64>();
+
+    let args = [&mut r as *mut u64 as usize, size, 0, 0, 0, 0];
+    match unsafe { syscall(libc::SYS_getrandom, args) } {
+        Ok(n) if n == size => r,
+        _ => exit(1),
+    }
+}
+
+/// Hands the request in the block to the host and waits for the reply
+///
+/// The shim exits if the host went away, because no reply will follow.
+pub fn call() {
+    let mut byte = 0u8;
+    let ptr = &mut byte as *mut u8 as usize;
+
+    unsafe {
+        match syscall(libc::SYS_write, [HOST_FD as usize, ptr, 1, 0, 0, 0]) {
+            Ok(1) => (),
+            _ => exit(1),
+        }
+
+        match syscall(libc::SYS_read, [HOST_FD as usize, ptr, 1, 0, 0, 0]) {
+            Ok(1) => (),
+            _ => exit(1),
+        }
+    }
+}
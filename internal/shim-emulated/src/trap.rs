@@ -0,0 +1,330 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The trap for the syscalls of the payload
+//!
+//! A seccomp filter passes the syscalls issued from the shim binary on to
+//! the kernel and turns every other one into a `SIGSYS`. Its handler runs
+//! the syscall through the `Handler` and stores the result in the saved
+//! registers of the payload.
+//!
+//! The faults of the payload are real signals of the shim process, which
+//! their handlers raise in the payload through the `Handler` as well. The
+//! signals of the payload are delivered by changing the saved registers.
+
+use crate::handler::Handler;
+use crate::sys::{self, exit};
+use crate::Layout;
+
+use core::mem::size_of;
+use nbytes::bytes;
+use sallyport::Block;
+use syscall::{Context, ProcessSyscallHandler, SyscallHandler};
+
+/// The size of the stack the `SIGSYS` handler runs on
+const STACK_SIZE: usize = bytes![256; KiB];
+
+// Classic BPF instructions
+const LD_ABS: u16 = 0x20; // BPF_LD | BPF_W | BPF_ABS
+const JEQ: u16 = 0x15; // BPF_JMP | BPF_JEQ | BPF_K
+const JGT: u16 = 0x25; // BPF_JMP | BPF_JGT | BPF_K
+const JGE: u16 = 0x35; // BPF_JMP | BPF_JGE | BPF_K
+const RET: u16 = 0x06; // BPF_RET | BPF_K
+
+// Offsets in `struct seccomp_data`
+const ARCH: u32 = 4;
+const IP_LO: u32 = 8;
+const IP_HI: u32 = 12;
+
+const AUDIT_ARCH_X86_64: u32 = 0xC000_003E;
+const SECCOMP_MODE_FILTER: usize = 2;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7FFF_0000;
+
+const SA_RESTORER: u64 = 0x0400_0000;
+
+/// The magic of the software bytes of an XSAVE image in a signal frame
+const FP_XSTATE_MAGIC1: u32 = 0x4650_5853;
+
+/// The offset of the software bytes in an FXSAVE or XSAVE image
+const SW_BYTES: usize = 464;
+
+extern "C" {
+    static __ehdr_start: u8;
+    static _end: u8;
+}
+
+/// A `struct sock_filter` instruction
+#[repr(C)]
+struct Filter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+/// A `struct sock_fprog`
+#[repr(C)]
+struct Program {
+    len: u16,
+    filter: *const Filter,
+}
+
+/// The kernel `struct sigaction`
+#[repr(C)]
+struct SigAction {
+    handler: usize,
+    flags: u64,
+    restorer: usize,
+    mask: u64,
+}
+
+/// A `stack_t`
+#[repr(C)]
+struct SigStack {
+    sp: usize,
+    flags: libc::c_int,
+    size: usize,
+}
+
+/// The `siginfo_t` of a `SIGSYS`
+#[repr(C)]
+#[allow(dead_code)]
+struct SigSys {
+    signo: libc::c_int,
+    errno: libc::c_int,
+    code: libc::c_int,
+    call_addr: usize,
+    syscall: libc::c_int,
+    arch: u32,
+}
+
+/// The `siginfo_t` of a fault
+#[repr(C)]
+#[allow(dead_code)]
+struct SigFault {
+    signo: libc::c_int,
+    errno: libc::c_int,
+    code: libc::c_int,
+    addr: usize,
+}
+
+/// The `ucontext_t` up to the extended state, whose registers start like `Context`
+#[repr(C)]
+#[allow(dead_code)]
+struct UContext {
+    flags: u64,
+    link: usize,
+    stack: SigStack,
+    regs: Context,
+    csgsfs: u64,
+    err: u64,
+    trapno: u64,
+    oldmask: u64,
+    cr2: u64,
+    fpstate: usize,
+}
+
+/// The software bytes the kernel writes to the extended state of a signal frame
+#[repr(C)]
+#[allow(dead_code)]
+struct SwBytes {
+    magic1: u32,
+    extended_size: u32,
+    xfeatures: u64,
+    xstate_size: u32,
+}
+
+impl UContext {
+    /// The extended state, which the kernel restores the payload with
+    ///
+    /// Without the magic in its software bytes, it is an FXSAVE image.
+    fn xstate<'a>(&self) -> &'a mut [u8] {
+        let fpstate = self.fpstate as *mut u8;
+        let sw = unsafe { &*(fpstate.add(SW_BYTES) as *const SwBytes) };
+
+        let size = match sw.magic1 {
+            FP_XSTATE_MAGIC1 => sw.xstate_size as usize,
+            _ => 512,
+        };
+
+        unsafe { core::slice::from_raw_parts_mut(fpstate, size) }
+    }
+}
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+/// The stack of the `SIGSYS` handler
+static mut STACK: Stack = Stack([0; STACK_SIZE]);
+
+/// The layout of the keep
+static mut LAYOUT: Option<&'static Layout> = None;
+
+const fn op(code: u16, jt: u8, jf: u8, k: u32) -> Filter {
+    Filter { code, jt, jf, k }
+}
+
+/// Installs the `SIGSYS` handler, the handlers of the faults and the seccomp filter
+///
+/// Exits if the kernel refuses any of them, because the payload would
+/// otherwise run its syscalls on the host kernel.
+pub fn install(layout: &'static Layout) {
+    unsafe { LAYOUT = Some(layout) };
+
+    let stack = SigStack {
+        sp: unsafe { STACK.0.as_ptr() } as usize,
+        flags: 0,
+        size: STACK_SIZE,
+    };
+
+    let action = |handler: usize| SigAction {
+        handler,
+        flags: (libc::SA_SIGINFO | libc::SA_ONSTACK) as u64 | SA_RESTORER,
+        restorer: restore as usize,
+        mask: !0,
+    };
+
+    let trap = action(sigsys as usize);
+    let fault = action(fault as usize);
+
+    let start = unsafe { &__ehdr_start as *const u8 } as u64;
+    let end = unsafe { &_end as *const u8 } as u64;
+    let (start_hi, start_lo) = ((start >> 32) as u32, start as u32);
+    let (end_hi, end_lo) = ((end >> 32) as u32, end as u32);
+
+    // Allow the syscall if `start <= ip < end`, else trap it.
+    let filter = [
+        op(LD_ABS, 0, 0, ARCH),
+        op(JEQ, 1, 0, AUDIT_ARCH_X86_64),
+        op(RET, 0, 0, SECCOMP_RET_KILL_PROCESS),
+        op(LD_ABS, 0, 0, IP_HI),
+        op(JGT, 3, 0, start_hi), // -> 8
+        op(JEQ, 0, 8, start_hi), // -> 14
+        op(LD_ABS, 0, 0, IP_LO),
+        op(JGE, 0, 6, start_lo), // -> 14
+        op(LD_ABS, 0, 0, IP_HI),
+        op(JGT, 4, 0, end_hi), // -> 14
+        op(JEQ, 0, 2, end_hi), // -> 13
+        op(LD_ABS, 0, 0, IP_LO),
+        op(JGE, 1, 0, end_lo), // -> 14
+        op(RET, 0, 0, SECCOMP_RET_ALLOW),
+        op(RET, 0, 0, SECCOMP_RET_TRAP),
+    ];
+
+    let program = Program {
+        len: filter.len() as u16,
+        filter: filter.as_ptr(),
+    };
+
+    let stack = &stack as *const SigStack as usize;
+    let trap = &trap as *const SigAction as usize;
+    let fault = &fault as *const SigAction as usize;
+    let program = &program as *const Program as usize;
+    let sigsys = libc::SIGSYS as usize;
+    let sigsegv = libc::SIGSEGV as usize;
+    let sigill = libc::SIGILL as usize;
+    let sigfpe = libc::SIGFPE as usize;
+    let mask = size_of::<u64>();
+
+    let calls = [
+        (libc::SYS_sigaltstack, [stack, 0, 0, 0, 0, 0]),
+        (libc::SYS_rt_sigaction, [sigsys, trap, 0, mask, 0, 0]),
+        (libc::SYS_rt_sigaction, [sigsegv, fault, 0, mask, 0, 0]),
+        (libc::SYS_rt_sigaction, [sigill, fault, 0, mask, 0, 0]),
+        (libc::SYS_rt_sigaction, [sigfpe, fault, 0, mask, 0, 0]),
+        (
+            libc::SYS_prctl,
+            [libc::PR_SET_NO_NEW_PRIVS as _, 1, 0, 0, 0, 0],
+        ),
+        (
+            libc::SYS_prctl,
+            [
+                libc::PR_SET_SECCOMP as _,
+                SECCOMP_MODE_FILTER,
+                program,
+                0,
+                0,
+                0,
+            ],
+        ),
+    ];
+
+    for (nr, args) in calls.iter() {
+        if unsafe { sys::syscall(*nr, *args) }.is_err() {
+            exit(1);
+        }
+    }
+}
+
+/// Returns from the `SIGSYS` handler
+#[naked]
+unsafe extern "C" fn restore() -> ! {
+    asm!(
+        "mov rax, {NR}",
+        "syscall",
+        NR = const libc::SYS_rt_sigreturn,
+        options(noreturn)
+    )
+}
+
+/// Handles a trapped syscall of the payload
+///
+/// Before the payload continues, it gets its pending signals.
+extern "C" fn sigsys(_signo: libc::c_int, info: &SigSys, ctx: &mut UContext) {
+    let layout = unsafe { LAYOUT.unwrap_or_else(|| exit(1)) };
+    let block = unsafe { &mut *(layout.block.start as *mut Block) };
+    let xstate = ctx.xstate();
+
+    let regs = &mut ctx.regs;
+    let argv = [
+        regs.rdi as usize,
+        regs.rsi as usize,
+        regs.rdx as usize,
+        regs.r10 as usize,
+        regs.r8 as usize,
+        regs.r9 as usize,
+    ];
+    let mut h = Handler::new(layout, block, argv, xstate);
+
+    match info.syscall as libc::c_long {
+        libc::SYS_rt_sigreturn => h.rt_sigreturn(regs),
+        nr => {
+            let ret = h.syscall(
+                argv[0].into(),
+                argv[1].into(),
+                argv[2].into(),
+                argv[3].into(),
+                argv[4].into(),
+                argv[5].into(),
+                nr as usize,
+            );
+
+            // Like the kernel, only the return value register is changed.
+            regs.rax = match ret {
+                Err(e) => -e as u64,
+                Ok([rax, _]) => rax.into(),
+            };
+        }
+    }
+
+    h.signal(regs);
+}
+
+/// Handles a fault of the payload
+///
+/// A fault of the shim itself ends the keep.
+extern "C" fn fault(signo: libc::c_int, info: &SigFault, ctx: &mut UContext) {
+    let layout = unsafe { LAYOUT.unwrap_or_else(|| exit(1)) };
+    let block = unsafe { &mut *(layout.block.start as *mut Block) };
+
+    let start = unsafe { &__ehdr_start as *const u8 } as u64;
+    let end = unsafe { &_end as *const u8 } as u64;
+    if (start..end).contains(&ctx.regs.rip) {
+        exit(1);
+    }
+
+    let mut h = Handler::new(layout, block, [0; 6], ctx.xstate());
+    h.fault(&mut ctx.regs, signo, info.code, info.addr);
+    h.signal(&mut ctx.regs);
+}
@@ -2,12 +2,7 @@
 
 //! Some basic address operations
 
-/// The offset of shim virtual address space to the physical address
-///
-/// physical address + `SHIM_VIRT_OFFSET` = shim virtual address
-///
-/// FIXME: change to dynamic offset with ASLR https://github.com/enarx/enarx/issues/624
-pub const SHIM_VIRT_OFFSET: u64 = 0xFFFF_FF80_0000_0000;
+pub use crate::hostlib::SHIM_VIRT_OFFSET;
 
 /// 2 MiB
 #[allow(clippy::integer_arithmetic)]
@@ -22,7 +22,7 @@ use x86_64::structures::paging::mapper::{MapToError, UnmapError};
 use x86_64::structures::paging::{
     self, Mapper, Page, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
 };
-use x86_64::{align_down, PhysAddr, VirtAddr};
+use x86_64::{align_down, align_up, PhysAddr, VirtAddr};
 
 /// An aligned 2MiB Page
 ///
@@ -149,13 +149,16 @@ impl EnarxAllocator {
 
         debug_assert_ne!(boot_info.mem_size, 0);
 
-        let free_start_phys = Address::<usize, _>::from(boot_info.code.end as *const u8);
+        // The keep manifest is the last thing the loader placed in memory
+        let free_start = align_up(boot_info.manifest.end as _, Page4KiB::size() as _) as usize;
+
+        let free_start_phys = Address::<usize, _>::from(free_start as *const u8);
         let shim_phys_page = ShimPhysAddr::from(free_start_phys);
-        let free_start: *mut u8 = ShimVirtAddr::from(shim_phys_page).into();
+        let free_start_virt: *mut u8 = ShimVirtAddr::from(shim_phys_page).into();
 
-        let heap_size = boot_info.mem_size.checked_sub(boot_info.code.end).unwrap();
+        let heap_size = boot_info.mem_size.checked_sub(free_start).unwrap();
 
-        let allocator = Heap::new(free_start as _, heap_size);
+        let allocator = Heap::new(free_start_virt as _, heap_size);
 
         EnarxAllocator {
             next_alloc,
@@ -0,0 +1,235 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exceptions of the payload
+//!
+//! A divide error, an invalid opcode, a general protection fault or a page
+//! fault of the payload raises SIGFPE, SIGILL or SIGSEGV in it. All other
+//! exceptions and interrupts have no gate and still end the keep with a
+//! triple fault, like every exception of the shim itself.
+
+use crate::gdt::KERNEL_CODE_SEGMENT;
+use crate::syscall::{fault, Frame};
+use core::mem::size_of;
+use spinning::Lazy;
+use syscall::{FPE_INTDIV, ILL_ILLOPN, SEGV_ACCERR, SEGV_MAPERR, SI_KERNEL};
+use x86_64::instructions::tables::lidt;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+/// The vector of the divide error
+const DIVIDE_ERROR: u64 = 0;
+/// The vector of the invalid opcode exception
+const INVALID_OPCODE: u64 = 6;
+/// The vector of the general protection fault
+const GENERAL_PROTECTION: u64 = 13;
+/// The vector of the page fault
+const PAGE_FAULT: u64 = 14;
+
+/// The bit of the error code of a page fault set for a present page
+const PAGE_FAULT_PROTECTION: u64 = 1;
+
+/// An interrupt gate of the IDT
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Gate {
+    offset_low: u16,
+    selector: u16,
+    options: u16,
+    offset_middle: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl Gate {
+    /// A gate, which is not present
+    const MISSING: Self = Self {
+        offset_low: 0,
+        selector: 0,
+        options: 0,
+        offset_middle: 0,
+        offset_high: 0,
+        reserved: 0,
+    };
+
+    /// A present interrupt gate to `handler` on the current stack
+    ///
+    /// Exceptions of the payload switch to the main kernel stack of the
+    /// CPU, like `syscall`, so the handler can use `gdt::cpu()`.
+    fn new(handler: unsafe extern "sysv64" fn() -> !) -> Self {
+        let [a, b, c, d, e, f, g, h] = (handler as usize as u64).to_le_bytes();
+
+        Self {
+            offset_low: u16::from_le_bytes([a, b]),
+            selector: KERNEL_CODE_SEGMENT as u16,
+            options: 0x8e00,
+            offset_middle: u16::from_le_bytes([c, d]),
+            offset_high: u32::from_le_bytes([e, f, g, h]),
+            reserved: 0,
+        }
+    }
+}
+
+/// The IDT shared by all CPUs
+#[repr(C, align(16))]
+struct Idt([Gate; 256]);
+
+static IDT: Lazy<Idt> = Lazy::new(|| {
+    let mut idt = Idt([Gate::MISSING; 256]);
+
+    idt.0[DIVIDE_ERROR as usize] = Gate::new(divide_error);
+    idt.0[INVALID_OPCODE as usize] = Gate::new(invalid_opcode);
+    idt.0[GENERAL_PROTECTION as usize] = Gate::new(general_protection);
+    idt.0[PAGE_FAULT as usize] = Gate::new(page_fault);
+
+    idt
+});
+
+/// Defines the entry of an exception, which jumps to `exception_common`
+///
+/// The entry saves `rbx` and `rbp`, to pass the error code of the
+/// exception, or zero, in `rbx` and its vector in `rbp`.
+macro_rules! exception {
+    (@entry $(#[$attr:meta])* $name:ident, $vector:expr, $save:literal) => {
+        $(#[$attr])*
+        ///
+        /// # Safety
+        ///
+        /// This function is not be called from rust.
+        #[inline(never)]
+        #[naked]
+        unsafe extern "sysv64" fn $name() -> ! {
+            asm!(
+                $save,
+                "push rbp",
+                "mov ebp, {VECTOR}",
+                "jmp {common}",
+                VECTOR = const $vector,
+                common = sym exception_common,
+                options(noreturn)
+            );
+        }
+    };
+
+    ($(#[$attr:meta])* error $name:ident, $vector:expr) => {
+        exception!(@entry $(#[$attr])* $name, $vector, "xchg rbx, QWORD PTR [rsp]");
+    };
+
+    ($(#[$attr:meta])* $name:ident, $vector:expr) => {
+        exception!(@entry $(#[$attr])* $name, $vector, "push rbx\nxor ebx, ebx");
+    };
+}
+
+exception!(
+    /// The entry of the divide error
+    divide_error,
+    DIVIDE_ERROR
+);
+exception!(
+    /// The entry of the invalid opcode exception
+    invalid_opcode,
+    INVALID_OPCODE
+);
+exception!(
+    /// The entry of the general protection fault
+    error general_protection,
+    GENERAL_PROTECTION
+);
+exception!(
+    /// The entry of the page fault
+    error page_fault,
+    PAGE_FAULT
+);
+
+/// The part of the exception entries shared by all
+///
+/// Completes the `Frame` of the interrupted code, which `fault_rust` may
+/// change, and returns to it.
+///
+/// # Safety
+///
+/// This function is not be called from rust.
+#[inline(never)]
+#[naked]
+unsafe extern "sysv64" fn exception_common() -> ! {
+    asm!("
+    push   r12
+    push   r13
+    push   r14
+    push   r15
+    push   rcx
+    push   rdi
+    push   rsi
+    push   rdx
+    push   r11
+    push   r10
+    push   r8
+    push   r9
+    push   rax
+
+    # the `Frame`, the vector and the error code as arguments
+    cld
+    mov    rdi,                     rsp
+    mov    rsi,                     rbp
+    mov    rdx,                     rbx
+    call   {fault_rust}
+
+    pop    rax
+    pop    r9
+    pop    r8
+    pop    r10
+    pop    r11
+    pop    rdx
+    pop    rsi
+    pop    rdi
+    pop    rcx
+    pop    r15
+    pop    r14
+    pop    r13
+    pop    r12
+    pop    rbp
+    pop    rbx
+
+    iretq
+    ",
+    fault_rust = sym fault_rust,
+    options(noreturn)
+    );
+}
+
+/// Handle an exception in rust
+extern "sysv64" fn fault_rust(frame: &mut Frame, vector: u64, error: u64) {
+    if frame.cs & 3 == 0 {
+        panic!(
+            "exception {} with error code {:#x} in the shim at {:#x}",
+            vector, error, frame.rip
+        );
+    }
+
+    let rip = frame.rip as usize;
+
+    match vector {
+        DIVIDE_ERROR => fault(frame, libc::SIGFPE, FPE_INTDIV, rip),
+        INVALID_OPCODE => fault(frame, libc::SIGILL, ILL_ILLOPN, rip),
+        GENERAL_PROTECTION => fault(frame, libc::SIGSEGV, SI_KERNEL, 0),
+        PAGE_FAULT => {
+            let code = match error & PAGE_FAULT_PROTECTION {
+                0 => SEGV_MAPERR,
+                _ => SEGV_ACCERR,
+            };
+
+            fault(frame, libc::SIGSEGV, code, Cr2::read().as_u64() as usize)
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Load the IDT on the calling CPU
+pub fn init() {
+    let dtp = DescriptorTablePointer {
+        limit: size_of::<Idt>().checked_sub(1).unwrap() as u16,
+        base: VirtAddr::new(&*IDT as *const Idt as u64),
+    };
+
+    unsafe { lidt(&dtp) };
+}
@@ -2,9 +2,9 @@
 
 //! Global Descriptor Table init
 
+use crate::hostlib::MAX_CPUS;
 use crate::shim_stack::{init_stack_with_guard, GuardedStack};
 use crate::syscall::_syscall_enter;
-use core::ops::Deref;
 use nbytes::bytes;
 use spinning::Lazy;
 use x86_64::instructions::segmentation::{load_ds, load_es, load_fs, load_gs, load_ss, set_cs};
@@ -23,6 +23,12 @@ pub const SHIM_STACK_START: u64 = 0xFFFF_FF48_4800_0000;
 #[allow(clippy::integer_arithmetic)]
 pub const SHIM_STACK_SIZE: u64 = bytes![8; MiB];
 
+/// The distance of the main kernel stacks of two CPUs
+///
+/// Leaves room for the guard pages and keeps every stack 2 MiB aligned.
+#[allow(clippy::integer_arithmetic)]
+const SHIM_STACK_STRIDE: u64 = SHIM_STACK_SIZE + bytes![2; MiB];
+
 /// The virtual address of the exception kernel stacks
 pub const SHIM_EX_STACK_START: u64 = 0xFFFF_FF48_F000_0000;
 
@@ -31,42 +37,77 @@ pub const SHIM_EX_STACK_START: u64 = 0xFFFF_FF48_F000_0000;
 pub const SHIM_EX_STACK_SIZE: u64 = bytes![4; KiB];
 
 /// The initial shim stack
-pub static INITIAL_STACK: Lazy<GuardedStack> = Lazy::new(|| {
+pub static INITIAL_STACK: Lazy<GuardedStack> = Lazy::new(|| stack(0));
+
+/// Allocates the main kernel stack of a CPU
+pub fn stack(cpu: usize) -> GuardedStack {
+    let offset = SHIM_STACK_STRIDE.checked_mul(cpu as _).unwrap();
+
     init_stack_with_guard(
-        VirtAddr::new(SHIM_STACK_START),
+        VirtAddr::new(SHIM_STACK_START.checked_add(offset).unwrap()),
         SHIM_STACK_SIZE,
         PageTableFlags::empty(),
     )
-});
+}
+
+/// The stack pointer of the main kernel stack of a CPU
+pub fn stack_pointer(cpu: usize) -> VirtAddr {
+    let offset = SHIM_STACK_STRIDE.checked_mul(cpu as _).unwrap();
+    let start = SHIM_STACK_START.checked_add(offset).unwrap();
+    VirtAddr::new(start.checked_add(SHIM_STACK_SIZE).unwrap())
+}
+
+/// The number of the running CPU
+///
+/// Only valid on the main kernel stack of the CPU, where the shim runs
+/// besides handling exceptions.
+pub fn cpu() -> usize {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+
+    let offset = rsp.checked_sub(SHIM_STACK_START).unwrap();
+    offset.checked_div(SHIM_STACK_STRIDE).unwrap() as usize
+}
+
+const NO_TSS: TaskStateSegment = TaskStateSegment::new();
+const NO_GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+
+/// The TSS of every CPU
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [NO_TSS; MAX_CPUS];
+
+/// The GDT of every CPU
+static mut GDT: [GlobalDescriptorTable; MAX_CPUS] = [NO_GDT; MAX_CPUS];
 
-/// The global TSS
-pub static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
+/// Creates the TSS of a CPU entering the shim on the kernel `stack`
+fn create_tss(cpu: usize, stack: VirtAddr) -> TaskStateSegment {
     let mut tss = TaskStateSegment::new();
 
-    tss.privilege_stack_table[0] = INITIAL_STACK.pointer;
-
-    // Assign the stacks for the exceptions and interrupts
-    unsafe {
-        tss.interrupt_stack_table
-            .iter_mut()
-            .enumerate()
-            .for_each(|(idx, p)| {
-                let offset: u64 = align_up(
-                    SHIM_EX_STACK_SIZE
-                        .checked_add(Page::<Size4KiB>::SIZE.checked_mul(2).unwrap())
-                        .unwrap(),
-                    Page::<Size2MiB>::SIZE,
-                );
-
-                let stack_offset = offset.checked_mul(idx as _).unwrap();
-                let start = VirtAddr::new(SHIM_EX_STACK_START.checked_add(stack_offset).unwrap());
-
-                *p = init_stack_with_guard(start, SHIM_EX_STACK_SIZE, PageTableFlags::empty())
-                    .pointer;
-            });
-    }
+    tss.privilege_stack_table[0] = stack;
+
+    // Assign the stacks for the exceptions and interrupts, after the ones
+    // of the CPUs before
+    let first = cpu.checked_mul(tss.interrupt_stack_table.len()).unwrap();
+
+    tss.interrupt_stack_table
+        .iter_mut()
+        .enumerate()
+        .for_each(|(idx, p)| {
+            let offset: u64 = align_up(
+                SHIM_EX_STACK_SIZE
+                    .checked_add(Page::<Size4KiB>::SIZE.checked_mul(2).unwrap())
+                    .unwrap(),
+                Page::<Size2MiB>::SIZE,
+            );
+
+            let idx = first.checked_add(idx).unwrap();
+            let stack_offset = offset.checked_mul(idx as _).unwrap();
+            let start = VirtAddr::new(SHIM_EX_STACK_START.checked_add(stack_offset).unwrap());
+
+            *p = init_stack_with_guard(start, SHIM_EX_STACK_SIZE, PageTableFlags::empty()).pointer;
+        });
+
     tss
-});
+}
 
 /// The Selectors used in the GDT setup
 pub struct Selectors {
@@ -82,13 +123,14 @@ pub struct Selectors {
     pub tss: SegmentSelector,
 }
 
-/// The global GDT
-pub static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
+/// Creates the GDT of a CPU with its `tss`
+fn create_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
     let mut gdt = GlobalDescriptorTable::new();
 
     // `syscall` loads segments from STAR MSR assuming a data_segment follows `kernel_code_segment`
     // so the ordering is crucial here. Star::write() will panic otherwise later.
     let code = gdt.add_entry(Descriptor::kernel_code_segment());
+    debug_assert_eq!(KERNEL_CODE_SEGMENT, code.0 as u64);
 
     let data = gdt.add_entry(Descriptor::kernel_data_segment());
 
@@ -100,8 +142,7 @@ pub static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
     let user_code = gdt.add_entry(Descriptor::user_code_segment());
     debug_assert_eq!(USER_CODE_SEGMENT, user_code.0 as u64);
 
-    // Important: TSS.deref() != &TSS because of lazy_static
-    let tss = gdt.add_entry(Descriptor::tss_segment(TSS.deref()));
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
 
     let selectors = Selectors {
         code,
@@ -112,7 +153,16 @@ pub static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
     };
 
     (gdt, selectors)
-});
+}
+
+/// The kernel code segment
+///
+/// For simplicity reasons, this is a constant in the gates of the IDT
+/// and here for debug_assert!()
+const KERNEL_CODE_SEGMENT_INDEX: u64 = 1;
+/// The Kernel Code Segment as a constant to be used in the IDT
+pub const KERNEL_CODE_SEGMENT: u64 =
+    (KERNEL_CODE_SEGMENT_INDEX << 3) | (PrivilegeLevel::Ring0 as u64);
 
 /// The user data segment
 ///
@@ -130,22 +180,31 @@ const USER_CODE_SEGMENT_INDEX: u64 = 4;
 /// The User Code Segment as a constant to be used in asm!() blocks
 pub const USER_CODE_SEGMENT: u64 = (USER_CODE_SEGMENT_INDEX << 3) | (PrivilegeLevel::Ring3 as u64);
 
-/// Initialize the GDT
+/// Initialize the GDT and the TSS of a CPU
+///
+/// The CPU enters the shim from the payload on the kernel `stack`.
 ///
 /// # Safety
 ///
-/// `unsafe` because the caller has to ensure it is only called once
-/// and in a single-threaded context.
-pub unsafe fn init() {
+/// `unsafe` because the caller has to ensure it is only called on that
+/// CPU, whenever it boots.
+pub unsafe fn init(cpu: usize, stack: VirtAddr) {
     #[cfg(debug_assertions)]
-    crate::eprintln!("init_gdt");
+    crate::eprintln!("init_gdt {}", cpu);
 
-    GDT.0.load();
+    // A CPU started again keeps the exception stacks of its TSS.
+    if TSS[cpu].privilege_stack_table[0] != stack {
+        TSS[cpu] = create_tss(cpu, stack);
+    }
+
+    let (gdt, selectors) = create_gdt(&TSS[cpu]);
+    GDT[cpu] = gdt;
+    GDT[cpu].load();
 
     // Setup the segment registers with the corresponding selectors
-    set_cs(GDT.1.code);
-    load_ss(GDT.1.data);
-    load_tss(GDT.1.tss);
+    set_cs(selectors.code);
+    load_ss(selectors.data);
+    load_tss(selectors.tss);
 
     // Clear the other segment registers
     load_ss(SegmentSelector(0));
@@ -155,7 +214,13 @@ pub unsafe fn init() {
     load_gs(SegmentSelector(0));
 
     // Set the selectors to be set when userspace uses `syscall`
-    Star::write(GDT.1.user_code, GDT.1.user_data, GDT.1.code, GDT.1.data).unwrap();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.code,
+        selectors.data,
+    )
+    .unwrap();
 
     // Set the pointer to the function to be called when userspace uses `syscall`
     LStar::write(VirtAddr::new(_syscall_enter as usize as u64));
@@ -164,6 +229,5 @@ pub unsafe fn init() {
     SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG);
 
     // Set the kernel gs base to the TSS to be used in `_syscall_enter`
-    // Important: TSS.deref() != &TSS because of lazy_static
-    KernelGsBase::write(VirtAddr::new(TSS.deref() as *const _ as u64));
+    KernelGsBase::write(VirtAddr::new(&TSS[cpu] as *const _ as u64));
 }
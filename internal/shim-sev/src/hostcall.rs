@@ -4,7 +4,7 @@
 
 use crate::addr::{HostVirtAddr, ShimPhysUnencryptedAddr};
 use crate::asm::_enarx_asm_triple_fault;
-use crate::hostlib::{MemInfo, SYSCALL_TRIGGER_PORT};
+use crate::hostlib::{Frame, MemInfo, SYSCALL_TRIGGER_PORT};
 use crate::spin::RwLocked;
 use crate::{BOOT_INFO, SHIM_HOSTCALL_PHYS_ADDR};
 use array_const_fn_init::array_const_fn_init;
@@ -12,7 +12,7 @@ use core::convert::TryFrom;
 use primordial::{Address, Register};
 use sallyport::{request, Block};
 use spinning::Lazy;
-use syscall::{SYS_ENARX_BALLOON_MEMORY, SYS_ENARX_MEM_INFO};
+use syscall::{SYS_ENARX_BACKTRACE, SYS_ENARX_BALLOON_MEMORY, SYS_ENARX_MEM_INFO, SYS_ENARX_SPAWN};
 use x86_64::instructions::port::Port;
 
 /// Host file descriptor
@@ -165,6 +165,23 @@ impl HostCall {
         Ok(mem_info)
     }
 
+    /// Report the `frames` of a backtrace for the host to print
+    pub fn backtrace(&mut self, frames: &[Frame]) -> sallyport::Result {
+        let cursor = self.block.as_mut().unwrap().cursor();
+        cursor.copy_from_slice(frames).or(Err(libc::EMSGSIZE))?;
+
+        self.block.as_mut().unwrap().msg.req = request!(SYS_ENARX_BACKTRACE => frames.len());
+        unsafe { self.hostcall() }
+    }
+
+    /// Ask the host for another vCPU, starting with the page tables at `cr3`
+    ///
+    /// Returns the number of the vCPU, a parked or a new one.
+    pub fn spawn(&mut self, cr3: u64) -> Result<usize, libc::c_int> {
+        self.block.as_mut().unwrap().msg.req = request!(SYS_ENARX_SPAWN => cr3 as usize);
+        Ok(unsafe { self.hostcall() }?[0].into())
+    }
+
     /// Exit the shim with a `status` code
     ///
     /// # Panics
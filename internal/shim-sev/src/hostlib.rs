@@ -11,10 +11,15 @@
 // * `%rdi` = `SYSCALL_PHYS_ADDR`, address of the page, where the loader placed a copy of `BootInfo`
 //            and which is used later on for the communication with the shim.
 // * `%rsi` = the start address of the shim memory (contents of `BootInfo.shim.start`)
+// * `%rdx` = the number of the vCPU, zero for the first one
 // * `%rip` = the address of the shim entry point taken from the elf header
 //
 // Although `%rsi` is redundant, it makes the initial startup function of the `shim` much easier.
 //
+// The loader starts further vCPUs only on request of the shim, with `SYS_ENARX_SPAWN`. They get
+// the same registers and their `%cr3` set to the page tables the shim passes along. The vCPU of
+// a thread that exited is started again before the loader creates a new one.
+//
 // # Shim
 //
 // The shim sets the unencrypted flag for the page at `SYSCALL_PHYS_ADDR` and uses that page
@@ -35,6 +40,13 @@
 /// FIXME: might change to another mechanism in the future
 pub const SYSCALL_TRIGGER_PORT: u16 = 0xFF;
 
+/// The offset of shim virtual address space to the physical address
+///
+/// physical address + `SHIM_VIRT_OFFSET` = shim virtual address
+///
+/// FIXME: change to dynamic offset with ASLR https://github.com/enarx/enarx/issues/624
+pub const SHIM_VIRT_OFFSET: u64 = 0xFFFF_FF80_0000_0000;
+
 use core::mem::{align_of, size_of, MaybeUninit};
 use lset::{Line, Span};
 use nbytes::bytes;
@@ -66,6 +78,10 @@ fn above(rel: impl Into<Line<usize>>, size: usize, align: usize) -> Option<Span<
     })
 }
 
+/// The maximum size of the initial payload stack
+#[allow(clippy::integer_arithmetic)]
+pub const MAX_STACK_SIZE: usize = bytes!(1; GiB);
+
 /// The maximum size of the injected secret for SEV keeps
 #[allow(clippy::integer_arithmetic)]
 pub const SEV_SECRET_MAX_SIZE: usize = bytes!(16; KiB);
@@ -100,10 +116,26 @@ pub struct BootInfo {
     pub shim: Line<usize>,
     /// Memory where the `code` is / has to be loaded
     pub code: Line<usize>,
+    /// Memory where the payload arguments and environment are / have to be loaded
+    ///
+    /// The region contains `argc` followed by `envc` NUL terminated strings.
+    pub args: Line<usize>,
+    /// Number of payload arguments, not counting `argv[0]`
+    pub argc: usize,
+    /// Number of payload environment variables
+    pub envc: usize,
+    /// Memory where the keep manifest is / has to be loaded
+    pub manifest: Line<usize>,
+    /// Size of the initial payload stack, or zero for the default
+    pub stack_size: usize,
     /// Memory size
     pub mem_size: usize,
     /// Number of `sallyport::Block` provided
     pub nr_syscall_blocks: usize,
+    /// NUL padded mount point of the tmpfs, or all zeros without one
+    pub tmpfs: [u8; 32],
+    /// NUL padded prefix of the sealed files, or all zeros without one
+    pub sealed: [u8; 32],
 }
 
 /// Basic information about the host memory
@@ -128,6 +160,22 @@ impl core::fmt::Debug for MemInfo {
     }
 }
 
+/// The maximum number of vCPUs of a keep
+pub const MAX_CPUS: usize = 64;
+
+/// The maximum number of frames of a backtrace
+pub const MAX_FRAMES: usize = 64;
+
+/// A frame of a backtrace, as reported with `SYS_ENARX_BACKTRACE`
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Non-zero if the address belongs to the payload instead of the shim
+    pub payload: usize,
+    /// Address relative to the virtual address the binary was linked at
+    pub offset: usize,
+}
+
 /// Error returned, if the virtual machine memory is to small for the shim to operate.
 ///
 /// Because of `no_std` it does not implement `std::error::Error`.
@@ -137,8 +185,8 @@ impl BootInfo {
     /// Calculates the memory layout of various components
     ///
     /// Given the size of the available memory `mem_size`, the addresses of `setup`
-    /// and the size of `shim` and `code`, this function calculates
-    /// the layout for the `shim` and `code`.
+    /// and the size of `shim`, `code`, `args` and `manifest`, this function calculates
+    /// the layout for the `shim`, `code`, `args` and `manifest`.
     ///
     /// # Errors
     ///
@@ -148,6 +196,8 @@ impl BootInfo {
         setup: Line<usize>,
         shim: Span<usize>,
         code: Span<usize>,
+        args: usize,
+        manifest: usize,
     ) -> Result<Self, NoMemory> {
         debug_assert!(
             setup.end < MAX_SETUP_SIZE,
@@ -164,14 +214,27 @@ impl BootInfo {
             .ok_or(NoMemory(()))?
             .into();
 
-        let mem_size = raise(code.end, Page::size()).ok_or(NoMemory(()))?;
+        let args: Line<usize> = above(code, args, Page::size()).ok_or(NoMemory(()))?.into();
+
+        let manifest: Line<usize> = above(args, manifest, Page::size())
+            .ok_or(NoMemory(()))?
+            .into();
+
+        let mem_size = raise(manifest.end, Page::size()).ok_or(NoMemory(()))?;
 
         Ok(Self {
             setup,
             shim,
             code,
+            args,
+            argc: 0,
+            envc: 0,
+            manifest,
+            stack_size: 0,
             mem_size,
             nr_syscall_blocks: 0,
+            tmpfs: [0; 32],
+            sealed: [0; 32],
         })
     }
 }
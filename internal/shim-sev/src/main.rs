@@ -22,6 +22,7 @@ pub mod addr;
 pub mod allocator;
 pub mod asm;
 pub mod attestation;
+pub mod exception;
 pub mod gdt;
 pub mod hostcall;
 /// Shared components for the shim and the loader
@@ -33,6 +34,7 @@ pub mod paging;
 pub mod payload;
 pub mod random;
 pub mod shim_stack;
+pub mod smp;
 pub mod spin;
 mod start;
 pub mod syscall;
@@ -40,7 +42,8 @@ pub mod usermode;
 
 use crate::addr::{ShimPhysUnencryptedAddr, ShimVirtAddr, SHIM_VIRT_OFFSET};
 use crate::attestation::SEV_SECRET;
-use crate::hostlib::BootInfo;
+use crate::hostcall::HOST_CALL_ALLOC;
+use crate::hostlib::{BootInfo, Frame, MAX_FRAMES};
 use crate::pagetables::switch_sallyport_to_unencrypted;
 use crate::paging::SHIM_PAGETABLE;
 use crate::payload::PAYLOAD_VIRT_ADDR;
@@ -138,7 +141,8 @@ entry_point!(shim_main);
 
 /// The entry point for the shim
 pub extern "C" fn shim_main() -> ! {
-    unsafe { gdt::init() };
+    unsafe { gdt::init(0, gdt::INITIAL_STACK.pointer) };
+    exception::init();
     payload::execute_payload()
 }
 
@@ -178,8 +182,6 @@ unsafe fn stack_trace() {
 
     asm!("mov {}, rbp", out(reg) rbp);
 
-    print::_eprint(format_args!("TRACE:\n"));
-
     if SHIM_PAGETABLE.try_read().is_none() {
         SHIM_PAGETABLE.force_unlock_write()
     }
@@ -194,8 +196,10 @@ unsafe fn stack_trace() {
 
     let active_table = SHIM_PAGETABLE.read();
 
-    //Maximum 64 frames
-    for _frame in 0..64 {
+    let mut frames = [Frame::default(); MAX_FRAMES];
+    let mut count: usize = 0;
+
+    for frame in frames.iter_mut() {
         if let Some(rip_rbp) = rbp.checked_add(size_of::<usize>() as _) {
             if active_table
                 .translate_addr(VirtAddr::new(rbp as _))
@@ -211,16 +215,25 @@ unsafe fn stack_trace() {
                     }
 
                     if let Some(rip) = rip.checked_sub(shim_offset) {
-                        print::_eprint(format_args!("  0x{:>016x}\n", rip));
-                        rbp = *(rbp as *const usize);
+                        *frame = Frame {
+                            payload: 0,
+                            offset: rip,
+                        };
                     } else if PAYLOAD_READY.load(Ordering::Relaxed) {
                         if let Some(rip) = rip.checked_sub(PAYLOAD_VIRT_ADDR.read().as_u64() as _) {
-                            print::_eprint(format_args!("P 0x{:>016x}\n", rip));
-                            rbp = *(rbp as *const usize);
+                            *frame = Frame {
+                                payload: 1,
+                                offset: rip,
+                            };
                         } else {
                             break;
                         }
+                    } else {
+                        break;
                     }
+
+                    count = count.saturating_add(1);
+                    rbp = *(rbp as *const usize);
                 } else {
                     // RIP zero
                     break;
@@ -234,4 +247,18 @@ unsafe fn stack_trace() {
             break;
         }
     }
+
+    // Let the host symbolize the frames, or else print them raw.
+    let reported = HOST_CALL_ALLOC.try_alloc().map_or(false, |mut host_call| {
+        host_call.backtrace(&frames[..count]).is_ok()
+    });
+
+    if !reported {
+        print::_eprint(format_args!("TRACE:\n"));
+
+        for frame in &frames[..count] {
+            let prefix = if frame.payload == 0 { " " } else { "P" };
+            print::_eprint(format_args!("{} 0x{:>016x}\n", prefix, frame.offset));
+        }
+    }
 }
@@ -3,6 +3,7 @@
 //! Functions dealing with the payload
 use crate::addr::{ShimPhysAddr, ShimVirtAddr};
 use crate::allocator::ALLOCATOR;
+use crate::hostlib::MAX_STACK_SIZE;
 use crate::paging::SHIM_PAGETABLE;
 use crate::random::random;
 use crate::shim_stack::init_stack_with_guard;
@@ -15,10 +16,11 @@ use crt0stack::{self, Builder, Entry};
 use goblin::elf::header::header64::Header;
 use goblin::elf::header::ELFMAG;
 use goblin::elf::program_header::program_header64::*;
+use lset::Line;
 use nbytes::bytes;
 use primordial::Address;
 use spinning::{Lazy, RwLock};
-use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
 use x86_64::{PhysAddr, VirtAddr};
 
 /// Payload virtual address, where the elf binary is mapped to, plus a random offset
@@ -132,15 +134,51 @@ fn map_elf(app_virt_start: VirtAddr) -> &'static Header {
     header
 }
 
+/// A region the loader placed in memory, as seen by the shim
+fn loaded(region: Line<usize>) -> &'static [u8] {
+    let addr = Address::<usize, u8>::from(region.start as *const u8);
+    let phys = ShimPhysAddr::<u8>::from(addr);
+    let virt = ShimVirtAddr::from(phys);
+    let ptr: *const u8 = virt.into();
+
+    let len = region.end.checked_sub(region.start).unwrap();
+    unsafe { core::slice::from_raw_parts(ptr, len) }
+}
+
+/// The payload arguments and environment the loader placed in memory
+///
+/// Returns the raw NUL separated strings and the number of arguments
+/// and environment variables.
+fn args() -> (&'static [u8], usize, usize) {
+    let boot_info = BOOT_INFO.read().unwrap();
+    (loaded(boot_info.args), boot_info.argc, boot_info.envc)
+}
+
+/// The unmodified bytes of the keep manifest, empty without a manifest
+#[allow(dead_code)]
+pub fn manifest() -> &'static [u8] {
+    loaded(BOOT_INFO.read().unwrap().manifest)
+}
+
 fn crt0setup(
     app_virt_start: VirtAddr,
     stack_slice: &'static mut [u8],
     header: &Header,
 ) -> (VirtAddr, u64) {
+    let (args, argc, envc) = args();
+    let mut strings = args
+        .split(|b| *b == 0)
+        .map(|s| core::str::from_utf8(s).expect("Invalid payload arguments"));
+
     let mut builder = Builder::new(stack_slice);
     builder.push("/init").unwrap();
+    for arg in strings.by_ref().take(argc) {
+        builder.push(arg).unwrap();
+    }
     let mut builder = builder.done().unwrap();
-    builder.push("LANG=C").unwrap();
+    for env in strings.take(envc) {
+        builder.push(env).unwrap();
+    }
     let mut builder = builder.done().unwrap();
 
     let ph_header = app_virt_start + header.e_phoff;
@@ -182,9 +220,15 @@ fn crt0setup(
 pub fn execute_payload() -> ! {
     let header = map_elf(*PAYLOAD_VIRT_ADDR.read());
 
+    let stack_size = match BOOT_INFO.read().unwrap().stack_size {
+        0 => PAYLOAD_STACK_SIZE,
+        size if size <= MAX_STACK_SIZE => x86_64::align_up(size as _, Size4KiB::SIZE),
+        _ => panic!("Payload stack too large"),
+    };
+
     let stack = init_stack_with_guard(
         PAYLOAD_STACK_VIRT_ADDR_BASE + (random() & 0xFFFF_F000),
-        PAYLOAD_STACK_SIZE,
+        stack_size,
         PageTableFlags::USER_ACCESSIBLE,
     );
 
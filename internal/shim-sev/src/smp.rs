@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Application processors
+//!
+//! The host creates every vCPU besides the bootstrap processor on request of
+//! the shim. It enters `_start` like the bootstrap processor, but only sets
+//! up its own stacks, GDT and TSS before it runs what it was started for.
+//!
+//! The vCPU of a thread that exited is parked by the host, which starts it
+//! again for a later request. Such a CPU keeps the stacks it allocated.
+
+use crate::exception;
+use crate::gdt;
+use crate::hostcall::HOST_CALL_ALLOC;
+use crate::hostlib::MAX_CPUS;
+use crate::spin::Locked;
+use crate::start::AP_BOOT_LOCK;
+use crate::switch_shim_stack;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::registers::control::Cr3;
+
+/// A function for a CPU to run, with its argument
+pub type Start = (extern "C" fn(usize) -> !, usize);
+
+/// What the CPU started last is to run, until it took it
+///
+/// `spawn` holds the lock until the host replied, so a CPU never picks up
+/// what a failed request was for.
+static PENDING: Locked<Option<Start>> = Locked::new(None);
+
+/// Whether each CPU allocated its main kernel stack, when it first booted
+static BOOTED: Locked<[bool; MAX_CPUS]> = Locked::new([false; MAX_CPUS]);
+
+/// The number of the CPU holding `AP_BOOT_LOCK`
+static BOOTING: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts another CPU running `start`
+///
+/// The host picks a parked or a new vCPU. Returns the number of the CPU.
+pub fn spawn(start: Start) -> Result<usize, libc::c_int> {
+    // Wait for the CPU started last to pick up what it is to run.
+    let mut pending = loop {
+        let pending = PENDING.lock();
+        if pending.is_none() {
+            break pending;
+        }
+
+        drop(pending);
+        core::hint::spin_loop();
+    };
+
+    *pending = Some(start);
+
+    // The CPU starts with the page tables of the shim.
+    let cr3 = Cr3::read().0.start_address().as_u64();
+
+    let started = HOST_CALL_ALLOC
+        .try_alloc()
+        .ok_or(libc::EIO)
+        .and_then(|mut host_call| host_call.spawn(cr3))
+        .and_then(|cpu| match cpu > 0 && cpu < MAX_CPUS {
+            true => Ok(cpu),
+            false => Err(libc::EIO),
+        });
+
+    if started.is_err() {
+        *pending = None;
+    }
+
+    started
+}
+
+/// The entry point of the application processors, on the shared boot stack
+///
+/// # Safety
+///
+/// Only `_start` may call this, holding `AP_BOOT_LOCK`.
+#[export_name = "_start_ap_main"]
+pub unsafe extern "C" fn start_ap_main(cpu: usize) -> ! {
+    BOOTING.store(cpu, Ordering::Relaxed);
+
+    let mut booted = BOOTED.lock();
+    if !booted[cpu] {
+        gdt::stack(cpu);
+        booted[cpu] = true;
+    }
+    drop(booted);
+
+    switch_shim_stack(ap_main, gdt::stack_pointer(cpu).as_u64())
+}
+
+/// Runs what an application processor was started for, on its own stack
+extern "C" fn ap_main() -> ! {
+    let cpu = BOOTING.load(Ordering::Relaxed);
+
+    // Off the boot stack, so the next application processor may use it
+    AP_BOOT_LOCK.store(false, Ordering::Release);
+
+    unsafe { gdt::init(cpu, gdt::stack_pointer(cpu)) };
+    exception::init();
+
+    // The host must not start CPUs the shim did not ask for.
+    let start = PENDING.lock().take();
+    let (f, arg) = start.unwrap_or_else(|| panic!("CPU {} was never spawned", cpu));
+
+    f(arg)
+}
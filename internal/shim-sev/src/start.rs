@@ -5,6 +5,7 @@
 //! see [`_start`](_start)
 
 use crate::addr::SHIM_VIRT_OFFSET;
+use core::sync::atomic::AtomicBool;
 use primordial::Page;
 use rcrt1::_dyn_reloc;
 
@@ -16,6 +17,14 @@ const INITIAL_STACK_PAGES: usize = 50;
 #[no_mangle]
 static INITIAL_SHIM_STACK: [Page; INITIAL_STACK_PAGES] = [Page::zeroed(); INITIAL_STACK_PAGES];
 
+/// The stack the application processors boot on, one after another
+#[no_mangle]
+static AP_BOOT_STACK: [Page; INITIAL_STACK_PAGES] = [Page::zeroed(); INITIAL_STACK_PAGES];
+
+/// Held by the application processor booting on `AP_BOOT_STACK`
+#[no_mangle]
+pub static AP_BOOT_LOCK: AtomicBool = AtomicBool::new(false);
+
 /// The initial function called at startup
 ///
 /// It sets up essential registers, page tables and jumps in shim virtual address space
@@ -24,12 +33,20 @@ static INITIAL_SHIM_STACK: [Page; INITIAL_STACK_PAGES] = [Page::zeroed(); INITIA
 /// Arguments expected from the hypervisor:
 /// * %rdi  = address of SYSCALL_PAGE (boot_info)
 /// * %rsi  = shim load offset
+/// * %rdx  = number of the CPU, zero for the bootstrap processor
+///
+/// The application processors find the page tables and the relocations
+/// done and their `%cr3` set by the hypervisor. They call `_start_ap_main`
+/// on `AP_BOOT_STACK` with their number instead.
 #[allow(clippy::integer_arithmetic)]
 #[no_mangle]
 #[naked]
 pub unsafe extern "sysv64" fn _start() -> ! {
     asm!(
         "
+    // backup the CPU number to r13, before cpuid overwrites it
+    mov     r13,    rdx
+
     // Check if we have a valid (0x8000_001F) CPUID leaf
     mov     eax,    0x80000000
     cpuid
@@ -98,6 +115,10 @@ SevExit:
     mov     ecx,    0xc0000080
     wrmsr
 
+    // application processors skip the setup of the bootstrap processor
+    test    r13,    r13
+    jnz     ApStart
+
     // Setup the pagetables
     // done dynamically, otherwise we would have to correct the dynamic symbols twice
 
@@ -201,6 +222,33 @@ _trampoline:
     // arg1 %rdi  = address of SYSCALL_PAGE (boot_info)
     // arg2 %rsi  = SEV C-bit mask
     call    _start_main
+
+ApStart:
+    // advance rip to kernel address space with {SHIM_VIRT_OFFSET}
+    lea     rax,    [rip + _ap_trampoline]
+    mov     rbx,    {SHIM_VIRT_OFFSET}
+    adox    rax,    rbx
+    jmp     rax
+
+_ap_trampoline:
+    // wait for the boot stack of the application processors
+    mov     al,     1
+ApWaitStack:
+    xchg    al,     BYTE PTR [rip + AP_BOOT_LOCK]
+    test    al,     al
+    jz      ApBootStack
+    pause
+    jmp     ApWaitStack
+
+ApBootStack:
+    lea     rsp,    [rip + AP_BOOT_STACK]
+    add     rsp,    {SIZE_OF_INITIAL_STACK}
+
+    // call _start_ap_main
+    // arg1 %rdi  = number of the CPU
+    mov     rdi,    r13
+    xor     rbp,    rbp
+    call    _start_ap_main
     ",
     SHIM_VIRT_OFFSET = const SHIM_VIRT_OFFSET,
     SIZE_OF_INITIAL_STACK = const INITIAL_STACK_PAGES * 4096,
@@ -7,19 +7,26 @@ use crate::allocator::ALLOCATOR;
 use crate::asm::_enarx_asm_triple_fault;
 use crate::attestation::SEV_SECRET;
 use crate::hostcall::{HostCall, HOST_CALL_ALLOC};
+use crate::hostlib::MAX_CPUS;
 use crate::paging::SHIM_PAGETABLE;
 use crate::payload::{NEXT_BRK_RWLOCK, NEXT_MMAP_RWLOCK};
-use crate::{eprintln, C_BIT_MASK};
+use crate::spin::Locked;
+use crate::usermode::{usermode_with, Registers};
+use crate::{eprintln, gdt, smp, BOOT_INFO, C_BIT_MASK};
+use core::alloc::Layout;
 use core::convert::TryFrom;
 use core::mem::size_of;
 use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
 use core::sync::atomic::Ordering;
-use primordial::{Address, Register};
+use primordial::{Address, Page as Page4KiB, Register};
 use sallyport::{Cursor, Request};
+use spinning::RwLock;
 use syscall::{
-    BaseSyscallHandler, EnarxSyscallHandler, FileSyscallHandler, MemorySyscallHandler,
-    NetworkSyscallHandler, ProcessSyscallHandler, SyscallHandler, SystemSyscallHandler,
-    ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS, SEV_TECH,
+    BaseSyscallHandler, Context, EnarxSyscallHandler, FileSyscallHandler, MemorySyscallHandler,
+    NetworkSyscallHandler, NewThread, PageAlloc, ProcessSyscallHandler, Sealed, SyscallHandler,
+    SystemSyscallHandler, Thread, Tmpfs, ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS,
+    SEV_TECH,
 };
 use untrusted::{AddressValidator, UntrustedRef, UntrustedRefMut, Validate, ValidateSlice};
 use x86_64::instructions::tlb::flush_all;
@@ -27,11 +34,23 @@ use x86_64::registers::{rdfsbase, rdgsbase, wrfsbase, wrgsbase};
 use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
 use x86_64::{align_up, VirtAddr};
 
-#[repr(C)]
-struct X8664DoubleReturn {
-    rax: u64,
-    rdx: u64,
-}
+/// The in-memory filesystem served to the payload
+static TMPFS: RwLock<Tmpfs> =
+    RwLock::<Tmpfs>::const_new(spinning::RawRwLock::const_new(), Tmpfs::new());
+
+/// The open sealed files of the payload
+static SEALED: RwLock<Sealed> =
+    RwLock::<Sealed>::const_new(spinning::RawRwLock::const_new(), Sealed::new());
+
+/// The thread of the payload each CPU runs
+static THREADS: Locked<[Thread; MAX_CPUS]> = Locked::new([Thread::new(); MAX_CPUS]);
+
+/// The threads `clone` started, until their CPU took them
+static CLONES: Locked<[Option<(Thread, Registers)>; MAX_CPUS]> = Locked::new([None; MAX_CPUS]);
+
+/// An FXSAVE image
+#[repr(C, align(16))]
+struct FxSave([u8; 512]);
 
 /// syscall service routine
 ///
@@ -59,18 +78,14 @@ pub unsafe extern "sysv64" fn _syscall_enter() -> ! {
     push   {USER_CODE_SEGMENT}
     push   rcx                                         # push userspace return pointer
 
+    # save all other registers of the payload, completing the `Frame`
     push   rbx
-    mov    rbx, rsp
-
-    # Arguments in registers:
-    # SYSV:    rdi, rsi, rdx, rcx, r8, r9
-    # SYSCALL: rdi, rsi, rdx, r10, r8, r9 and syscall number in rax
-    mov    rcx,                     r10
-
-    # These will be preserved by `syscall_rust` via the SYS-V ABI
-    # rbx, rsp, rbp, r12, r13, r14, r15
-
-    # save registers
+    push   rbp
+    push   r12
+    push   r13
+    push   r14
+    push   r15
+    push   rcx
     push   rdi
     push   rsi
     push   rdx
@@ -78,29 +93,29 @@ pub unsafe extern "sysv64" fn _syscall_enter() -> ! {
     push   r10
     push   r8
     push   r9
-
-    # syscall number on the stack as the seventh argument
     push   rax
 
+    # the `Frame` as the first argument
+    mov    rdi,                     rsp
     call   {syscall_rust}
 
-    # skip %rax pop, as it is the return value
-    add    rsp,                     0x8
-
-    # restore registers
+    # return to the registers in the `Frame`, which `syscall_rust` changed
+    pop    rax
     pop    r9
     pop    r8
     pop    r10
     pop    r11
-    add    rsp,                     0x8               # skip rdx, because it is a return value
+    pop    rdx
     pop    rsi
     pop    rdi
-
+    pop    rcx
+    pop    r15
+    pop    r14
+    pop    r13
+    pop    r12
+    pop    rbp
     pop    rbx
 
-    xor    rcx,                     rcx               # do not leak contents to userspace
-    xor    r11,                     r11               # do not leak contents to userspace
-
     swapgs                                            # restore gs
 
     iretq
@@ -114,46 +129,181 @@ pub unsafe extern "sysv64" fn _syscall_enter() -> ! {
     );
 }
 
+/// The registers of the payload `_syscall_enter` and the exception handlers save
+///
+/// The stack frame of `iretq` is on top. After `syscall`, `rcx` and `r11`
+/// hold `rip` and `rflags`.
+#[repr(C)]
+#[derive(Debug)]
+#[allow(missing_docs)]
+pub struct Frame {
+    pub rax: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rcx: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl Frame {
+    /// The registers of the payload
+    fn context(&self) -> Context {
+        Context {
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rdi: self.rdi,
+            rsi: self.rsi,
+            rbp: self.rbp,
+            rbx: self.rbx,
+            rdx: self.rdx,
+            rax: self.rax,
+            rcx: self.rcx,
+            rsp: self.rsp,
+            rip: self.rip,
+            rflags: self.rflags,
+        }
+    }
+
+    /// Lets the payload continue with the registers in `ctx`
+    fn restore(&mut self, ctx: &Context) {
+        self.r8 = ctx.r8;
+        self.r9 = ctx.r9;
+        self.r10 = ctx.r10;
+        self.r11 = ctx.r11;
+        self.r12 = ctx.r12;
+        self.r13 = ctx.r13;
+        self.r14 = ctx.r14;
+        self.r15 = ctx.r15;
+        self.rdi = ctx.rdi;
+        self.rsi = ctx.rsi;
+        self.rbp = ctx.rbp;
+        self.rbx = ctx.rbx;
+        self.rdx = ctx.rdx;
+        self.rax = ctx.rax;
+        self.rcx = ctx.rcx;
+        self.rsp = ctx.rsp;
+        self.rip = ctx.rip;
+        self.rflags = ctx.rflags;
+    }
+}
+
 /// Handle a syscall in rust
-#[allow(clippy::many_single_char_names)]
-extern "sysv64" fn syscall_rust(
-    a: Register<usize>,
-    b: Register<usize>,
-    c: Register<usize>,
-    d: Register<usize>,
-    e: Register<usize>,
-    f: Register<usize>,
-    nr: usize,
-) -> X8664DoubleReturn {
-    let orig_rdx: usize = c.into();
-
-    let mut h = Handler {
-        hostcall: HOST_CALL_ALLOC.try_alloc().unwrap(),
-        argv: [a.into(), b.into(), c.into(), d.into(), e.into(), f.into()],
-    };
-
-    let ret = h.syscall(a, b, c, d, e, f, nr);
-
-    match ret {
-        Err(e) => X8664DoubleReturn {
-            rax: e.checked_neg().unwrap() as _,
-            // Preserve `rdx` as it is normally not clobbered with a syscall
-            rdx: orig_rdx as _,
-        },
-        Ok([rax, rdx]) => X8664DoubleReturn {
-            rax: rax.into(),
-            rdx: rdx.into(),
-        },
+///
+/// Before the payload continues, it gets its pending signals.
+extern "sysv64" fn syscall_rust(frame: &mut Frame) {
+    let mut ctx = frame.context();
+
+    {
+        let argv = [
+            frame.rdi as usize,
+            frame.rsi as usize,
+            frame.rdx as usize,
+            frame.r10 as usize,
+            frame.r8 as usize,
+            frame.r9 as usize,
+        ];
+        let nr = frame.rax as usize;
+
+        let mut h = Handler {
+            hostcall: HOST_CALL_ALLOC.try_alloc().unwrap(),
+            argv,
+            frame,
+        };
+
+        match nr as libc::c_long {
+            libc::SYS_rt_sigreturn => h.rt_sigreturn(&mut ctx),
+            _ => {
+                let [a, b, c, d, e, f] = argv;
+                let ret = h.syscall(
+                    a.into(),
+                    b.into(),
+                    c.into(),
+                    d.into(),
+                    e.into(),
+                    f.into(),
+                    nr,
+                );
+
+                match ret {
+                    // `rdx` is normally not clobbered with a syscall
+                    Err(e) => ctx.rax = e.checked_neg().unwrap() as _,
+                    Ok([rax, rdx]) => {
+                        ctx.rax = rax.into();
+                        ctx.rdx = rdx.into();
+                    }
+                }
+            }
+        }
+
+        h.signal(&mut ctx);
+        h.leave(&ctx);
     }
+
+    frame.restore(&ctx);
+}
+
+/// Raises `sig` in the payload for a fault at the registers in `frame`
+///
+/// `code` and `addr` are the `si_code` and `si_addr` of the signal.
+pub fn fault(frame: &mut Frame, sig: libc::c_int, code: libc::c_int, addr: usize) {
+    let mut ctx = frame.context();
+
+    {
+        let mut h = Handler {
+            hostcall: HOST_CALL_ALLOC.try_alloc().unwrap(),
+            argv: [0; 6],
+            frame,
+        };
+
+        h.fault(&mut ctx, sig, code, addr);
+        h.signal(&mut ctx);
+        h.leave(&ctx);
+    }
+
+    frame.restore(&ctx);
 }
 
 /// The syscall Handler
-struct Handler {
+struct Handler<'a> {
     hostcall: HostCall,
     argv: [usize; 6],
+    frame: &'a Frame,
+}
+
+impl<'a> Handler<'a> {
+    /// Checks the registers the payload continues with
+    ///
+    /// A handler may return to any `rip` with `rt_sigreturn`, but `iretq`
+    /// faults in the shim on a non-canonical one.
+    #[allow(clippy::integer_arithmetic)]
+    fn leave(&mut self, ctx: &Context) {
+        if VirtAddr::try_new(ctx.rip).is_err() {
+            self.exit_group(128 + libc::SIGSEGV);
+        }
+    }
 }
 
-impl AddressValidator for Handler {
+impl<'a> AddressValidator for Handler<'a> {
     #[inline(always)]
     fn validate_const_mem_fn(&self, _ptr: *const (), _size: usize) -> bool {
         // FIXME: https://github.com/enarx/enarx/issues/630
@@ -167,12 +317,42 @@ impl AddressValidator for Handler {
     }
 }
 
-impl SyscallHandler for Handler {}
-impl SystemSyscallHandler for Handler {}
-impl NetworkSyscallHandler for Handler {}
-impl FileSyscallHandler for Handler {}
+impl<'a> SyscallHandler for Handler<'a> {}
+impl<'a> SystemSyscallHandler for Handler<'a> {}
+impl<'a> NetworkSyscallHandler for Handler<'a> {}
+
+impl<'a> FileSyscallHandler for Handler<'a> {
+    fn tmpfs<T>(&mut self, f: impl FnOnce(&mut Tmpfs, &mut dyn PageAlloc) -> T) -> Option<T> {
+        let mount = BOOT_INFO.read().unwrap().tmpfs;
+        let mut tmpfs = TMPFS.write();
+        Some(f(tmpfs.mount(&mount)?, &mut TmpfsPages))
+    }
+
+    fn sealed<T>(&mut self, f: impl FnOnce(&mut Sealed) -> T) -> Option<T> {
+        let prefix = BOOT_INFO.read().unwrap().sealed;
+        let secret = |derive: fn(&[u8]) -> [u8; 32]| SEV_SECRET.read().try_as_slice().map(derive);
+        let mut sealed = SEALED.write();
+        Some(f(sealed.mount(&prefix, secret)?))
+    }
+}
+
+/// Allocates the pages of the tmpfs from the shim heap
+struct TmpfsPages;
+
+impl PageAlloc for TmpfsPages {
+    fn alloc(&mut self) -> Option<NonNull<Page4KiB>> {
+        let page = ALLOCATOR.write().try_alloc(Layout::new::<Page4KiB>())?;
+        Some(page.cast())
+    }
 
-impl BaseSyscallHandler for Handler {
+    unsafe fn free(&mut self, page: NonNull<Page4KiB>) {
+        ALLOCATOR
+            .write()
+            .deallocate(page.as_ptr() as _, Layout::new::<Page4KiB>());
+    }
+}
+
+impl<'a> BaseSyscallHandler for Handler<'a> {
     fn unknown_syscall(
         &mut self,
         _a: Register<usize>,
@@ -218,7 +398,7 @@ impl BaseSyscallHandler for Handler {
     }
 }
 
-impl EnarxSyscallHandler for Handler {
+impl<'a> EnarxSyscallHandler for Handler<'a> {
     fn get_attestation(
         &mut self,
         _nonce: UntrustedRef<u8>,
@@ -254,7 +434,7 @@ impl EnarxSyscallHandler for Handler {
     }
 }
 
-impl ProcessSyscallHandler for Handler {
+impl<'a> ProcessSyscallHandler for Handler<'a> {
     fn arch_prctl(&mut self, code: i32, addr: u64) -> sallyport::Result {
         self.trace("arch_prctl", 2);
         match code {
@@ -296,9 +476,75 @@ impl ProcessSyscallHandler for Handler {
             }
         }
     }
+
+    fn thread<T>(&mut self, f: impl FnOnce(&mut Thread) -> T) -> T {
+        f(&mut THREADS.lock()[gdt::cpu()])
+    }
+
+    /// The shim enables no state components in `XCR0` besides x87, so the
+    /// FXSAVE image of the registers holds all of the state.
+    fn xstate<T>(&mut self, f: impl FnOnce(&mut [u8]) -> T) -> T {
+        let mut xstate = FxSave([0; 512]);
+
+        unsafe { asm!("fxsave64 [{}]", in(reg) &mut xstate as *mut FxSave, options(nostack)) };
+        let ret = f(&mut xstate.0);
+        unsafe { asm!("fxrstor64 [{}]", in(reg) &xstate as *const FxSave, options(nostack)) };
+
+        ret
+    }
+
+    /// Starts the thread on a new CPU
+    fn spawn(&mut self, new: NewThread) -> Result<(), libc::c_int> {
+        let [rdi, rsi, rdx, r10, r8, r9] = self.argv;
+
+        let regs = Registers {
+            rbx: self.frame.rbx,
+            rdx: rdx as _,
+            rsi: rsi as _,
+            rdi: rdi as _,
+            rbp: self.frame.rbp,
+            r8: r8 as _,
+            r9: r9 as _,
+            r10: r10 as _,
+            r12: self.frame.r12,
+            r13: self.frame.r13,
+            r14: self.frame.r14,
+            r15: self.frame.r15,
+            rip: self.frame.rip,
+            rflags: self.frame.rflags,
+            rsp: new.stack as _,
+            fsbase: new
+                .tls
+                .map_or_else(|| unsafe { rdfsbase() }, |tls| tls as _),
+            ..Default::default()
+        };
+
+        let slot = {
+            let mut clones = CLONES.lock();
+            let slot = clones
+                .iter()
+                .position(Option::is_none)
+                .ok_or(libc::EAGAIN)?;
+            clones[slot] = Some((new.thread, regs));
+            slot
+        };
+
+        smp::spawn((run_clone, slot)).map(|_| ()).map_err(|e| {
+            CLONES.lock()[slot] = None;
+            e
+        })
+    }
+}
+
+/// Runs a thread started by `clone` on its new CPU
+extern "C" fn run_clone(slot: usize) -> ! {
+    let (thread, regs) = CLONES.lock()[slot].take().unwrap();
+    THREADS.lock()[gdt::cpu()] = thread;
+
+    unsafe { usermode_with(&regs) }
 }
 
-impl MemorySyscallHandler for Handler {
+impl<'a> MemorySyscallHandler for Handler<'a> {
     fn mprotect(&mut self, addr: UntrustedRef<u8>, len: usize, prot: i32) -> sallyport::Result {
         self.trace("mprotect", 3);
         let addr = addr.as_ptr();
@@ -55,3 +55,83 @@ pub unsafe fn usermode(ip: u64, sp: u64) -> ! {
     options(noreturn, nomem)
     );
 }
+
+/// The registers a thread of the payload starts with
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+#[allow(missing_docs)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub fsbase: u64,
+}
+
+/// Enter Ring 3 with all the general purpose registers and the base of `%fs` of `regs`
+///
+/// # Safety
+///
+/// Because the caller can give any `rip` and `rsp` including 0, this
+/// function is unsafe.
+pub unsafe fn usermode_with(regs: &Registers) -> ! {
+    asm!("
+        push     {USER_DATA_SEGMENT}
+        push     QWORD PTR [rax + 0x88] # rsp
+        push     QWORD PTR [rax + 0x80] # rflags
+        push     {USER_CODE_SEGMENT}
+        push     QWORD PTR [rax + 0x78] # rip
+
+        # clear all segment selectors
+        xor      rbx,                   rbx
+        mov      ds,                    rbx
+        mov      es,                    rbx
+        mov      fs,                    rbx
+        mov      gs,                    rbx
+
+        mov      rbx,                   QWORD PTR [rax + 0x90]
+        wrfsbase rbx
+
+        # clear the FPU
+        fninit
+
+        mov      rbx,                   QWORD PTR [rax + 0x08]
+        mov      rcx,                   QWORD PTR [rax + 0x10]
+        mov      rdx,                   QWORD PTR [rax + 0x18]
+        mov      rsi,                   QWORD PTR [rax + 0x20]
+        mov      rdi,                   QWORD PTR [rax + 0x28]
+        mov      rbp,                   QWORD PTR [rax + 0x30]
+        mov      r8,                    QWORD PTR [rax + 0x38]
+        mov      r9,                    QWORD PTR [rax + 0x40]
+        mov      r10,                   QWORD PTR [rax + 0x48]
+        mov      r11,                   QWORD PTR [rax + 0x50]
+        mov      r12,                   QWORD PTR [rax + 0x58]
+        mov      r13,                   QWORD PTR [rax + 0x60]
+        mov      r14,                   QWORD PTR [rax + 0x68]
+        mov      r15,                   QWORD PTR [rax + 0x70]
+        mov      rax,                   QWORD PTR [rax]
+
+        # do a simulated return from interrupt
+        # this sets the segments and rip from the stack
+        iretq
+          ",
+    USER_DATA_SEGMENT = const USER_DATA_SEGMENT,
+    USER_CODE_SEGMENT = const USER_CODE_SEGMENT,
+    in("rax") regs as *const Registers,
+    options(noreturn)
+    );
+}
@@ -82,6 +82,7 @@ mod _internal {
     push    r11
 
     # relocate the dynamic symbols
+    # (on every TCS, which only writes the same values again)
     # rdi - address of _DYNAMIC section
     # rsi - shim load offset from Layout.shim.start
     mov     rsi,          QWORD PTR [rcx + {SHIM}]
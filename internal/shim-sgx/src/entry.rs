@@ -1,9 +1,17 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use core::sync::atomic::{AtomicBool, Ordering};
 use crt0stack::{Builder, Entry, Handle, OutOfSpace};
 use goblin::elf::header::{header64::Header, ELFMAG};
+use nbytes::bytes;
 
-use crate::Layout;
+use crate::{thread, Layout};
+
+/// The size of the crt0 stack, large enough for the payload arguments
+const CRT0_SIZE: usize = bytes![128; KiB];
+
+/// Whether a TCS was entered and started the payload
+static STARTED: AtomicBool = AtomicBool::new(false);
 
 fn exit(code: usize) -> ! {
     unsafe {
@@ -28,6 +36,17 @@ fn random() -> u64 {
     exit(1)
 }
 
+/// The unmodified bytes of the keep manifest, empty without a manifest
+#[allow(dead_code)]
+pub fn manifest(layout: &Layout) -> &[u8] {
+    unsafe {
+        core::slice::from_raw_parts(
+            layout.manifest.start as *const u8,
+            layout.manifest.end - layout.manifest.start,
+        )
+    }
+}
+
 fn crt0setup<'a>(
     layout: &Layout,
     hdr: &Header,
@@ -36,13 +55,28 @@ fn crt0setup<'a>(
     let rand = unsafe { core::mem::transmute([random(), random()]) };
     let phdr = layout.code.start as u64 + hdr.e_phoff;
 
+    let args = unsafe {
+        core::slice::from_raw_parts(
+            layout.args.start as *const u8,
+            layout.args.end - layout.args.start,
+        )
+    };
+    let mut strings = args
+        .split(|b| *b == 0)
+        .map(|s| core::str::from_utf8(s).unwrap_or_else(|_| exit(1)));
+
     // Set the arguments
     let mut builder = Builder::new(crt0);
     builder.push("/init")?;
+    for arg in strings.by_ref().take(layout.argc) {
+        builder.push(arg)?;
+    }
 
     // Set the environment
     let mut builder = builder.done()?;
-    builder.push("LANG=C")?;
+    for env in strings.take(layout.envc) {
+        builder.push(env)?;
+    }
 
     // Set the aux vector
     let mut builder = builder.done()?;
@@ -68,6 +102,13 @@ fn crt0setup<'a>(
 
 #[no_mangle]
 pub extern "C" fn entry(_rdi: u64, _rsi: u64, _rdx: u64, layout: &Layout, _r8: u64, _r9: u64) -> ! {
+    // Any further TCS runs a thread started by `clone`, and the host must
+    // not enter one the shim did not ask for.
+    if STARTED.swap(true, Ordering::SeqCst) {
+        let (new, regs) = thread::pending().unwrap_or_else(|| exit(1));
+        unsafe { thread::start(layout, new, &regs) }
+    }
+
     // Validate the ELF header.
     let hdr = unsafe { &*(layout.code.start as *const Header) };
 
@@ -76,7 +117,7 @@ pub extern "C" fn entry(_rdi: u64, _rsi: u64, _rdx: u64, layout: &Layout, _r8: u
     }
 
     // Prepare the crt0 stack.
-    let mut crt0 = [0u8; 1024];
+    let mut crt0 = [0u8; CRT0_SIZE];
     let space = random() as usize & 0xf0;
     let handle = match crt0setup(layout, hdr, &mut crt0[space..]) {
         Err(OutOfSpace) => exit(1),
@@ -6,7 +6,10 @@ use sgx::types::ssa::{Exception, StateSaveArea};
 use crate::enclave::Context;
 use crate::handler::Handler;
 use crate::Layout;
-use syscall::{BaseSyscallHandler, ProcessSyscallHandler, SyscallHandler, SYS_ENARX_ERESUME};
+use syscall::{
+    BaseSyscallHandler, ProcessSyscallHandler, SyscallHandler, FPE_INTDIV, ILL_ILLOPN,
+    SYS_ENARX_ERESUME,
+};
 
 // Opcode constants, details in Volume 2 of the Intel 64 and IA-32 Architectures Software
 // Developer's Manual
@@ -29,6 +32,14 @@ pub extern "C" fn event(
     match h.aex.gpr.exitinfo.exception() {
         Some(Exception::InvalidOpcode) => {
             match unsafe { h.aex.gpr.rip.into_slice(2usize) } {
+                OP_SYSCALL if usize::from(h.aex.gpr.rax) == libc::SYS_rt_sigreturn as usize => {
+                    h.aex.gpr.rip = (usize::from(h.aex.gpr.rip) + 2).into();
+
+                    let mut regs = registers(h.aex);
+                    h.rt_sigreturn(&mut regs);
+                    restore(h.aex, &regs);
+                }
+
                 OP_SYSCALL => {
                     let ret = h.syscall(
                         h.aex.gpr.rdi.into(),
@@ -40,34 +51,97 @@ pub extern "C" fn event(
                         h.aex.gpr.rax.into(),
                     );
 
-                    aex.gpr.rip = (usize::from(aex.gpr.rip) + 2).into();
+                    h.aex.gpr.rip = (usize::from(h.aex.gpr.rip) + 2).into();
                     match ret {
-                        Err(e) => aex.gpr.rax = (-e).into(),
+                        Err(e) => h.aex.gpr.rax = (-e).into(),
                         Ok([rax, rdx]) => {
-                            aex.gpr.rax = rax.into();
-                            aex.gpr.rdx = rdx.into();
+                            h.aex.gpr.rax = rax.into();
+                            h.aex.gpr.rdx = rdx.into();
                         }
                     }
                 }
 
                 OP_CPUID => {
                     h.cpuid();
-                    aex.gpr.rip = (usize::from(aex.gpr.rip) + 2).into();
+                    h.aex.gpr.rip = (usize::from(h.aex.gpr.rip) + 2).into();
                 }
 
                 // unsupported opcode
                 r => {
                     debugln!(h, "unsupported opcode: {:?}", r);
-                    h.exit(1)
+
+                    let mut regs = registers(h.aex);
+                    let rip = regs.rip as usize;
+                    h.fault(&mut regs, libc::SIGILL, ILL_ILLOPN, rip);
+                    restore(h.aex, &regs);
                 }
             }
         }
 
-        // Not InvalidOpcode
+        Some(Exception::DivideError) => {
+            let mut regs = registers(h.aex);
+            let rip = regs.rip as usize;
+            h.fault(&mut regs, libc::SIGFPE, FPE_INTDIV, rip);
+            restore(h.aex, &regs);
+        }
+
+        // Without EXINFO in MISCSELECT, page faults and general protection
+        // faults of the payload cannot be told from an attack.
         _ => {
             h.attacked();
         }
     }
 
+    // Deliver the pending signals, before the enclave resumes.
+    let mut regs = registers(h.aex);
+    h.signal(&mut regs);
+    restore(h.aex, &regs);
+
     block.msg.req.num = SYS_ENARX_ERESUME.into();
 }
+
+/// The registers of the payload saved in `aex`
+fn registers(aex: &StateSaveArea) -> syscall::Context {
+    syscall::Context {
+        r8: aex.gpr.r8.into(),
+        r9: aex.gpr.r9.into(),
+        r10: aex.gpr.r10.into(),
+        r11: aex.gpr.r11.into(),
+        r12: aex.gpr.r12.into(),
+        r13: aex.gpr.r13.into(),
+        r14: aex.gpr.r14.into(),
+        r15: aex.gpr.r15.into(),
+        rdi: aex.gpr.rdi.into(),
+        rsi: aex.gpr.rsi.into(),
+        rbp: aex.gpr.rbp.into(),
+        rbx: aex.gpr.rbx.into(),
+        rdx: aex.gpr.rdx.into(),
+        rax: aex.gpr.rax.into(),
+        rcx: aex.gpr.rcx.into(),
+        rsp: aex.gpr.rsp.into(),
+        rip: aex.gpr.rip.into(),
+        rflags: aex.gpr.rflags.into(),
+    }
+}
+
+/// Lets the payload resume with the registers in `regs`
+fn restore(aex: &mut StateSaveArea, regs: &syscall::Context) {
+    aex.gpr.r8 = regs.r8.into();
+    aex.gpr.r9 = regs.r9.into();
+    aex.gpr.r10 = regs.r10.into();
+    aex.gpr.r11 = regs.r11.into();
+    aex.gpr.r12 = regs.r12.into();
+    aex.gpr.r13 = regs.r13.into();
+    aex.gpr.r14 = regs.r14.into();
+    aex.gpr.r15 = regs.r15.into();
+    aex.gpr.rdi = regs.rdi.into();
+    aex.gpr.rsi = regs.rsi.into();
+    aex.gpr.rbp = regs.rbp.into();
+    aex.gpr.rbx = regs.rbx.into();
+    aex.gpr.rdx = regs.rdx.into();
+    aex.gpr.rax = regs.rax.into();
+    aex.gpr.rcx = regs.rcx.into();
+    aex.gpr.rsp = regs.rsp.into();
+    aex.gpr.rip = regs.rip.into();
+    aex.gpr.rflags = regs.rflags.into();
+}
@@ -1,9 +1,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::seal::seal_key;
+use crate::thread::{self, Registers};
 use crate::Layout;
 
 use core::fmt::Write;
-use primordial::Register;
+use core::ptr::NonNull;
+use primordial::{Page, Register};
 
 use sallyport::{request, Block, Cursor, Request};
 use sgx::{
@@ -17,17 +20,66 @@ use sgx::{
     },
 };
 use sgx_heap::Heap;
+use spinning::{Mutex, RwLock};
 use syscall::{
     BaseSyscallHandler, EnarxSyscallHandler, FileSyscallHandler, MemorySyscallHandler,
-    NetworkSyscallHandler, ProcessSyscallHandler, SyscallHandler, SystemSyscallHandler,
-    ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS, SGX_DUMMY_QUOTE, SGX_DUMMY_TI,
-    SGX_QUOTE_SIZE, SGX_TECH, SYS_ENARX_CPUID, SYS_ENARX_GETATT,
+    NetworkSyscallHandler, NewThread, PageAlloc, ProcessSyscallHandler, Sealed, SyscallHandler,
+    SystemSyscallHandler, Thread, Tmpfs, ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS,
+    SGX_DUMMY_QUOTE, SGX_DUMMY_TI, SGX_QUOTE_SIZE, SGX_TECH, SYS_ENARX_CPUID, SYS_ENARX_GETATT,
+    SYS_ENARX_SPAWN,
 };
 use untrusted::{AddressValidator, UntrustedRef, UntrustedRefMut, ValidateSlice};
 
 pub const TRACE: bool = false;
+
+/// The size of the XSAVE area of an SSA frame
+///
+/// It ends before the MISC and GPR areas, and the shim keeps its syscall
+/// return stack pointer in the last quadword.
+const XSAVE_SIZE: usize = 4096 - 184 - 16 - 8;
 use crate::enclave::{syscall, Context};
 
+/// The in-memory filesystem served to the payload
+static TMPFS: RwLock<Tmpfs> =
+    RwLock::<Tmpfs>::const_new(spinning::RawRwLock::const_new(), Tmpfs::new());
+
+/// The open sealed files of the payload
+static SEALED: RwLock<Sealed> =
+    RwLock::<Sealed>::const_new(spinning::RawRwLock::const_new(), Sealed::new());
+
+/// Serializes the use of the heap, which keeps its state in itself
+static HEAP: Mutex<()> = Mutex::<()>::const_new(spinning::RawMutex::const_new(), ());
+
+/// Calls `f` with the heap of the enclave
+fn heap<T>(layout: &Layout, f: impl FnOnce(&mut Heap) -> T) -> T {
+    let _lock = HEAP.lock();
+    f(&mut unsafe { Heap::new(layout.heap.into()) })
+}
+
+/// Allocates the pages of the tmpfs from the heap
+struct TmpfsPages<'a>(&'a Layout);
+
+impl<'a> PageAlloc for TmpfsPages<'a> {
+    fn alloc(&mut self) -> Option<NonNull<Page>> {
+        let page = heap(self.0, |heap| {
+            heap.mmap::<Page>(
+                0,
+                Page::size(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        });
+
+        NonNull::new(page.ok()?)
+    }
+
+    unsafe fn free(&mut self, page: NonNull<Page>) {
+        let _ = heap(self.0, |heap| heap.munmap(page.as_ptr(), Page::size()));
+    }
+}
+
 pub struct Handler<'a> {
     pub aex: &'a mut StateSaveArea,
     layout: &'a Layout,
@@ -108,6 +160,12 @@ impl<'a> Handler<'a> {
             );
         }
     }
+
+    /// Whether `fd` is served by the shim itself rather than the host
+    fn served(&mut self, fd: libc::c_int) -> bool {
+        self.tmpfs(|fs, _| fs.owns(fd)).unwrap_or(false)
+            || self.sealed(|s| s.owns(fd)).unwrap_or(false)
+    }
 }
 
 impl<'a> AddressValidator for Handler<'a> {
@@ -154,7 +212,7 @@ impl<'a> BaseSyscallHandler for Handler<'a> {
     /// tripping the circuit breaker causes the enclave to immediately
     /// EEXIT.
     fn attacked(&mut self) -> ! {
-        self.exit(1)
+        self.exit_group(1)
     }
 
     #[inline]
@@ -215,9 +273,66 @@ impl<'a> ProcessSyscallHandler for Handler<'a> {
 
         Ok(Default::default())
     }
+
+    fn thread<T>(&mut self, f: impl FnOnce(&mut Thread) -> T) -> T {
+        thread::with(self.layout, f)
+    }
+
+    /// The AEX saved the state to the XSAVE area at the start of the SSA
+    /// frame, from which ERESUME restores it.
+    fn xstate<T>(&mut self, f: impl FnOnce(&mut [u8]) -> T) -> T {
+        let xsave = self.aex as *mut StateSaveArea as *mut u8;
+        f(unsafe { core::slice::from_raw_parts_mut(xsave, XSAVE_SIZE) })
+    }
+
+    /// Starts the thread on another TCS
+    fn spawn(&mut self, new: NewThread) -> Result<(), libc::c_int> {
+        let gpr = &self.aex.gpr;
+
+        // The thread continues after the `syscall` instruction.
+        let regs = Registers {
+            rbx: gpr.rbx.into(),
+            rcx: gpr.rcx.into(),
+            rdx: gpr.rdx.into(),
+            rsi: gpr.rsi.into(),
+            rdi: gpr.rdi.into(),
+            rbp: gpr.rbp.into(),
+            r8: gpr.r8.into(),
+            r9: gpr.r9.into(),
+            r10: gpr.r10.into(),
+            r11: gpr.r11.into(),
+            r12: gpr.r12.into(),
+            r13: gpr.r13.into(),
+            r14: gpr.r14.into(),
+            r15: gpr.r15.into(),
+            rip: u64::from(gpr.rip) + 2,
+            rflags: gpr.rflags.into(),
+            rsp: new.stack as _,
+            fsbase: new.tls.map_or_else(|| gpr.fsbase.into(), |tls| tls as _),
+            ..Default::default()
+        };
+
+        thread::spawn(new.thread, regs, || unsafe {
+            self.proxy(request!(SYS_ENARX_SPAWN))
+        })
+    }
 }
 
 impl<'a> FileSyscallHandler for Handler<'a> {
+    fn tmpfs<T>(&mut self, f: impl FnOnce(&mut Tmpfs, &mut dyn PageAlloc) -> T) -> Option<T> {
+        let mut tmpfs = TMPFS.write();
+        Some(f(
+            tmpfs.mount(&self.layout.tmpfs)?,
+            &mut TmpfsPages(self.layout),
+        ))
+    }
+
+    fn sealed<T>(&mut self, f: impl FnOnce(&mut Sealed) -> T) -> Option<T> {
+        let mut sealed = SEALED.write();
+        let secret = |derive: fn(&[u8]) -> [u8; 32]| seal_key().map(|key| derive(&key));
+        Some(f(sealed.mount(&self.layout.sealed, secret)?))
+    }
+
     /// Do a readv() syscall
     fn readv(
         &mut self,
@@ -230,6 +345,16 @@ impl<'a> FileSyscallHandler for Handler<'a> {
         let mut size = 0usize;
         let trusted = iovec.validate_slice(iovcnt, self).ok_or(libc::EFAULT)?;
 
+        // Files served by the shim are read one buffer at a time.
+        if self.served(fd) {
+            for t in trusted {
+                let ret = self.read(fd, (t.iov_base as *mut u8).into(), t.iov_len)?;
+                size += usize::from(ret[0]);
+            }
+
+            return Ok([size.into(), 0.into()]);
+        }
+
         let c = self.new_cursor();
 
         let (c, untrusted) = c
@@ -284,6 +409,22 @@ impl<'a> FileSyscallHandler for Handler<'a> {
 
         let mut size = 0usize;
         let trusted = iovec.validate_slice(iovcnt, self).ok_or(libc::EFAULT)?;
+
+        // Files served by the shim are written one buffer at a time.
+        if self.served(fd) {
+            for t in trusted {
+                let ret = self.write(fd, (t.iov_base as *const u8).into(), t.iov_len)?;
+                let written = usize::from(ret[0]);
+                size += written;
+
+                if written != t.iov_len {
+                    break;
+                }
+            }
+
+            return Ok([size.into(), 0.into()]);
+        }
+
         let c = self.new_cursor();
         let (c, untrusted) = c
             .copy_from_slice::<libc::iovec>(trusted)
@@ -314,8 +455,7 @@ impl<'a> MemorySyscallHandler for Handler<'a> {
     fn brk(&mut self, addr: *const u8) -> sallyport::Result {
         self.trace("brk", 1);
 
-        let mut heap = unsafe { Heap::new(self.layout.heap.into()) };
-        let ret = heap.brk(addr as _);
+        let ret = heap(self.layout, |heap| heap.brk(addr as _));
         Ok([ret.into(), Default::default()])
     }
 
@@ -345,15 +485,16 @@ impl<'a> MemorySyscallHandler for Handler<'a> {
     ) -> sallyport::Result {
         self.trace("mmap", 6);
 
-        let mut heap = unsafe { Heap::new(self.layout.heap.into()) };
-        let ret = heap.mmap::<libc::c_void>(
-            addr.as_ptr() as _,
-            length,
-            prot,
-            flags,
-            fd, // Allow truncation!
-            offset,
-        )?;
+        let ret = heap(self.layout, |heap| {
+            heap.mmap::<libc::c_void>(
+                addr.as_ptr() as _,
+                length,
+                prot,
+                flags,
+                fd, // Allow truncation!
+                offset,
+            )
+        })?;
 
         Ok([ret.into(), Default::default()])
     }
@@ -362,8 +503,9 @@ impl<'a> MemorySyscallHandler for Handler<'a> {
     fn munmap(&mut self, addr: UntrustedRef<u8>, length: libc::size_t) -> sallyport::Result {
         self.trace("munmap", 2);
 
-        let mut heap = unsafe { Heap::new(self.layout.heap.into()) };
-        heap.munmap::<libc::c_void>(addr.as_ptr() as _, length)?;
+        heap(self.layout, |heap| {
+            heap.munmap::<libc::c_void>(addr.as_ptr() as _, length)
+        })?;
         Ok(Default::default())
     }
 
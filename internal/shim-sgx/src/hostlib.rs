@@ -4,6 +4,9 @@
 
 use lset::Line;
 
+/// The maximum number of threads of an enclave, each with a TCS of its own
+pub const MAX_THREADS: usize = 256;
+
 /// The enclave layout
 #[repr(C)]
 #[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
@@ -21,8 +24,36 @@ pub struct Layout {
     pub heap: Line<usize>,
 
     /// The boundaries of the stack.
+    ///
+    /// The copy of the layout after each TCS holds the stack of its thread.
     pub stack: Line<usize>,
 
     /// The boundaries of the shim.
     pub shim: Line<usize>,
+
+    /// The boundaries of the payload arguments and environment.
+    ///
+    /// The region contains `argc` followed by `envc` NUL terminated strings.
+    pub args: Line<usize>,
+
+    /// The number of payload arguments, not counting `argv[0]`.
+    pub argc: usize,
+
+    /// The number of payload environment variables.
+    pub envc: usize,
+
+    /// The boundaries of the keep manifest.
+    pub manifest: Line<usize>,
+
+    /// The NUL padded mount point of the tmpfs, or all zeros without one.
+    pub tmpfs: [u8; 32],
+
+    /// The NUL padded prefix of the sealed files, or all zeros without one.
+    pub sealed: [u8; 32],
+
+    /// The number of threads, each with a TCS, SSA frames and a stack.
+    pub threads: usize,
+
+    /// The index of the thread, whose TCS this copy of the layout follows.
+    pub thread: usize,
 }
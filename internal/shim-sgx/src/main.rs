@@ -71,5 +71,7 @@ mod entry;
 mod event;
 mod handler;
 mod hostlib;
+mod seal;
+mod thread;
 
 use hostlib::Layout;
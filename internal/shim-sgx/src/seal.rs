@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The seal key of the enclave
+//!
+//! The key is bound to the measurement of the enclave, so only the same
+//! enclave can derive it again, on the same CPU. It is also bound to the
+//! current security version of the CPU, so an older, vulnerable microcode
+//! cannot derive it. A microcode update changes the key, though.
+
+/// ENCLU leaf of EREPORT
+const EREPORT: u64 = 0;
+
+/// ENCLU leaf of EGETKEY
+const EGETKEY: u64 = 1;
+
+/// KEYNAME of the seal key
+const SEAL_KEY: u16 = 4;
+
+/// KEYPOLICY bit binding the key to MRENCLAVE
+const MRENCLAVE: u16 = 1 << 0;
+
+/// The attributes the key is bound to: INIT, DEBUG, PROVISIONKEY and the
+/// reserved upper bits
+const FLAGS_MASK: u64 = 0xFF00_0000_0000_000B;
+
+/// The KEYREQUEST structure of EGETKEY
+#[repr(C, align(512))]
+struct KeyRequest {
+    name: u16,
+    policy: u16,
+    isvsvn: u16,
+    reserved0: u16,
+    cpusvn: [u8; 16],
+    attribute_mask: [u64; 2],
+    id: [u8; 32],
+    misc_mask: u32,
+    configsvn: u16,
+    reserved1: [u8; 434],
+}
+
+/// The key EGETKEY writes
+#[repr(C, align(16))]
+struct Key([u8; 16]);
+
+/// The TARGETINFO structure of EREPORT, all zero for a report the enclave
+/// only reads itself
+#[repr(C, align(512))]
+struct TargetInfo([u8; 512]);
+
+/// The REPORTDATA of EREPORT
+#[repr(C, align(128))]
+struct ReportData([u8; 64]);
+
+/// The REPORT EREPORT writes, whose body starts with the CPUSVN
+#[repr(C, align(512))]
+struct Report([u8; 432]);
+
+/// Returns the security version of the CPU the enclave runs on
+fn cpusvn() -> [u8; 16] {
+    let target = TargetInfo([0; 512]);
+    let data = ReportData([0; 64]);
+    let mut report = Report([0; 432]);
+
+    // rbx is reserved by LLVM, so the target is swapped in and out of it.
+    unsafe {
+        asm!(
+            "xchg {target}, rbx",
+            "enclu",
+            "xchg {target}, rbx",
+            target = inout(reg) &target => _,
+            inout("rax") EREPORT => _,
+            in("rcx") &data,
+            in("rdx") &mut report,
+        );
+    }
+
+    let mut cpusvn = [0; 16];
+    cpusvn.copy_from_slice(&report.0[..16]);
+    cpusvn
+}
+
+/// Returns the seal key bound to MRENCLAVE, or `None` if EGETKEY fails
+pub fn seal_key() -> Option<[u8; 16]> {
+    let request = KeyRequest {
+        name: SEAL_KEY,
+        policy: MRENCLAVE,
+        isvsvn: 0,
+        reserved0: 0,
+        cpusvn: cpusvn(),
+        attribute_mask: [FLAGS_MASK, 0],
+        id: [0; 32],
+        misc_mask: 0,
+        configsvn: 0,
+        reserved1: [0; 434],
+    };
+
+    let mut key = Key([0; 16]);
+    let ret: u64;
+
+    // rbx is reserved by LLVM, so the request is swapped in and out of it.
+    unsafe {
+        asm!(
+            "xchg {request}, rbx",
+            "enclu",
+            "xchg {request}, rbx",
+            request = inout(reg) &request => _,
+            inout("rax") EGETKEY => ret,
+            in("rcx") &mut key,
+        );
+    }
+
+    match ret {
+        0 => Some(key.0),
+        _ => None,
+    }
+}
@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Threads of the payload
+//!
+//! Every thread of the payload runs on a TCS of its own. `clone` leaves the
+//! new thread and its registers with the shim and asks the host to enter
+//! another TCS, which picks them up in `entry` and jumps to the payload.
+
+use crate::hostlib::MAX_THREADS;
+use crate::Layout;
+
+use spinning::{Mutex, RawMutex};
+use syscall::Thread;
+
+/// The registers a thread of the payload starts with
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+#[allow(missing_docs)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub fsbase: u64,
+}
+
+/// The thread of the payload on each TCS
+///
+/// Each TCS only uses the thread of its copy of the layout.
+static mut THREADS: [Thread; MAX_THREADS] = [Thread::new(); MAX_THREADS];
+
+/// The thread `clone` started, until the host entered a TCS for it
+///
+/// `spawn` holds the lock until the host replied, so a TCS never picks up
+/// the thread of a failed `clone`.
+static PENDING: Mutex<Option<(Thread, Registers)>> =
+    Mutex::<Option<(Thread, Registers)>>::const_new(RawMutex::const_new(), None);
+
+/// Calls `f` with the thread of the TCS `layout` follows
+pub fn with<T>(layout: &Layout, f: impl FnOnce(&mut Thread) -> T) -> T {
+    f(unsafe { &mut THREADS[layout.thread] })
+}
+
+/// Starts `thread` with `regs` on the TCS `host` asks the host to enter
+pub fn spawn(
+    thread: Thread,
+    regs: Registers,
+    host: impl FnOnce() -> sallyport::Result,
+) -> Result<(), libc::c_int> {
+    // Wait for the last thread started to be picked up.
+    let mut pending = loop {
+        let pending = PENDING.lock();
+        if pending.is_none() {
+            break pending;
+        }
+
+        drop(pending);
+        core::hint::spin_loop();
+    };
+
+    *pending = Some((thread, regs));
+    host().map(|_| ()).map_err(|e| {
+        *pending = None;
+        e
+    })
+}
+
+/// Takes the thread `clone` started, if any
+pub fn pending() -> Option<(Thread, Registers)> {
+    PENDING.lock().take()
+}
+
+/// Runs `thread` with `regs` on the TCS `layout` follows
+///
+/// # Safety
+///
+/// Because the caller can give any `rip` and `rsp` including 0, this
+/// function is unsafe.
+pub unsafe fn start(layout: &Layout, thread: Thread, regs: &Registers) -> ! {
+    THREADS[layout.thread] = thread;
+
+    asm!("
+        mov      rbx,                   QWORD PTR [rax + 0x90]
+        wrfsbase rbx
+
+        # return to rip with rflags on the stack of the thread
+        mov      rsp,                   QWORD PTR [rax + 0x88]
+        push     QWORD PTR [rax + 0x78] # rip
+        push     QWORD PTR [rax + 0x80] # rflags
+
+        mov      rbx,                   QWORD PTR [rax + 0x08]
+        mov      rcx,                   QWORD PTR [rax + 0x10]
+        mov      rdx,                   QWORD PTR [rax + 0x18]
+        mov      rsi,                   QWORD PTR [rax + 0x20]
+        mov      rdi,                   QWORD PTR [rax + 0x28]
+        mov      rbp,                   QWORD PTR [rax + 0x30]
+        mov      r8,                    QWORD PTR [rax + 0x38]
+        mov      r9,                    QWORD PTR [rax + 0x40]
+        mov      r10,                   QWORD PTR [rax + 0x48]
+        mov      r11,                   QWORD PTR [rax + 0x50]
+        mov      r12,                   QWORD PTR [rax + 0x58]
+        mov      r13,                   QWORD PTR [rax + 0x60]
+        mov      r14,                   QWORD PTR [rax + 0x68]
+        mov      r15,                   QWORD PTR [rax + 0x70]
+
+        popfq
+        mov      rax,                   QWORD PTR [rax]
+        ret
+          ",
+    in("rax") regs as *const Registers,
+    options(noreturn)
+    );
+}
@@ -2,16 +2,43 @@
 
 //! file syscalls
 
+use crate::path::{normalize, PATH_MAX};
+use crate::sealed::{self, Sealed};
+use crate::tmpfs::{PageAlloc, Tmpfs};
 use crate::BaseSyscallHandler;
+use core::convert::TryFrom;
 use core::mem::MaybeUninit;
 use sallyport::{request, Block, Result};
 use untrusted::{AddressValidator, UntrustedRef, UntrustedRefMut, Validate, ValidateSlice};
 
 /// file syscalls
 pub trait FileSyscallHandler: BaseSyscallHandler + AddressValidator + Sized {
+    /// Runs `f` on the tmpfs of the keep and the allocator for its pages
+    ///
+    /// Returns `None` if the shim serves no tmpfs, which is the default.
+    fn tmpfs<T>(&mut self, _f: impl FnOnce(&mut Tmpfs, &mut dyn PageAlloc) -> T) -> Option<T> {
+        None
+    }
+
+    /// Runs `f` on the table of the open sealed files of the keep
+    ///
+    /// Returns `None` if the shim seals no files, which is the default.
+    fn sealed<T>(&mut self, _f: impl FnOnce(&mut Sealed) -> T) -> Option<T> {
+        None
+    }
+
     /// syscall
     fn close(&mut self, fd: libc::c_int) -> Result {
         self.trace("close", 1);
+
+        if let Some(ret) = self.tmpfs(|fs, pages| fs.close(fd, pages)).flatten() {
+            return ret;
+        }
+
+        if self.sealed(|s| s.owns(fd)).unwrap_or(false) {
+            return sealed::close(self, fd);
+        }
+
         let ret = unsafe { self.proxy(request!(libc::SYS_close => fd))? };
         Ok(ret)
     }
@@ -22,6 +49,14 @@ pub trait FileSyscallHandler: BaseSyscallHandler + AddressValidator + Sized {
 
         let buf = buf.validate_slice(count, self).ok_or(libc::EFAULT)?;
 
+        if let Some(ret) = self.tmpfs(|fs, _| fs.read(fd, buf)).flatten() {
+            return ret;
+        }
+
+        if let Some(file) = self.sealed(|s| s.file(fd)).flatten() {
+            return sealed::read(self, file, buf, None);
+        }
+
         let c = self.new_cursor();
 
         // Limit the read to `Block::buf_capacity()`
@@ -81,6 +116,14 @@ pub trait FileSyscallHandler: BaseSyscallHandler + AddressValidator + Sized {
 
         let buf = buf.validate_slice(count, self).ok_or(libc::EFAULT)?;
 
+        if let Some(ret) = self.tmpfs(|fs, pages| fs.write(fd, buf, pages)).flatten() {
+            return ret;
+        }
+
+        if let Some(file) = self.sealed(|s| s.file(fd)).flatten() {
+            return sealed::write(self, file, buf, None);
+        }
+
         let c = self.new_cursor();
         let (_, buf) = c.copy_from_slice(buf.as_ref()).or(Err(libc::EMSGSIZE))?;
         let buf = buf.as_ptr();
@@ -253,7 +296,30 @@ pub trait FileSyscallHandler: BaseSyscallHandler + AddressValidator + Sized {
                 */
                 Ok(Default::default())
             }
-            _ => Err(libc::EBADF),
+            _ => {
+                let statbuf = statbuf.validate(self).ok_or(libc::EFAULT)?;
+
+                if let Some(ret) = self.tmpfs(|fs, _| fs.fstat(fd, statbuf)).flatten() {
+                    return ret;
+                }
+
+                let c = self.new_cursor();
+                let (_, buf) = c.alloc::<libc::stat>(1).or(Err(libc::EMSGSIZE))?;
+                let host_virt = Self::translate_shim_to_host_addr(buf.as_ptr());
+
+                let ret = unsafe { self.proxy(request!(libc::SYS_fstat => fd, host_virt))? };
+
+                let c = self.new_cursor();
+                let (_, mut stat) = unsafe { c.read::<libc::stat>() }.or(Err(libc::EFAULT))?;
+
+                if self.sealed(|s| s.owns(fd)).unwrap_or(false) {
+                    sealed::stat(&mut stat)?;
+                }
+
+                *statbuf = stat;
+
+                Ok(ret)
+            }
         }
     }
 
@@ -453,4 +519,316 @@ pub trait FileSyscallHandler: BaseSyscallHandler + AddressValidator + Sized {
         self.trace("dup3", 3);
         unsafe { self.proxy(request!(libc::SYS_dup3 => oldfd, newfd, flags)) }
     }
+
+    /// syscall
+    fn openat(
+        &mut self,
+        dirfd: libc::c_int,
+        pathname: UntrustedRef<u8>,
+        flags: libc::c_int,
+        mode: libc::mode_t,
+    ) -> Result {
+        self.trace("openat", 4);
+
+        let pathname = validate_path(pathname, self)?;
+        let mut buf = [0; PATH_MAX];
+        let path = absolute(self, dirfd, pathname, &mut buf)?;
+
+        let tmpfs = self.tmpfs(|fs, pages| fs.openat(dirfd, path, flags, mode, pages));
+        if let Some(ret) = tmpfs.flatten() {
+            return ret;
+        }
+
+        if let Some(id) = self.sealed(|s| s.id(dirfd, path)).flatten() {
+            return sealed::openat(self, dirfd, pathname, flags, mode, id?);
+        }
+
+        let c = self.new_cursor();
+        let (_, buf) = c.copy_from_slice(pathname).or(Err(libc::EMSGSIZE))?;
+        let host_virt = Self::translate_shim_to_host_addr(buf.as_ptr());
+
+        unsafe { self.proxy(request!(libc::SYS_openat => dirfd, host_virt, flags, mode)) }
+    }
+
+    /// syscall
+    fn newfstatat(
+        &mut self,
+        dirfd: libc::c_int,
+        pathname: UntrustedRef<u8>,
+        statbuf: UntrustedRefMut<libc::stat>,
+        flags: libc::c_int,
+    ) -> Result {
+        self.trace("newfstatat", 4);
+
+        let pathname = validate_path(pathname, self)?;
+        let statbuf = statbuf.validate(self).ok_or(libc::EFAULT)?;
+        let mut buf = [0; PATH_MAX];
+        let path = absolute(self, dirfd, pathname, &mut buf)?;
+
+        let tmpfs = self.tmpfs(|fs, _| fs.newfstatat(dirfd, path, statbuf, flags));
+        if let Some(ret) = tmpfs.flatten() {
+            return ret;
+        }
+
+        let id = self.sealed(|s| s.id(dirfd, path)).flatten();
+        let is_sealed = id.transpose()?.is_some();
+
+        let c = self.new_cursor();
+        let (c, path) = c.copy_from_slice(pathname).or(Err(libc::EMSGSIZE))?;
+        let (_, stat) = c.alloc::<libc::stat>(1).or(Err(libc::EMSGSIZE))?;
+        let path_virt = Self::translate_shim_to_host_addr(path.as_ptr());
+        let stat_virt = Self::translate_shim_to_host_addr(stat.as_ptr());
+
+        let ret = unsafe {
+            self.proxy(request!(libc::SYS_newfstatat => dirfd, path_virt, stat_virt, flags))?
+        };
+
+        let c = self.new_cursor();
+        let (c, _) = c.alloc::<u8>(pathname.len()).or(Err(libc::EMSGSIZE))?;
+        let (_, mut stat) = unsafe { c.read::<libc::stat>() }.or(Err(libc::EFAULT))?;
+
+        if is_sealed {
+            sealed::stat(&mut stat)?;
+        }
+
+        *statbuf = stat;
+
+        Ok(ret)
+    }
+
+    /// syscall
+    fn lseek(&mut self, fd: libc::c_int, offset: libc::off_t, whence: libc::c_int) -> Result {
+        self.trace("lseek", 3);
+
+        if let Some(ret) = self.tmpfs(|fs, _| fs.lseek(fd, offset, whence)).flatten() {
+            return ret;
+        }
+
+        if let Some(file) = self.sealed(|s| s.file(fd)).flatten() {
+            return sealed::lseek(self, file, offset, whence);
+        }
+
+        unsafe { self.proxy(request!(libc::SYS_lseek => fd, offset, whence)) }
+    }
+
+    /// syscall
+    fn pread64(
+        &mut self,
+        fd: libc::c_int,
+        buf: UntrustedRefMut<u8>,
+        count: libc::size_t,
+        offset: libc::off_t,
+    ) -> Result {
+        self.trace("pread64", 4);
+
+        let buf = buf.validate_slice(count, self).ok_or(libc::EFAULT)?;
+
+        if let Some(ret) = self.tmpfs(|fs, _| fs.pread64(fd, buf, offset)).flatten() {
+            return ret;
+        }
+
+        if let Some(file) = self.sealed(|s| s.file(fd)).flatten() {
+            let offset = usize::try_from(offset).or(Err(libc::EINVAL))?;
+            return sealed::read(self, file, buf, Some(offset));
+        }
+
+        // Limit the read to `Block::buf_capacity()`
+        let count = usize::min(count, Block::buf_capacity());
+
+        let c = self.new_cursor();
+        let (_, hostbuf) = c.alloc::<u8>(count).or(Err(libc::EMSGSIZE))?;
+        let host_virt = Self::translate_shim_to_host_addr(hostbuf.as_ptr());
+
+        let ret =
+            unsafe { self.proxy(request!(libc::SYS_pread64 => fd, host_virt, count, offset))? };
+
+        let result_len: usize = ret[0].into();
+
+        if count < result_len {
+            self.attacked();
+        }
+
+        let c = self.new_cursor();
+        unsafe {
+            c.copy_into_slice(count, &mut buf[..result_len])
+                .or(Err(libc::EFAULT))?;
+        }
+
+        Ok(ret)
+    }
+
+    /// syscall
+    fn pwrite64(
+        &mut self,
+        fd: libc::c_int,
+        buf: UntrustedRef<u8>,
+        count: libc::size_t,
+        offset: libc::off_t,
+    ) -> Result {
+        self.trace("pwrite64", 4);
+
+        // Limit the write to `Block::buf_capacity()`
+        let count = usize::min(count, Block::buf_capacity());
+
+        let buf = buf.validate_slice(count, self).ok_or(libc::EFAULT)?;
+
+        let tmpfs = self.tmpfs(|fs, pages| fs.pwrite64(fd, buf, offset, pages));
+        if let Some(ret) = tmpfs.flatten() {
+            return ret;
+        }
+
+        if let Some(file) = self.sealed(|s| s.file(fd)).flatten() {
+            let offset = usize::try_from(offset).or(Err(libc::EINVAL))?;
+            return sealed::write(self, file, buf, Some(offset));
+        }
+
+        let c = self.new_cursor();
+        let (_, hostbuf) = c.copy_from_slice(buf).or(Err(libc::EMSGSIZE))?;
+        let host_virt = Self::translate_shim_to_host_addr(hostbuf.as_ptr());
+
+        let ret =
+            unsafe { self.proxy(request!(libc::SYS_pwrite64 => fd, host_virt, count, offset))? };
+
+        let result_len: usize = ret[0].into();
+
+        if result_len > count {
+            self.attacked()
+        }
+
+        Ok(ret)
+    }
+
+    /// syscall
+    fn getdents64(&mut self, fd: libc::c_int, dirp: UntrustedRefMut<u8>, count: usize) -> Result {
+        self.trace("getdents64", 3);
+
+        let dirp = dirp.validate_slice(count, self).ok_or(libc::EFAULT)?;
+
+        if let Some(ret) = self.tmpfs(|fs, _| fs.getdents64(fd, dirp)).flatten() {
+            return ret;
+        }
+
+        // Limit the entries to `Block::buf_capacity()`
+        let count = usize::min(count, Block::buf_capacity());
+
+        let c = self.new_cursor();
+        let (_, hostbuf) = c.alloc::<u8>(count).or(Err(libc::EMSGSIZE))?;
+        let host_virt = Self::translate_shim_to_host_addr(hostbuf.as_ptr());
+
+        let ret = unsafe { self.proxy(request!(libc::SYS_getdents64 => fd, host_virt, count))? };
+
+        let result_len: usize = ret[0].into();
+
+        if count < result_len {
+            self.attacked();
+        }
+
+        let c = self.new_cursor();
+        unsafe {
+            c.copy_into_slice(count, &mut dirp[..result_len])
+                .or(Err(libc::EFAULT))?;
+        }
+
+        Ok(ret)
+    }
+
+    /// syscall
+    fn unlink(&mut self, pathname: UntrustedRef<u8>) -> Result {
+        self.trace("unlink", 1);
+
+        let pathname = validate_path(pathname, self)?;
+        let mut buf = [0; PATH_MAX];
+        let path = absolute(self, libc::AT_FDCWD, pathname, &mut buf)?;
+
+        if let Some(ret) = self.tmpfs(|fs, pages| fs.unlink(path, pages)).flatten() {
+            return ret;
+        }
+
+        let c = self.new_cursor();
+        let (_, buf) = c.copy_from_slice(pathname).or(Err(libc::EMSGSIZE))?;
+        let host_virt = Self::translate_shim_to_host_addr(buf.as_ptr());
+
+        unsafe { self.proxy(request!(libc::SYS_unlink => host_virt)) }
+    }
+
+    /// syscall
+    fn mkdir(&mut self, pathname: UntrustedRef<u8>, mode: libc::mode_t) -> Result {
+        self.trace("mkdir", 2);
+
+        let pathname = validate_path(pathname, self)?;
+        let mut buf = [0; PATH_MAX];
+        let path = absolute(self, libc::AT_FDCWD, pathname, &mut buf)?;
+
+        if let Some(ret) = self.tmpfs(|fs, _| fs.mkdir(path, mode)).flatten() {
+            return ret;
+        }
+
+        let c = self.new_cursor();
+        let (_, buf) = c.copy_from_slice(pathname).or(Err(libc::EMSGSIZE))?;
+        let host_virt = Self::translate_shim_to_host_addr(buf.as_ptr());
+
+        unsafe { self.proxy(request!(libc::SYS_mkdir => host_virt, mode)) }
+    }
+}
+
+/// Makes a path relative to the working directory absolute, in `buf`
+///
+/// The tmpfs and the sealed files only know absolute paths. The keep cannot
+/// change its working directory, which is the one of the host, so the host
+/// is asked for it. Other paths are returned unchanged, as are all paths if
+/// the keep has neither a tmpfs nor sealed files.
+fn absolute<'a, H: FileSyscallHandler>(
+    h: &mut H,
+    dirfd: libc::c_int,
+    path: &'a [u8],
+    buf: &'a mut [u8; PATH_MAX],
+) -> core::result::Result<&'a [u8], libc::c_int> {
+    if dirfd != libc::AT_FDCWD || matches!(path.first(), None | Some(b'/') | Some(0)) {
+        return Ok(path);
+    }
+
+    if h.tmpfs(|_, _| ()).is_none() && h.sealed(|_| ()).is_none() {
+        return Ok(path);
+    }
+
+    let c = h.new_cursor();
+    let (_, cwd) = c.alloc::<u8>(PATH_MAX).or(Err(libc::EMSGSIZE))?;
+    let host_virt = H::translate_shim_to_host_addr(cwd.as_ptr());
+
+    let ret = unsafe { h.proxy(request!(libc::SYS_getcwd => host_virt, PATH_MAX))? };
+    let len = usize::from(ret[0]);
+
+    if len > PATH_MAX {
+        h.attacked();
+    }
+
+    let mut cwd = [0u8; PATH_MAX];
+    let c = h.new_cursor();
+    unsafe { c.copy_into_slice(len, &mut cwd[..len]) }.or(Err(libc::EFAULT))?;
+
+    normalize(&cwd[..len], path, buf)
+}
+
+/// Validates the NUL-terminated path at `pathname`
+///
+/// Returns the path including the terminating NUL.
+fn validate_path<'a>(
+    pathname: UntrustedRef<'a, u8>,
+    validator: &impl AddressValidator,
+) -> core::result::Result<&'a [u8], libc::c_int> {
+    let ptr = pathname.as_ptr();
+
+    for len in 0..libc::PATH_MAX as usize {
+        let byte = UntrustedRef::from(ptr.wrapping_add(len))
+            .validate(validator)
+            .ok_or(libc::EFAULT)?;
+
+        if *byte == 0 {
+            return UntrustedRef::from(ptr)
+                .validate_slice(len + 1, validator)
+                .ok_or(libc::EFAULT);
+        }
+    }
+
+    Err(libc::ENAMETOOLONG)
 }
@@ -0,0 +1,271 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Futexes of the payload
+//!
+//! All threads of the payload share the address space of the keep, so a
+//! futex is just an address, private or not, and its waiters are kept in a
+//! table inside the keep. Waking them only marks them in the table, so a
+//! wakeup never needs the host. A waiter spins until it is woken and only
+//! yields its CPU to the host, if that takes longer.
+
+use crate::{BaseSyscallHandler, MAX_THREADS};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use libc::{c_int, c_long, clockid_t, time_t, timespec, EAGAIN, EINVAL, ENOMEM, ETIMEDOUT};
+use primordial::Register;
+use sallyport::{request, Result};
+
+/// The maximum number of waiting threads
+///
+/// Each thread waits on one futex at most, so a waiter always finds a slot.
+const MAX_WAITERS: usize = MAX_THREADS;
+
+/// How often a waiter looks for its wakeup, before it yields its CPU
+const SPINS: usize = 0x10000;
+
+/// The nanoseconds of a second
+const NSEC_PER_SEC: c_long = 1_000_000_000;
+
+/// A thread waiting on a futex
+struct Waiter {
+    /// The address of the futex, or 0 if the slot is free
+    addr: AtomicUsize,
+
+    /// The bits of `FUTEX_WAKE_BITSET` the waiter is woken for
+    bitset: AtomicU32,
+
+    /// Whether the waiter was woken
+    woken: AtomicBool,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WAITER: Waiter = Waiter {
+    addr: AtomicUsize::new(0),
+    bitset: AtomicU32::new(0),
+    woken: AtomicBool::new(false),
+};
+
+/// The waiters on all futexes, which only change with `LOCK` held
+static WAITERS: [Waiter; MAX_WAITERS] = [NO_WAITER; MAX_WAITERS];
+
+/// Guards `WAITERS`
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Holds `LOCK`, until it is dropped
+struct Guard;
+
+impl Guard {
+    fn lock() -> Self {
+        while LOCK
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+
+        Guard
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        LOCK.store(false, Ordering::Release);
+    }
+}
+
+/// The time a waiter gives up at
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Deadline {
+    clock: clockid_t,
+    time: (time_t, c_long),
+}
+
+impl Deadline {
+    /// The deadline `timeout` from now, on the monotonic clock
+    pub(crate) fn after<H: BaseSyscallHandler>(
+        h: &mut H,
+        timeout: &timespec,
+    ) -> core::result::Result<Self, c_int> {
+        let (sec, nsec) = valid(timeout)?;
+        let now = now(h, libc::CLOCK_MONOTONIC)?;
+
+        let nsec = now.tv_nsec + nsec;
+        let sec = now
+            .tv_sec
+            .saturating_add(sec)
+            .saturating_add(nsec / NSEC_PER_SEC);
+
+        Ok(Self {
+            clock: libc::CLOCK_MONOTONIC,
+            time: (sec, nsec % NSEC_PER_SEC),
+        })
+    }
+
+    /// The deadline at the absolute `time` of `clock`
+    pub(crate) fn at(clock: clockid_t, time: &timespec) -> core::result::Result<Self, c_int> {
+        Ok(Self {
+            clock,
+            time: valid(time)?,
+        })
+    }
+
+    /// Whether the deadline has passed
+    fn passed<H: BaseSyscallHandler>(&self, h: &mut H) -> core::result::Result<bool, c_int> {
+        let now = now(h, self.clock)?;
+        Ok((now.tv_sec, now.tv_nsec) >= self.time)
+    }
+}
+
+/// Returns the seconds and nanoseconds of a valid timeout
+fn valid(time: &timespec) -> core::result::Result<(time_t, c_long), c_int> {
+    if time.tv_sec < 0 || time.tv_nsec < 0 || time.tv_nsec >= NSEC_PER_SEC {
+        return Err(EINVAL);
+    }
+
+    Ok((time.tv_sec, time.tv_nsec))
+}
+
+/// Reads `clock` of the host
+fn now<H: BaseSyscallHandler>(
+    h: &mut H,
+    clock: clockid_t,
+) -> core::result::Result<timespec, c_int> {
+    let c = h.new_cursor();
+    let (_, buf) = c.alloc::<timespec>(1).or(Err(libc::EMSGSIZE))?;
+    let host_virt = H::translate_shim_to_host_addr(buf[0].as_ptr());
+
+    unsafe { h.proxy(request!(libc::SYS_clock_gettime => clock, host_virt))? };
+
+    let c = h.new_cursor();
+    Ok(unsafe { c.read().or(Err(libc::EMSGSIZE))?.1 })
+}
+
+/// Waits on `futex`, if it still holds `val`, until it is woken for any of the bits of `bitset`
+pub(crate) fn wait<H: BaseSyscallHandler>(
+    h: &mut H,
+    futex: &u32,
+    val: u32,
+    bitset: u32,
+    deadline: Option<Deadline>,
+) -> Result {
+    if bitset == 0 {
+        return Err(EINVAL);
+    }
+
+    let waiter = {
+        let _guard = Guard::lock();
+
+        // The waker changes the futex before it takes the lock.
+        let word = unsafe { &*(futex as *const u32 as *const AtomicU32) };
+        if word.load(Ordering::Relaxed) != val {
+            return Err(EAGAIN);
+        }
+
+        let waiter = WAITERS
+            .iter()
+            .find(|w| w.addr.load(Ordering::Relaxed) == 0)
+            .ok_or(ENOMEM)?;
+
+        waiter.woken.store(false, Ordering::Relaxed);
+        waiter.bitset.store(bitset, Ordering::Relaxed);
+        waiter
+            .addr
+            .store(futex as *const u32 as usize, Ordering::Relaxed);
+        waiter
+    };
+
+    let blocked = block(h, waiter, deadline.as_ref());
+
+    // A wakeup racing with a timeout still counts.
+    let _guard = Guard::lock();
+    waiter.addr.store(0, Ordering::Relaxed);
+
+    match waiter.woken.load(Ordering::Relaxed) {
+        true => Ok(Default::default()),
+        false => blocked.map(|()| Default::default()),
+    }
+}
+
+/// Spins until `waiter` is woken, yielding the CPU to the host now and then
+fn block<H: BaseSyscallHandler>(
+    h: &mut H,
+    waiter: &Waiter,
+    deadline: Option<&Deadline>,
+) -> core::result::Result<(), c_int> {
+    loop {
+        for _ in 0..SPINS {
+            if waiter.woken.load(Ordering::Acquire) {
+                return Ok(());
+            }
+
+            core::hint::spin_loop();
+        }
+
+        if let Some(deadline) = deadline {
+            if deadline.passed(h)? {
+                return Err(ETIMEDOUT);
+            }
+        }
+
+        unsafe { h.proxy(request!(libc::SYS_sched_yield))? };
+    }
+}
+
+/// Wakes up to `count` waiters of the futex at `addr` for any of the bits of `bitset`
+///
+/// Returns the number of woken waiters.
+pub(crate) fn wake(addr: usize, count: usize, bitset: u32) -> usize {
+    let _guard = Guard::lock();
+
+    let waiters = WAITERS
+        .iter()
+        .filter(|w| w.addr.load(Ordering::Relaxed) == addr)
+        .filter(|w| w.bitset.load(Ordering::Relaxed) & bitset != 0)
+        .filter(|w| !w.woken.load(Ordering::Relaxed))
+        .take(count);
+
+    let mut woken = 0;
+    for waiter in waiters {
+        waiter.woken.store(true, Ordering::Release);
+        woken += 1;
+    }
+
+    woken
+}
+
+/// Wakes up to `count` waiters of `futex` and moves up to `moved` others to the futex at `to`
+///
+/// With `expected`, `futex` still has to hold it. Returns the number of
+/// woken and moved waiters.
+pub(crate) fn requeue(
+    futex: &u32,
+    count: usize,
+    to: usize,
+    moved: usize,
+    expected: Option<u32>,
+) -> Result {
+    let _guard = Guard::lock();
+
+    let word = unsafe { &*(futex as *const u32 as *const AtomicU32) };
+    if expected.map_or(false, |val| word.load(Ordering::Relaxed) != val) {
+        return Err(EAGAIN);
+    }
+
+    let addr = futex as *const u32 as usize;
+    let waiters = WAITERS
+        .iter()
+        .filter(|w| w.addr.load(Ordering::Relaxed) == addr)
+        .filter(|w| !w.woken.load(Ordering::Relaxed))
+        .take(count.saturating_add(moved));
+
+    let mut total = 0;
+    for waiter in waiters {
+        match total < count {
+            true => waiter.woken.store(true, Ordering::Release),
+            false => waiter.addr.store(to, Ordering::Relaxed),
+        }
+
+        total += 1;
+    }
+
+    Ok([total.into(), 0.into()])
+}
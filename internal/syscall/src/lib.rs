@@ -9,10 +9,16 @@
 mod base;
 mod enarx;
 mod file;
+mod futex;
 mod memory;
 mod network;
+mod path;
 mod process;
+mod sealed;
+mod signal;
 mod system;
+mod thread;
+mod tmpfs;
 
 use core::convert::TryInto;
 use primordial::Register;
@@ -25,7 +31,11 @@ pub use crate::file::FileSyscallHandler;
 pub use crate::memory::MemorySyscallHandler;
 pub use crate::network::NetworkSyscallHandler;
 pub use crate::process::ProcessSyscallHandler;
+pub use crate::sealed::Sealed;
+pub use crate::signal::{Context, SigAction};
 pub use crate::system::SystemSyscallHandler;
+pub use crate::thread::{NewThread, Thread};
+pub use crate::tmpfs::{PageAlloc, Tmpfs};
 
 // import Enarx syscall constants
 include!("../../../src/syscall/mod.rs");
@@ -40,6 +50,18 @@ pub const ARCH_GET_FS: libc::c_int = 0x1003;
 /// missing in libc
 pub const ARCH_GET_GS: libc::c_int = 0x1004;
 
+// si_code values of faults not available in the libc crate
+/// missing in libc
+pub const SEGV_MAPERR: libc::c_int = 1;
+/// missing in libc
+pub const SEGV_ACCERR: libc::c_int = 2;
+/// missing in libc
+pub const ILL_ILLOPN: libc::c_int = 2;
+/// missing in libc
+pub const FPE_INTDIV: libc::c_int = 1;
+/// missing in libc
+pub const SI_KERNEL: libc::c_int = 0x80;
+
 /// Fake pid returned by enarx
 pub const FAKE_PID: usize = 1000;
 /// Fake uid returned by enarx
@@ -47,12 +69,10 @@ pub const FAKE_UID: usize = 1000;
 /// Fake gid returned by enarx
 pub const FAKE_GID: usize = 1000;
 
-/// not defined in libc
+/// The maximum number of threads of the payload in any shim
 ///
-/// FIXME
-pub struct KernelSigSet;
-
-type KernelSigAction = [u64; 4];
+/// It is at least the number of vCPUs of shim-sev and of TCSs of shim-sgx.
+pub const MAX_THREADS: usize = 256;
 
 /// A trait defining a shim syscall handler
 ///
@@ -101,6 +121,25 @@ pub trait SyscallHandler:
             libc::SYS_exit => self.exit(usize::from(a) as _),
             libc::SYS_exit_group => self.exit_group(usize::from(a) as _),
             libc::SYS_set_tid_address => self.set_tid_address(a.into()),
+            libc::SYS_clone => {
+                self.clone(usize::from(a) as _, b.into(), c.into(), d.into(), e.into())
+            }
+            libc::SYS_futex => self.futex(
+                a.into(),
+                usize::from(b) as _,
+                usize::from(c) as _,
+                d.into(),
+                e.into(),
+                usize::from(f) as _,
+            ),
+            libc::SYS_set_robust_list => self.set_robust_list(a.into(), b.into()),
+            libc::SYS_gettid => self.gettid(),
+            libc::SYS_kill => self.kill(usize::from(a) as _, usize::from(b) as _),
+            libc::SYS_tgkill => self.tgkill(
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
             libc::SYS_rt_sigaction => {
                 self.rt_sigaction(usize::from(a) as _, b.into(), c.into(), d.into())
             }
@@ -163,6 +202,29 @@ pub trait SyscallHandler:
                 usize::from(b) as _,
                 usize::from(c) as _,
             ),
+            libc::SYS_openat => self.openat(
+                usize::from(a) as _,
+                b.into(),
+                usize::from(c) as _,
+                usize::from(d) as _,
+            ),
+            libc::SYS_newfstatat => {
+                self.newfstatat(usize::from(a) as _, b.into(), c.into(), usize::from(d) as _)
+            }
+            libc::SYS_lseek => self.lseek(
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_pread64 => {
+                self.pread64(usize::from(a) as _, b.into(), c.into(), usize::from(d) as _)
+            }
+            libc::SYS_pwrite64 => {
+                self.pwrite64(usize::from(a) as _, b.into(), c.into(), usize::from(d) as _)
+            }
+            libc::SYS_getdents64 => self.getdents64(usize::from(a) as _, b.into(), c.into()),
+            libc::SYS_unlink => self.unlink(a.into()),
+            libc::SYS_mkdir => self.mkdir(a.into(), usize::from(b) as _),
 
             // NetworkSyscallHandler
             libc::SYS_socket => self.socket(
@@ -211,6 +273,11 @@ pub trait SyscallHandler:
             }
         };
 
+        // The host only forwards a signal, which interrupted one of its syscalls.
+        if let Err(libc::EINTR) | Err(libc::EPIPE) = ret {
+            signal::forwarded(self);
+        }
+
         #[cfg(target_arch = "x86_64")]
         if nr < 0xEA00 {
             // Non Enarx syscalls don't use `ret[1]` and have
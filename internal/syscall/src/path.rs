@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Lexical normalisation of paths
+//!
+//! The tmpfs and the sealed files decide by its path whether a syscall is
+//! theirs. Paths are compared in normal form, so no other spelling of a
+//! path below their prefix, like `//tmp/x`, `/./tmp/x` or `/var/../tmp/x`,
+//! reaches the host.
+
+use libc::{c_int, ENAMETOOLONG};
+
+/// The maximum length of a path, including the terminating NUL
+pub const PATH_MAX: usize = libc::PATH_MAX as usize;
+
+/// Writes the normal form of `path` to `buf` and returns it
+///
+/// A relative `path` is resolved against the absolute directory `dir`. The
+/// normal form is absolute and has no empty, `.` or `..` components; `..`
+/// in the root directory stays there. Terminating NULs are ignored.
+pub fn normalize<'a>(
+    dir: &[u8],
+    path: &[u8],
+    buf: &'a mut [u8; PATH_MAX],
+) -> Result<&'a [u8], c_int> {
+    let dir = trim_nul(dir);
+    let path = trim_nul(path);
+
+    let dir = match path.first() {
+        Some(b'/') => &[],
+        _ => dir,
+    };
+
+    let mut len = 0;
+    for name in dir.split(|b| *b == b'/').chain(path.split(|b| *b == b'/')) {
+        match name {
+            b"" | b"." => (),
+            b".." => len = buf[..len].iter().rposition(|b| *b == b'/').unwrap_or(0),
+            _ => {
+                let end = len + 1 + name.len();
+                if end >= PATH_MAX {
+                    return Err(ENAMETOOLONG);
+                }
+
+                buf[len] = b'/';
+                buf[len + 1..end].copy_from_slice(name);
+                len = end;
+            }
+        }
+    }
+
+    if len == 0 {
+        buf[0] = b'/';
+        len = 1;
+    }
+
+    Ok(&buf[..len])
+}
+
+/// Returns the rest of `path` below `prefix`, both in normal form
+///
+/// The rest is empty for `prefix` itself and starts with a slash otherwise.
+pub fn below<'a>(path: &'a [u8], prefix: &[u8]) -> Option<&'a [u8]> {
+    let rest = path.strip_prefix(prefix)?;
+
+    match rest.first() {
+        None | Some(b'/') => Some(rest),
+        Some(_) => None,
+    }
+}
+
+/// Strips the terminating NULs of a path
+fn trim_nul(mut path: &[u8]) -> &[u8] {
+    while let Some(rest) = path.strip_suffix(&[0]) {
+        path = rest;
+    }
+
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normal(dir: &str, path: &str) -> String {
+        let mut buf = [0; PATH_MAX];
+        let path = normalize(dir.as_bytes(), path.as_bytes(), &mut buf).unwrap();
+        String::from_utf8(path.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn spellings() {
+        for path in &[
+            "/tmp/x",
+            "//tmp/x",
+            "/./tmp/x",
+            "/var/../tmp/x",
+            "/tmp//x/.\0",
+        ] {
+            assert_eq!(normal("/home", path), "/tmp/x");
+        }
+
+        assert_eq!(normal("/", "/../../tmp/x"), "/tmp/x");
+        assert_eq!(normal("/", "/"), "/");
+        assert_eq!(normal("/", "/tmp/.."), "/");
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(normal("/tmp\0", "x"), "/tmp/x");
+        assert_eq!(normal("/home/user", "../../tmp/./x"), "/tmp/x");
+        assert_eq!(normal("/home/user", "../../../../tmp/x"), "/tmp/x");
+        assert_eq!(normal("/tmp", ""), "/tmp");
+    }
+
+    #[test]
+    fn too_long() {
+        let mut buf = [0; PATH_MAX];
+        let path = [b'x'; PATH_MAX];
+        assert_eq!(normalize(b"/", &path, &mut buf), Err(ENAMETOOLONG));
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!(below(b"/tmp/x", b"/tmp"), Some(&b"/x"[..]));
+        assert_eq!(below(b"/tmp", b"/tmp"), Some(&b""[..]));
+        assert_eq!(below(b"/tmpx", b"/tmp"), None);
+        assert_eq!(below(b"/", b"/tmp"), None);
+    }
+}
@@ -2,7 +2,12 @@
 
 //! process syscalls
 
-use crate::{BaseSyscallHandler, KernelSigAction, KernelSigSet, FAKE_GID, FAKE_PID, FAKE_UID};
+use crate::futex::{self, Deadline};
+use crate::signal::{self, Context, SigAction};
+use crate::thread::{
+    self, NewThread, Thread, CLONE_OTHER_FLAGS, CLONE_THREAD_FLAGS, ROBUST_LIST_HEAD_SIZE,
+};
+use crate::{BaseSyscallHandler, FAKE_GID, FAKE_PID, FAKE_UID};
 use sallyport::{request, Result};
 use untrusted::{AddressValidator, UntrustedRef, UntrustedRefMut, Validate};
 
@@ -11,10 +16,241 @@ pub trait ProcessSyscallHandler: BaseSyscallHandler + AddressValidator + Sized {
     /// syscall
     fn arch_prctl(&mut self, code: libc::c_int, addr: libc::c_ulong) -> Result;
 
+    /// Calls `f` with the calling thread of the payload
+    fn thread<T>(&mut self, f: impl FnOnce(&mut Thread) -> T) -> T;
+
+    /// Calls `f` with the extended state the calling thread returns to the payload with
+    ///
+    /// The state of the floating point unit, SSE and AVX is in the format
+    /// of FXSAVE or, if the image is larger than 512 bytes, of XSAVE. The
+    /// changes of `f` are restored for the payload.
+    fn xstate<T>(&mut self, f: impl FnOnce(&mut [u8]) -> T) -> T;
+
+    /// Starts a thread of the payload on a vCPU or TCS of its own
+    ///
+    /// The thread returns from `clone()` with `%rax` zero, on the stack and
+    /// with the TLS of `new`, but otherwise with the registers of the
+    /// calling thread. On it, `thread()` has to return `new.thread`.
+    fn spawn(&mut self, new: NewThread) -> core::result::Result<(), libc::c_int>;
+
+    /// Do a clone() syscall
+    ///
+    /// Only threads can be started, as there is no other process in the keep.
+    fn clone(
+        &mut self,
+        flags: libc::c_int,
+        stack: usize,
+        ptid: UntrustedRefMut<libc::pid_t>,
+        ctid: UntrustedRefMut<libc::pid_t>,
+        tls: usize,
+    ) -> Result {
+        self.trace("clone", 5);
+
+        // The low byte is the signal sent to the parent of a process.
+        let flags = flags & !0xff;
+
+        if flags & CLONE_THREAD_FLAGS != CLONE_THREAD_FLAGS {
+            return Err(libc::ENOSYS);
+        }
+
+        if flags & !(CLONE_THREAD_FLAGS | CLONE_OTHER_FLAGS) != 0 || stack == 0 {
+            return Err(libc::EINVAL);
+        }
+
+        let clear_child_tid = match flags & libc::CLONE_CHILD_CLEARTID {
+            0 => 0,
+            _ => ctid.as_ptr() as usize,
+        };
+
+        // The new thread blocks the signals the calling thread blocks.
+        let signals = self.thread(|thread| thread.signals.inherit());
+
+        let new = NewThread {
+            thread: Thread::spawned(clear_child_tid, signals),
+            stack,
+            tls: match flags & libc::CLONE_SETTLS {
+                0 => None,
+                _ => Some(tls),
+            },
+        };
+
+        let tid = new.thread.tid();
+
+        // Both ids are written before the thread starts.
+        if flags & libc::CLONE_PARENT_SETTID != 0 {
+            *ptid.validate(self).ok_or(libc::EFAULT)? = tid;
+        }
+
+        if flags & libc::CLONE_CHILD_SETTID != 0 {
+            *ctid.validate(self).ok_or(libc::EFAULT)? = tid;
+        }
+
+        self.spawn(new)?;
+
+        Ok([(tid as usize).into(), 0.into()])
+    }
+
+    /// Do a futex() syscall
+    ///
+    /// The futexes live inside the keep, so waking a thread needs no host.
+    /// Priority inheritance and `FUTEX_WAKE_OP` are not supported.
+    fn futex(
+        &mut self,
+        uaddr: UntrustedRef<u32>,
+        op: libc::c_int,
+        val: u32,
+        timeout: usize,
+        uaddr2: UntrustedRef<u32>,
+        val3: u32,
+    ) -> Result {
+        self.trace("futex", 6);
+
+        let word = uaddr.validate(self).ok_or(libc::EFAULT)?;
+        let addr = word as *const u32 as usize;
+        let timeout = UntrustedRef::from(timeout as *const libc::timespec);
+
+        // For the requeue operations, the timeout is the second count.
+        let moved = timeout.as_ptr() as usize;
+
+        let clock = match op & libc::FUTEX_CLOCK_REALTIME {
+            0 => libc::CLOCK_MONOTONIC,
+            _ => libc::CLOCK_REALTIME,
+        };
+
+        let cmd = op & !(libc::FUTEX_PRIVATE_FLAG | libc::FUTEX_CLOCK_REALTIME);
+
+        match cmd {
+            libc::FUTEX_WAIT => {
+                let deadline = match timeout.as_ptr().is_null() {
+                    true => None,
+                    false => {
+                        let timeout = timeout.validate(self).ok_or(libc::EFAULT)?;
+                        Some(Deadline::after(self, timeout)?)
+                    }
+                };
+
+                futex::wait(self, word, val, !0, deadline)
+            }
+
+            libc::FUTEX_WAIT_BITSET => {
+                let deadline = match timeout.as_ptr().is_null() {
+                    true => None,
+                    false => {
+                        let timeout = timeout.validate(self).ok_or(libc::EFAULT)?;
+                        Some(Deadline::at(clock, timeout)?)
+                    }
+                };
+
+                futex::wait(self, word, val, val3, deadline)
+            }
+
+            libc::FUTEX_WAKE => Ok([futex::wake(addr, val as usize, !0).into(), 0.into()]),
+
+            libc::FUTEX_WAKE_BITSET => Ok([futex::wake(addr, val as usize, val3).into(), 0.into()]),
+
+            libc::FUTEX_REQUEUE | libc::FUTEX_CMP_REQUEUE => {
+                let to = uaddr2.validate(self).ok_or(libc::EFAULT)?;
+                let expected = match cmd {
+                    libc::FUTEX_CMP_REQUEUE => Some(val3),
+                    _ => None,
+                };
+
+                futex::requeue(
+                    word,
+                    val as usize,
+                    to as *const u32 as usize,
+                    moved,
+                    expected,
+                )
+            }
+
+            _ => Err(libc::ENOSYS),
+        }
+    }
+
+    /// Do a set_robust_list() syscall
+    fn set_robust_list(&mut self, head: usize, len: libc::size_t) -> Result {
+        self.trace("set_robust_list", 2);
+
+        if len != ROBUST_LIST_HEAD_SIZE {
+            return Err(libc::EINVAL);
+        }
+
+        self.thread(|thread| thread.set_robust_list(head));
+        Ok(Default::default())
+    }
+
+    /// Do a gettid() syscall
+    fn gettid(&mut self) -> Result {
+        self.trace("gettid", 0);
+        let tid = self.thread(|thread| thread.tid());
+        Ok([(tid as usize).into(), 0.into()])
+    }
+
+    /// Do a kill() syscall
+    ///
+    /// The payload is the only process in the keep.
+    fn kill(&mut self, pid: libc::pid_t, sig: libc::c_int) -> Result {
+        self.trace("kill", 2);
+
+        if sig != 0 && !signal::valid(sig) {
+            return Err(libc::EINVAL);
+        }
+
+        if pid != FAKE_PID as libc::pid_t && pid != 0 {
+            return Err(libc::ESRCH);
+        }
+
+        if sig != 0 {
+            signal::send(sig);
+        }
+
+        Ok(Default::default())
+    }
+
+    /// Do a tgkill() syscall
+    ///
+    /// A signal for the calling thread is delivered, before the syscall
+    /// returns, unless the thread blocks it. One for another thread waits
+    /// until that thread returns to the payload and does not block it.
+    fn tgkill(&mut self, tgid: libc::pid_t, tid: libc::pid_t, sig: libc::c_int) -> Result {
+        self.trace("tgkill", 3);
+
+        if sig != 0 && !signal::valid(sig) {
+            return Err(libc::EINVAL);
+        }
+
+        if tgid != FAKE_PID as libc::pid_t || !thread::exists(tid) {
+            return Err(libc::ESRCH);
+        }
+
+        if sig != 0 {
+            let own = self.thread(|thread| {
+                let own = thread.tid() == tid;
+                if own {
+                    thread.signals.send(sig);
+                }
+                own
+            });
+
+            if !own {
+                signal::send_to(tid, sig)?;
+            }
+        }
+
+        Ok(Default::default())
+    }
+
     /// Proxy an exit() syscall
+    ///
+    /// Only the calling thread exits. Before, it releases its futexes.
     fn exit(&mut self, status: libc::c_int) -> ! {
         self.trace("exit", 1);
 
+        let thread = self.thread(|thread| *thread);
+        thread.release(self);
+        signal::forget(thread.tid());
+
         #[allow(unused_must_use)]
         loop {
             unsafe { self.proxy(request!(libc::SYS_exit => status)) };
@@ -24,8 +260,7 @@ pub trait ProcessSyscallHandler: BaseSyscallHandler + AddressValidator + Sized {
 
     /// Proxy an exitgroup() syscall
     ///
-    /// TODO: Currently we are only using one thread, so this will behave the
-    /// same way as exit(). In the future, this implementation will change.
+    /// The host ends all threads of the keep.
     fn exit_group(&mut self, status: libc::c_int) -> ! {
         self.trace("exit_group", 1);
 
@@ -37,75 +272,148 @@ pub trait ProcessSyscallHandler: BaseSyscallHandler + AddressValidator + Sized {
     }
 
     /// Do a set_tid_address() syscall
-    ///
-    /// This is currently unimplemented and returns a dummy thread id.
-    fn set_tid_address(&mut self, _tidptr: *const libc::c_int) -> Result {
+    fn set_tid_address(&mut self, tidptr: *const libc::c_int) -> Result {
         self.trace("set_tid_address", 1);
-        // FIXME
-        //eprintln!("SC> set_tid_address(…) = 1");
-        Ok([1.into(), 0.into()])
+        let tid = self.thread(|thread| {
+            thread.set_clear_child_tid(tidptr as usize);
+            thread.tid()
+        });
+
+        Ok([(tid as usize).into(), 0.into()])
     }
 
     /// Do a rt_sigaction() system call
     ///
-    /// We don't support signals yet. So, fake success.
+    /// The handler only runs, when the thread returns to the payload. So,
+    /// it needs `SA_RESTORER` like on Linux.
     fn rt_sigaction(
         &mut self,
         signum: libc::c_int,
-        act: UntrustedRef<KernelSigAction>,
-        oldact: UntrustedRefMut<KernelSigAction>,
+        act: UntrustedRef<SigAction>,
+        oldact: UntrustedRefMut<SigAction>,
         size: usize,
     ) -> Result {
         self.trace("rt_sigaction", 4);
 
-        const SIGRTMAX: libc::c_int = 64; // TODO: add to libc crate
-        static mut ACTIONS: [KernelSigAction; SIGRTMAX as usize] = [[0; 4]; SIGRTMAX as usize];
-
-        if signum >= SIGRTMAX || size != 8 {
+        if size != 8 {
             return Err(libc::EINVAL);
         }
 
-        unsafe {
-            if !oldact.as_ptr().is_null() {
-                let oldact = oldact.validate(self).ok_or(libc::EFAULT)?;
-                *(oldact) = ACTIONS[signum as usize];
-            }
+        let act = match act.as_ptr().is_null() {
+            true => None,
+            false => Some(*act.validate(self).ok_or(libc::EFAULT)?),
+        };
 
-            if !act.as_ptr().is_null() {
-                let act = act.validate(self).ok_or(libc::EFAULT)?;
-                ACTIONS[signum as usize] = *act;
-            }
+        let oldact = match oldact.as_ptr().is_null() {
+            true => None,
+            false => Some(oldact.validate(self).ok_or(libc::EFAULT)?),
+        };
+
+        let old = signal::set_action(signum, act.as_ref())?;
+        if let Some(oldact) = oldact {
+            *oldact = old;
         }
 
         Ok(Default::default())
     }
+
     /// Do a rt_sigprocmask() syscall
-    ///
-    /// We don't support signals yet. So, fake success.
     fn rt_sigprocmask(
         &mut self,
-        _how: libc::c_int,
-        _set: UntrustedRef<KernelSigSet>,
-        _oldset: UntrustedRefMut<KernelSigSet>,
-        _sigsetsize: libc::size_t,
+        how: libc::c_int,
+        set: UntrustedRef<u64>,
+        oldset: UntrustedRefMut<u64>,
+        sigsetsize: libc::size_t,
     ) -> Result {
-        // FIXME
         self.trace("rt_sigprocmask", 4);
+
+        if sigsetsize != 8 {
+            return Err(libc::EINVAL);
+        }
+
+        let set = match set.as_ptr().is_null() {
+            true => None,
+            false => Some(*set.validate(self).ok_or(libc::EFAULT)?),
+        };
+
+        let oldset = match oldset.as_ptr().is_null() {
+            true => None,
+            false => Some(oldset.validate(self).ok_or(libc::EFAULT)?),
+        };
+
+        let old = self.thread(|thread| thread.signals.set_mask(how, set))?;
+        if let Some(oldset) = oldset {
+            *oldset = old;
+        }
+
         Ok(Default::default())
     }
 
     /// Do a sigaltstack() syscall
-    ///
-    /// This is currently unimplemented and returns success.
     fn sigaltstack(
         &mut self,
-        _ss: UntrustedRef<libc::stack_t>,
-        _old_ss: UntrustedRefMut<libc::stack_t>,
+        ss: UntrustedRef<libc::stack_t>,
+        old_ss: UntrustedRefMut<libc::stack_t>,
     ) -> Result {
         self.trace("sigaltstack", 2);
 
+        let ss = match ss.as_ptr().is_null() {
+            true => None,
+            false => Some(*ss.validate(self).ok_or(libc::EFAULT)?),
+        };
+
+        let old_ss = match old_ss.as_ptr().is_null() {
+            true => None,
+            false => Some(old_ss.validate(self).ok_or(libc::EFAULT)?),
+        };
+
+        let old = self.thread(|thread| thread.signals.set_stack(ss.as_ref()))?;
+        if let Some(old_ss) = old_ss {
+            *old_ss = old;
+        }
+
         Ok(Default::default())
     }
+
+    /// Do a rt_sigreturn() syscall
+    ///
+    /// Returns from a handler to the registers of the thread saved in its
+    /// frame on the stack of the thread in `ctx`. The shims handle it
+    /// themselves, as it changes all registers.
+    fn rt_sigreturn(&mut self, ctx: &mut Context) {
+        self.trace("rt_sigreturn", 0);
+
+        if signal::restore(self, ctx).is_err() {
+            self.exit_group(128 + libc::SIGSEGV);
+        }
+    }
+
+    /// Delivers the pending signals the calling thread does not block
+    ///
+    /// The shims call it with the registers the thread returns to the
+    /// payload with. If a signal has a handler, the thread continues in it
+    /// instead and the remaining signals wait for its next return.
+    fn signal(&mut self, ctx: &mut Context) {
+        while let Some(sig) = self.thread(|thread| thread.take_signal()) {
+            if signal::deliver_sent(self, ctx, sig) {
+                break;
+            }
+        }
+    }
+
+    /// Raises `sig` for a fault of the payload at the registers in `ctx`
+    ///
+    /// `code` and `addr` are the `si_code` and `si_addr` of the signal.
+    /// Without a handler, or if the thread blocks `sig`, the payload
+    /// terminates like on Linux.
+    fn fault(&mut self, ctx: &mut Context, sig: libc::c_int, code: libc::c_int, addr: usize) {
+        if signal::fatal(self, sig) {
+            self.exit_group(128 + sig);
+        }
+
+        signal::deliver(self, ctx, sig, code, [addr as u64, 0]);
+    }
+
     /// syscall
     fn getpid(&mut self) -> Result {
         self.trace("getpid", 0);
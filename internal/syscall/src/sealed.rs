@@ -0,0 +1,636 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Files sealed to the keep, but stored on the host
+//!
+//! The files below the sealed prefix are stored on the host as a sequence
+//! of records. Each record holds one block of the file, encrypted and
+//! authenticated with AES-256-GCM under a random nonce:
+//!
+//! ```text
+//! | nonce (12) | ciphertext (up to 4096) | tag (16) |
+//! ```
+//!
+//! Only the last record may hold less than a full block. The additional
+//! data of a record binds it to the path of its file, its index and whether
+//! it is the last one, so the host can neither move records between files
+//! or positions nor cut records off the end of a file unnoticed. A record
+//! which fails to open makes the syscall fail with `EIO`.
+//!
+//! Nothing binds a file to its version, though. The host can truncate a
+//! file to 0 bytes, which reads as an empty file, or roll the whole file
+//! back to one it stored before, and neither is noticed.
+//!
+//! The key is derived from secret material only the keep has, so the host
+//! never sees the contents of the files. Their names, sizes and the order
+//! of writes are visible to it, though.
+
+use crate::path::{below, normalize, PATH_MAX};
+use crate::{BaseSyscallHandler, FileSyscallHandler, SEALED_PATH_MAX};
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use aes_gcm::{Aes256Gcm, Nonce, Tag};
+use core::convert::TryFrom;
+use libc::{c_int, off_t, EACCES, EBADF, EFAULT, EFBIG, EINVAL, EIO, EISDIR, EMFILE, EMSGSIZE};
+use sallyport::{request, Result};
+use sha2::{Digest, Sha256};
+
+/// The maximum number of open sealed files
+const MAX_FILES: usize = 32;
+
+/// The size of the plaintext of a full record
+const BLOCK: usize = 4096;
+
+/// The size of the nonce at the start of a record
+const NONCE: usize = 12;
+
+/// The size of the tag at the end of a record
+const TAG: usize = 16;
+
+/// The size of a full record
+const RECORD: usize = NONCE + BLOCK + TAG;
+
+/// Distinguishes the key of the sealed files from other keys derived from
+/// the same secret
+const KEY_CONTEXT: &[u8] = b"enarx sealed file key";
+
+#[derive(Copy, Clone)]
+enum Key {
+    /// The shim has not been asked for the secret yet
+    Unknown,
+
+    /// The keep has no secret to derive the key from
+    Missing,
+
+    Some([u8; 32]),
+}
+
+/// An open sealed file
+#[derive(Copy, Clone)]
+pub(crate) struct File {
+    /// The file descriptor on the host
+    pub(crate) fd: c_int,
+
+    flags: c_int,
+
+    /// The position in the plaintext of the file
+    offset: usize,
+
+    /// The hash of the path below the prefix
+    id: [u8; 32],
+
+    key: [u8; 32],
+}
+
+/// The open files below the sealed prefix
+///
+/// The methods taking a path return `None` if it is not below the prefix,
+/// so the syscall is for the host as usual.
+pub struct Sealed {
+    prefix: [u8; SEALED_PATH_MAX],
+    key: Key,
+    files: [Option<File>; MAX_FILES],
+}
+
+impl Default for Sealed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sealed {
+    /// Creates a table without a prefix and without open files
+    pub const fn new() -> Self {
+        Self {
+            prefix: [0; SEALED_PATH_MAX],
+            key: Key::Unknown,
+            files: [None; MAX_FILES],
+        }
+    }
+
+    /// Seals the files below the NUL padded `path`, which is in normal form
+    ///
+    /// `secret` is called once with the function deriving the key from the
+    /// secret material of the keep, which it returns. Without a key,
+    /// opening a file below the prefix fails with `EACCES`. Returns `None`
+    /// if `path` is empty.
+    pub fn mount(
+        &mut self,
+        path: &[u8; SEALED_PATH_MAX],
+        secret: impl FnOnce(fn(&[u8]) -> [u8; 32]) -> Option<[u8; 32]>,
+    ) -> Option<&mut Self> {
+        if path[0] == 0 {
+            return None;
+        }
+
+        if let Key::Unknown = self.key {
+            self.key = match secret(derive) {
+                Some(key) => Key::Some(key),
+                None => Key::Missing,
+            };
+        }
+
+        self.prefix = *path;
+        Some(self)
+    }
+
+    /// Whether `fd` is a file descriptor of a sealed file
+    pub fn owns(&self, fd: c_int) -> bool {
+        self.file(fd).is_some()
+    }
+
+    /// Returns the hash identifying a path below the prefix
+    ///
+    /// The path is compared to the prefix in normal form, which is hashed,
+    /// so every spelling of a path identifies the same file. Paths relative
+    /// to the working directory have to be made absolute before. Paths
+    /// relative to a sealed directory are refused, as are paths without a
+    /// normal form, because the host must not get them if they are sealed.
+    pub(crate) fn id(
+        &self,
+        dirfd: c_int,
+        path: &[u8],
+    ) -> Option<core::result::Result<[u8; 32], c_int>> {
+        let path = path.strip_suffix(&[0]).unwrap_or(path);
+
+        if path.first() != Some(&b'/') {
+            return self.owns(dirfd).then(|| Err(EACCES));
+        }
+
+        let mut buf = [0; PATH_MAX];
+        let path = match normalize(b"/", path, &mut buf) {
+            Ok(path) => path,
+            Err(_) => return Some(Err(EACCES)),
+        };
+
+        let len = self
+            .prefix
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(SEALED_PATH_MAX);
+        let rest = below(path, &self.prefix[..len])?;
+
+        let key = match self.key {
+            Key::Some(key) => key,
+            _ => return Some(Err(EACCES)),
+        };
+
+        let mut hash = Sha256::new();
+        hash.update(&key);
+        hash.update(rest);
+        Some(Ok(hash.finalize().into()))
+    }
+
+    /// Records a file opened on the host
+    pub(crate) fn insert(&mut self, fd: c_int, flags: c_int, id: [u8; 32]) -> Result {
+        let key = match self.key {
+            Key::Some(key) => key,
+            _ => return Err(EACCES),
+        };
+
+        let slot = self.files.iter_mut().find(|f| f.is_none()).ok_or(EMFILE)?;
+        *slot = Some(File {
+            fd,
+            flags,
+            offset: 0,
+            id,
+            key,
+        });
+
+        Ok([fd.into(), 0.into()])
+    }
+
+    /// Forgets a file, which is about to be closed on the host
+    pub(crate) fn remove(&mut self, fd: c_int) {
+        for slot in self.files.iter_mut() {
+            if slot.map_or(false, |f| f.fd == fd) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Returns an open sealed file
+    pub(crate) fn file(&self, fd: c_int) -> Option<File> {
+        self.files.iter().flatten().find(|f| f.fd == fd).copied()
+    }
+
+    /// Moves the position of an open sealed file
+    fn seek_to(&mut self, fd: c_int, offset: usize) {
+        for file in self.files.iter_mut().flatten() {
+            if file.fd == fd {
+                file.offset = offset;
+            }
+        }
+    }
+}
+
+/// syscall
+///
+/// Opens the file on the host for reading as well, because partial blocks
+/// have to be read back to be written.
+pub(crate) fn openat<H: FileSyscallHandler>(
+    h: &mut H,
+    dirfd: c_int,
+    path: &[u8],
+    flags: c_int,
+    mode: libc::mode_t,
+    id: [u8; 32],
+) -> Result {
+    let mut host_flags = flags & !libc::O_APPEND;
+    if flags & libc::O_ACCMODE == libc::O_WRONLY {
+        host_flags = (host_flags & !libc::O_ACCMODE) | libc::O_RDWR;
+    }
+
+    let c = h.new_cursor();
+    let (_, buf) = c.copy_from_slice(path).or(Err(EMSGSIZE))?;
+    let host_virt = H::translate_shim_to_host_addr(buf.as_ptr());
+
+    let ret = unsafe { h.proxy(request!(libc::SYS_openat => dirfd, host_virt, host_flags, mode))? };
+    let fd = usize::from(ret[0]) as c_int;
+
+    match h.sealed(|s| s.insert(fd, flags, id)).unwrap_or(Err(EACCES)) {
+        Ok(ret) => Ok(ret),
+        Err(errno) => {
+            let _ = unsafe { h.proxy(request!(libc::SYS_close => fd)) };
+            Err(errno)
+        }
+    }
+}
+
+/// syscall
+pub(crate) fn close<H: FileSyscallHandler>(h: &mut H, fd: c_int) -> Result {
+    h.sealed(|s| s.remove(fd));
+    unsafe { h.proxy(request!(libc::SYS_close => fd)) }
+}
+
+/// Reads at the position of the file, or at `offset`
+pub(crate) fn read<H: FileSyscallHandler>(
+    h: &mut H,
+    file: File,
+    buf: &mut [u8],
+    offset: Option<usize>,
+) -> Result {
+    if file.flags & libc::O_ACCMODE == libc::O_WRONLY {
+        return Err(EBADF);
+    }
+
+    let start = offset.unwrap_or(file.offset);
+    let size = size(h, file.fd)?;
+    let end = usize::min(size, start.saturating_add(buf.len()));
+
+    let mut record = [0u8; RECORD];
+    let mut pos = start;
+
+    while pos < end {
+        let index = pos / BLOCK;
+        let block = load(h, &file, index, size, &mut record)?;
+        let skip = pos - index * BLOCK;
+        let len = usize::min(block.len() - skip, end - pos);
+
+        buf[pos - start..][..len].copy_from_slice(&block[skip..][..len]);
+        pos += len;
+    }
+
+    if offset.is_none() {
+        h.sealed(|s| s.seek_to(file.fd, pos));
+    }
+
+    Ok([(pos - start).into(), 0.into()])
+}
+
+/// Writes at the position of the file, or at `offset`
+///
+/// Every block the write touches is sealed again. So is the previous last
+/// block if the file grows, because it is not the last one anymore.
+pub(crate) fn write<H: FileSyscallHandler>(
+    h: &mut H,
+    file: File,
+    buf: &[u8],
+    offset: Option<usize>,
+) -> Result {
+    if file.flags & libc::O_ACCMODE == libc::O_RDONLY {
+        return Err(EBADF);
+    }
+
+    let size = size(h, file.fd)?;
+    let start = match offset {
+        None if file.flags & libc::O_APPEND != 0 => size,
+        None => file.offset,
+        Some(offset) => offset,
+    };
+
+    if buf.is_empty() {
+        return Ok([0.into(), 0.into()]);
+    }
+
+    let end = start.checked_add(buf.len()).ok_or(EFBIG)?;
+    off_t::try_from(end).or(Err(EFBIG))?;
+    let new_size = usize::max(size, end);
+
+    let mut first = usize::min(start, size) / BLOCK;
+    if end > size && size > 0 {
+        first = usize::min(first, (size - 1) / BLOCK);
+    }
+
+    for index in first..=(end - 1) / BLOCK {
+        let pos = index * BLOCK;
+        let len = usize::min(BLOCK, new_size - pos);
+        let old = usize::min(BLOCK, size.saturating_sub(pos));
+
+        let mut record = [0u8; RECORD];
+        if old > 0 && (start > pos || end < pos + len) {
+            load(h, &file, index, size, &mut record)?;
+        }
+
+        let from = usize::max(start, pos);
+        let to = usize::min(end, pos + len);
+        if from < to {
+            let data = &buf[from - start..to - start];
+            record[NONCE + from - pos..][..data.len()].copy_from_slice(data);
+        }
+
+        let last = index == (new_size - 1) / BLOCK;
+        let sealed = seal(&file, index, last, &mut record, len)?;
+        host_pwrite(h, file.fd, sealed, index * RECORD)?;
+    }
+
+    if offset.is_none() {
+        h.sealed(|s| s.seek_to(file.fd, end));
+    }
+
+    Ok([buf.len().into(), 0.into()])
+}
+
+/// syscall
+pub(crate) fn lseek<H: FileSyscallHandler>(
+    h: &mut H,
+    file: File,
+    offset: off_t,
+    whence: c_int,
+) -> Result {
+    let base = match whence {
+        libc::SEEK_SET => 0,
+        libc::SEEK_CUR => file.offset,
+        libc::SEEK_END => size(h, file.fd)?,
+        _ => return Err(EINVAL),
+    };
+
+    let base = off_t::try_from(base).or(Err(EINVAL))?;
+    let offset = base.checked_add(offset).ok_or(EINVAL)?;
+    if offset < 0 {
+        return Err(EINVAL);
+    }
+
+    h.sealed(|s| s.seek_to(file.fd, offset as usize));
+    Ok([(offset as usize).into(), 0.into()])
+}
+
+/// Replaces the size of a sealed file in `stat` by the size of its plaintext
+pub(crate) fn stat(stat: &mut libc::stat) -> core::result::Result<(), c_int> {
+    if stat.st_mode & libc::S_IFMT == libc::S_IFREG {
+        stat.st_size = plain_size(stat.st_size as usize)? as _;
+    }
+
+    Ok(())
+}
+
+/// Returns the size of the plaintext of a sealed file
+fn size<H: BaseSyscallHandler>(h: &mut H, fd: c_int) -> core::result::Result<usize, c_int> {
+    let c = h.new_cursor();
+    let (_, buf) = c.alloc::<libc::stat>(1).or(Err(EMSGSIZE))?;
+    let host_virt = H::translate_shim_to_host_addr(buf.as_ptr());
+
+    unsafe { h.proxy(request!(libc::SYS_fstat => fd, host_virt))? };
+
+    let c = h.new_cursor();
+    let (_, stat) = unsafe { c.read::<libc::stat>() }.or(Err(EFAULT))?;
+
+    match stat.st_mode & libc::S_IFMT {
+        libc::S_IFREG => plain_size(stat.st_size as usize),
+        libc::S_IFDIR => Err(EISDIR),
+        _ => Err(EINVAL),
+    }
+}
+
+/// Returns the size of the plaintext stored in `size` bytes of records
+fn plain_size(size: usize) -> core::result::Result<usize, c_int> {
+    match size % RECORD {
+        0 => Ok(size / RECORD * BLOCK),
+        rest if rest > NONCE + TAG => Ok(size / RECORD * BLOCK + rest - NONCE - TAG),
+        _ => Err(EIO),
+    }
+}
+
+/// Reads and opens the record `index` of a file with `size` bytes of plaintext
+///
+/// Returns the plaintext, which is stored in `record` after the nonce.
+fn load<'a, H: BaseSyscallHandler>(
+    h: &mut H,
+    file: &File,
+    index: usize,
+    size: usize,
+    record: &'a mut [u8; RECORD],
+) -> core::result::Result<&'a mut [u8], c_int> {
+    let len = usize::min(BLOCK, size - index * BLOCK);
+    let last = index == (size - 1) / BLOCK;
+
+    host_pread(h, file.fd, &mut record[..NONCE + len + TAG], index * RECORD)?;
+    unseal(file, index, last, record, len)
+}
+
+/// Opens the record `index` in `record`, which holds `len` bytes of plaintext
+///
+/// Returns the plaintext, which is stored in `record` after the nonce.
+fn unseal<'a>(
+    file: &File,
+    index: usize,
+    last: bool,
+    record: &'a mut [u8; RECORD],
+    len: usize,
+) -> core::result::Result<&'a mut [u8], c_int> {
+    let (nonce, rest) = record.split_at_mut(NONCE);
+    let (block, rest) = rest.split_at_mut(len);
+    let tag = Tag::clone_from_slice(&rest[..TAG]);
+
+    cipher(file)
+        .decrypt_in_place_detached(
+            Nonce::from_slice(nonce),
+            &aad(file, index, last),
+            block,
+            &tag,
+        )
+        .or(Err(EIO))?;
+
+    Ok(block)
+}
+
+/// Seals the first `len` bytes of plaintext stored in `record` after the nonce
+///
+/// Returns the whole record.
+fn seal<'a>(
+    file: &File,
+    index: usize,
+    last: bool,
+    record: &'a mut [u8; RECORD],
+    len: usize,
+) -> core::result::Result<&'a [u8], c_int> {
+    let (nonce, rest) = record.split_at_mut(NONCE);
+    let (block, rest) = rest.split_at_mut(len);
+
+    for chunk in nonce.chunks_mut(8) {
+        let random = rdrand().ok_or(EIO)?;
+        chunk.copy_from_slice(&random.to_ne_bytes()[..chunk.len()]);
+    }
+
+    let tag = cipher(file)
+        .encrypt_in_place_detached(Nonce::from_slice(nonce), &aad(file, index, last), block)
+        .or(Err(EIO))?;
+    rest[..TAG].copy_from_slice(&tag);
+
+    Ok(&record[..NONCE + len + TAG])
+}
+
+/// Derives the key of the sealed files from secret material
+fn derive(secret: &[u8]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update(KEY_CONTEXT);
+    hash.update(secret);
+    hash.finalize().into()
+}
+
+fn cipher(file: &File) -> Aes256Gcm {
+    Aes256Gcm::new(GenericArray::from_slice(&file.key))
+}
+
+/// The additional data of a record
+fn aad(file: &File, index: usize, last: bool) -> [u8; 41] {
+    let mut aad = [0u8; 41];
+    aad[..32].copy_from_slice(&file.id);
+    aad[32..40].copy_from_slice(&(index as u64).to_le_bytes());
+    aad[40] = last as u8;
+    aad
+}
+
+/// Returns a random number, or `None` if the CPU has none
+fn rdrand() -> Option<u64> {
+    let mut random = 0u64;
+
+    for _ in 0..10 {
+        if unsafe { core::arch::x86_64::_rdrand64_step(&mut random) } == 1 {
+            return Some(random);
+        }
+    }
+
+    None
+}
+
+/// Reads all of `buf` from the host file at `offset`
+fn host_pread<H: BaseSyscallHandler>(
+    h: &mut H,
+    fd: c_int,
+    buf: &mut [u8],
+    offset: usize,
+) -> core::result::Result<(), c_int> {
+    let count = buf.len();
+
+    let c = h.new_cursor();
+    let (_, hostbuf) = c.alloc::<u8>(count).or(Err(EMSGSIZE))?;
+    let host_virt = H::translate_shim_to_host_addr(hostbuf.as_ptr());
+
+    let ret = unsafe { h.proxy(request!(libc::SYS_pread64 => fd, host_virt, count, offset))? };
+
+    if usize::from(ret[0]) != count {
+        return Err(EIO);
+    }
+
+    let c = h.new_cursor();
+    unsafe { c.copy_into_slice(count, buf).or(Err(EFAULT)) }?;
+
+    Ok(())
+}
+
+/// Writes all of `buf` to the host file at `offset`
+fn host_pwrite<H: BaseSyscallHandler>(
+    h: &mut H,
+    fd: c_int,
+    buf: &[u8],
+    offset: usize,
+) -> core::result::Result<(), c_int> {
+    let count = buf.len();
+
+    let c = h.new_cursor();
+    let (_, hostbuf) = c.copy_from_slice(buf).or(Err(EMSGSIZE))?;
+    let host_virt = H::translate_shim_to_host_addr(hostbuf.as_ptr());
+
+    let ret = unsafe { h.proxy(request!(libc::SYS_pwrite64 => fd, host_virt, count, offset))? };
+
+    if usize::from(ret[0]) != count {
+        return Err(EIO);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: File = File {
+        fd: 3,
+        flags: libc::O_RDWR,
+        offset: 0,
+        id: [1; 32],
+        key: [2; 32],
+    };
+
+    #[test]
+    fn plain_sizes() {
+        assert_eq!(plain_size(0), Ok(0));
+        assert_eq!(plain_size(2 * RECORD), Ok(2 * BLOCK));
+        assert_eq!(plain_size(RECORD + NONCE + 1 + TAG), Ok(BLOCK + 1));
+
+        // A record needs at least one byte of plaintext.
+        assert_eq!(plain_size(RECORD + NONCE + TAG), Err(EIO));
+        assert_eq!(plain_size(1), Err(EIO));
+    }
+
+    #[test]
+    fn additional_data() {
+        let aad = aad(&FILE, 0x0102, true);
+        assert_eq!(aad[..32], FILE.id);
+        assert_eq!(aad[32..40], [2, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(aad[40], 1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut record = [0u8; RECORD];
+        record[NONCE..][..5].copy_from_slice(b"hello");
+
+        let sealed = seal(&FILE, 1, true, &mut record, 5).unwrap().to_vec();
+        assert_eq!(sealed.len(), NONCE + 5 + TAG);
+        assert_ne!(&sealed[NONCE..][..5], b"hello");
+
+        let open = |file: &File, index: usize, last: bool| {
+            let mut record = [0u8; RECORD];
+            record[..sealed.len()].copy_from_slice(&sealed);
+            unseal(file, index, last, &mut record, 5).map(|block| block.to_vec())
+        };
+
+        assert_eq!(open(&FILE, 1, true), Ok(b"hello".to_vec()));
+
+        // Moved to another file, to another index or no longer the last one
+        let other = File {
+            id: [3; 32],
+            ..FILE
+        };
+        assert_eq!(open(&other, 1, true), Err(EIO));
+        assert_eq!(open(&FILE, 0, true), Err(EIO));
+        assert_eq!(open(&FILE, 1, false), Err(EIO));
+
+        // Each record gets a nonce of its own.
+        let mut again = [0u8; RECORD];
+        again[NONCE..][..5].copy_from_slice(b"hello");
+        let again = seal(&FILE, 1, true, &mut again, 5).unwrap();
+        assert_ne!(again[..NONCE], sealed[..NONCE]);
+    }
+}
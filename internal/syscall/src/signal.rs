@@ -0,0 +1,676 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signals of the payload
+//!
+//! The actions of the signals are shared by all threads of the payload,
+//! while each thread has its own mask and alternate stack. A signal is only
+//! delivered, when a thread returns to the payload from a syscall or a
+//! fault: like Linux, the shim pushes a frame with the registers of the
+//! thread onto its stack and continues in the handler, whose restorer
+//! returns to them with `rt_sigreturn`.
+//!
+//! Above the frame, the shim saves the floating point, SSE and AVX state of
+//! the thread, which `rt_sigreturn` restores. A signal sent to the payload
+//! as a whole is delivered to the next thread returning to the payload,
+//! which does not block it. A signal sent to another thread waits for that
+//! thread. Syscalls are never restarted after a handler.
+
+use crate::process::ProcessSyscallHandler;
+use crate::{BaseSyscallHandler, FAKE_PID, FAKE_UID, MAX_THREADS, SYS_ENARX_SIGNALS};
+use core::convert::TryInto;
+use core::mem::size_of;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use libc::{c_int, pid_t, stack_t, EAGAIN, EINVAL, ENOMEM, SIGKILL, SIGSEGV, SIGSTOP};
+use primordial::Register;
+use sallyport::request;
+use spinning::{Mutex, RawMutex};
+use untrusted::{UntrustedRef, UntrustedRefMut, Validate};
+
+/// The number of signals, `SIGRTMAX`
+const NSIG: c_int = 64;
+
+/// The maximum number of threads with signals from other threads they did not take yet
+const QUEUED_MAX: usize = MAX_THREADS;
+
+/// missing in libc
+const SA_RESTORER: u64 = 0x0400_0000;
+
+/// missing in libc
+const SI_USER: c_int = 0;
+
+/// The size of the red zone below the stack pointer, which a frame skips
+const RED_ZONE: usize = 128;
+
+/// The flags of `rflags`, which a handler may change with `rt_sigreturn`
+///
+/// Like Linux: AC, RF, OF, DF, TF, SF, ZF, AF, PF and CF
+const FIX_RFLAGS: u64 = 0x0005_0dd5;
+
+/// The flags of `rflags`, which are cleared for a handler: DF and TF
+const HANDLER_RFLAGS: u64 = 0x0500;
+
+/// The alignment of the extended state in a frame, which XSAVE demands
+const XSTATE_ALIGN: usize = 64;
+
+/// The `MXCSR` register in an FXSAVE or XSAVE image
+const MXCSR: Range<usize> = 24..28;
+
+/// The valid bits of `MXCSR` in an FXSAVE or XSAVE image
+const MXCSR_MASK: Range<usize> = 28..32;
+
+/// The valid bits of `MXCSR`, if the CPU does not tell
+const MXCSR_MASK_DEFAULT: u32 = 0xffbf;
+
+/// The bytes of an FXSAVE or XSAVE image left to software
+///
+/// On Linux, they tell the kernel how to restore the state.
+const SW_BYTES: Range<usize> = 464..512;
+
+/// The `XSTATE_BV` of the header of an XSAVE image
+const XSTATE_BV: Range<usize> = 512..520;
+
+/// The rest of the header of an XSAVE image, zero in standard format
+const XSAVE_HEADER_REST: Range<usize> = 520..576;
+
+/// The signal set with `sig`
+const fn bit(sig: c_int) -> u64 {
+    1 << (sig - 1)
+}
+
+/// The `SA_*` flag `flag` as a flag of the kernel `struct sigaction`
+const fn flag(flag: c_int) -> u64 {
+    flag as u32 as u64
+}
+
+/// The signals, which can neither be caught nor blocked
+const UNBLOCKABLE: u64 = bit(SIGKILL) | bit(SIGSTOP);
+
+/// The signals, whose default action does nothing
+///
+/// A keep has no job control, so stopping and continuing it does nothing
+/// either.
+const IGNORED: u64 = bit(libc::SIGCHLD)
+    | bit(libc::SIGCONT)
+    | bit(libc::SIGURG)
+    | bit(libc::SIGWINCH)
+    | bit(libc::SIGSTOP)
+    | bit(libc::SIGTSTP)
+    | bit(libc::SIGTTIN)
+    | bit(libc::SIGTTOU);
+
+/// The signals the host may forward to the payload
+const FORWARDED: u64 = bit(libc::SIGINT) | bit(libc::SIGPIPE) | bit(libc::SIGTERM);
+
+/// The kernel `struct sigaction`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SigAction {
+    handler: usize,
+    flags: u64,
+    restorer: usize,
+    mask: u64,
+}
+
+impl SigAction {
+    const DEFAULT: Self = Self {
+        handler: libc::SIG_DFL,
+        flags: 0,
+        restorer: 0,
+        mask: 0,
+    };
+
+    /// Whether the action calls a handler of the payload
+    fn handles(&self) -> bool {
+        self.handler != libc::SIG_DFL && self.handler != libc::SIG_IGN
+    }
+}
+
+/// The action of each signal
+type Actions = [SigAction; NSIG as usize];
+
+/// The actions of all signals
+static ACTIONS: Mutex<Actions> =
+    Mutex::<Actions>::const_new(RawMutex::const_new(), [SigAction::DEFAULT; NSIG as usize]);
+
+/// The signals sent to the payload as a whole, which no thread took yet
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// The ids of threads and the signals other threads sent to them
+///
+/// A free entry has the id 0. Each thread moves its signals to its own
+/// `Signals`, when it returns to the payload.
+static QUEUED: Mutex<[(pid_t, u64); QUEUED_MAX]> =
+    Mutex::<[(pid_t, u64); QUEUED_MAX]>::const_new(RawMutex::const_new(), [(0, 0); QUEUED_MAX]);
+
+/// The registers of a thread of the payload
+///
+/// They are in the order of `struct sigcontext` of Linux, which is also the
+/// order of the first entries of `gregs` in `mcontext_t`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+#[allow(missing_docs)]
+pub struct Context {
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rsp: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+/// The `struct sigcontext` of Linux
+#[repr(C)]
+struct MContext {
+    regs: Context,
+    csgsfs: u64,
+    err: u64,
+    trapno: u64,
+    oldmask: u64,
+    cr2: u64,
+    fpstate: usize,
+    reserved: [u64; 8],
+}
+
+/// The `struct ucontext` of Linux
+#[repr(C)]
+struct UContext {
+    flags: u64,
+    link: usize,
+    stack: stack_t,
+    mcontext: MContext,
+    mask: u64,
+}
+
+/// The `siginfo_t` of a signal
+///
+/// Signals sent by the payload and faults only use the first two fields
+/// after the code: the process and user id or the address of the fault.
+#[repr(C)]
+struct SigInfo {
+    signo: c_int,
+    errno: c_int,
+    code: c_int,
+    fields: [u64; 14],
+}
+
+/// The `struct rt_sigframe` of Linux, which a handler returns into
+#[repr(C)]
+struct Frame {
+    restorer: usize,
+    uc: UContext,
+    info: SigInfo,
+}
+
+/// The signal state of a thread
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Signals {
+    /// The blocked signals
+    mask: u64,
+
+    /// The signals sent to the thread, but not delivered yet
+    pending: u64,
+
+    /// The start and size of the alternate stack, if any
+    stack: Option<(usize, usize)>,
+}
+
+impl Signals {
+    /// The signal state of the initial thread
+    pub(crate) const fn new() -> Self {
+        Self {
+            mask: 0,
+            pending: 0,
+            stack: None,
+        }
+    }
+
+    /// The signal state of a thread started by this one, which only keeps the mask
+    pub(crate) fn inherit(&self) -> Self {
+        Self {
+            mask: self.mask,
+            ..Self::new()
+        }
+    }
+
+    /// Changes the mask like `rt_sigprocmask`, returning the old one
+    pub(crate) fn set_mask(&mut self, how: c_int, set: Option<u64>) -> Result<u64, c_int> {
+        let old = self.mask;
+
+        if let Some(set) = set {
+            self.mask = match how {
+                libc::SIG_BLOCK => old | set,
+                libc::SIG_UNBLOCK => old & !set,
+                libc::SIG_SETMASK => set,
+                _ => return Err(EINVAL),
+            } & !UNBLOCKABLE;
+        }
+
+        Ok(old)
+    }
+
+    /// Changes the alternate stack like `sigaltstack`, returning the old one
+    pub(crate) fn set_stack(&mut self, stack: Option<&stack_t>) -> Result<stack_t, c_int> {
+        let old = match self.stack {
+            Some((sp, size)) => stack_t {
+                ss_sp: sp as _,
+                ss_flags: 0,
+                ss_size: size,
+            },
+            None => stack_t {
+                ss_sp: core::ptr::null_mut(),
+                ss_flags: libc::SS_DISABLE,
+                ss_size: 0,
+            },
+        };
+
+        if let Some(stack) = stack {
+            self.stack = match stack.ss_flags {
+                libc::SS_DISABLE => None,
+                0 | libc::SS_ONSTACK if stack.ss_size < libc::MINSIGSTKSZ => return Err(ENOMEM),
+                0 | libc::SS_ONSTACK => Some((stack.ss_sp as usize, stack.ss_size)),
+                _ => return Err(EINVAL),
+            };
+        }
+
+        Ok(old)
+    }
+
+    /// Marks `sig` pending for the thread
+    pub(crate) fn send(&mut self, sig: c_int) {
+        self.pending |= bit(sig);
+    }
+
+    /// Whether the thread blocks `sig`
+    pub(crate) fn blocks(&self, sig: c_int) -> bool {
+        self.mask & bit(sig) != 0
+    }
+
+    /// Takes the lowest pending signal the thread `tid` does not block
+    pub(crate) fn take(&mut self, tid: pid_t) -> Option<c_int> {
+        self.pending |= forget(tid);
+
+        loop {
+            let pending = (self.pending | PENDING.load(Ordering::Relaxed)) & !self.mask;
+            if pending == 0 {
+                return None;
+            }
+
+            let sig = pending.trailing_zeros() as c_int + 1;
+
+            if self.pending & bit(sig) != 0 {
+                self.pending &= !bit(sig);
+                return Some(sig);
+            }
+
+            // Another thread may have taken it in the meantime.
+            if PENDING.fetch_and(!bit(sig), Ordering::SeqCst) & bit(sig) != 0 {
+                return Some(sig);
+            }
+        }
+    }
+
+    /// Whether `sp` lies on the alternate stack
+    fn on_stack(&self, sp: usize) -> bool {
+        match self.stack {
+            Some((start, size)) => sp > start && sp <= start.wrapping_add(size),
+            None => false,
+        }
+    }
+}
+
+/// Whether `sig` is a valid signal number
+pub(crate) fn valid(sig: c_int) -> bool {
+    (1..=NSIG).contains(&sig)
+}
+
+/// Marks `sig` pending for the payload as a whole
+pub(crate) fn send(sig: c_int) {
+    PENDING.fetch_or(bit(sig), Ordering::SeqCst);
+}
+
+/// Marks `sig` pending for the thread `tid`, which is not the calling thread
+///
+/// Fails with `EAGAIN`, if too many threads have signals they did not take yet.
+pub(crate) fn send_to(tid: pid_t, sig: c_int) -> Result<(), c_int> {
+    let mut queued = QUEUED.lock();
+
+    let index = match queued.iter().position(|(id, _)| *id == tid) {
+        Some(index) => index,
+        None => queued.iter().position(|(id, _)| *id == 0).ok_or(EAGAIN)?,
+    };
+
+    queued[index] = (tid, queued[index].1 | bit(sig));
+    Ok(())
+}
+
+/// Removes and returns the signals other threads sent to the thread `tid`
+pub(crate) fn forget(tid: pid_t) -> u64 {
+    let mut queued = QUEUED.lock();
+
+    match queued.iter_mut().find(|(id, _)| *id == tid) {
+        Some(entry) => core::mem::replace(entry, (0, 0)).1,
+        None => 0,
+    }
+}
+
+/// Changes the action of `sig` like `rt_sigaction`, returning the old one
+pub(crate) fn set_action(sig: c_int, action: Option<&SigAction>) -> Result<SigAction, c_int> {
+    if !valid(sig) || (action.is_some() && UNBLOCKABLE & bit(sig) != 0) {
+        return Err(EINVAL);
+    }
+
+    let mut actions = ACTIONS.lock();
+    let old = actions[sig as usize - 1];
+
+    if let Some(action) = action {
+        actions[sig as usize - 1] = SigAction {
+            mask: action.mask & !UNBLOCKABLE,
+            ..*action
+        };
+
+        // Like POSIX demands, an ignored signal is not kept pending.
+        if action.handler == libc::SIG_IGN {
+            PENDING.fetch_and(!bit(sig), Ordering::SeqCst);
+            for (_, pending) in QUEUED.lock().iter_mut() {
+                *pending &= !bit(sig);
+            }
+        }
+    }
+
+    Ok(old)
+}
+
+/// Takes the action to deliver `sig` with
+///
+/// An action with `SA_RESETHAND` is only used once.
+fn take_action(sig: c_int) -> SigAction {
+    let mut actions = ACTIONS.lock();
+    let action = actions[sig as usize - 1];
+
+    if action.handles() && action.flags & flag(libc::SA_RESETHAND) != 0 {
+        actions[sig as usize - 1] = SigAction::DEFAULT;
+    }
+
+    action
+}
+
+/// Whether the default action of `sig` does nothing
+fn ignored_by_default(sig: c_int) -> bool {
+    IGNORED & bit(sig) != 0
+}
+
+/// Fetches the signals the host caught for the payload
+///
+/// The host interrupts its syscall for the payload, when it catches one.
+pub(crate) fn forwarded<H: BaseSyscallHandler>(h: &mut H) {
+    if let Ok([set, _]) = unsafe { h.proxy(request!(SYS_ENARX_SIGNALS)) } {
+        PENDING.fetch_or(usize::from(set) as u64 & FORWARDED, Ordering::SeqCst);
+    }
+}
+
+/// Delivers `sig` with the `code` and `fields` of its `siginfo_t` to the calling thread
+///
+/// Without a handler, the signal terminates the payload or is dropped.
+/// Returns, whether the thread continues in a handler.
+pub(crate) fn deliver<H: ProcessSyscallHandler>(
+    h: &mut H,
+    ctx: &mut Context,
+    sig: c_int,
+    code: c_int,
+    fields: [u64; 2],
+) -> bool {
+    let action = take_action(sig);
+
+    match action.handler {
+        _ if action.handles() => (),
+        libc::SIG_IGN => return false,
+        _ if ignored_by_default(sig) => return false,
+        _ => h.exit_group(128 + sig),
+    }
+
+    // Like Linux, a handler, which cannot be entered, is a fault.
+    if push(h, ctx, sig, code, fields, &action).is_err() {
+        h.exit_group(128 + SIGSEGV);
+    }
+
+    true
+}
+
+/// Delivers `sig` sent by the payload itself or the host to the calling thread
+///
+/// Returns, whether the thread continues in a handler.
+pub(crate) fn deliver_sent<H: ProcessSyscallHandler>(
+    h: &mut H,
+    ctx: &mut Context,
+    sig: c_int,
+) -> bool {
+    let sender = FAKE_PID as u64 | (FAKE_UID as u64) << 32;
+    deliver(h, ctx, sig, SI_USER, [sender, 0])
+}
+
+/// Whether a fault raising `sig` terminates the payload
+///
+/// Like Linux, a fault, which is blocked or has no handler, does.
+pub(crate) fn fatal<H: ProcessSyscallHandler>(h: &mut H, sig: c_int) -> bool {
+    h.thread(|thread| thread.signals.blocks(sig)) || !ACTIONS.lock()[sig as usize - 1].handles()
+}
+
+/// Pushes the frame of the handler of `sig` and continues in the handler
+fn push<H: ProcessSyscallHandler>(
+    h: &mut H,
+    ctx: &mut Context,
+    sig: c_int,
+    code: c_int,
+    fields: [u64; 2],
+    action: &SigAction,
+) -> Result<(), ()> {
+    if action.flags & SA_RESTORER == 0 {
+        return Err(());
+    }
+
+    let signals = h.thread(|thread| thread.signals);
+    let rsp = ctx.rsp as usize;
+
+    let top = match signals.stack {
+        Some((start, size))
+            if action.flags & flag(libc::SA_ONSTACK) != 0 && !signals.on_stack(rsp) =>
+        {
+            start.wrapping_add(size)
+        }
+        _ => rsp.wrapping_sub(RED_ZONE),
+    };
+
+    // Like Linux, the extended state goes above the frame.
+    let size = h.xstate(|xstate| xstate.len());
+    let fpstate = top.wrapping_sub(size) & !(XSTATE_ALIGN - 1);
+    let saved = UntrustedRefMut::from(fpstate as *mut u8)
+        .validate_slice(size, h)
+        .ok_or(())?;
+    h.xstate(|xstate| saved.copy_from_slice(xstate));
+
+    // On entry to the handler, the stack is aligned like after a call.
+    let addr = (fpstate.wrapping_sub(size_of::<Frame>()) & !15).wrapping_sub(8);
+    let frame = UntrustedRefMut::from(addr as *mut Frame)
+        .validate(h)
+        .ok_or(())?;
+
+    let stack = match signals.stack {
+        Some((sp, size)) => stack_t {
+            ss_sp: sp as _,
+            ss_flags: match signals.on_stack(rsp) {
+                true => libc::SS_ONSTACK,
+                false => 0,
+            },
+            ss_size: size,
+        },
+        None => stack_t {
+            ss_sp: core::ptr::null_mut(),
+            ss_flags: libc::SS_DISABLE,
+            ss_size: 0,
+        },
+    };
+
+    let mut fill = [0; 14];
+    fill[..2].copy_from_slice(&fields);
+
+    *frame = Frame {
+        restorer: action.restorer,
+        uc: UContext {
+            flags: 0,
+            link: 0,
+            stack,
+            mcontext: MContext {
+                regs: *ctx,
+                csgsfs: 0,
+                err: 0,
+                trapno: 0,
+                oldmask: signals.mask,
+                cr2: 0,
+                fpstate,
+                reserved: [0; 8],
+            },
+            mask: signals.mask,
+        },
+        info: SigInfo {
+            signo: sig,
+            errno: 0,
+            code,
+            fields: fill,
+        },
+    };
+
+    let mut mask = action.mask;
+    if action.flags & flag(libc::SA_NODEFER) == 0 {
+        mask |= bit(sig);
+    }
+
+    h.thread(|thread| thread.signals.mask = (signals.mask | mask) & !UNBLOCKABLE);
+
+    ctx.rdi = sig as u64;
+    ctx.rsi = &frame.info as *const SigInfo as u64;
+    ctx.rdx = &frame.uc as *const UContext as u64;
+    ctx.rax = 0;
+    ctx.rsp = addr as u64;
+    ctx.rip = action.handler as u64;
+    ctx.rflags &= !HANDLER_RFLAGS;
+
+    Ok(())
+}
+
+/// Restores the registers, extended state and mask saved in the frame of a returning handler
+///
+/// The restorer of the frame popped the return address off the stack. A
+/// frame without extended state keeps the current one.
+pub(crate) fn restore<H: ProcessSyscallHandler>(h: &mut H, ctx: &mut Context) -> Result<(), ()> {
+    let addr = (ctx.rsp as usize).wrapping_sub(size_of::<usize>());
+    let frame = UntrustedRef::from(addr as *const Frame)
+        .validate(h)
+        .ok_or(())?;
+
+    let fpstate = frame.uc.mcontext.fpstate;
+    if fpstate != 0 {
+        let size = h.xstate(|xstate| xstate.len());
+        let saved = UntrustedRef::from(fpstate as *const u8)
+            .validate_slice(size, h)
+            .ok_or(())?;
+        h.xstate(|xstate| restore_xstate(xstate, saved));
+    }
+
+    let regs = &frame.uc.mcontext.regs;
+    let rflags = (ctx.rflags & !FIX_RFLAGS) | (regs.rflags & FIX_RFLAGS);
+    *ctx = Context { rflags, ..*regs };
+
+    let mask = frame.uc.mask & !UNBLOCKABLE;
+    h.thread(|thread| thread.signals.mask = mask);
+
+    Ok(())
+}
+
+/// Copies the extended state `saved` by a handler to `xstate`
+///
+/// Like Linux, a handler cannot make the state fail to restore: `MXCSR`
+/// keeps its reserved bits clear, the XSAVE header is in standard format
+/// and only has the components of `XCR0`, and the software bytes stay.
+fn restore_xstate(xstate: &mut [u8], saved: &[u8]) {
+    let mut sw = [0; SW_BYTES.end - SW_BYTES.start];
+    sw.copy_from_slice(&xstate[SW_BYTES]);
+
+    let mxcsr_mask = match u32::from_le_bytes(xstate[MXCSR_MASK].try_into().unwrap()) {
+        0 => MXCSR_MASK_DEFAULT,
+        mask => mask,
+    };
+
+    xstate.copy_from_slice(saved);
+    xstate[SW_BYTES].copy_from_slice(&sw);
+
+    let mxcsr = u32::from_le_bytes(xstate[MXCSR].try_into().unwrap()) & mxcsr_mask;
+    xstate[MXCSR].copy_from_slice(&mxcsr.to_le_bytes());
+
+    if xstate.len() >= XSAVE_HEADER_REST.end {
+        let bv = u64::from_le_bytes(xstate[XSTATE_BV].try_into().unwrap()) & unsafe { xcr0() };
+        xstate[XSTATE_BV].copy_from_slice(&bv.to_le_bytes());
+        xstate[XSAVE_HEADER_REST].iter_mut().for_each(|b| *b = 0);
+    }
+}
+
+/// The state components the CPU saves with XSAVE
+///
+/// # Safety
+///
+/// Only valid with `CR4.OSXSAVE` set, which every shim using XSAVE images
+/// runs with.
+#[target_feature(enable = "xsave")]
+unsafe fn xcr0() -> u64 {
+    core::arch::x86_64::_xgetbv(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fxsave() {
+        let mut xstate = [0u8; 512];
+        xstate[MXCSR_MASK].copy_from_slice(&0xffffu32.to_le_bytes());
+        xstate[SW_BYTES].iter_mut().for_each(|b| *b = 0xaa);
+
+        let mut saved = [0x55u8; 512];
+        saved[MXCSR].copy_from_slice(&0xdead_1f80u32.to_le_bytes());
+        restore_xstate(&mut xstate, &saved);
+
+        assert_eq!(xstate[..MXCSR.start], saved[..MXCSR.start]);
+        assert_eq!(xstate[MXCSR], 0x1f80u32.to_le_bytes());
+        let rest = MXCSR_MASK.start..SW_BYTES.start;
+        assert_eq!(xstate[rest.clone()], saved[rest]);
+        assert!(xstate[SW_BYTES].iter().all(|b| *b == 0xaa));
+    }
+
+    #[test]
+    fn directed() {
+        let tid = FAKE_PID as pid_t + 1000;
+        send_to(tid, libc::SIGUSR1).unwrap();
+        send_to(tid, libc::SIGUSR2).unwrap();
+
+        let mut other = Signals::new();
+        assert_eq!(other.take(tid + 1), None);
+
+        let mut signals = Signals::new();
+        assert_eq!(signals.take(tid), Some(libc::SIGUSR1));
+        assert_eq!(signals.take(tid), Some(libc::SIGUSR2));
+        assert_eq!(signals.take(tid), None);
+        assert_eq!(forget(tid), 0);
+    }
+}
@@ -0,0 +1,200 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Threads of the payload
+//!
+//! The shims run every thread of the payload on a vCPU or TCS of its own
+//! and keep a `Thread` for each. The thread ids and what happens to the
+//! futexes of a thread, when it exits, are the same for all shims.
+
+use crate::signal::Signals;
+use crate::{futex, FAKE_PID};
+use core::mem::size_of;
+use core::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use libc::{c_int, pid_t};
+use untrusted::{AddressValidator, UntrustedRef, UntrustedRefMut, Validate};
+
+/// The flags of `clone`, which start a thread
+pub(crate) const CLONE_THREAD_FLAGS: c_int =
+    libc::CLONE_VM | libc::CLONE_SIGHAND | libc::CLONE_THREAD;
+
+/// The other flags of `clone` a thread may be started with
+pub(crate) const CLONE_OTHER_FLAGS: c_int = libc::CLONE_FS
+    | libc::CLONE_FILES
+    | libc::CLONE_SYSVSEM
+    | libc::CLONE_SETTLS
+    | libc::CLONE_PARENT_SETTID
+    | libc::CLONE_CHILD_SETTID
+    | libc::CLONE_CHILD_CLEARTID
+    | libc::CLONE_DETACHED;
+
+/// The size of `struct robust_list_head`
+pub(crate) const ROBUST_LIST_HEAD_SIZE: usize = 3 * size_of::<usize>();
+
+/// The maximum number of futexes on a robust list, like Linux
+const ROBUST_LIST_LIMIT: usize = 2048;
+
+/// missing in libc
+const FUTEX_WAITERS: u32 = 0x8000_0000;
+/// missing in libc
+const FUTEX_OWNER_DIED: u32 = 0x4000_0000;
+/// missing in libc
+const FUTEX_TID_MASK: u32 = 0x3fff_ffff;
+
+/// The id of the next thread
+static NEXT_TID: AtomicI32 = AtomicI32::new(FAKE_PID as pid_t + 1);
+
+/// A thread of the payload
+#[derive(Copy, Clone, Debug)]
+pub struct Thread {
+    tid: pid_t,
+    clear_child_tid: usize,
+    robust_list: usize,
+    pub(crate) signals: Signals,
+}
+
+impl Default for Thread {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Thread {
+    /// Creates the initial thread of the payload, whose id is the process id
+    pub const fn new() -> Self {
+        Self {
+            tid: FAKE_PID as _,
+            clear_child_tid: 0,
+            robust_list: 0,
+            signals: Signals::new(),
+        }
+    }
+
+    /// Creates a thread with a new id and the signal state `signals`
+    pub(crate) fn spawned(clear_child_tid: usize, signals: Signals) -> Self {
+        Self {
+            tid: NEXT_TID.fetch_add(1, Ordering::Relaxed),
+            clear_child_tid,
+            robust_list: 0,
+            signals,
+        }
+    }
+
+    /// The id of the thread
+    pub fn tid(&self) -> pid_t {
+        self.tid
+    }
+
+    /// Takes the lowest pending signal the thread does not block
+    pub(crate) fn take_signal(&mut self) -> Option<c_int> {
+        self.signals.take(self.tid)
+    }
+
+    /// Sets the address cleared and woken, when the thread exits
+    pub(crate) fn set_clear_child_tid(&mut self, addr: usize) {
+        self.clear_child_tid = addr;
+    }
+
+    /// Sets the `struct robust_list_head` of the thread
+    pub(crate) fn set_robust_list(&mut self, head: usize) {
+        self.robust_list = head;
+    }
+
+    /// Releases the futexes of the exiting thread
+    ///
+    /// Like Linux, marks the futexes on the robust list the thread still
+    /// holds and wakes one waiter of each. Then clears the address of
+    /// `set_tid_address` or `CLONE_CHILD_CLEARTID` and wakes its waiter.
+    pub(crate) fn release(&self, validator: &impl AddressValidator) {
+        if self.robust_list != 0 {
+            self.release_robust_list(validator);
+        }
+
+        let clear_child_tid = UntrustedRefMut::from(self.clear_child_tid as *mut pid_t);
+        if let Some(tid) = clear_child_tid.validate(validator) {
+            *tid = 0;
+            futex::wake(self.clear_child_tid, 1, !0);
+        }
+    }
+
+    /// Walks the robust list, until it ends or looks corrupted
+    fn release_robust_list(&self, validator: &impl AddressValidator) {
+        let read = |addr: usize| -> Option<usize> {
+            let word = UntrustedRef::from(addr as *const usize).validate(validator)?;
+            Some(*word)
+        };
+
+        // `struct robust_list_head`: the first entry, the offset of the
+        // futex in an entry and the entry about to be taken or released.
+        // Bit 0 of an entry marks a priority inheritance futex.
+        let head = self.robust_list;
+        let field = |index: usize| read(head.wrapping_add(index * size_of::<usize>()));
+        let (first, offset, pending) = match (field(0), field(1), field(2)) {
+            (Some(first), Some(offset), Some(pending)) => (first & !1, offset, pending & !1),
+            _ => return,
+        };
+
+        let mut entry = first;
+        for _ in 0..ROBUST_LIST_LIMIT {
+            if entry == head {
+                break;
+            }
+
+            let next = match read(entry) {
+                Some(next) => next & !1,
+                None => return,
+            };
+
+            if entry != pending {
+                self.release_futex(validator, entry.wrapping_add(offset));
+            }
+
+            entry = next;
+        }
+
+        if pending != 0 {
+            self.release_futex(validator, pending.wrapping_add(offset));
+        }
+    }
+
+    /// Marks a futex of the robust list held by the thread and wakes a waiter
+    fn release_futex(&self, validator: &impl AddressValidator, addr: usize) {
+        let word = match UntrustedRef::from(addr as *const u32).validate(validator) {
+            Some(word) => unsafe { &*(word as *const u32 as *const AtomicU32) },
+            None => return,
+        };
+
+        let old = word.load(Ordering::Relaxed);
+        if old & FUTEX_TID_MASK != self.tid as u32 {
+            return;
+        }
+
+        let new = (old & FUTEX_WAITERS) | FUTEX_OWNER_DIED;
+        if word
+            .compare_exchange(old, new, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+            && old & FUTEX_WAITERS != 0
+        {
+            futex::wake(addr, 1, !0);
+        }
+    }
+}
+
+/// Whether `tid` is the id of a thread of the payload
+///
+/// Threads keep their id after they exited.
+pub(crate) fn exists(tid: pid_t) -> bool {
+    tid >= FAKE_PID as pid_t && tid < NEXT_TID.load(Ordering::Relaxed)
+}
+
+/// A thread `clone` asks the shim to start
+#[derive(Copy, Clone, Debug)]
+pub struct NewThread {
+    /// The thread, which the shim returns in `ProcessSyscallHandler::thread` on it
+    pub thread: Thread,
+
+    /// The stack pointer of the thread
+    pub stack: usize,
+
+    /// The base of `%fs` of the thread with `CLONE_SETTLS`
+    pub tls: Option<usize>,
+}
@@ -0,0 +1,1041 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! An in-memory filesystem served inside the keep
+//!
+//! The files below the mount point never reach the host. Their contents
+//! are stored in pages of the shim's own, encrypted memory, which a
+//! `PageAlloc` hands out. Everything else lives in fixed size tables, so
+//! no allocator is needed otherwise.
+
+use crate::path::{below, normalize, PATH_MAX};
+use crate::{FAKE_GID, FAKE_UID, TMPFS_PATH_MAX};
+use core::mem::{size_of, MaybeUninit};
+use core::ptr::NonNull;
+use libc::{
+    c_int, mode_t, off_t, EBADF, EEXIST, EFBIG, EINVAL, EISDIR, EMFILE, ENAMETOOLONG, ENOENT,
+    ENOSPC, ENOTDIR,
+};
+use primordial::Page;
+use sallyport::Result;
+
+/// The maximum number of files and directories
+const MAX_NODES: usize = 64;
+
+/// The maximum number of open files
+const MAX_FILES: usize = 32;
+
+/// The maximum length of a file name
+const NAME_MAX: usize = 64;
+
+/// The size of a page
+const PAGE_SIZE: usize = size_of::<Page>();
+
+/// The maximum number of data pages of a file, which fill one index page
+const MAX_PAGES: usize = PAGE_SIZE / size_of::<Option<NonNull<Page>>>();
+
+/// The maximum size of a file
+const MAX_SIZE: usize = MAX_PAGES * PAGE_SIZE;
+
+/// The first file descriptor of the tmpfs, far above the ones of the host
+const FD_BASE: c_int = 0x10000;
+
+/// The node number of the mount point itself
+const ROOT: usize = MAX_NODES;
+
+/// The offset of `d_name` in `struct linux_dirent64`
+const DIRENT_NAME: usize = 19;
+
+/// Hands out the pages the contents of the files are stored in
+pub trait PageAlloc {
+    /// Allocates a page
+    fn alloc(&mut self) -> Option<NonNull<Page>>;
+
+    /// Frees a page returned by `alloc`
+    ///
+    /// # Safety
+    ///
+    /// The caller has to ensure the page is not used anymore.
+    unsafe fn free(&mut self, page: NonNull<Page>);
+}
+
+/// The data pages of a file
+type Index = [Option<NonNull<Page>>; MAX_PAGES];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Free,
+    File,
+    Dir,
+}
+
+/// A file or directory
+#[derive(Copy, Clone)]
+struct Node {
+    kind: Kind,
+
+    /// Whether the node can be found by name, which unlinked files that
+    /// are still open cannot
+    linked: bool,
+
+    parent: usize,
+    name: [u8; NAME_MAX],
+    len: usize,
+    mode: mode_t,
+    size: usize,
+    index: Option<NonNull<Index>>,
+}
+
+impl Node {
+    const FREE: Self = Self {
+        kind: Kind::Free,
+        linked: false,
+        parent: ROOT,
+        name: [0; NAME_MAX],
+        len: 0,
+        mode: 0,
+        size: 0,
+        index: None,
+    };
+
+    fn name(&self) -> &[u8] {
+        &self.name[..self.len]
+    }
+}
+
+/// An open file description
+#[derive(Copy, Clone)]
+struct File {
+    node: Option<usize>,
+    flags: c_int,
+
+    /// The position in the file, or the next entry of a directory
+    offset: usize,
+}
+
+impl File {
+    const CLOSED: Self = Self {
+        node: None,
+        flags: 0,
+        offset: 0,
+    };
+}
+
+/// An in-memory filesystem
+///
+/// The methods taking a file descriptor or a path return `None` if it
+/// does not belong to the tmpfs, so the syscall is for the host.
+pub struct Tmpfs {
+    mount: [u8; TMPFS_PATH_MAX],
+    nodes: [Node; MAX_NODES],
+    files: [File; MAX_FILES],
+}
+
+// The pages of the files are only reachable through the tmpfs.
+unsafe impl Send for Tmpfs {}
+unsafe impl Sync for Tmpfs {}
+
+impl Default for Tmpfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tmpfs {
+    /// Creates an empty tmpfs, which is not mounted yet
+    pub const fn new() -> Self {
+        Self {
+            mount: [0; TMPFS_PATH_MAX],
+            nodes: [Node::FREE; MAX_NODES],
+            files: [File::CLOSED; MAX_FILES],
+        }
+    }
+
+    /// Mounts the tmpfs at the NUL padded `path`, which is in normal form
+    ///
+    /// Returns `None` if `path` is empty. Mounting again keeps all files.
+    pub fn mount(&mut self, path: &[u8; TMPFS_PATH_MAX]) -> Option<&mut Self> {
+        if path[0] == 0 {
+            return None;
+        }
+
+        self.mount = *path;
+        Some(self)
+    }
+
+    /// Whether `fd` is a file descriptor of the tmpfs
+    pub fn owns(&self, fd: c_int) -> bool {
+        self.slot(fd).is_some()
+    }
+
+    /// syscall
+    pub fn openat(
+        &mut self,
+        dirfd: c_int,
+        path: &[u8],
+        flags: c_int,
+        mode: mode_t,
+        pages: &mut dyn PageAlloc,
+    ) -> Option<Result> {
+        let mut buf = [0; PATH_MAX];
+        let start = self.start(dirfd, path, &mut buf)?;
+        Some(start.and_then(|(dir, path)| self.open(dir, path, flags, mode, pages)))
+    }
+
+    /// syscall
+    pub fn close(&mut self, fd: c_int, pages: &mut dyn PageAlloc) -> Option<Result> {
+        let slot = self.slot(fd)?;
+        Some(self.release(slot, pages))
+    }
+
+    /// syscall
+    pub fn read(&mut self, fd: c_int, buf: &mut [u8]) -> Option<Result> {
+        let slot = self.slot(fd)?;
+        Some(self.read_at(slot, buf, None))
+    }
+
+    /// syscall
+    pub fn write(&mut self, fd: c_int, buf: &[u8], pages: &mut dyn PageAlloc) -> Option<Result> {
+        let slot = self.slot(fd)?;
+        Some(self.write_at(slot, buf, None, pages))
+    }
+
+    /// syscall
+    pub fn pread64(&mut self, fd: c_int, buf: &mut [u8], offset: off_t) -> Option<Result> {
+        let slot = self.slot(fd)?;
+        Some(position(offset).and_then(|offset| self.read_at(slot, buf, Some(offset))))
+    }
+
+    /// syscall
+    pub fn pwrite64(
+        &mut self,
+        fd: c_int,
+        buf: &[u8],
+        offset: off_t,
+        pages: &mut dyn PageAlloc,
+    ) -> Option<Result> {
+        let slot = self.slot(fd)?;
+        Some(position(offset).and_then(|offset| self.write_at(slot, buf, Some(offset), pages)))
+    }
+
+    /// syscall
+    pub fn lseek(&mut self, fd: c_int, offset: off_t, whence: c_int) -> Option<Result> {
+        let slot = self.slot(fd)?;
+        Some(self.seek(slot, offset, whence))
+    }
+
+    /// syscall
+    pub fn getdents64(&mut self, fd: c_int, dirp: &mut [u8]) -> Option<Result> {
+        let slot = self.slot(fd)?;
+        Some(self.readdir(slot, dirp))
+    }
+
+    /// syscall
+    pub fn fstat(&mut self, fd: c_int, statbuf: &mut libc::stat) -> Option<Result> {
+        let slot = self.slot(fd)?;
+        Some(self.node(slot).and_then(|node| self.stat(node, statbuf)))
+    }
+
+    /// syscall
+    pub fn newfstatat(
+        &mut self,
+        dirfd: c_int,
+        path: &[u8],
+        statbuf: &mut libc::stat,
+        flags: c_int,
+    ) -> Option<Result> {
+        if flags & libc::AT_EMPTY_PATH != 0 && trim_nul(path).is_empty() {
+            return self.fstat(dirfd, statbuf);
+        }
+
+        let mut buf = [0; PATH_MAX];
+        let start = self.start(dirfd, path, &mut buf)?;
+        Some(start.and_then(|(dir, path)| {
+            let (dir, name) = self.walk(dir, path)?;
+            let node = self.lookup(dir, name)?;
+            self.stat(node, statbuf)
+        }))
+    }
+
+    /// syscall
+    pub fn unlink(&mut self, path: &[u8], pages: &mut dyn PageAlloc) -> Option<Result> {
+        let mut buf = [0; PATH_MAX];
+        let start = self.start(libc::AT_FDCWD, path, &mut buf)?;
+        Some(start.and_then(|(dir, path)| {
+            let (dir, name) = self.walk(dir, path)?;
+            let node = self.lookup(dir, name)?;
+
+            if self.is_dir(node) {
+                return Err(EISDIR);
+            }
+
+            self.nodes[node].linked = false;
+            self.collect(node, pages);
+            Ok(Default::default())
+        }))
+    }
+
+    /// syscall
+    pub fn mkdir(&mut self, path: &[u8], mode: mode_t) -> Option<Result> {
+        let mut buf = [0; PATH_MAX];
+        let start = self.start(libc::AT_FDCWD, path, &mut buf)?;
+        Some(start.and_then(|(dir, path)| {
+            let (dir, name) = self.walk(dir, path)?;
+
+            if self.lookup(dir, name).is_ok() {
+                return Err(EEXIST);
+            }
+
+            self.create(dir, name, Kind::Dir, mode)?;
+            Ok(Default::default())
+        }))
+    }
+
+    /// Returns the index of the open file `fd`, if it belongs to the tmpfs
+    fn slot(&self, fd: c_int) -> Option<usize> {
+        let files = FD_BASE..FD_BASE + MAX_FILES as c_int;
+        files.contains(&fd).then(|| (fd - FD_BASE) as usize)
+    }
+
+    /// Returns the node of an open file
+    fn node(&self, slot: usize) -> core::result::Result<usize, c_int> {
+        self.files[slot].node.ok_or(EBADF)
+    }
+
+    /// Returns the directory a path is relative to and the rest of the path
+    ///
+    /// An absolute path is compared to the mount point in normal form, which
+    /// is written to `buf`. Paths relative to the working directory have to
+    /// be made absolute before. Returns `None` if the path is neither below
+    /// the mount point nor relative to a directory of the tmpfs.
+    fn start<'p>(
+        &self,
+        dirfd: c_int,
+        path: &'p [u8],
+        buf: &'p mut [u8; PATH_MAX],
+    ) -> Option<core::result::Result<(usize, &'p [u8]), c_int>> {
+        let path = trim_nul(path);
+
+        if path.first() == Some(&b'/') {
+            let path = match normalize(b"/", path, buf) {
+                Ok(path) => path,
+                Err(errno) => return Some(Err(errno)),
+            };
+
+            let len = self
+                .mount
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(TMPFS_PATH_MAX);
+            return below(path, &self.mount[..len]).map(|rest| Ok((ROOT, rest)));
+        }
+
+        let slot = self.slot(dirfd)?;
+
+        if path.is_empty() {
+            return Some(Err(ENOENT));
+        }
+
+        Some(self.node(slot).map(|dir| (dir, path)))
+    }
+
+    /// Walks to the directory holding the last component of `path`
+    ///
+    /// Returns the directory and the last component, which is empty if
+    /// `path` names `dir` itself.
+    fn walk<'p>(
+        &self,
+        mut dir: usize,
+        path: &'p [u8],
+    ) -> core::result::Result<(usize, &'p [u8]), c_int> {
+        let mut names = path
+            .split(|b| *b == b'/')
+            .filter(|n| !n.is_empty())
+            .peekable();
+
+        while let Some(name) = names.next() {
+            if !self.is_dir(dir) {
+                return Err(ENOTDIR);
+            }
+
+            if names.peek().is_none() {
+                return Ok((dir, name));
+            }
+
+            dir = self.lookup(dir, name)?;
+        }
+
+        Ok((dir, &[]))
+    }
+
+    /// Finds the entry `name` of the directory `dir`
+    fn lookup(&self, dir: usize, name: &[u8]) -> core::result::Result<usize, c_int> {
+        match name {
+            b"" | b"." => Ok(dir),
+            b".." => Ok(self.parent(dir)),
+            _ => self
+                .nodes
+                .iter()
+                .position(|n| n.linked && n.parent == dir && n.name() == name)
+                .ok_or(ENOENT),
+        }
+    }
+
+    fn parent(&self, node: usize) -> usize {
+        match node {
+            ROOT => ROOT,
+            _ => self.nodes[node].parent,
+        }
+    }
+
+    fn is_dir(&self, node: usize) -> bool {
+        node == ROOT || self.nodes[node].kind == Kind::Dir
+    }
+
+    /// Creates the entry `name` in the directory `dir`
+    fn create(
+        &mut self,
+        dir: usize,
+        name: &[u8],
+        kind: Kind,
+        mode: mode_t,
+    ) -> core::result::Result<usize, c_int> {
+        if name.len() > NAME_MAX {
+            return Err(ENAMETOOLONG);
+        }
+
+        if let b"" | b"." | b".." = name {
+            return Err(EEXIST);
+        }
+
+        let node = self
+            .nodes
+            .iter()
+            .position(|n| n.kind == Kind::Free)
+            .ok_or(ENOSPC)?;
+
+        let mut new = Node {
+            kind,
+            linked: true,
+            parent: dir,
+            mode: mode & 0o7777,
+            len: name.len(),
+            ..Node::FREE
+        };
+        new.name[..name.len()].copy_from_slice(name);
+
+        self.nodes[node] = new;
+        Ok(node)
+    }
+
+    fn open(
+        &mut self,
+        dir: usize,
+        path: &[u8],
+        flags: c_int,
+        mode: mode_t,
+        pages: &mut dyn PageAlloc,
+    ) -> Result {
+        let slot = self
+            .files
+            .iter()
+            .position(|f| f.node.is_none())
+            .ok_or(EMFILE)?;
+
+        let create = flags & libc::O_CREAT != 0;
+        let (dir, name) = self.walk(dir, path)?;
+
+        let node = match self.lookup(dir, name) {
+            Ok(_) if create && flags & libc::O_EXCL != 0 => return Err(EEXIST),
+            Ok(node) => node,
+            Err(ENOENT) if create => self.create(dir, name, Kind::File, mode)?,
+            Err(errno) => return Err(errno),
+        };
+
+        let write = flags & libc::O_ACCMODE != libc::O_RDONLY;
+
+        if self.is_dir(node) && write {
+            return Err(EISDIR);
+        }
+
+        if !self.is_dir(node) && flags & libc::O_DIRECTORY != 0 {
+            return Err(ENOTDIR);
+        }
+
+        if write && flags & libc::O_TRUNC != 0 {
+            self.truncate(node, pages);
+        }
+
+        self.files[slot] = File {
+            node: Some(node),
+            flags,
+            offset: 0,
+        };
+
+        Ok([(FD_BASE as usize + slot).into(), 0.into()])
+    }
+
+    fn release(&mut self, slot: usize, pages: &mut dyn PageAlloc) -> Result {
+        let node = self.files[slot].node.take().ok_or(EBADF)?;
+        self.collect(node, pages);
+        Ok(Default::default())
+    }
+
+    /// Frees a node, once it is neither linked nor open anymore
+    fn collect(&mut self, node: usize, pages: &mut dyn PageAlloc) {
+        if node == ROOT
+            || self.nodes[node].linked
+            || self.files.iter().any(|f| f.node == Some(node))
+        {
+            return;
+        }
+
+        self.truncate(node, pages);
+        self.nodes[node] = Node::FREE;
+    }
+
+    /// Frees all pages of a file
+    fn truncate(&mut self, node: usize, pages: &mut dyn PageAlloc) {
+        if let Some(index) = self.nodes[node].index.take() {
+            unsafe {
+                for page in (*index.as_ptr()).iter().flatten() {
+                    pages.free(*page);
+                }
+
+                pages.free(index.cast());
+            }
+        }
+
+        self.nodes[node].size = 0;
+    }
+
+    /// Returns the data page `n` of a file, unless it was never written
+    fn page(&self, node: usize, n: usize) -> Option<NonNull<Page>> {
+        let index = self.nodes[node].index?;
+        unsafe { (*index.as_ptr())[n] }
+    }
+
+    /// Returns the data page `n` of a file, allocating it if needed
+    fn page_mut(
+        &mut self,
+        node: usize,
+        n: usize,
+        pages: &mut dyn PageAlloc,
+    ) -> Option<NonNull<Page>> {
+        let index = match self.nodes[node].index {
+            Some(index) => index,
+            None => {
+                let index = zeroed(pages)?.cast();
+                self.nodes[node].index = Some(index);
+                index
+            }
+        };
+
+        let page = unsafe { &mut (*index.as_ptr())[n] };
+        if page.is_none() {
+            *page = Some(zeroed(pages)?);
+        }
+
+        *page
+    }
+
+    fn read_at(&mut self, slot: usize, buf: &mut [u8], offset: Option<usize>) -> Result {
+        let node = self.node(slot)?;
+        let file = self.files[slot];
+
+        if file.flags & libc::O_ACCMODE == libc::O_WRONLY {
+            return Err(EBADF);
+        }
+
+        if self.is_dir(node) {
+            return Err(EISDIR);
+        }
+
+        let start = offset.unwrap_or(file.offset);
+        let len = buf.len().min(self.nodes[node].size.saturating_sub(start));
+
+        let mut done = 0;
+        while done < len {
+            let pos = start + done;
+            let off = pos % PAGE_SIZE;
+            let chunk = (len - done).min(PAGE_SIZE - off);
+            let dst = &mut buf[done..done + chunk];
+
+            match self.page(node, pos / PAGE_SIZE) {
+                Some(page) => dst.copy_from_slice(unsafe { &bytes(page)[off..off + chunk] }),
+                None => dst.iter_mut().for_each(|b| *b = 0),
+            }
+
+            done += chunk;
+        }
+
+        if offset.is_none() {
+            self.files[slot].offset = start + len;
+        }
+
+        Ok([len.into(), 0.into()])
+    }
+
+    fn write_at(
+        &mut self,
+        slot: usize,
+        buf: &[u8],
+        offset: Option<usize>,
+        pages: &mut dyn PageAlloc,
+    ) -> Result {
+        let node = self.node(slot)?;
+        let file = self.files[slot];
+
+        // Directories are only ever opened read-only.
+        if file.flags & libc::O_ACCMODE == libc::O_RDONLY {
+            return Err(EBADF);
+        }
+
+        let start = match offset {
+            Some(offset) => offset,
+            None if file.flags & libc::O_APPEND != 0 => self.nodes[node].size,
+            None => file.offset,
+        };
+
+        let len = buf.len().min(MAX_SIZE.saturating_sub(start));
+        if len == 0 && !buf.is_empty() {
+            return Err(EFBIG);
+        }
+
+        let mut done = 0;
+        while done < len {
+            let pos = start + done;
+            let off = pos % PAGE_SIZE;
+            let chunk = (len - done).min(PAGE_SIZE - off);
+
+            let page = match self.page_mut(node, pos / PAGE_SIZE, pages) {
+                Some(page) => page,
+                None => break,
+            };
+
+            unsafe { bytes(page)[off..off + chunk].copy_from_slice(&buf[done..done + chunk]) };
+            done += chunk;
+        }
+
+        if done == 0 && len > 0 {
+            return Err(ENOSPC);
+        }
+
+        let size = &mut self.nodes[node].size;
+        *size = (*size).max(start + done);
+
+        if offset.is_none() {
+            self.files[slot].offset = start + done;
+        }
+
+        Ok([done.into(), 0.into()])
+    }
+
+    fn seek(&mut self, slot: usize, offset: off_t, whence: c_int) -> Result {
+        let node = self.node(slot)?;
+
+        let base = match whence {
+            libc::SEEK_SET => 0,
+            libc::SEEK_CUR => self.files[slot].offset as off_t,
+            libc::SEEK_END if self.is_dir(node) => return Err(EINVAL),
+            libc::SEEK_END => self.nodes[node].size as off_t,
+            _ => return Err(EINVAL),
+        };
+
+        let pos = position(base.checked_add(offset).ok_or(EINVAL)?)?;
+        self.files[slot].offset = pos;
+        Ok([pos.into(), 0.into()])
+    }
+
+    /// Fills `dirp` with `struct linux_dirent64` entries
+    ///
+    /// The offset of a directory counts `.`, `..` and then all nodes.
+    fn readdir(&mut self, slot: usize, dirp: &mut [u8]) -> Result {
+        let dir = self.node(slot)?;
+
+        if !self.is_dir(dir) {
+            return Err(ENOTDIR);
+        }
+
+        let mut pos = self.files[slot].offset;
+        let mut len = 0;
+
+        while pos < MAX_NODES + 2 {
+            let (node, kind, name): (usize, u8, &[u8]) = match pos {
+                0 => (dir, libc::DT_DIR, b"."),
+                1 => (self.parent(dir), libc::DT_DIR, b".."),
+                n => {
+                    let node = &self.nodes[n - 2];
+
+                    if !node.linked || node.parent != dir {
+                        pos += 1;
+                        continue;
+                    }
+
+                    match node.kind {
+                        Kind::Dir => (n - 2, libc::DT_DIR, node.name()),
+                        _ => (n - 2, libc::DT_REG, node.name()),
+                    }
+                }
+            };
+
+            let reclen = (DIRENT_NAME + name.len() + 1 + 7) & !7;
+            let rec = match dirp.get_mut(len..len + reclen) {
+                Some(rec) => rec,
+                None if len == 0 => return Err(EINVAL),
+                None => break,
+            };
+
+            rec.iter_mut().for_each(|b| *b = 0);
+            rec[0..8].copy_from_slice(&ino(node).to_ne_bytes());
+            rec[8..16].copy_from_slice(&(pos as i64 + 1).to_ne_bytes());
+            rec[16..18].copy_from_slice(&(reclen as u16).to_ne_bytes());
+            rec[18] = kind;
+            rec[DIRENT_NAME..DIRENT_NAME + name.len()].copy_from_slice(name);
+
+            len += reclen;
+            pos += 1;
+        }
+
+        self.files[slot].offset = pos;
+        Ok([len.into(), 0.into()])
+    }
+
+    fn stat(&self, node: usize, statbuf: &mut libc::stat) -> Result {
+        let mut stat = unsafe { MaybeUninit::<libc::stat>::zeroed().assume_init() };
+
+        stat.st_ino = ino(node);
+        stat.st_uid = FAKE_UID as _;
+        stat.st_gid = FAKE_GID as _;
+        stat.st_blksize = PAGE_SIZE as _;
+
+        match node {
+            ROOT => {
+                stat.st_mode = libc::S_IFDIR | 0o1777;
+                stat.st_nlink = 2;
+            }
+
+            _ if self.is_dir(node) => {
+                stat.st_mode = libc::S_IFDIR | self.nodes[node].mode;
+                stat.st_nlink = 2;
+            }
+
+            _ => {
+                let pages = match self.nodes[node].index {
+                    Some(index) => unsafe { (*index.as_ptr()).iter().flatten().count() },
+                    None => 0,
+                };
+
+                stat.st_mode = libc::S_IFREG | self.nodes[node].mode;
+                stat.st_nlink = 1;
+                stat.st_size = self.nodes[node].size as _;
+                stat.st_blocks = (pages * PAGE_SIZE / 512) as _;
+            }
+        }
+
+        *statbuf = stat;
+        Ok(Default::default())
+    }
+}
+
+/// The inode number of a node, which must not be zero
+fn ino(node: usize) -> u64 {
+    node as u64 + 1
+}
+
+/// Checks a file offset
+fn position(offset: off_t) -> core::result::Result<usize, c_int> {
+    if offset < 0 {
+        return Err(EINVAL);
+    }
+
+    Ok(offset as usize)
+}
+
+/// Strips the terminating NUL of a path, if any
+fn trim_nul(path: &[u8]) -> &[u8] {
+    path.strip_suffix(&[0]).unwrap_or(path)
+}
+
+/// Allocates a page filled with zeros
+fn zeroed(pages: &mut dyn PageAlloc) -> Option<NonNull<Page>> {
+    let page = pages.alloc()?;
+    unsafe { page.as_ptr().write_bytes(0, 1) };
+    Some(page)
+}
+
+/// The bytes of a page
+///
+/// # Safety
+///
+/// The caller has to ensure the page is allocated and not borrowed otherwise.
+unsafe fn bytes<'a>(page: NonNull<Page>) -> &'a mut [u8] {
+    core::slice::from_raw_parts_mut(page.as_ptr() as *mut u8, PAGE_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{alloc, dealloc, Layout};
+
+    /// Allocates the pages from the heap of the test, up to `limit`
+    struct Pages {
+        live: usize,
+        limit: usize,
+    }
+
+    impl Pages {
+        fn new() -> Self {
+            Self {
+                live: 0,
+                limit: usize::MAX,
+            }
+        }
+    }
+
+    impl PageAlloc for Pages {
+        fn alloc(&mut self) -> Option<NonNull<Page>> {
+            if self.live == self.limit {
+                return None;
+            }
+
+            self.live += 1;
+            NonNull::new(unsafe { alloc(Layout::new::<Page>()) } as *mut Page)
+        }
+
+        unsafe fn free(&mut self, page: NonNull<Page>) {
+            self.live -= 1;
+            dealloc(page.as_ptr() as *mut u8, Layout::new::<Page>());
+        }
+    }
+
+    fn mounted() -> Tmpfs {
+        let mut path = [0; TMPFS_PATH_MAX];
+        path[..4].copy_from_slice(b"/tmp");
+
+        let mut tmpfs = Tmpfs::new();
+        tmpfs.mount(&path).unwrap();
+        tmpfs
+    }
+
+    fn ret(result: Result) -> core::result::Result<usize, c_int> {
+        result.map(|ret| usize::from(ret[0]))
+    }
+
+    fn open(tmpfs: &mut Tmpfs, pages: &mut Pages, path: &str, flags: c_int) -> Result {
+        tmpfs
+            .openat(libc::AT_FDCWD, path.as_bytes(), flags, 0o644, pages)
+            .unwrap()
+    }
+
+    fn fd(result: Result) -> c_int {
+        ret(result).unwrap() as c_int
+    }
+
+    fn size(tmpfs: &mut Tmpfs, fd: c_int) -> usize {
+        let mut stat = unsafe { MaybeUninit::<libc::stat>::zeroed().assume_init() };
+        tmpfs.fstat(fd, &mut stat).unwrap().unwrap();
+        stat.st_size as usize
+    }
+
+    /// Returns the names of the `struct linux_dirent64` entries in `dirp`
+    fn names(dirp: &[u8]) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut rec = dirp;
+
+        while !rec.is_empty() {
+            let reclen = u16::from_ne_bytes([rec[16], rec[17]]) as usize;
+            let name = &rec[DIRENT_NAME..reclen];
+            let len = name.iter().position(|b| *b == 0).unwrap();
+            names.push(String::from_utf8(name[..len].to_vec()).unwrap());
+            rec = &rec[reclen..];
+        }
+
+        names
+    }
+
+    #[test]
+    fn open_flags() {
+        let mut tmpfs = mounted();
+        let mut pages = Pages::new();
+
+        assert!(tmpfs
+            .openat(libc::AT_FDCWD, b"/home/x", 0, 0, &mut pages)
+            .is_none());
+        assert_eq!(
+            open(&mut tmpfs, &mut pages, "/tmp/x", libc::O_RDWR),
+            Err(ENOENT)
+        );
+
+        let flags = libc::O_RDWR | libc::O_CREAT;
+        let x = fd(open(&mut tmpfs, &mut pages, "/tmp/x", flags));
+        assert!(tmpfs.owns(x));
+        assert_eq!(ret(tmpfs.write(x, b"hello", &mut pages).unwrap()), Ok(5));
+        assert_eq!(ret(tmpfs.close(x, &mut pages).unwrap()), Ok(0));
+
+        let excl = flags | libc::O_EXCL;
+        assert_eq!(open(&mut tmpfs, &mut pages, "/tmp/x", excl), Err(EEXIST));
+
+        let x = fd(open(&mut tmpfs, &mut pages, "//tmp/./x", libc::O_RDONLY));
+        let mut buf = [0; 8];
+        assert_eq!(ret(tmpfs.read(x, &mut buf).unwrap()), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(ret(tmpfs.write(x, b"!", &mut pages).unwrap()), Err(EBADF));
+        tmpfs.close(x, &mut pages).unwrap().unwrap();
+
+        let x = fd(open(
+            &mut tmpfs,
+            &mut pages,
+            "/tmp/x",
+            libc::O_WRONLY | libc::O_TRUNC,
+        ));
+        assert_eq!(size(&mut tmpfs, x), 0);
+        assert_eq!(pages.live, 0);
+        tmpfs.close(x, &mut pages).unwrap().unwrap();
+    }
+
+    #[test]
+    fn sparse() {
+        let mut tmpfs = mounted();
+        let mut pages = Pages::new();
+
+        let flags = libc::O_RDWR | libc::O_CREAT;
+        let x = fd(open(&mut tmpfs, &mut pages, "/tmp/x", flags));
+
+        let offset = 2 * PAGE_SIZE + 1;
+        assert_eq!(
+            ret(tmpfs.pwrite64(x, b"x", offset as _, &mut pages).unwrap()),
+            Ok(1)
+        );
+        assert_eq!(size(&mut tmpfs, x), offset + 1);
+
+        // The index and the last page, but none of the pages before
+        assert_eq!(pages.live, 2);
+
+        let mut buf = vec![0xff; 4 * PAGE_SIZE];
+        assert_eq!(ret(tmpfs.read(x, &mut buf).unwrap()), Ok(offset + 1));
+        assert!(buf[..offset].iter().all(|b| *b == 0));
+        assert_eq!(buf[offset], b'x');
+
+        tmpfs.close(x, &mut pages).unwrap().unwrap();
+    }
+
+    #[test]
+    fn too_big() {
+        let mut tmpfs = mounted();
+        let mut pages = Pages::new();
+
+        let flags = libc::O_RDWR | libc::O_CREAT;
+        let x = fd(open(&mut tmpfs, &mut pages, "/tmp/x", flags));
+
+        let end = MAX_SIZE as off_t;
+        assert_eq!(
+            ret(tmpfs.pwrite64(x, b"x", end, &mut pages).unwrap()),
+            Err(EFBIG)
+        );
+        assert_eq!(
+            ret(tmpfs.pwrite64(x, b"xy", end - 1, &mut pages).unwrap()),
+            Ok(1)
+        );
+        assert_eq!(size(&mut tmpfs, x), MAX_SIZE);
+
+        tmpfs.close(x, &mut pages).unwrap().unwrap();
+    }
+
+    #[test]
+    fn getdents64() {
+        let mut tmpfs = mounted();
+        let mut pages = Pages::new();
+
+        assert_eq!(ret(tmpfs.mkdir(b"/tmp/d", 0o755).unwrap()), Ok(0));
+        for name in &["/tmp/a", "/tmp/d/b"] {
+            let x = fd(open(
+                &mut tmpfs,
+                &mut pages,
+                name,
+                libc::O_CREAT | libc::O_WRONLY,
+            ));
+            tmpfs.close(x, &mut pages).unwrap().unwrap();
+        }
+
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY;
+        let dir = fd(open(&mut tmpfs, &mut pages, "/tmp", flags));
+
+        let mut small = [0; DIRENT_NAME];
+        assert_eq!(ret(tmpfs.getdents64(dir, &mut small).unwrap()), Err(EINVAL));
+
+        // Only `.` fits, the next call resumes after it.
+        let mut one = [0; 24];
+        assert_eq!(ret(tmpfs.getdents64(dir, &mut one).unwrap()), Ok(24));
+        assert_eq!(names(&one), ["."]);
+
+        let mut buf = [0; 1024];
+        let len = ret(tmpfs.getdents64(dir, &mut buf).unwrap()).unwrap();
+        assert_eq!(names(&buf[..len]), ["..", "d", "a"]);
+        assert_eq!(ret(tmpfs.getdents64(dir, &mut buf).unwrap()), Ok(0));
+
+        tmpfs.close(dir, &mut pages).unwrap().unwrap();
+    }
+
+    #[test]
+    fn unlink_open() {
+        let mut tmpfs = mounted();
+        let mut pages = Pages::new();
+
+        let flags = libc::O_RDWR | libc::O_CREAT;
+        let x = fd(open(&mut tmpfs, &mut pages, "/tmp/x", flags));
+        tmpfs.write(x, b"hello", &mut pages).unwrap().unwrap();
+
+        assert_eq!(ret(tmpfs.unlink(b"/tmp/x", &mut pages).unwrap()), Ok(0));
+        assert_eq!(
+            open(&mut tmpfs, &mut pages, "/tmp/x", libc::O_RDONLY),
+            Err(ENOENT)
+        );
+        assert_eq!(
+            ret(tmpfs.unlink(b"/tmp/x", &mut pages).unwrap()),
+            Err(ENOENT)
+        );
+
+        let mut buf = [0; 5];
+        assert_eq!(ret(tmpfs.pread64(x, &mut buf, 0).unwrap()), Ok(5));
+        assert_eq!(&buf, b"hello");
+        assert_eq!(pages.live, 2);
+
+        tmpfs.close(x, &mut pages).unwrap().unwrap();
+        assert_eq!(pages.live, 0);
+
+        // The node is free again.
+        let x = fd(open(&mut tmpfs, &mut pages, "/tmp/x", flags));
+        assert_eq!(size(&mut tmpfs, x), 0);
+        tmpfs.close(x, &mut pages).unwrap().unwrap();
+    }
+
+    #[test]
+    fn limits() {
+        let mut tmpfs = mounted();
+        let mut pages = Pages::new();
+
+        let flags = libc::O_RDWR | libc::O_CREAT;
+        let fds: Vec<_> = (0..MAX_FILES)
+            .map(|_| fd(open(&mut tmpfs, &mut pages, "/tmp/x", flags)))
+            .collect();
+        assert_eq!(open(&mut tmpfs, &mut pages, "/tmp/x", flags), Err(EMFILE));
+        for x in fds {
+            tmpfs.close(x, &mut pages).unwrap().unwrap();
+        }
+
+        for n in 1..MAX_NODES {
+            let path = format!("/tmp/{}", n);
+            let x = fd(open(&mut tmpfs, &mut pages, &path, flags));
+            tmpfs.close(x, &mut pages).unwrap().unwrap();
+        }
+        assert_eq!(open(&mut tmpfs, &mut pages, "/tmp/y", flags), Err(ENOSPC));
+        assert_eq!(ret(tmpfs.mkdir(b"/tmp/y", 0o755).unwrap()), Err(ENOSPC));
+
+        // The index fits, but the data page does not.
+        pages.limit = 1;
+        let x = fd(open(&mut tmpfs, &mut pages, "/tmp/x", flags));
+        assert_eq!(ret(tmpfs.write(x, b"x", &mut pages).unwrap()), Err(ENOSPC));
+        assert_eq!(size(&mut tmpfs, x), 0);
+        tmpfs.close(x, &mut pages).unwrap().unwrap();
+    }
+}
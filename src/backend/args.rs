@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The arguments, environment and manifest passed to the keep.
+
+use crate::manifest::Manifest;
+use crate::syscall::{SEALED_PATH_MAX, TMPFS_PATH_MAX};
+
+use anyhow::Result;
+use nbytes::bytes;
+
+use std::io::Error;
+use std::mem::size_of;
+
+/// The maximum space the arguments and environment may occupy on the
+/// initial payload stack, including the pointer arrays
+const MAX_SIZE: usize = bytes![64; KiB];
+
+/// The arguments and environment of the payload and the keep manifest
+///
+/// The shims always pass `/init` as `argv[0]` to the payload, so `argv`
+/// only contains the arguments following it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Args {
+    /// The payload arguments, not including `argv[0]`
+    pub argv: Vec<String>,
+
+    /// The payload environment variables in `KEY=VALUE` form
+    pub envp: Vec<String>,
+
+    /// The manifest the keep was described with, if any
+    pub manifest: Option<Manifest>,
+
+    /// The absolute path the in-keep tmpfs is mounted at, if any
+    pub tmpfs: Option<String>,
+
+    /// The absolute path below which files are sealed to the keep, if any
+    pub sealed: Option<String>,
+}
+
+impl Args {
+    /// Encodes the arguments for the shims
+    ///
+    /// All arguments followed by all environment variables are stored
+    /// as NUL terminated strings.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+
+        for string in self.argv.iter().chain(self.envp.iter()) {
+            if string.as_bytes().contains(&0) {
+                return Err(Error::from_raw_os_error(libc::EINVAL).into());
+            }
+
+            bytes.extend_from_slice(string.as_bytes());
+            bytes.push(0);
+        }
+
+        // argv[0], both arrays and their NULL terminators
+        let pointers = (self.argv.len() + self.envp.len() + 3) * size_of::<usize>();
+        if bytes.len() + pointers > MAX_SIZE {
+            return Err(Error::from_raw_os_error(libc::E2BIG).into());
+        }
+
+        Ok(bytes)
+    }
+
+    /// The unmodified bytes of the manifest to load into the keep
+    pub(crate) fn manifest(&self) -> &[u8] {
+        self.manifest
+            .as_ref()
+            .map(|m| &m.raw[..])
+            .unwrap_or_default()
+    }
+
+    /// The size of the initial payload stack requested by the manifest
+    pub(crate) fn stack(&self) -> Option<usize> {
+        self.manifest.as_ref().and_then(|m| m.memory.stack)
+    }
+
+    /// The size of the heap requested by the manifest
+    pub(crate) fn heap(&self) -> Option<usize> {
+        self.manifest.as_ref().and_then(|m| m.memory.heap)
+    }
+
+    /// The number of threads requested by the manifest
+    pub(crate) fn threads(&self) -> Option<usize> {
+        self.manifest.as_ref().and_then(|m| m.memory.threads)
+    }
+
+    /// Encodes the mount point of the tmpfs for the shims
+    ///
+    /// The path is padded with NUL bytes, so it is empty without a tmpfs.
+    pub(crate) fn tmpfs(&self) -> Result<[u8; TMPFS_PATH_MAX]> {
+        pad(self.tmpfs.as_deref())
+    }
+
+    /// Encodes the prefix of the sealed files for the shims
+    ///
+    /// The path is padded with NUL bytes, so it is empty without sealing.
+    pub(crate) fn sealed(&self) -> Result<[u8; SEALED_PATH_MAX]> {
+        pad(self.sealed.as_deref())
+    }
+}
+
+/// Pads an absolute path with NUL bytes, after bringing it to normal form
+///
+/// The shims compare paths in normal form: without empty or `.` components
+/// and without trailing slashes. Paths with `..` components are refused.
+fn pad<const N: usize>(path: Option<&str>) -> Result<[u8; N]> {
+    let mut padded = [0; N];
+
+    if let Some(path) = path {
+        let names: Vec<_> = path
+            .split('/')
+            .filter(|n| !matches!(*n, "" | "."))
+            .collect();
+
+        if !path.starts_with('/') || names.is_empty() || names.contains(&"..") {
+            return Err(Error::from_raw_os_error(libc::EINVAL).into());
+        }
+
+        let path = format!("/{}", names.join("/"));
+        if path.contains('\0') {
+            return Err(Error::from_raw_os_error(libc::EINVAL).into());
+        }
+
+        if path.len() >= N {
+            return Err(Error::from_raw_os_error(libc::ENAMETOOLONG).into());
+        }
+
+        padded[..path.len()].copy_from_slice(path.as_bytes());
+    }
+
+    Ok(padded)
+}
@@ -0,0 +1,19 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The options for debugging a keep from the host
+
+use std::path::PathBuf;
+
+/// How the host debugs a keep
+///
+/// Unlike the `Args`, these options are never passed into the keep. They
+/// only change what the loader does with it, so they are not measured.
+/// Only the `kvm` backend can be debugged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DebugOptions {
+    /// The unix socket to wait on for gdb before starting the keep, if any
+    pub gdb: Option<PathBuf>,
+
+    /// The file to write an ELF core dump of the keep to, if it fails
+    pub core_dump: Option<PathBuf>,
+}
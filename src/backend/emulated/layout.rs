@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::sallyport::Block;
+
+use lset::Span;
+use nbytes::bytes;
+use primordial::Page;
+
+use std::mem::size_of;
+
+const KEEP: usize = bytes![32; TiB];
+const ALIGN: usize = bytes![2; MiB];
+const STACK: usize = bytes![8; MiB];
+const HEAP: usize = bytes![128; MiB];
+
+/// The maximum size of the heap and of the stack
+pub const MAX_SIZE: usize = bytes![64; GiB];
+
+const fn lower(value: usize, boundary: usize) -> usize {
+    value / boundary * boundary
+}
+
+const fn raise(value: usize, boundary: usize) -> usize {
+    lower(value + boundary - 1, boundary)
+}
+
+fn above(rel: impl Into<Line<usize>>, size: usize) -> Span<usize> {
+    Span {
+        start: raise(rel.into().end, ALIGN),
+        count: size,
+    }
+}
+
+include!("../../../internal/shim-emulated/src/hostlib.rs");
+
+impl Layout {
+    /// Calculate the memory layout of the emulated keep
+    ///
+    /// The `heap` and `stack` sizes default to `HEAP` and `STACK`.
+    pub fn calculate(
+        code: Line<usize>,
+        args: usize,
+        manifest: usize,
+        heap: Option<usize>,
+        stack: Option<usize>,
+    ) -> Self {
+        assert_eq!(code.start, 0);
+
+        let prefix = Span {
+            start: KEEP,
+            count: Page::size(),
+        };
+        let block = above(prefix, raise(size_of::<Block>(), Page::size()));
+        let args = above(block, args);
+        let manifest = above(args, manifest);
+        let code = above(manifest, Span::from(code).count);
+        let heap = above(code, raise(heap.unwrap_or(HEAP), Page::size()));
+        let stack = above(heap, raise(stack.unwrap_or(STACK), Page::size()));
+
+        Self {
+            keep: Line {
+                start: KEEP,
+                end: Line::from(stack).end,
+            },
+
+            prefix: prefix.into(),
+            block: block.into(),
+            code: code.into(),
+            heap: heap.into(),
+            stack: stack.into(),
+
+            args: args.into(),
+            argc: 0,
+            envc: 0,
+
+            manifest: manifest.into(),
+
+            tmpfs: [0; 32],
+            sealed: [0; 32],
+        }
+    }
+}
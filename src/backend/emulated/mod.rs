@@ -0,0 +1,325 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A keep emulated by an ordinary process
+//!
+//! The shim runs the payload in a child process and traps its syscalls with
+//! a seccomp filter. It handles them with the same syscall code as the
+//! shims of the hardware backends, and proxies them to the host through a
+//! sallyport block shared with the loader. This exercises the whole syscall
+//! path of a keep on machines without SEV or SGX.
+
+mod layout;
+
+use crate::backend::{self, signals, Args, Command, Datum, DebugOptions, Report, Shadow};
+use crate::binary::Component;
+use crate::sallyport::Block;
+use crate::syscall::SYS_ENARX_SIGNALS;
+use layout::{Layout, HOST_FD, IMAGE_FD, MAX_SIZE};
+
+use anyhow::{anyhow, bail, Result};
+use lset::Span;
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+const SHIM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/bin/shim-emulated"));
+
+const ACTIONS: &str = "/proc/sys/kernel/seccomp/actions_avail";
+
+fn seccomp() -> Datum {
+    let actions = std::fs::read_to_string(ACTIONS).unwrap_or_default();
+
+    Datum {
+        name: "Seccomp".into(),
+        pass: actions.split_whitespace().any(|action| action == "trap"),
+        info: Some(ACTIONS.into()),
+        mesg: None,
+    }
+}
+
+pub struct Backend;
+
+impl backend::Backend for Backend {
+    fn name(&self) -> &'static str {
+        "emulated"
+    }
+
+    fn data(&self) -> Vec<Datum> {
+        vec![seccomp()]
+    }
+
+    fn build(
+        &self,
+        code: Component,
+        args: &Args,
+        _debug: &DebugOptions,
+        _sock: Option<&Path>,
+    ) -> Result<Arc<dyn backend::Keep>> {
+        let (layout, image) = load(code, args)?;
+        Ok(Arc::new(Keep::spawn(layout, image)?))
+    }
+
+    fn measure(&self, _code: Component, _args: &Args) -> Result<Report> {
+        bail!("Keep backend 'emulated' cannot be measured.")
+    }
+}
+
+/// Lays out the keep and writes its image
+///
+/// The image starts with the layout. Every other byte of it belongs at
+/// the same offset from the start of the keep.
+fn load(code: Component, args: &Args) -> Result<(Layout, File)> {
+    if !code.pie {
+        bail!("Keep backend 'emulated' requires a position independent payload.");
+    }
+
+    let argv = args.encode()?;
+    let manifest = args.manifest();
+
+    // Bounded, the sizes cannot overflow the layout.
+    let (heap, stack) = (args.heap(), args.stack());
+    if heap.map_or(false, |n| n > MAX_SIZE) || stack.map_or(false, |n| n > MAX_SIZE) {
+        bail!(
+            "The heap and stack of emulated keeps are at most {} bytes each",
+            MAX_SIZE
+        );
+    }
+
+    let mut layout = Layout::calculate(code.region(), argv.len(), manifest.len(), heap, stack);
+    layout.argc = args.argv.len();
+    layout.envc = args.envp.len();
+    layout.tmpfs = args.tmpfs()?;
+    layout.sealed = args.sealed()?;
+
+    let image = memfd("enarx-keep")?;
+    image.set_len(Span::from(layout.keep).count as u64)?;
+
+    let write =
+        |addr: usize, bytes: &[u8]| image.write_all_at(bytes, (addr - layout.keep.start) as u64);
+
+    let bytes = unsafe {
+        std::slice::from_raw_parts(&layout as *const Layout as *const u8, size_of::<Layout>())
+    };
+
+    write(layout.prefix.start, bytes)?;
+    write(layout.args.start, &argv)?;
+    write(layout.manifest.start, manifest)?;
+
+    for seg in &code.segments {
+        write(layout.code.start + seg.dst, unsafe {
+            seg.src.align_to::<u8>().1
+        })?;
+    }
+
+    Ok((layout, image))
+}
+
+/// Creates an anonymous file in memory
+fn memfd(name: &str) -> Result<File> {
+    let name = CString::new(name)?;
+
+    match unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) } {
+        fd if fd < 0 => Err(Error::last_os_error().into()),
+        fd => Ok(unsafe { File::from_raw_fd(fd) }),
+    }
+}
+
+/// Returns the result of a libc call or the error it set
+fn check(ret: libc::c_int) -> std::io::Result<libc::c_int> {
+    match ret {
+        ret if ret < 0 => Err(Error::last_os_error()),
+        ret => Ok(ret),
+    }
+}
+
+/// Duplicates `fd` to the lowest free descriptor above the ones the shim
+/// is passed
+fn above_shim_fds(fd: RawFd) -> std::io::Result<RawFd> {
+    check(unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, HOST_FD + 1) })
+}
+
+/// The sallyport block, mapped at the same address as in the shim
+struct Shared {
+    block: *mut Block,
+    size: usize,
+}
+
+impl Shared {
+    fn map(layout: &Layout, image: &File) -> Result<Self> {
+        let block = Span::from(layout.block);
+        let offset = block.start - layout.keep.start;
+
+        let addr = unsafe {
+            libc::mmap(
+                block.start as _,
+                block.count,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_FIXED_NOREPLACE,
+                image.as_raw_fd(),
+                offset as _,
+            )
+        };
+
+        if addr == libc::MAP_FAILED {
+            return Err(Error::last_os_error().into());
+        }
+
+        // Kernels before 4.17 treat the address as a hint only.
+        if addr as usize != block.start {
+            unsafe { libc::munmap(addr, block.count) };
+            return Err(Error::from_raw_os_error(libc::EEXIST).into());
+        }
+
+        Ok(Self {
+            block: addr as _,
+            size: block.count,
+        })
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.block as _, self.size) };
+    }
+}
+
+/// An emulated keep
+struct Keep {
+    layout: Layout,
+    block: Shared,
+    host: UnixStream,
+    child: Mutex<Child>,
+    added: AtomicBool,
+}
+
+impl Keep {
+    /// Starts the shim process of the keep
+    fn spawn(layout: Layout, image: File) -> Result<Self> {
+        let block = Shared::map(&layout, &image)?;
+        let (host, keep) = UnixStream::pair()?;
+
+        // The shim binary must not occupy a descriptor passed to it.
+        let shim = memfd("shim-emulated")?;
+        let shim = unsafe { File::from_raw_fd(above_shim_fds(shim.as_raw_fd())?) };
+        (&shim).write_all(SHIM)?;
+
+        let image = image.as_raw_fd();
+        let keep = keep.as_raw_fd();
+
+        let path = format!("/proc/self/fd/{}", shim.as_raw_fd());
+        let mut command = std::process::Command::new(path);
+        command.stdin(Stdio::null()).stdout(Stdio::null());
+
+        unsafe {
+            command.pre_exec(move || {
+                // Move both out of the way first, so neither replaces the other.
+                let image = above_shim_fds(image)?;
+                let keep = above_shim_fds(keep)?;
+
+                check(libc::dup2(image, IMAGE_FD))?;
+                check(libc::dup2(keep, HOST_FD))?;
+                check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+
+                // A Ctrl-C on the terminal signals the whole process group,
+                // but reaches the payload through the loader.
+                libc::signal(libc::SIGINT, libc::SIG_IGN);
+                Ok(())
+            });
+        }
+
+        Ok(Self {
+            layout,
+            block,
+            host,
+            child: Mutex::new(command.spawn()?),
+            added: AtomicBool::new(false),
+        })
+    }
+}
+
+impl Drop for Keep {
+    fn drop(&mut self) {
+        let mut child = self.child.lock().unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+impl backend::Keep for Keep {
+    fn add_thread(self: Arc<Self>) -> Result<Box<dyn backend::Thread>> {
+        if self.added.swap(true, Ordering::SeqCst) {
+            return Err(anyhow!("out of threads"));
+        }
+
+        Ok(Box::new(Thread {
+            keep: self,
+            waiting: false,
+            shadow: Shadow::default(),
+        }))
+    }
+}
+
+struct Thread {
+    keep: Arc<Keep>,
+
+    /// Whether the shim waits for the reply to a request
+    waiting: bool,
+
+    /// The copy of the block the host works on
+    shadow: Shadow,
+}
+
+impl backend::Thread for Thread {
+    fn enter(&mut self) -> Result<Command> {
+        let mut host = &self.keep.host;
+        let mut byte = [0u8];
+
+        self.shadow.restore();
+
+        // Send the reply to the previous request, then wait for the next one.
+        let sent = match self.waiting {
+            true => host.write_all(&byte),
+            false => Ok(()),
+        };
+
+        match sent.and_then(|_| host.read_exact(&mut byte)) {
+            Ok(()) => self.waiting = true,
+
+            Err(e) => match e.kind() {
+                ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => {
+                    let status = self.keep.child.lock().unwrap().wait()?;
+                    bail!("The emulated keep exited unexpectedly: {}", status);
+                }
+                _ => return Err(e.into()),
+            },
+        }
+
+        let block = unsafe { &mut *self.keep.block.block };
+
+        if usize::from(unsafe { block.msg.req.num }) as i64 == SYS_ENARX_SIGNALS {
+            let set = signals::take() as usize;
+            block.msg.rep = Ok([set.into(), 0.into()]).into();
+            return Ok(Command::Continue);
+        }
+
+        // The payload may change the block at any time, so the host only
+        // works on a copy of it.
+        match unsafe { self.shadow.copy(block) } {
+            Ok(block) => Ok(Command::SysCall(block)),
+            Err(errno) => {
+                block.msg.rep = Err(errno).into();
+                Ok(Command::Continue)
+            }
+        }
+    }
+}
@@ -12,11 +12,13 @@ pub use vm::{
     Arch, Builder, Hook, Hv2GpFn, Vm, X86,
 };
 
-use crate::backend::{self, Datum, Keep};
+use crate::backend::{self, Args, Datum, DebugOptions, Keep, Report};
 use crate::binary::Component;
+use shim::BootInfo;
 
 use anyhow::Result;
 use kvm_ioctls::Kvm;
+use lset::Line;
 
 use std::path::Path;
 use std::sync::{Arc, RwLock};
@@ -46,6 +48,34 @@ fn kvm_version() -> Datum {
     }
 }
 
+/// Adds the digests and the memory layout of a VM-based keep to its report
+pub fn complete(mut report: Report, digests: &[Measurement], boot_info: &BootInfo) -> Report {
+    let regions = [
+        ("setup", boot_info.setup),
+        ("shim", boot_info.shim),
+        ("code", boot_info.code),
+        ("args", boot_info.args),
+        ("manifest", boot_info.manifest),
+        (
+            "memory",
+            Line {
+                start: 0,
+                end: boot_info.mem_size,
+            },
+        ),
+    ];
+
+    report.digests = digests
+        .iter()
+        .map(|d| (d.kind.to_string(), backend::hex(&d.digest)))
+        .collect();
+    report.layout = regions
+        .iter()
+        .map(|(name, line)| (name.to_string(), (*line).into()))
+        .collect();
+    report
+}
+
 pub struct Backend;
 
 impl backend::Backend for Backend {
@@ -57,27 +87,30 @@ impl backend::Backend for Backend {
         vec![dev_kvm(), kvm_version()]
     }
 
-    fn build(&self, code: Component, _sock: Option<&Path>) -> Result<Arc<dyn Keep>> {
+    fn build(
+        &self,
+        code: Component,
+        args: &Args,
+        debug: &DebugOptions,
+        _sock: Option<&Path>,
+    ) -> Result<Arc<dyn Keep>> {
         let shim = Component::from_bytes(SHIM)?;
 
-        let vm = Builder::new(shim, code, builder::Kvm)
+        let vm = Builder::new(shim, code, args.clone(), builder::Kvm)
+            .debug(debug.clone())
             .build::<X86, ()>()?
             .vm();
 
         Ok(Arc::new(RwLock::new(vm)))
     }
 
-    fn measure(&self, code: Component) -> Result<String> {
+    fn measure(&self, code: Component, args: &Args) -> Result<Report> {
         let shim = Component::from_bytes(SHIM)?;
+        let report = Report::new("kvm", &shim, &code)?;
 
-        let digest = Builder::new(shim, code, builder::Kvm)
-            .build::<X86, ()>()?
-            .measurement();
+        let (digests, boot_info) =
+            Builder::new(shim, code, args.clone(), builder::Kvm).measure::<X86>()?;
 
-        let json = format!(
-            r#"{{ "backend": "kvm", "{}": {:?} }}"#,
-            digest.kind, digest.digest
-        );
-        Ok(json)
+        Ok(complete(report, &digests, &boot_info))
     }
 }
@@ -0,0 +1,106 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Symbolized backtraces of the shim and the payload
+
+use super::mem::Memory;
+use super::personality::Personality;
+use super::{Arch, Vm};
+use crate::backend::kvm::shim::{Frame, MAX_FRAMES, SHIM_VIRT_OFFSET};
+
+use anyhow::Result;
+use kvm_ioctls::VcpuFd;
+
+use std::convert::TryInto;
+use std::fmt::Write;
+
+/// Formats the frames of a backtrace with the symbols of the shim and the
+/// payload
+///
+/// Like the raw backtraces of the shim, every frame starts with its address
+/// relative to its binary, prefixed with `P` for the payload.
+pub fn format<A: Arch, P: Personality>(vm: &Vm<A, P>, frames: &[Frame]) -> String {
+    let offsets = |payload: bool| {
+        frames
+            .iter()
+            .filter(|frame| (frame.payload != 0) == payload)
+            .map(|frame| frame.offset as u64)
+            .collect::<Vec<_>>()
+    };
+
+    let mut shim = vm.shim_symbols.lookup(&offsets(false)).into_iter();
+    let mut code = vm.code_symbols.lookup(&offsets(true)).into_iter();
+
+    let mut trace = String::from("TRACE:\n");
+    for frame in frames {
+        let (prefix, names) = match frame.payload {
+            0 => (" ", shim.next()),
+            _ => ("P", code.next()),
+        };
+
+        let addr = format!("{} 0x{:>016x}", prefix, frame.offset);
+        let mut names = names.unwrap_or_default().into_iter();
+
+        match names.next() {
+            Some(name) => writeln!(trace, "{} {}", addr, name).unwrap(),
+            None => writeln!(trace, "{}", addr).unwrap(),
+        }
+
+        // Align the functions the first one was inlined into.
+        for name in names {
+            writeln!(trace, "{:width$} {}", "", name, width = addr.len()).unwrap();
+        }
+    }
+
+    trace
+}
+
+/// Unwinds the stack of a vCPU by its frame pointers
+///
+/// The shim and the payload are found by their virtual and physical
+/// addresses, respectively. If the memory of the keep is encrypted, only
+/// the current instruction of the shim can be found.
+pub fn unwind<A: Arch, P: Personality>(fd: &VcpuFd, vm: &Vm<A, P>) -> Result<Vec<Frame>> {
+    let regs = fd.get_regs()?;
+    let mem = match P::ENCRYPTED {
+        true => None,
+        false => Some(Memory::new(vm, fd.get_sregs()?.cr3)),
+    };
+
+    let shim = SHIM_VIRT_OFFSET + vm.shim_start.as_u64();
+    let word = |addr: u64| -> Option<u64> {
+        let bytes = mem.as_ref()?.read(addr, 8);
+        Some(u64::from_le_bytes(bytes[..].try_into().ok()?))
+    };
+
+    let mut frames = Vec::new();
+    let (mut rip, mut rbp) = (regs.rip, regs.rbp);
+
+    while frames.len() < MAX_FRAMES {
+        let frame = match rip.checked_sub(shim) {
+            Some(offset) => Frame {
+                payload: 0,
+                offset: offset as _,
+            },
+
+            None => match mem.as_ref().and_then(|mem| mem.translate(rip)) {
+                Some(phys) if (vm.code.start..vm.code.end).contains(&(phys as usize)) => Frame {
+                    payload: 1,
+                    offset: phys as usize - vm.code.start,
+                },
+                _ => break,
+            },
+        };
+        frames.push(frame);
+
+        // Continue just before the return address of the caller.
+        match (word(rbp), rbp.checked_add(8).and_then(word)) {
+            (Some(next), Some(ret)) if ret > 0 => {
+                rip = ret - 1;
+                rbp = next;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(frames)
+}
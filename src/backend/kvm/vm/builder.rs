@@ -1,13 +1,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::*;
-use crate::backend::kvm::shim::BootInfo;
+use crate::backend::kvm::shim::{BootInfo, MAX_STACK_SIZE};
+use crate::backend::{Args, DebugOptions};
 use crate::binary::Component;
 use crate::sallyport::Block;
 
 use personality::Personality;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use kvm_ioctls::{Kvm, VmFd};
 use lset::{Line, Span};
 use mmarinus::{perms, Kind, Map};
@@ -28,7 +29,7 @@ fn num_syscall_blocks<A: image::Arch>() -> usize {
 /// Implementors may choose to override this so that they can enable
 /// certain bits in the resulting physical address (i.e., SEV memory
 /// encryption).
-pub type Hv2GpFn = dyn Fn(VirtAddr, VirtAddr) -> PhysAddr;
+pub type Hv2GpFn = dyn Fn(VirtAddr, VirtAddr) -> PhysAddr + Send + Sync;
 
 pub trait Hook {
     fn preferred_digest() -> measure::Kind {
@@ -57,59 +58,63 @@ pub struct Builder<T: Hook> {
     hook: T,
     shim: Component,
     code: Component,
+    args: Args,
+    debug: DebugOptions,
+    report: bool,
 }
 
 pub struct Built<A: image::Arch, P: Personality> {
     vm: Vm<A, P>,
-    msr: measure::Measurement,
+    msrs: Vec<measure::Measurement>,
+    boot_info: BootInfo,
 }
 
 impl<T: Hook> Builder<T> {
-    pub fn new(shim: Component, code: Component, hook: T) -> Self {
-        Self { shim, code, hook }
+    pub fn new(shim: Component, code: Component, args: Args, hook: T) -> Self {
+        Self {
+            shim,
+            code,
+            args,
+            debug: DebugOptions::default(),
+            report: false,
+            hook,
+        }
+    }
+
+    /// Debugs the keep from the host
+    pub fn debug(mut self, debug: DebugOptions) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Calculates the digests of a report while building, not only the preferred one
+    pub fn report(mut self) -> Self {
+        self.report = true;
+        self
     }
 
     pub fn build<A: image::Arch, P: Personality>(mut self) -> Result<Built<A, P>> {
         let kvm = Kvm::new()?;
         let mut fd = kvm.create_vm()?;
 
-        let mut boot_info = Self::calculate_setup_region::<A>(
-            self.shim.region().into(),
-            self.code.region().into(),
-        )?;
+        let (map, boot_info) = self.load::<A>()?;
 
-        let mem_size = align_up(boot_info.mem_size as _, size_of::<Page>() as _);
-        // fill out remaining fields of `BootInfo`
-        boot_info.nr_syscall_blocks = num_syscall_blocks::<A>();
-        boot_info.mem_size = mem_size as _;
-
-        let (map, region) = Self::allocate_address_space(mem_size as _)?;
+        let region = KvmUserspaceMemoryRegion {
+            slot: 0,
+            flags: 0,
+            guest_phys_addr: 0,
+            memory_size: map.size() as _,
+            userspace_addr: map.addr() as _,
+        };
         unsafe { fd.set_user_memory_region(region)? };
 
-        let initial_state = unsafe { &mut *(map.addr() as *mut () as *mut image::Image<A>) };
-
-        let components = &mut [
-            (&mut self.shim, boot_info.shim.start),
-            (&mut self.code, boot_info.code.start),
-        ];
-        initial_state.commit(&map, &boot_info, &self.hook, components);
+        let initial_state = unsafe { &*(map.addr() as *const () as *const image::Image<A>) };
 
         let shim_start = boot_info.shim.start;
         let shim_entry = PhysAddr::new(self.shim.entry as _);
         self.hook.shim_loaded(&mut fd, &map)?;
 
-        let msr = {
-            let mut hasher = Hasher::new(T::preferred_digest().into())?;
-            let address_space =
-                unsafe { std::slice::from_raw_parts(map.addr() as *const u8, map.size()) };
-            hasher.update(address_space)?;
-            let digest_bytes = hasher.finish()?;
-
-            measure::Measurement {
-                kind: T::preferred_digest(),
-                digest: digest_bytes,
-            }
-        };
+        let msrs = Self::digests(&map, self.report)?;
 
         // Be sure to perform any measurements before this hook is called! At
         // least in the case of SEV, the address space will be encrypted during
@@ -131,54 +136,147 @@ impl<T: Hook> Builder<T> {
             hv2gp: self.hook.hv2gp(),
             shim_entry,
             shim_start: PhysAddr::new(shim_start as _),
+            shim_symbols: self.shim.symbols.clone(),
+            code: boot_info.code,
+            code_symbols: self.code.symbols.clone(),
             arch,
+            gdb: self.debug.gdb.clone(),
+            core_dump: self.debug.core_dump.clone(),
+            cpus: 0,
+            parked: Vec::new(),
             _phantom: PhantomData,
             _personality: PhantomData,
         };
 
-        Ok(Built { vm, msr })
+        Ok(Built {
+            vm,
+            msrs,
+            boot_info,
+        })
+    }
+
+    /// Measures the initial address space of the keep without creating it
+    ///
+    /// This needs neither KVM nor any other hardware support.
+    pub fn measure<A: image::Arch>(mut self) -> Result<(Vec<measure::Measurement>, BootInfo)> {
+        let (map, boot_info) = self.load::<A>()?;
+        Ok((Self::digests(&map, true)?, boot_info))
+    }
+
+    /// Creates the initial address space and loads the shim, the code and
+    /// the payload arguments into it
+    fn load<A: image::Arch>(&mut self) -> Result<(Map<perms::ReadWrite>, BootInfo)> {
+        let args = self.args.encode()?;
+        let manifest = self.args.manifest();
+
+        let mut boot_info = Self::calculate_setup_region::<A>(
+            self.shim.region().into(),
+            self.code.region().into(),
+            args.len(),
+            manifest.len(),
+        )?;
+
+        let mem_size = align_up(boot_info.mem_size as _, size_of::<Page>() as _);
+        // fill out remaining fields of `BootInfo`
+        boot_info.nr_syscall_blocks = num_syscall_blocks::<A>();
+        boot_info.argc = self.args.argv.len();
+        boot_info.envc = self.args.envp.len();
+        boot_info.stack_size = match self.args.stack() {
+            Some(size) if size > MAX_STACK_SIZE => {
+                bail!(
+                    "kvm and sev keeps have a stack of at most {} bytes",
+                    MAX_STACK_SIZE
+                )
+            }
+            size => size.unwrap_or_default(),
+        };
+        boot_info.tmpfs = self.args.tmpfs()?;
+        boot_info.sealed = self.args.sealed()?;
+        boot_info.mem_size = mem_size as _;
+
+        let map = Self::allocate_address_space(mem_size as _)?;
+
+        let initial_state = unsafe { &mut *(map.addr() as *mut () as *mut image::Image<A>) };
+
+        let components = &mut [
+            (&mut self.shim, boot_info.shim.start),
+            (&mut self.code, boot_info.code.start),
+        ];
+        initial_state.commit(&map, &boot_info, &self.hook, components, &args, manifest);
+
+        Ok((map, boot_info))
+    }
+
+    /// Calculates the digests of the address space, the preferred one first
+    ///
+    /// Each digest hashes the whole address space, so the ones of a report
+    /// are only calculated with `report`.
+    fn digests(map: &Map<perms::ReadWrite>, report: bool) -> Result<Vec<measure::Measurement>> {
+        let reported: &[measure::Kind] = match report {
+            true => &measure::Kind::REPORTED,
+            false => &[],
+        };
+
+        let preferred = T::preferred_digest();
+        let kinds = std::iter::once(preferred)
+            .filter(|kind| *kind != measure::Kind::Null)
+            .chain(reported.iter().copied());
+
+        let address_space =
+            unsafe { std::slice::from_raw_parts(map.addr() as *const u8, map.size()) };
+
+        let mut msrs: Vec<measure::Measurement> = Vec::new();
+        for kind in kinds {
+            if msrs.iter().any(|msr| msr.kind == kind) {
+                continue;
+            }
+
+            let mut hasher = Hasher::new(kind.into())?;
+            hasher.update(address_space)?;
+            msrs.push(measure::Measurement {
+                kind,
+                digest: hasher.finish()?,
+            });
+        }
+
+        Ok(msrs)
     }
 
     fn calculate_setup_region<A: image::Arch>(
         shim_size: Span<usize>,
         code_size: Span<usize>,
+        args_size: usize,
+        manifest_size: usize,
     ) -> Result<BootInfo> {
         let setup_size = Line {
             start: 0,
             end: size_of::<image::Image<A>>() + num_syscall_blocks::<A>(),
         };
 
-        let boot_info = BootInfo::calculate(setup_size, shim_size, code_size)
-            .map_err(|_| std::io::Error::from_raw_os_error(libc::ENOMEM))?;
+        let boot_info =
+            BootInfo::calculate(setup_size, shim_size, code_size, args_size, manifest_size)
+                .map_err(|_| std::io::Error::from_raw_os_error(libc::ENOMEM))?;
 
         Ok(boot_info)
     }
 
-    fn allocate_address_space(
-        mem_size: usize,
-    ) -> Result<(Map<perms::ReadWrite>, KvmUserspaceMemoryRegion)> {
+    fn allocate_address_space(mem_size: usize) -> Result<Map<perms::ReadWrite>> {
         let map = Map::map(mem_size)
             .anywhere()
             .anonymously()
             .known::<perms::ReadWrite>(Kind::Private)?;
 
-        let region = KvmUserspaceMemoryRegion {
-            slot: 0,
-            flags: 0,
-            guest_phys_addr: 0,
-            memory_size: mem_size as _,
-            userspace_addr: map.addr() as _,
-        };
-
-        let res = (map, region);
-
-        Ok(res)
+        Ok(map)
     }
 }
 
 impl<A: image::Arch, P: Personality> Built<A, P> {
-    pub fn measurement(&self) -> measure::Measurement {
-        self.msr
+    pub fn measurements(&self) -> &[measure::Measurement] {
+        &self.msrs
+    }
+
+    pub fn boot_info(&self) -> BootInfo {
+        self.boot_info
     }
 
     pub fn vm(self) -> Vm<A, P> {
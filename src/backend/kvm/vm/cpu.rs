@@ -1,13 +1,16 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use super::{KvmSegment, Vm};
+use super::{add_cpu, backtrace, dump, Gdb, KvmSegment, Vm};
 
-use crate::backend::kvm::shim::{MemInfo, SYSCALL_TRIGGER_PORT};
+use crate::backend::kvm::shim::{Frame, MemInfo, MAX_FRAMES, SYSCALL_TRIGGER_PORT};
 use crate::backend::kvm::vm::image::x86::X86;
 use crate::backend::kvm::vm::image::Arch;
-use crate::backend::{Command, Thread};
+use crate::backend::{signals, Command, Shadow, Thread};
 use crate::sallyport::{Block, Reply};
-use crate::syscall::{SYS_ENARX_BALLOON_MEMORY, SYS_ENARX_MEM_INFO};
+use crate::syscall::{
+    SYS_ENARX_BACKTRACE, SYS_ENARX_BALLOON_MEMORY, SYS_ENARX_MEM_INFO, SYS_ENARX_SIGNALS,
+    SYS_ENARX_SPAWN,
+};
 
 use super::personality::Personality;
 
@@ -18,40 +21,56 @@ use x86_64::registers::control::{Cr0Flags, Cr4Flags};
 use x86_64::registers::model_specific::EferFlags;
 use x86_64::PhysAddr;
 
+use std::mem::ManuallyDrop;
 use std::sync::{Arc, RwLock};
 
 pub struct Cpu<A: Arch, P: Personality> {
-    fd: VcpuFd,
+    id: usize,
+    fd: ManuallyDrop<VcpuFd>,
     keep: Arc<RwLock<Vm<A, P>>>,
+    gdb: Option<Gdb>,
+    shadow: Shadow,
 }
 
 impl<P: Personality> Cpu<X86, P> {
     pub fn new(
+        id: usize,
         fd: VcpuFd,
         keep: Arc<RwLock<Vm<X86, P>>>,
         entry: PhysAddr,
         cr3: PhysAddr,
     ) -> Result<Self> {
-        let mut cpu = Self { fd, keep };
+        // Set up before the vCPU is wrapped, which parks it when dropped.
+        Self::set_gen_regs(&fd, entry)?;
+        Self::set_special_regs(&fd, cr3)?;
 
-        cpu.set_gen_regs(entry)?;
-        cpu.set_special_regs(cr3)?;
+        Ok(Self {
+            id,
+            fd: ManuallyDrop::new(fd),
+            keep,
+            gdb: None,
+            shadow: Shadow::default(),
+        })
+    }
 
-        Ok(cpu)
+    /// Lets gdb debug the vCPU, starting before its first instruction
+    pub fn debug(&mut self, gdb: Gdb, vm: &Vm<X86, P>) -> Result<()> {
+        self.gdb = gdb.stop(&self.fd, vm, libc::SIGTRAP)?;
+        Ok(())
     }
 
-    fn set_gen_regs(&mut self, entry: PhysAddr) -> Result<()> {
-        let mut regs = self.fd.get_regs()?;
+    fn set_gen_regs(fd: &VcpuFd, entry: PhysAddr) -> Result<()> {
+        let mut regs = fd.get_regs()?;
 
         regs.rip = entry.as_u64();
         regs.rflags |= 0x2;
 
-        self.fd.set_regs(&regs)?;
+        fd.set_regs(&regs)?;
         Ok(())
     }
 
-    fn set_special_regs(&mut self, cr3: PhysAddr) -> Result<()> {
-        let mut sregs = self.fd.get_sregs()?;
+    fn set_special_regs(fd: &VcpuFd, cr3: PhysAddr) -> Result<()> {
+        let mut sregs = fd.get_sregs()?;
 
         let cs = KvmSegment {
             base: 0,
@@ -80,14 +99,38 @@ impl<P: Personality> Cpu<X86, P> {
         sregs.cr3 = cr3.as_u64();
         sregs.cr4 = (Cr4Flags::PHYSICAL_ADDRESS_EXTENSION).bits();
 
-        self.fd.set_sregs(&sregs)?;
+        fd.set_sregs(&sregs)?;
         Ok(())
     }
 }
 
+impl<A: Arch, P: Personality> Drop for Cpu<A, P> {
+    /// Parks the vCPU for the next thread the shim starts
+    ///
+    /// The bootstrap processor cannot start again as an application
+    /// processor, so it is never parked.
+    fn drop(&mut self) {
+        let fd = unsafe { ManuallyDrop::take(&mut self.fd) };
+
+        if self.id > 0 {
+            if let Ok(mut keep) = self.keep.write() {
+                keep.parked.push((self.id, fd));
+            }
+        }
+    }
+}
+
 impl<P: Personality> Thread for Cpu<X86, P> {
     fn enter(&mut self) -> Result<Command> {
-        match self.fd.run()? {
+        self.shadow.restore();
+
+        let exit = match self.fd.run() {
+            // A signal for the keep interrupted the vCPU, which just continues.
+            Err(e) if e.errno() == libc::EINTR => return Ok(Command::Continue),
+            exit => exit?,
+        };
+
+        match exit {
             VcpuExit::IoOut(port, data) => match port {
                 SYSCALL_TRIGGER_PORT => {
                     let mut keep = self.keep.write().unwrap();
@@ -101,13 +144,19 @@ impl<P: Personality> Thread for Cpu<X86, P> {
                             keep.syscall_blocks.count.get(),
                         )
                         .get_mut(block_nr)
-                        .unwrap()
+                        .ok_or_else(|| anyhow!("invalid syscall block: {}", block_nr))?
                     };
 
                     let syscall_nr: i64 = unsafe { sallyport.msg.req.num.into() };
 
                     match syscall_nr {
-                        0..=512 => Ok(Command::SysCall(sallyport)),
+                        0..=512 => match unsafe { self.shadow.copy(sallyport) } {
+                            Ok(block) => Ok(Command::SysCall(block)),
+                            Err(errno) => {
+                                sallyport.msg.rep = Reply::from(Err(errno));
+                                Ok(Command::Continue)
+                            }
+                        },
 
                         SYS_ENARX_BALLOON_MEMORY => {
                             let pages = unsafe { sallyport.msg.req.arg[0].into() };
@@ -141,16 +190,93 @@ impl<P: Personality> Thread for Cpu<X86, P> {
                             Ok(Command::Continue)
                         }
 
+                        SYS_ENARX_BACKTRACE => {
+                            let count: usize = unsafe { sallyport.msg.req.arg[0].into() };
+                            let mut frames = [Frame::default(); MAX_FRAMES];
+                            let frames = &mut frames[..count.min(MAX_FRAMES)];
+
+                            let c = sallyport.cursor();
+                            unsafe { c.copy_into_slice(frames.len(), frames) }
+                                .map_err(|_| anyhow!("Failed to read the frames from the Block"))?;
+
+                            eprint!("{}", backtrace::format(&*keep, frames));
+
+                            let ok_result: [Register<usize>; 2] = [0.into(), 0.into()];
+                            sallyport.msg.rep = Reply::from(Ok(ok_result));
+
+                            Ok(Command::Continue)
+                        }
+
+                        SYS_ENARX_SPAWN => {
+                            let cr3: usize = unsafe { sallyport.msg.req.arg[0].into() };
+
+                            let cpu = PhysAddr::try_new(cr3 as _)
+                                .map_err(|_| anyhow!("Invalid page tables for a vCPU"))
+                                .and_then(|cr3| add_cpu(&mut keep, self.keep.clone(), Some(cr3)));
+
+                            match cpu {
+                                Ok(cpu) => {
+                                    let ok_result: [Register<usize>; 2] = [cpu.id.into(), 0.into()];
+                                    sallyport.msg.rep = Reply::from(Ok(ok_result));
+
+                                    Ok(Command::Spawn(Box::new(cpu)))
+                                }
+                                Err(e) => {
+                                    eprintln!("Failed to start another vCPU: {:#}", e);
+                                    sallyport.msg.rep = Reply::from(Err(libc::EAGAIN));
+                                    Ok(Command::Continue)
+                                }
+                            }
+                        }
+
+                        SYS_ENARX_SIGNALS => {
+                            let ok_result: [Register<usize>; 2] =
+                                [(signals::take() as usize).into(), 0.into()];
+                            sallyport.msg.rep = Reply::from(Ok(ok_result));
+                            Ok(Command::Continue)
+                        }
+
                         _ => unimplemented!(),
                     }
                 }
                 _ => Err(anyhow!("data from unexpected port: {}", port)),
             },
+            VcpuExit::Debug(_) if self.gdb.is_some() => {
+                let keep = self.keep.read().unwrap();
+                if let Some(gdb) = self.gdb.take() {
+                    self.gdb = gdb.stop(&self.fd, &*keep, libc::SIGTRAP)?;
+                }
+
+                Ok(Command::Continue)
+            }
             exit_reason => {
+                let keep = self.keep.read().unwrap();
+
+                // Let gdb inspect the vCPU before the keep fails.
+                if let Some(gdb) = self.gdb.take() {
+                    gdb.stop(&self.fd, &*keep, libc::SIGSEGV)?;
+                }
+
+                // A failed dump must not hide why the keep failed.
+                if let Some(path) = keep.core_dump.as_ref() {
+                    match dump::write(path, &*keep, &[&*self.fd], libc::SIGSEGV) {
+                        Ok(()) => eprintln!("Dumped the keep to {}", path.display()),
+                        Err(e) => eprintln!("Failed to dump the keep to {}: {}", path.display(), e),
+                    }
+                }
+
+                let frames = backtrace::unwind(&self.fd, &*keep)?;
+                let trace = backtrace::format(&*keep, &frames);
+
                 if cfg!(debug_assertions) {
-                    Err(anyhow!("{:?} {:#x?}", exit_reason, self.fd.get_regs()))
+                    Err(anyhow!(
+                        "{:?} {:#x?}\n{}",
+                        exit_reason,
+                        self.fd.get_regs(),
+                        trace
+                    ))
                 } else {
-                    Err(anyhow!("{:?}", exit_reason))
+                    Err(anyhow!("{:?}\n{}", exit_reason, trace))
                 }
             }
         }
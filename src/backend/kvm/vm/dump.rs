@@ -0,0 +1,258 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! ELF core dumps of failed keeps
+//!
+//! The guest physical memory is dumped at the virtual addresses the shim
+//! maps it to, and the auxiliary vector holds the entry point of the shim,
+//! so `gdb shim-sev core` relocates the shim by itself. The payload is
+//! located by the `ENARX` notes.
+
+use super::personality::Personality;
+use super::{Arch, Vm};
+use crate::backend::kvm::shim::SHIM_VIRT_OFFSET;
+
+use anyhow::Result;
+use goblin::elf::header::{ELFCLASS64, ELFDATA2LSB, ELFMAG, EM_X86_64, ET_CORE, EV_CURRENT};
+use goblin::elf::program_header::{PF_R, PF_W, PF_X, PT_LOAD, PT_NOTE};
+use kvm_ioctls::VcpuFd;
+use primordial::Page;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_FPREGSET: u32 = 2;
+const NT_AUXV: u32 = 6;
+
+const AT_NULL: u64 = 0;
+const AT_ENTRY: u64 = 9;
+
+/// The size of `struct elf_prstatus` and the offset of its registers
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_REGS: usize = 112;
+
+/// The size of `struct user_fpregs_struct`, the `fxsave` area
+const FPREGSET_SIZE: usize = 512;
+
+/// The `ENARX` note with the physical and the virtual load address of the shim
+pub const NT_ENARX_SHIM: u32 = 1;
+
+/// The `ENARX` note with the physical start and end address of the payload
+pub const NT_ENARX_CODE: u32 = 2;
+
+/// Writes a core dump of the keep with the registers of `vcpus`
+///
+/// Each vCPU becomes a thread with its index plus one as id, stopped by
+/// `signal`.
+pub fn write<A: Arch, P: Personality>(
+    path: &Path,
+    vm: &Vm<A, P>,
+    vcpus: &[&VcpuFd],
+    signal: i32,
+) -> Result<()> {
+    let mut notes = Vec::new();
+    for (id, vcpu) in vcpus.iter().enumerate() {
+        note(
+            &mut notes,
+            "CORE",
+            NT_PRSTATUS,
+            &prstatus(vcpu, id as i32 + 1, signal)?,
+        );
+        note(&mut notes, "CORE", NT_FPREGSET, &fpregset(vcpu)?);
+    }
+
+    let shim = SHIM_VIRT_OFFSET + vm.shim_start.as_u64();
+    let entry = SHIM_VIRT_OFFSET + vm.shim_entry.as_u64();
+    let code = [vm.code.start as u64, vm.code.end as u64];
+
+    note(
+        &mut notes,
+        "CORE",
+        NT_AUXV,
+        &words(&[AT_ENTRY, entry, AT_NULL, 0]),
+    );
+    note(
+        &mut notes,
+        "ENARX",
+        NT_ENARX_SHIM,
+        &words(&[vm.shim_start.as_u64(), shim]),
+    );
+    note(&mut notes, "ENARX", NT_ENARX_CODE, &words(&code));
+
+    let phnum = 1 + vm.regions.len();
+    let notes_offset = EHDR_SIZE + PHDR_SIZE * phnum;
+
+    let mut headers = Vec::with_capacity(notes_offset);
+    headers.extend_from_slice(ELFMAG);
+    headers.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+    headers.resize(16, 0);
+    headers.extend_from_slice(&ET_CORE.to_le_bytes());
+    headers.extend_from_slice(&EM_X86_64.to_le_bytes());
+    headers.extend_from_slice(&(EV_CURRENT as u32).to_le_bytes());
+    headers.extend_from_slice(&words(&[0, EHDR_SIZE as u64, 0]));
+    headers.extend_from_slice(&0u32.to_le_bytes());
+    for half in &[EHDR_SIZE, PHDR_SIZE, phnum, 0, 0, 0] {
+        headers.extend_from_slice(&(*half as u16).to_le_bytes());
+    }
+
+    phdr(
+        &mut headers,
+        PT_NOTE,
+        0,
+        notes_offset,
+        [0, 0, notes.len() as u64],
+        4,
+    );
+
+    // The memory starts at the first page after the notes.
+    let mut offset = notes_offset + notes.len();
+    let padding = (Page::size() - offset % Page::size()) % Page::size();
+    offset += padding;
+
+    for region in &vm.regions {
+        let guest = region.as_guest();
+        let virt = SHIM_VIRT_OFFSET + guest.start.as_u64();
+        let addrs = [virt, guest.start.as_u64(), guest.count];
+
+        phdr(
+            &mut headers,
+            PT_LOAD,
+            PF_R | PF_W | PF_X,
+            offset,
+            addrs,
+            Page::size(),
+        );
+        offset += guest.count as usize;
+    }
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&headers)?;
+    file.write_all(&notes)?;
+    file.write_all(&vec![0; padding])?;
+
+    for region in &vm.regions {
+        let virt = region.as_virt();
+        let bytes =
+            unsafe { std::slice::from_raw_parts(virt.start.as_ptr::<u8>(), virt.count as usize) };
+        file.write_all(bytes)?;
+    }
+
+    file.flush()?;
+    Ok(())
+}
+
+/// Encodes `words` in little endian
+fn words(words: &[u64]) -> Vec<u8> {
+    words
+        .iter()
+        .flat_map(|word| word.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// Appends a program header whose `addrs` are its virtual and physical
+/// address and its size
+fn phdr(
+    headers: &mut Vec<u8>,
+    kind: u32,
+    flags: u32,
+    offset: usize,
+    addrs: [u64; 3],
+    align: usize,
+) {
+    let [vaddr, paddr, size] = addrs;
+
+    headers.extend_from_slice(&kind.to_le_bytes());
+    headers.extend_from_slice(&flags.to_le_bytes());
+    headers.extend_from_slice(&words(&[
+        offset as u64,
+        vaddr,
+        paddr,
+        size,
+        size,
+        align as u64,
+    ]));
+}
+
+/// Appends a note, padding its name and description to four bytes
+fn note(notes: &mut Vec<u8>, name: &str, kind: u32, desc: &[u8]) {
+    let pad = |notes: &mut Vec<u8>| notes.resize((notes.len() + 3) & !3, 0);
+
+    notes.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+    notes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    notes.extend_from_slice(&kind.to_le_bytes());
+
+    notes.extend_from_slice(name.as_bytes());
+    notes.push(0);
+    pad(notes);
+
+    notes.extend_from_slice(desc);
+    pad(notes);
+}
+
+/// Encodes the general purpose registers of a vCPU as `struct elf_prstatus`
+fn prstatus(vcpu: &VcpuFd, id: i32, signal: i32) -> Result<Vec<u8>> {
+    let regs = vcpu.get_regs()?;
+    let sregs = vcpu.get_sregs()?;
+
+    // In the order of `struct user_regs_struct`, with no syscall for orig_rax
+    let user_regs = [
+        regs.r15,
+        regs.r14,
+        regs.r13,
+        regs.r12,
+        regs.rbp,
+        regs.rbx,
+        regs.r11,
+        regs.r10,
+        regs.r9,
+        regs.r8,
+        regs.rax,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        !0,
+        regs.rip,
+        sregs.cs.selector.into(),
+        regs.rflags,
+        regs.rsp,
+        sregs.ss.selector.into(),
+        sregs.fs.base,
+        sregs.gs.base,
+        sregs.ds.selector.into(),
+        sregs.es.selector.into(),
+        sregs.fs.selector.into(),
+        sregs.gs.selector.into(),
+    ];
+
+    let mut status = vec![0; PRSTATUS_SIZE];
+    status[0..4].copy_from_slice(&signal.to_le_bytes());
+    status[12..14].copy_from_slice(&(signal as i16).to_le_bytes());
+    status[32..36].copy_from_slice(&id.to_le_bytes());
+    status[PRSTATUS_REGS..][..user_regs.len() * 8].copy_from_slice(&words(&user_regs));
+
+    Ok(status)
+}
+
+/// Encodes the x87 and SSE registers of a vCPU as `struct user_fpregs_struct`
+fn fpregset(vcpu: &VcpuFd) -> Result<Vec<u8>> {
+    let fpu = vcpu.get_fpu()?;
+
+    let mut fpregs = Vec::with_capacity(FPREGSET_SIZE);
+    fpregs.extend_from_slice(&fpu.fcw.to_le_bytes());
+    fpregs.extend_from_slice(&fpu.fsw.to_le_bytes());
+    fpregs.extend_from_slice(&u16::from(fpu.ftwx).to_le_bytes());
+    fpregs.extend_from_slice(&fpu.last_opcode.to_le_bytes());
+    fpregs.extend_from_slice(&words(&[fpu.last_ip, fpu.last_dp]));
+    fpregs.extend_from_slice(&fpu.mxcsr.to_le_bytes());
+    fpregs.extend_from_slice(&0u32.to_le_bytes());
+    fpregs.extend(fpu.fpr.iter().flatten());
+    fpregs.extend(fpu.xmm.iter().flatten());
+    fpregs.resize(FPREGSET_SIZE, 0);
+
+    Ok(fpregs)
+}
@@ -0,0 +1,411 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A GDB remote stub for the vCPU of a KVM keep
+//!
+//! The stub speaks the GDB remote serial protocol on a unix socket. While
+//! the vCPU is stopped, gdb can read and write its registers and the guest
+//! memory, which the stub finds by walking the page tables of the guest.
+//! Software breakpoints are `int3` instructions written to the guest and
+//! hardware breakpoints use the debug registers of the vCPU.
+//!
+//! The vCPU stops before its first instruction, on every breakpoint and
+//! single step and before the keep fails. Guest memory is accessed through
+//! the host mappings, so this only works for keeps with plain memory.
+
+use super::mem::Memory;
+use super::personality::Personality;
+use super::{Arch, Vm};
+use crate::backend::kvm::shim::SHIM_VIRT_OFFSET;
+
+use anyhow::{bail, Result};
+use kvm_bindings::{
+    kvm_guest_debug, KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP,
+    KVM_GUESTDBG_USE_SW_BP,
+};
+use kvm_ioctls::VcpuFd;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+/// The maximum size of a packet
+const PACKET_SIZE: usize = 0x1000;
+
+/// The `int3` instruction
+const INT3: u8 = 0xCC;
+
+/// The number of hardware breakpoints, in `DR0` to `DR3`
+const HW_BREAKPOINTS: usize = 4;
+
+/// The general purpose registers of the `g` packet, up to `rip`
+const GPRS: usize = 17;
+
+// Error replies
+const EFAULT: &[u8] = b"E0e";
+const EINVAL: &[u8] = b"E16";
+const ENOSPC: &[u8] = b"E1c";
+
+pub struct Gdb {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+
+    /// The original bytes at the software breakpoints
+    breakpoints: HashMap<u64, u8>,
+
+    /// The addresses of the hardware breakpoints
+    hw: [Option<u64>; HW_BREAKPOINTS],
+
+    /// Whether gdb waits for the vCPU to stop
+    resumed: bool,
+}
+
+impl Gdb {
+    /// Waits for gdb to connect to the socket at `path`
+    pub fn listen(path: &Path) -> Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        eprintln!("Waiting for gdb to connect to {}", path.display());
+
+        let (stream, _) = listener.accept()?;
+        std::fs::remove_file(path)?;
+
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            breakpoints: HashMap::new(),
+            hw: [None; HW_BREAKPOINTS],
+            resumed: false,
+        })
+    }
+
+    /// Reports a stop of the vCPU with `signal` and serves gdb until it
+    /// resumes the vCPU
+    ///
+    /// Returns the stub again, unless gdb detached from the keep.
+    pub fn stop<A: Arch, P: Personality>(
+        mut self,
+        fd: &VcpuFd,
+        vm: &Vm<A, P>,
+        signal: i32,
+    ) -> Result<Option<Self>> {
+        let mem = Memory::new(vm, fd.get_sregs()?.cr3);
+
+        let rip = fd.get_regs()?.rip;
+        let reason = match signal {
+            libc::SIGTRAP if self.breakpoints.contains_key(&rip) => "swbreak:;",
+            libc::SIGTRAP if self.hw.contains(&Some(rip)) => "hwbreak:;",
+            _ => "",
+        };
+        let stop = format!("T{:02x}{}", signal, reason).into_bytes();
+
+        // Initially, gdb asks for the stop with `?` instead.
+        if self.resumed {
+            self.send(&stop)?;
+        }
+
+        loop {
+            let packet = self.recv()?;
+            let (kind, rest) = match packet.split_first() {
+                Some((kind, rest)) => (*kind, rest),
+                None => (0, &packet[..]),
+            };
+
+            let reply = match kind {
+                b'?' => stop.clone(),
+                b'g' => hex(&registers(fd)?),
+                b'G' => match unhex(rest) {
+                    Some(bytes) if bytes.len() >= GPRS * 8 + 4 => {
+                        set_registers(fd, &bytes)?;
+                        b"OK".to_vec()
+                    }
+                    _ => EINVAL.to_vec(),
+                },
+
+                b'm' => match numbers(rest).as_deref() {
+                    Some(&[addr, len]) => {
+                        let bytes = mem.read(addr, len.min(PACKET_SIZE as u64 / 2));
+                        match bytes.is_empty() && len > 0 {
+                            true => EFAULT.to_vec(),
+                            false => hex(&bytes),
+                        }
+                    }
+                    _ => EINVAL.to_vec(),
+                },
+                b'M' => match split(rest, b':') {
+                    Some((args, data)) => match (numbers(args).as_deref(), unhex(data)) {
+                        (Some(&[addr, _]), Some(data)) if mem.write(addr, &data) => b"OK".to_vec(),
+                        (Some(&[_, _]), Some(_)) => EFAULT.to_vec(),
+                        _ => EINVAL.to_vec(),
+                    },
+                    None => EINVAL.to_vec(),
+                },
+
+                b'Z' | b'z' => self.breakpoint(&mem, kind == b'Z', rest),
+
+                b'c' | b's' => {
+                    if !rest.is_empty() {
+                        let mut regs = fd.get_regs()?;
+                        regs.rip = match numbers(rest).as_deref() {
+                            Some(&[rip]) => rip,
+                            _ => bail!("gdb sent an invalid address"),
+                        };
+                        fd.set_regs(&regs)?;
+                    }
+
+                    self.resume(fd, kind == b's')?;
+                    return Ok(Some(self));
+                }
+
+                b'D' => {
+                    self.send(b"OK")?;
+                    self.detach(fd, &mem)?;
+                    return Ok(None);
+                }
+
+                b'k' => bail!("gdb killed the keep"),
+
+                b'q' if rest.starts_with(b"Supported") => {
+                    format!("PacketSize={:x};swbreak+;hwbreak+", PACKET_SIZE).into_bytes()
+                }
+                b'q' if rest == b"Attached" => b"1".to_vec(),
+                b'q' if rest == b"Offsets" => {
+                    let offset = SHIM_VIRT_OFFSET + vm.shim_start.as_u64();
+                    format!("Text={0:x};Data={0:x};Bss={0:x}", offset).into_bytes()
+                }
+
+                b'H' => b"OK".to_vec(),
+                _ => Vec::new(),
+            };
+
+            self.send(&reply)?;
+        }
+    }
+
+    /// Inserts or removes a software or hardware breakpoint
+    fn breakpoint<A: Arch, P: Personality>(
+        &mut self,
+        mem: &Memory<A, P>,
+        insert: bool,
+        rest: &[u8],
+    ) -> Vec<u8> {
+        let (kind, addr) = match numbers(rest).as_deref() {
+            Some(&[kind, addr, _]) => (kind, addr),
+            _ => return EINVAL.to_vec(),
+        };
+
+        match (kind, insert) {
+            (0, true) if !self.breakpoints.contains_key(&addr) => match mem.read(addr, 1).first() {
+                Some(byte) if mem.write(addr, &[INT3]) => {
+                    self.breakpoints.insert(addr, *byte);
+                }
+                _ => return EFAULT.to_vec(),
+            },
+
+            (0, false) => {
+                if let Some(byte) = self.breakpoints.remove(&addr) {
+                    mem.write(addr, &[byte]);
+                }
+            }
+
+            (1, true) if !self.hw.contains(&Some(addr)) => {
+                match self.hw.iter_mut().find(|slot| slot.is_none()) {
+                    Some(slot) => *slot = Some(addr),
+                    None => return ENOSPC.to_vec(),
+                }
+            }
+
+            (1, false) => {
+                for slot in self.hw.iter_mut().filter(|slot| **slot == Some(addr)) {
+                    *slot = None;
+                }
+            }
+
+            (0, true) | (1, true) => (),
+
+            // gdb falls back to other means for the unsupported kinds.
+            _ => return Vec::new(),
+        }
+
+        b"OK".to_vec()
+    }
+
+    /// Lets the vCPU run until the next breakpoint or, with `step`, for a
+    /// single instruction
+    fn resume(&mut self, fd: &VcpuFd, step: bool) -> Result<()> {
+        let mut debug = kvm_guest_debug {
+            control: KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP,
+            ..Default::default()
+        };
+
+        for (i, addr) in self.hw.iter().enumerate() {
+            if let Some(addr) = addr {
+                debug.arch.debugreg[i] = *addr;
+                debug.arch.debugreg[7] |= 1 << (2 * i);
+                debug.control |= KVM_GUESTDBG_USE_HW_BP;
+            }
+        }
+
+        if step {
+            debug.control |= KVM_GUESTDBG_SINGLESTEP;
+        }
+
+        fd.set_guest_debug(&debug)?;
+        self.resumed = true;
+        Ok(())
+    }
+
+    /// Removes all breakpoints and lets the vCPU run undisturbed
+    fn detach<A: Arch, P: Personality>(&mut self, fd: &VcpuFd, mem: &Memory<A, P>) -> Result<()> {
+        for (addr, byte) in self.breakpoints.drain() {
+            mem.write(addr, &[byte]);
+        }
+
+        fd.set_guest_debug(&kvm_guest_debug::default())?;
+        Ok(())
+    }
+
+    /// Receives the next packet and acknowledges it
+    fn recv(&mut self) -> Result<Vec<u8>> {
+        loop {
+            // Skip acknowledgements and interrupts up to the next packet.
+            let mut packet = Vec::new();
+            self.reader.read_until(b'$', &mut packet)?;
+            if packet.last() != Some(&b'$') {
+                bail!("gdb closed the connection");
+            }
+
+            packet.clear();
+            self.reader.read_until(b'#', &mut packet)?;
+            if packet.pop() != Some(b'#') {
+                bail!("gdb closed the connection");
+            }
+
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum)?;
+
+            let sum = std::str::from_utf8(&sum).ok();
+            if sum.and_then(|s| u8::from_str_radix(s, 16).ok()) == Some(checksum(&packet)) {
+                self.writer.write_all(b"+")?;
+                return Ok(packet);
+            }
+
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    /// Sends a packet until gdb acknowledges it
+    fn send(&mut self, packet: &[u8]) -> Result<()> {
+        let mut bytes = Vec::with_capacity(packet.len() + 4);
+        bytes.push(b'$');
+        bytes.extend_from_slice(packet);
+        bytes.extend_from_slice(format!("#{:02x}", checksum(packet)).as_bytes());
+
+        loop {
+            self.writer.write_all(&bytes)?;
+
+            let mut ack = [0];
+            self.reader.read_exact(&mut ack)?;
+            if ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Encodes the registers of the vCPU in the order of the `g` packet
+fn registers(fd: &VcpuFd) -> Result<Vec<u8>> {
+    let regs = fd.get_regs()?;
+    let sregs = fd.get_sregs()?;
+
+    let gprs: [u64; GPRS] = [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ];
+    let segments = [sregs.cs, sregs.ss, sregs.ds, sregs.es, sregs.fs, sregs.gs];
+
+    let mut bytes = Vec::new();
+    for gpr in gprs.iter() {
+        bytes.extend_from_slice(&gpr.to_le_bytes());
+    }
+
+    bytes.extend_from_slice(&(regs.rflags as u32).to_le_bytes());
+    for segment in segments.iter() {
+        bytes.extend_from_slice(&u32::from(segment.selector).to_le_bytes());
+    }
+
+    Ok(bytes)
+}
+
+/// Sets the general purpose registers and flags from a `G` packet
+///
+/// The segment registers are left alone.
+fn set_registers(fd: &VcpuFd, bytes: &[u8]) -> Result<()> {
+    let mut regs = fd.get_regs()?;
+    let (gprs, rest) = bytes.split_at(GPRS * 8);
+
+    let mut fields = [
+        &mut regs.rax,
+        &mut regs.rbx,
+        &mut regs.rcx,
+        &mut regs.rdx,
+        &mut regs.rsi,
+        &mut regs.rdi,
+        &mut regs.rbp,
+        &mut regs.rsp,
+        &mut regs.r8,
+        &mut regs.r9,
+        &mut regs.r10,
+        &mut regs.r11,
+        &mut regs.r12,
+        &mut regs.r13,
+        &mut regs.r14,
+        &mut regs.r15,
+        &mut regs.rip,
+    ];
+
+    for (field, value) in fields.iter_mut().zip(gprs.chunks_exact(8)) {
+        **field = u64::from_le_bytes(value.try_into()?);
+    }
+
+    let flags = u32::from_le_bytes(rest[..4].try_into()?);
+    regs.rflags = (regs.rflags & !0xFFFF_FFFF) | u64::from(flags);
+
+    fd.set_regs(&regs)?;
+    Ok(())
+}
+
+fn checksum(packet: &[u8]) -> u8 {
+    packet.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|b| format!("{:02x}", b).into_bytes())
+        .collect()
+}
+
+fn unhex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Parses comma separated hexadecimal numbers
+fn numbers(bytes: &[u8]) -> Option<Vec<u64>> {
+    bytes
+        .split(|b| *b == b',')
+        .map(|n| u64::from_str_radix(std::str::from_utf8(n).ok()?, 16).ok())
+        .collect()
+}
+
+/// Splits `bytes` at the first `separator`
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = bytes.iter().position(|b| *b == separator)?;
+    Some((&bytes[..at], &bytes[at + 1..]))
+}
@@ -47,6 +47,8 @@ impl<A: Arch> Image<A> {
         boot_info: &BootInfo,
         hook: &impl Hook,
         components: &mut [(&mut Component, usize)],
+        args: &[u8],
+        manifest: &[u8],
     ) {
         assert_eq!(backing.addr() % align_of::<Self>(), 0);
         assert!(
@@ -66,6 +68,14 @@ impl<A: Arch> Image<A> {
         for (component, offset) in components {
             self.load_component(VirtAddr::new(backing.addr() as _), component, *offset);
         }
+
+        // Load the payload arguments and environment and the manifest.
+        for (region, bytes) in &[(boot_info.args, args), (boot_info.manifest, manifest)] {
+            assert_eq!(region.end - region.start, bytes.len());
+            let dst = VirtAddr::new(backing.addr() as u64 + region.start as u64).as_mut_ptr();
+            let dst = unsafe { std::slice::from_raw_parts_mut(dst, bytes.len()) };
+            dst.copy_from_slice(bytes);
+        }
     }
 
     fn load_component(&mut self, start: VirtAddr, component: &mut Component, offset: usize) {
@@ -4,16 +4,25 @@ use std::fmt;
 
 use openssl::hash::{DigestBytes, MessageDigest};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Kind {
     Sha256,
+    Sha384,
+    Sha512,
     Null,
 }
 
+impl Kind {
+    /// The digests of the keep in its report, besides the preferred one
+    pub const REPORTED: [Kind; 3] = [Kind::Sha256, Kind::Sha384, Kind::Sha512];
+}
+
 impl fmt::Display for Kind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
             Self::Sha256 => "sha256",
+            Self::Sha384 => "sha384",
+            Self::Sha512 => "sha512",
             Self::Null => "null",
         };
 
@@ -25,6 +34,8 @@ impl From<Kind> for MessageDigest {
     fn from(k: Kind) -> MessageDigest {
         match k {
             Kind::Sha256 => MessageDigest::sha256(),
+            Kind::Sha384 => MessageDigest::sha384(),
+            Kind::Sha512 => MessageDigest::sha512(),
             Kind::Null => MessageDigest::null(),
         }
     }
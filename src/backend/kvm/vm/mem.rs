@@ -1,11 +1,17 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use super::KvmUserspaceMemoryRegion;
+use super::personality::Personality;
+use super::{Arch, KvmUserspaceMemoryRegion, Vm};
 
 use lset::Span;
 use mmarinus::{perms, Map};
 use x86_64::{PhysAddr, VirtAddr};
 
+// Page table entry bits
+const PRESENT: u64 = 1 << 0;
+const HUGE: u64 = 1 << 7;
+const ADDRESS: u64 = 0x000F_FFFF_FFFF_F000;
+
 pub struct Region {
     kvm_region: KvmUserspaceMemoryRegion,
     _backing: Map<perms::ReadWrite>,
@@ -33,3 +39,86 @@ impl Region {
         }
     }
 }
+
+/// The guest memory, as seen by a vCPU
+///
+/// The memory is accessed through the host mappings of the regions, so
+/// this only works for keeps with plain memory.
+pub struct Memory<'a, A: Arch, P: Personality> {
+    vm: &'a Vm<A, P>,
+    cr3: u64,
+}
+
+impl<'a, A: Arch, P: Personality> Memory<'a, A, P> {
+    /// Accesses the memory through the page tables at `cr3`
+    pub fn new(vm: &'a Vm<A, P>, cr3: u64) -> Self {
+        Self { vm, cr3 }
+    }
+
+    /// Finds the host address of a guest physical address
+    fn host(&self, addr: u64) -> Option<*mut u8> {
+        self.vm.regions.iter().find_map(|region| {
+            let guest = region.as_guest();
+            let offset = addr.checked_sub(guest.start.as_u64())?;
+
+            match offset < guest.count {
+                true => Some((region.as_virt().start + offset).as_mut_ptr()),
+                false => None,
+            }
+        })
+    }
+
+    /// Translates a guest virtual address with the page tables of the vCPU
+    pub fn translate(&self, addr: u64) -> Option<u64> {
+        let mut table = self.cr3 & ADDRESS;
+
+        for level in (0..4).rev() {
+            let shift = 12 + 9 * level;
+            let index = (addr >> shift) & 0x1FF;
+            let entry = unsafe { *(self.host(table + index * 8)? as *const u64) };
+
+            if entry & PRESENT == 0 {
+                return None;
+            }
+
+            // 1 GiB and 2 MiB pages end the walk early.
+            if level == 0 || (level < 3 && entry & HUGE != 0) {
+                let mask = (1 << shift) - 1;
+                return Some((entry & ADDRESS & !mask) | (addr & mask));
+            }
+
+            table = entry & ADDRESS;
+        }
+
+        None
+    }
+
+    /// Reads up to `len` bytes, stopping at the first unmapped one
+    pub fn read(&self, addr: u64, len: u64) -> Vec<u8> {
+        (addr..addr.saturating_add(len))
+            .map(|addr| self.translate(addr).and_then(|phys| self.host(phys)))
+            .take_while(Option::is_some)
+            .map(|host| unsafe { *host.unwrap() })
+            .collect()
+    }
+
+    /// Writes all `bytes` or, if any of them is unmapped, none of them
+    pub fn write(&self, addr: u64, bytes: &[u8]) -> bool {
+        let hosts: Option<Vec<_>> = (0..bytes.len() as u64)
+            .map(|i| {
+                self.translate(addr.checked_add(i)?)
+                    .and_then(|phys| self.host(phys))
+            })
+            .collect();
+
+        match hosts {
+            Some(hosts) => {
+                for (host, byte) in hosts.into_iter().zip(bytes) {
+                    unsafe { *host = *byte };
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -1,16 +1,21 @@
 // SPDX-License-Identifier: Apache-2.0
 
+mod backtrace;
 pub mod builder;
 mod cpu;
+mod dump;
+mod gdb;
 pub mod image;
 pub mod measure;
 mod mem;
 pub mod personality;
 
-use crate::backend::kvm::shim::MAX_SETUP_SIZE;
+use crate::backend::kvm::shim::{MAX_CPUS, MAX_SETUP_SIZE};
 use crate::backend::{Keep, Thread};
+use crate::binary::Symbols;
 
 use cpu::Cpu;
+use gdb::Gdb;
 use mem::Region;
 use personality::Personality;
 
@@ -19,16 +24,17 @@ pub use image::{x86::X86, Arch};
 pub use kvm_bindings::kvm_segment as KvmSegment;
 pub use kvm_bindings::kvm_userspace_memory_region as KvmUserspaceMemoryRegion;
 
-use anyhow::Result;
-use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
-use kvm_ioctls::{Kvm, VmFd};
-use lset::Span;
+use anyhow::{bail, Result};
+use kvm_bindings::{kvm_regs, KVM_MAX_CPUID_ENTRIES};
+use kvm_ioctls::{Kvm, VcpuFd, VmFd};
+use lset::{Line, Span};
 use mmarinus::{perms, Kind, Map};
 use primordial::Page;
 use x86_64::{PhysAddr, VirtAddr};
 
 use std::marker::PhantomData;
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
 pub struct Vm<A: Arch, P: Personality> {
@@ -38,8 +44,15 @@ pub struct Vm<A: Arch, P: Personality> {
     syscall_blocks: Span<VirtAddr, NonZeroUsize>,
     shim_entry: PhysAddr,
     shim_start: PhysAddr,
+    shim_symbols: Symbols,
+    code: Line<usize>,
+    code_symbols: Symbols,
     hv2gp: Box<Hv2GpFn>,
     arch: VirtAddr,
+    gdb: Option<PathBuf>,
+    core_dump: Option<PathBuf>,
+    cpus: usize,
+    parked: Vec<(usize, VcpuFd)>,
     _phantom: PhantomData<A>,
     _personality: PhantomData<P>,
 }
@@ -77,28 +90,71 @@ impl<A: Arch, P: Personality> Vm<A, P> {
 
 impl<P: 'static + Personality> Keep for RwLock<Vm<X86, P>> {
     fn add_thread(self: Arc<Self>) -> Result<Box<dyn Thread>> {
-        let keep = self.write().unwrap();
-        let id = 0;
-        let region_zero = &keep.regions[0];
-        let address_space = region_zero.as_virt();
-
-        let vcpu = keep.fd.create_vcpu(id as _)?;
-
-        let mut regs = vcpu.get_regs()?;
-        if id == 0 {
-            regs.rsi = keep.shim_start.as_u64();
-            regs.rdi = keep.syscall_blocks.start.as_u64() - address_space.start.as_u64();
-        } else {
-            unimplemented!()
+        let mut keep = self.write().unwrap();
+        if keep.cpus > 0 {
+            bail!("The shim starts the further vCPUs of the keep itself");
         }
 
-        vcpu.set_regs(&regs)?;
-        vcpu.set_cpuid2(&keep.kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?)?;
+        Ok(Box::new(add_cpu(&mut keep, self.clone(), None)?))
+    }
+}
+
+/// Creates the next vCPU of the keep
+///
+/// The first one boots the shim with the page tables of the initial image.
+/// The shim asks for the others with the `cr3` of its own page tables. The
+/// vCPU of a thread that exited is parked and started again before a new
+/// one is created.
+fn add_cpu<P: 'static + Personality>(
+    keep: &mut Vm<X86, P>,
+    this: Arc<RwLock<Vm<X86, P>>>,
+    cr3: Option<PhysAddr>,
+) -> Result<Cpu<X86, P>> {
+    // The gdb stub only controls the first vCPU.
+    if keep.cpus > 0 && keep.gdb.is_some() {
+        bail!("A keep debugged with gdb cannot start further vCPUs");
+    }
+
+    // A core dump only holds the registers of the failing vCPU, while the
+    // others may still be running.
+    if keep.cpus > 0 && keep.core_dump.is_some() {
+        bail!("A keep dumping core cannot start further vCPUs");
+    }
 
+    let (id, vcpu) = match keep.parked.pop() {
+        Some(parked) => parked,
+        None if keep.cpus >= MAX_CPUS => bail!("out of vCPUs"),
+        None => {
+            let id = keep.cpus;
+            let vcpu = keep.fd.create_vcpu(id as _)?;
+            keep.cpus += 1;
+            (id, vcpu)
+        }
+    };
+
+    let region_zero = &keep.regions[0];
+    let address_space = region_zero.as_virt();
+
+    // A parked vCPU starts over with the registers of a new one.
+    let regs = kvm_regs {
+        rsi: keep.shim_start.as_u64(),
+        rdi: keep.syscall_blocks.start.as_u64() - address_space.start.as_u64(),
+        rdx: id as _,
+        ..Default::default()
+    };
+
+    vcpu.set_regs(&regs)?;
+    vcpu.set_cpuid2(&keep.kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?)?;
+
+    let cr3 = cr3.unwrap_or_else(|| {
         let arch = unsafe { &*(keep.arch.as_ptr() as *const X86) };
-        let cr3 = (keep.hv2gp)(VirtAddr::from_ptr(&arch.pml4t), address_space.start);
+        (keep.hv2gp)(VirtAddr::from_ptr(&arch.pml4t), address_space.start)
+    });
 
-        let thread = Cpu::new(vcpu, self.clone(), keep.shim_entry, cr3)?;
-        Ok(Box::new(thread))
+    let mut thread = Cpu::new(id, vcpu, this, keep.shim_entry, cr3)?;
+    if let Some(path) = keep.gdb.as_ref() {
+        thread.debug(Gdb::listen(path)?, keep)?;
     }
+
+    Ok(thread)
 }
@@ -11,7 +11,13 @@ use super::KvmUserspaceMemoryRegion;
 /// events as needed.
 ///
 /// If a personality is not needed, pass in Unit.
-pub trait Personality {
+///
+/// The vCPUs of a keep run on threads of their own, so it has to be
+/// shareable between them.
+pub trait Personality: Send + Sync {
+    /// Whether the memory of the guest is encrypted, so the host cannot read it
+    const ENCRYPTED: bool = false;
+
     fn add_memory(_vm: &VmFd, _region: &KvmUserspaceMemoryRegion) {}
 }
 
@@ -1,5 +1,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
+#[cfg(feature = "backend-emulated")]
+pub mod emulated;
 #[cfg(feature = "backend-kvm")]
 pub mod kvm;
 #[cfg(feature = "backend-sev")]
@@ -7,16 +9,33 @@ pub mod sev;
 #[cfg(feature = "backend-sgx")]
 pub mod sgx;
 
+mod args;
+mod debug;
 mod probe;
+mod proxy;
+mod report;
+mod sanitize;
+mod signals;
+
+pub use args::Args;
+pub use debug::DebugOptions;
+pub use proxy::{Chain, Layer, Passthrough};
+pub use report::{Region, Report};
+pub use signals::forward_signals;
+
+pub(crate) use report::hex;
+pub(crate) use sanitize::Shadow;
 
 use crate::binary::Component;
-use crate::sallyport::Block;
+use crate::sallyport::{Block, Reply, Request};
 
 use std::path::Path;
 use std::sync::Arc;
 
 use anyhow::Result;
+use serde::Serialize;
 
+/// A keep technology that the loader knows how to deploy to
 pub trait Backend {
     /// The name of the backend
     fn name(&self) -> &'static str;
@@ -30,13 +49,20 @@ pub trait Backend {
     fn data(&self) -> Vec<Datum>;
 
     /// Create a keep instance on this backend
-    fn build(&self, code: Component, sock: Option<&Path>) -> Result<Arc<dyn Keep>>;
-
-    /// Create a keep instance on this backend, measure the keep
-    /// and output a json record for the specific backend
-    fn measure(&self, code: Component) -> Result<String>;
+    fn build(
+        &self,
+        code: Component,
+        args: &Args,
+        debug: &DebugOptions,
+        sock: Option<&Path>,
+    ) -> Result<Arc<dyn Keep>>;
+
+    /// Create a keep instance on this backend and measure the keep
+    fn measure(&self, code: Component, args: &Args) -> Result<Report>;
 }
 
+/// A single platform support test for a backend
+#[derive(Debug, Serialize)]
 pub struct Datum {
     /// The name of this datum.
     pub name: String,
@@ -51,19 +77,56 @@ pub struct Datum {
     pub mesg: Option<String>,
 }
 
+/// A keep instance created by a `Backend`
 pub trait Keep {
     /// Creates a new thread in the keep.
     fn add_thread(self: Arc<Self>) -> Result<Box<dyn Thread>>;
 }
 
+/// A thread of execution inside of a `Keep`
 pub trait Thread {
     /// Enters the keep.
     fn enter(&mut self) -> Result<Command>;
 }
 
+/// The reason a `Thread` returned from the keep
+///
+/// Later keeps may return for other reasons, so hosts have to handle
+/// unknown commands.
+#[non_exhaustive]
 pub enum Command<'a> {
+    /// The keep requests a syscall to be proxied to the host
+    ///
+    /// The `Block` is a checked copy of the one in the keep, which only the
+    /// host can write and which all pointers of the request point into. The
+    /// reply has to be written to it before the thread is entered again.
     #[allow(dead_code)]
     SysCall(&'a mut Block),
+
+    /// The keep handled the exit itself, just enter the thread again
     #[allow(dead_code)]
     Continue,
+
+    /// The keep started another thread, which the host has to enter, too
+    ///
+    /// The new thread runs in parallel to the others, so the host usually
+    /// enters it on an OS thread of its own.
+    #[allow(dead_code)]
+    Spawn(Box<dyn Thread + Send>),
+}
+
+/// A handler for the syscalls a keep proxies to the host
+///
+/// The host hands the request of every `Command::SysCall` to a proxy and
+/// writes its reply back to the `Block`. A proxy may execute the request on
+/// the host, emulate it or rewrite it before passing it on to another proxy.
+pub trait SyscallProxy {
+    /// Handles a request of the keep and returns the reply
+    ///
+    /// # Safety
+    ///
+    /// The request may be executed on the host, so its pointer arguments
+    /// must point to memory the syscall may access, usually into the checked
+    /// copy of the syscall block of a `Command::SysCall`.
+    unsafe fn proxy(&mut self, req: &Request) -> Result<Reply>;
 }
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Composable handling of the syscalls a keep proxies to the host
+
+use super::{signals, SyscallProxy};
+use crate::sallyport::{Reply, Request};
+
+use anyhow::Result;
+
+/// Executes every request as a syscall on the host
+///
+/// A blocking request fails with `EINTR` instead, while a signal forwarded
+/// to the keep is pending.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Passthrough;
+
+impl SyscallProxy for Passthrough {
+    unsafe fn proxy(&mut self, req: &Request) -> Result<Reply> {
+        if signals::interrupts(req) {
+            return Ok(Err(libc::EINTR).into());
+        }
+
+        Ok(req.syscall())
+    }
+}
+
+/// A stage of a `Chain`, which sees each request before the rest of the chain
+pub trait Layer {
+    /// Handles a request, usually by passing it on to `next`
+    ///
+    /// # Safety
+    ///
+    /// Like for `SyscallProxy::proxy`, the pointer arguments of the request
+    /// must point to memory the syscall may access.
+    unsafe fn handle(&mut self, req: &Request, next: &mut dyn SyscallProxy) -> Result<Reply>;
+}
+
+/// A `SyscallProxy` that hands each request through a list of layers
+///
+/// The layers see the requests in the order they were added. The last one
+/// passes the request on to the proxy the chain was created with:
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// use enarx_keepldr::{Chain, Passthrough, Policy, TraceFormat, Tracer};
+///
+/// let proxy = Chain::new(Passthrough)
+///     .layer(Tracer::new(std::io::stderr(), TraceFormat::Text))
+///     .layer(Policy::from_path("policy.toml")?);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Chain {
+    layers: Vec<Box<dyn Layer>>,
+    last: Box<dyn SyscallProxy>,
+}
+
+impl Chain {
+    /// Creates a chain without any layers
+    pub fn new(last: impl SyscallProxy + 'static) -> Self {
+        Self {
+            layers: Vec::new(),
+            last: Box::new(last),
+        }
+    }
+
+    /// Adds a layer after all existing ones
+    pub fn layer(mut self, layer: impl Layer + 'static) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+}
+
+impl SyscallProxy for Chain {
+    unsafe fn proxy(&mut self, req: &Request) -> Result<Reply> {
+        Rest(&mut self.layers, &mut *self.last).proxy(req)
+    }
+}
+
+/// The part of a `Chain` after a layer
+struct Rest<'a>(&'a mut [Box<dyn Layer>], &'a mut dyn SyscallProxy);
+
+impl SyscallProxy for Rest<'_> {
+    unsafe fn proxy(&mut self, req: &Request) -> Result<Reply> {
+        match self.0.split_first_mut() {
+            Some((layer, layers)) => layer.handle(req, &mut Rest(layers, &mut *self.1)),
+            None => self.1.proxy(req),
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The measurement report of a keep
+
+use crate::binary::Component;
+
+use anyhow::Result;
+use lset::Line;
+use openssl::hash::{Hasher, MessageDigest};
+use primordial::Page;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+/// The digest algorithms used for the shim and the payload
+const ALGORITHMS: &[(&str, fn() -> MessageDigest)] = &[
+    ("sha256", MessageDigest::sha256),
+    ("sha384", MessageDigest::sha384),
+    ("sha512", MessageDigest::sha512),
+];
+
+/// The measurement of a keep as output by `Backend::measure`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    /// The version of the report format (see `Report::VERSION`)
+    pub version: u32,
+
+    /// The name of the backend the keep was measured for
+    pub backend: String,
+
+    /// The hex digests of the initial keep by digest algorithm
+    pub digests: BTreeMap<String, String>,
+
+    /// The hex launch measurement, if the backend has one besides the digests
+    ///
+    /// Only offline SEV reports have one, because the launch measurement of
+    /// a keep on the hardware depends on the session of its launch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub launch: Option<String>,
+
+    /// The hex digests of the loadable segments of the shim by digest algorithm
+    pub shim: BTreeMap<String, String>,
+
+    /// The hex digests of the loadable segments of the payload by digest algorithm
+    pub code: BTreeMap<String, String>,
+
+    /// The memory regions of the keep by name
+    pub layout: BTreeMap<String, Region>,
+}
+
+/// A memory region of a keep
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Region {
+    /// The first address of the region
+    pub start: usize,
+
+    /// The address after the end of the region
+    pub end: usize,
+}
+
+impl From<Line<usize>> for Region {
+    fn from(line: Line<usize>) -> Self {
+        Self {
+            start: line.start,
+            end: line.end,
+        }
+    }
+}
+
+impl Report {
+    /// The current version of the report format
+    pub const VERSION: u32 = 1;
+
+    /// Creates a report for the given shim and payload, without any digests
+    /// of the keep itself
+    pub(crate) fn new(backend: &str, shim: &Component, code: &Component) -> Result<Self> {
+        Ok(Self {
+            version: Self::VERSION,
+            backend: backend.into(),
+            digests: BTreeMap::new(),
+            launch: None,
+            shim: digests(shim)?,
+            code: digests(code)?,
+            layout: BTreeMap::new(),
+        })
+    }
+}
+
+/// Encodes bytes as a lowercase hex string
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Calculates the digests of the loadable segments of a binary
+fn digests(component: &Component) -> Result<BTreeMap<String, String>> {
+    let mut digests = BTreeMap::new();
+
+    for (name, md) in ALGORITHMS {
+        let mut hasher = Hasher::new(md())?;
+
+        for segment in &component.segments {
+            for page in &segment.src {
+                let page = page as *const Page as *const u8;
+                let page = unsafe { std::slice::from_raw_parts(page, Page::size()) };
+                hasher.update(page)?;
+            }
+        }
+
+        digests.insert(name.to_string(), hex(&hasher.finish()?));
+    }
+
+    Ok(digests)
+}
@@ -0,0 +1,392 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host-side validation of proxied syscall requests
+//!
+//! The pointer arguments of a request are host addresses of buffers the
+//! shim has set up in its syscall block. A compromised or buggy shim could
+//! instead make the loader read or write anywhere in its own address space,
+//! so all buffers are checked to lie inside the block before a request is
+//! executed.
+//!
+//! Other vCPUs or threads of the keep can write to the block at any time,
+//! also between the check and the syscall. So the block is first copied to
+//! memory only the host can write, the request is checked there and all of
+//! its pointers, including those in `iovec` arrays, are moved into the copy.
+//! The request is executed from the copy, which is copied back afterwards.
+
+use crate::sallyport::{Block, Request};
+use crate::syscall::SYS_ENARX_GETATT;
+
+use libc::{c_int, EFAULT, EINVAL, ENOSYS, ENOTTY};
+use lset::{Line, Span};
+
+use std::mem::size_of;
+
+/// The block of the keep a request points into and its copy
+struct Region {
+    /// The addresses of the block of the keep
+    shared: Line<usize>,
+
+    /// The address of the copy of the block
+    copy: usize,
+}
+
+impl Region {
+    /// Checks that `len` bytes at `ptr` lie inside the block and returns
+    /// their address in the copy
+    ///
+    /// An empty buffer becomes NULL, so the host never touches the keep.
+    fn buf(&self, ptr: usize, len: usize) -> Result<usize, c_int> {
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let end = ptr.checked_add(len).ok_or(EFAULT)?;
+        if ptr < self.shared.start || end > self.shared.end {
+            return Err(EFAULT);
+        }
+
+        Ok(ptr - self.shared.start + self.copy)
+    }
+
+    /// Checks a buffer of `count` items of type `T`
+    fn array<T>(&self, ptr: usize, count: usize) -> Result<usize, c_int> {
+        let len = count.checked_mul(size_of::<T>()).ok_or(EFAULT)?;
+        self.buf(ptr, len)
+    }
+
+    /// Checks a buffer, which may also be NULL
+    fn nullable(&self, ptr: usize, len: usize) -> Result<usize, c_int> {
+        match ptr {
+            0 => Ok(0),
+            _ => self.buf(ptr, len),
+        }
+    }
+
+    /// Checks a value of type `T` and reads it from the copy
+    fn read<T: Copy>(&self, ptr: usize) -> Result<T, c_int> {
+        let ptr = self.array::<T>(ptr, 1)?;
+        Ok(unsafe { (ptr as *const T).read_unaligned() })
+    }
+
+    /// Checks a NUL-terminated string
+    fn cstr(&self, ptr: usize) -> Result<usize, c_int> {
+        if ptr < self.shared.start || ptr >= self.shared.end {
+            return Err(EFAULT);
+        }
+
+        let copy = ptr - self.shared.start + self.copy;
+        let len = self.shared.end - ptr;
+        let bytes = unsafe { std::slice::from_raw_parts(copy as *const u8, len) };
+        if !bytes.contains(&0) {
+            return Err(EFAULT);
+        }
+
+        Ok(copy)
+    }
+
+    /// Checks an array of `iovec` and all of the buffers it points to
+    ///
+    /// The `iovec` in the copy are changed to point into the copy, and the
+    /// offsets and original values of their pointers are added to `moved`.
+    fn iovec(
+        &self,
+        ptr: usize,
+        count: usize,
+        moved: &mut Vec<(usize, usize)>,
+    ) -> Result<usize, c_int> {
+        let array = self.array::<libc::iovec>(ptr, count)?;
+
+        for i in 0..count {
+            let at = (array + i * size_of::<libc::iovec>()) as *mut libc::iovec;
+            let mut iov = unsafe { at.read_unaligned() };
+
+            let base = iov.iov_base as usize;
+            iov.iov_base = self.buf(base, iov.iov_len)? as _;
+            unsafe { at.write_unaligned(iov) };
+
+            moved.push((at as usize - self.copy, base));
+        }
+
+        Ok(array)
+    }
+
+    /// Checks a socket address buffer, whose size is stored at `len`
+    ///
+    /// Both pointers may be NULL. Returns both pointers in the copy.
+    fn sockaddr(&self, ptr: usize, len: usize) -> Result<(usize, usize), c_int> {
+        if len == 0 {
+            return Ok((self.nullable(ptr, 0)?, 0));
+        }
+
+        let size: libc::socklen_t = self.read(len)?;
+        let len = self.array::<libc::socklen_t>(len, 1)?;
+        Ok((self.nullable(ptr, size as usize)?, len))
+    }
+}
+
+/// Checks that all buffers of a request lie inside `region`
+///
+/// Returns the request with all pointers moved into the copy, or the errno
+/// to reply with if the request must not be executed: `EFAULT` for a buffer
+/// outside of the block and `ENOSYS` for a syscall the loader does not know
+/// the arguments of.
+fn sanitize(
+    req: &Request,
+    region: &Region,
+    moved: &mut Vec<(usize, usize)>,
+) -> Result<Request, c_int> {
+    let num = usize::from(req.num) as i64;
+    let mut arg = [0usize; 7];
+    for (arg, reg) in arg.iter_mut().zip(req.arg.iter()) {
+        *arg = usize::from(*reg);
+    }
+
+    match num {
+        libc::SYS_read | libc::SYS_write => arg[1] = region.buf(arg[1], arg[2])?,
+        libc::SYS_readv | libc::SYS_writev => arg[1] = region.iovec(arg[1], arg[2], moved)?,
+        libc::SYS_pread64 | libc::SYS_pwrite64 => arg[1] = region.buf(arg[1], arg[2])?,
+        libc::SYS_getdents64 => arg[1] = region.buf(arg[1], arg[2])?,
+        libc::SYS_getcwd => arg[0] = region.buf(arg[0], arg[1])?,
+        libc::SYS_fstat => arg[1] = region.array::<libc::stat>(arg[1], 1)?,
+        libc::SYS_openat => arg[1] = region.cstr(arg[1])?,
+        libc::SYS_newfstatat => {
+            arg[1] = region.cstr(arg[1])?;
+            arg[2] = region.array::<libc::stat>(arg[2], 1)?;
+        }
+        libc::SYS_unlink | libc::SYS_mkdir => arg[0] = region.cstr(arg[0])?,
+        libc::SYS_pipe => arg[0] = region.array::<c_int>(arg[0], 2)?,
+        libc::SYS_poll => arg[0] = region.array::<libc::pollfd>(arg[0], arg[1])?,
+        libc::SYS_clock_gettime => arg[1] = region.array::<libc::timespec>(arg[1], 1)?,
+
+        libc::SYS_ioctl => match arg[1] as libc::Ioctl {
+            libc::FIONBIO => arg[2] = region.array::<c_int>(arg[2], 1)?,
+            libc::TIOCGWINSZ => arg[2] = region.array::<libc::winsize>(arg[2], 1)?,
+            _ => return Err(ENOTTY),
+        },
+
+        libc::SYS_fcntl => match arg[1] as c_int {
+            libc::F_GETFD | libc::F_SETFD | libc::F_GETFL | libc::F_SETFL => (),
+            _ => return Err(EINVAL),
+        },
+
+        libc::SYS_epoll_ctl => {
+            arg[3] = region.nullable(arg[3], size_of::<libc::epoll_event>())?;
+        }
+        libc::SYS_epoll_wait => {
+            let maxevents = (arg[2] as c_int).max(0) as usize;
+            arg[1] = region.array::<libc::epoll_event>(arg[1], maxevents)?;
+        }
+
+        libc::SYS_bind | libc::SYS_connect => arg[1] = region.buf(arg[1], arg[2])?,
+        libc::SYS_accept | libc::SYS_accept4 | libc::SYS_getsockname => {
+            let (addr, len) = region.sockaddr(arg[1], arg[2])?;
+            arg[1] = addr;
+            arg[2] = len;
+        }
+        libc::SYS_setsockopt => arg[3] = region.buf(arg[3], arg[4])?,
+
+        libc::SYS_recvfrom => {
+            arg[1] = region.buf(arg[1], arg[2])?;
+            let (addr, len) = region.sockaddr(arg[4], arg[5])?;
+            arg[4] = addr;
+            arg[5] = len;
+        }
+
+        libc::SYS_sendto => {
+            arg[1] = region.buf(arg[1], arg[2])?;
+            arg[4] = region.nullable(arg[4], arg[5])?;
+        }
+
+        SYS_ENARX_GETATT => {
+            arg[0] = region.nullable(arg[0], arg[1])?;
+            arg[2] = region.buf(arg[2], arg[3])?;
+        }
+
+        libc::SYS_close
+        | libc::SYS_dup
+        | libc::SYS_dup2
+        | libc::SYS_dup3
+        | libc::SYS_lseek
+        | libc::SYS_exit
+        | libc::SYS_exit_group
+        | libc::SYS_sched_yield
+        | libc::SYS_socket
+        | libc::SYS_listen
+        | libc::SYS_eventfd2
+        | libc::SYS_epoll_create1 => (),
+
+        _ => return Err(ENOSYS),
+    }
+
+    let mut req = *req;
+    for (reg, arg) in req.arg.iter_mut().zip(arg.iter()) {
+        *reg = (*arg).into();
+    }
+
+    Ok(req)
+}
+
+/// A copy of the syscall block of a keep, which only the host can write
+///
+/// Each thread of a keep copies its block to a shadow of its own, before
+/// the request in it is handled, and back again before it enters the keep
+/// the next time.
+pub struct Shadow {
+    block: Box<Block>,
+
+    /// The request as copied from the keep, before its pointers were moved
+    req: Request,
+
+    /// The offsets and original values of the pointers moved in the copy
+    moved: Vec<(usize, usize)>,
+
+    /// The address of the block the copy was taken from, until it is restored
+    shared: Option<usize>,
+}
+
+impl Default for Shadow {
+    fn default() -> Self {
+        Self {
+            block: Box::new(Block::default()),
+            req: Request::default(),
+            moved: Vec::new(),
+            shared: None,
+        }
+    }
+}
+
+impl Shadow {
+    /// Copies the block of the keep at `shared` and checks its request
+    ///
+    /// Returns the copy to execute the request from, whose pointers all
+    /// point into the copy. Otherwise, returns the errno to reply with in
+    /// the block of the keep.
+    ///
+    /// # Safety
+    ///
+    /// `shared` must point to a block the host can read and write, even
+    /// though the keep may change it at any time.
+    pub unsafe fn copy(&mut self, shared: *const Block) -> Result<&mut Block, c_int> {
+        self.restore();
+
+        std::ptr::copy_nonoverlapping(shared, &mut *self.block, 1);
+        self.req = self.block.msg.req;
+        self.moved.clear();
+
+        let region = Region {
+            shared: Line::from(Span {
+                start: shared as usize,
+                count: size_of::<Block>(),
+            }),
+            copy: &*self.block as *const Block as usize,
+        };
+
+        self.block.msg.req = sanitize(&self.req, &region, &mut self.moved)?;
+        self.shared = Some(shared as usize);
+        Ok(&mut self.block)
+    }
+
+    /// Copies the reply and all buffers back to the block of the keep
+    ///
+    /// The pointers moved into the copy get their original values again, so
+    /// the keep never learns about the memory of the host. Does nothing if
+    /// the last copy was restored already.
+    pub fn restore(&mut self) {
+        let shared = match self.shared.take() {
+            Some(shared) => shared as *mut Block,
+            None => return,
+        };
+
+        let rep = unsafe { self.block.msg.rep };
+        let copy = &mut *self.block as *mut Block as usize;
+        for (offset, value) in self.moved.drain(..) {
+            unsafe { ((copy + offset) as *mut usize).write_unaligned(value) };
+        }
+
+        self.block.msg.req = self.req;
+        self.block.msg.rep = rep;
+        unsafe { std::ptr::copy_nonoverlapping(&*self.block, shared, 1) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request;
+
+    use primordial::Register;
+
+    use std::os::unix::io::RawFd;
+
+    /// Places `bytes` at `offset` in the buffer of `block` and returns their address
+    fn place(block: &mut Block, offset: usize, bytes: &[u8]) -> usize {
+        let (c, _) = block.cursor().alloc::<u8>(offset).unwrap();
+        let (_, dst) = c.copy_from_slice(bytes).unwrap();
+        dst.as_ptr() as usize
+    }
+
+    fn pipe() -> (RawFd, RawFd) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        (fds[0], fds[1])
+    }
+
+    #[test]
+    fn outside() {
+        let mut shared = Box::new(Block::default());
+        let outside = [0u8; 16];
+
+        shared.msg.req = request!(libc::SYS_write => 1, outside.as_ptr() as usize, 16);
+        let mut shadow = Shadow::default();
+        let result = unsafe { shadow.copy(&*shared) };
+        assert_eq!(result.err(), Some(EFAULT));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn mutated_after_check() {
+        let (rd, wr) = pipe();
+        let mut shared = Box::new(Block::default());
+
+        let data = place(&mut shared, 0, b"hello");
+        let iov = [libc::iovec {
+            iov_base: data as _,
+            iov_len: 5,
+        }];
+        let bytes = unsafe {
+            std::slice::from_raw_parts(iov.as_ptr() as *const u8, size_of::<libc::iovec>())
+        };
+        let iov = place(&mut shared, 64, bytes) as *mut libc::iovec;
+        shared.msg.req = request!(libc::SYS_writev => wr, iov as usize, 1);
+
+        let mut shadow = Shadow::default();
+        let req = unsafe { shadow.copy(&*shared) }.unwrap().msg.req;
+
+        // Another thread of the keep points the request and its iovec
+        // outside of the block and makes them larger, after the check.
+        let outside = [b'x'; 4096];
+        let evil = libc::iovec {
+            iov_base: outside.as_ptr() as _,
+            iov_len: outside.len(),
+        };
+        unsafe { iov.write_unaligned(evil) };
+        shared.msg.req = request!(libc::SYS_writev => wr, outside.as_ptr() as usize, 1);
+
+        let rep = unsafe { req.syscall() };
+        let mut buf = [0u8; 16];
+        let len = unsafe { libc::read(rd, buf.as_mut_ptr() as _, buf.len()) };
+        assert_eq!(&buf[..len as usize], b"hello");
+
+        // The keep gets the reply, but not the addresses of the copy.
+        shadow.block.msg.rep = rep;
+        shadow.restore();
+        assert_eq!(unsafe { shared.msg.rep }, rep);
+        assert_eq!(unsafe { iov.read_unaligned() }.iov_base as usize, data);
+
+        unsafe {
+            libc::close(rd);
+            libc::close(wr);
+        }
+    }
+}
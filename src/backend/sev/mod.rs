@@ -2,18 +2,17 @@
 
 mod builder;
 mod ioctl;
+pub mod offline;
 mod personality;
 mod runtime;
 mod unattested_launch;
 
-use crate::backend::kvm::Builder;
-use crate::backend::kvm::SHIM;
-use crate::backend::kvm::X86;
+use crate::backend::kvm::{self, Builder, SHIM, X86};
 use crate::backend::probe::x86_64::{CpuId, Vendor};
-use crate::backend::{self, Datum, Keep};
+use crate::backend::{self, Args, Datum, DebugOptions, Keep, Report};
 use crate::binary::Component;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use std::arch::x86_64::__cpuid_count;
 use std::fs::OpenOptions;
@@ -246,30 +245,49 @@ impl backend::Backend for Backend {
         data
     }
 
-    fn build(&self, code: Component, sock: Option<&Path>) -> Result<Arc<dyn Keep>> {
+    fn build(
+        &self,
+        code: Component,
+        args: &Args,
+        debug: &DebugOptions,
+        sock: Option<&Path>,
+    ) -> Result<Arc<dyn Keep>> {
+        // The memory of the keep is encrypted, so gdb could not read it.
+        if debug.gdb.is_some() {
+            bail!("Keep backend 'sev' cannot be debugged with gdb.");
+        }
+
+        // Nor could it be dumped.
+        if debug.core_dump.is_some() {
+            bail!("Keep backend 'sev' cannot dump core.");
+        }
+
         let shim = Component::from_bytes(SHIM)?;
         let sock = attestation_bridge(sock)?;
 
-        let vm = Builder::new(shim, code, builder::Sev::new(sock))
+        let vm = Builder::new(shim, code, args.clone(), builder::Sev::new(sock))
             .build::<X86, personality::Sev>()?
             .vm();
 
         Ok(Arc::new(RwLock::new(vm)))
     }
 
-    fn measure(&self, code: Component) -> Result<String> {
+    fn measure(&self, code: Component, args: &Args) -> Result<Report> {
         let shim = Component::from_bytes(SHIM)?;
+        let report = Report::new("sev", &shim, &code)?;
         let sock = attestation_bridge(None)?;
 
-        let digest = Builder::new(shim, code, builder::Sev::new(sock))
-            .build::<X86, ()>()?
-            .measurement();
-
-        let json = format!(
-            r#"{{ "backend": "sev", "{}": {:?} }}"#,
-            digest.kind, digest.digest
-        );
-        Ok(json)
+        let built = Builder::new(shim, code, args.clone(), builder::Sev::new(sock))
+            .report()
+            .build::<X86, ()>()?;
+
+        // The launch measurement is left out: it depends on the session of
+        // this launch, which no verifier could know in advance.
+        Ok(kvm::complete(
+            report,
+            built.measurements(),
+            &built.boot_info(),
+        ))
     }
 }
 
@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Software calculation of the SEV launch measurement
+//!
+//! The initial address space of a SEV keep is rebuilt exactly as the
+//! backend would build it, but without KVM or any SEV hardware. This allows
+//! verifiers to know the expected launch measurement ahead of deployment.
+
+use crate::backend::kvm::{self, measure, Builder, Hv2GpFn, SHIM, X86};
+use crate::backend::{hex, Args, Report};
+use crate::binary::Component;
+
+use anyhow::Result;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use x86_64::PhysAddr;
+
+/// The context byte that prefixes the measured data (see the SEV API
+/// specification of `LAUNCH_MEASURE`)
+const MEASURE_CONTEXT: u8 = 0x04;
+
+/// The parameters of the SEV launch the measurement depends on
+#[derive(Clone, Debug)]
+pub struct Launch {
+    /// The guest policy
+    pub policy: u32,
+
+    /// The firmware API major version, API minor version and build ID
+    pub build: (u8, u8, u8),
+
+    /// The location of the C-bit in guest physical addresses
+    pub c_bit: u32,
+
+    /// The transport integrity key of the launch session
+    pub tik: [u8; 16],
+
+    /// The nonce the firmware returned along with the measurement
+    pub nonce: [u8; 16],
+}
+
+/// Loads the keep in software, encrypting the page tables like SEV hardware
+struct Offline(u32);
+
+impl kvm::Hook for Offline {
+    fn preferred_digest() -> measure::Kind {
+        measure::Kind::Sha256
+    }
+
+    fn hv2gp(&self) -> Box<Hv2GpFn> {
+        let c_bit_loc = self.0;
+
+        Box::new(move |target, start| {
+            PhysAddr::new((target.as_u64() - start.as_u64()) | 1 << c_bit_loc)
+        })
+    }
+}
+
+/// Calculates the launch digest and the launch measurement of a SEV keep
+pub fn measure(code: Component, args: &Args, launch: &Launch) -> Result<Report> {
+    let shim = Component::from_bytes(SHIM)?;
+    let report = Report::new("sev", &shim, &code)?;
+
+    let (digests, boot_info) =
+        Builder::new(shim, code, args.clone(), Offline(launch.c_bit)).measure::<X86>()?;
+
+    // The preferred digest comes first, which is the one the firmware takes.
+    let measurement = launch_measurement(launch, &digests[0].digest)?;
+
+    let mut report = kvm::complete(report, &digests, &boot_info);
+    report.launch = Some(hex(&measurement));
+    Ok(report)
+}
+
+/// Calculates the launch measurement of the launch digest `digest`
+fn launch_measurement(launch: &Launch, digest: &[u8]) -> Result<Vec<u8>> {
+    let (major, minor, build) = launch.build;
+    let key = PKey::hmac(&launch.tik)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(&[MEASURE_CONTEXT, major, minor, build])?;
+    signer.update(&launch.policy.to_le_bytes())?;
+    signer.update(digest)?;
+    signer.update(&launch.nonce)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answer() {
+        let mut tik = [0; 16];
+        let mut nonce = [0; 16];
+        for (i, (t, n)) in tik.iter_mut().zip(nonce.iter_mut()).enumerate() {
+            *t = i as u8;
+            *n = 0x10 + i as u8;
+        }
+
+        let launch = Launch {
+            policy: 1,
+            build: (0, 24, 15),
+            c_bit: 47,
+            tik,
+            nonce,
+        };
+
+        let digest = openssl::sha::sha256(b"enarx");
+        let measurement = launch_measurement(&launch, &digest).unwrap();
+
+        // HMAC-SHA256(tik, 04 00 18 0f || 01 00 00 00 || digest || nonce)
+        assert_eq!(
+            measurement,
+            [
+                0x0d, 0x23, 0x99, 0x39, 0xe0, 0xff, 0xda, 0xe7, 0x9f, 0x4a, 0x68, 0xff, 0x30, 0xe7,
+                0xb0, 0x33, 0x9c, 0xd8, 0x5e, 0x0e, 0xf9, 0x61, 0x98, 0x6c, 0x2d, 0xb7, 0x31, 0x0f,
+                0x5b, 0xcc, 0x14, 0x2d,
+            ]
+        );
+    }
+}
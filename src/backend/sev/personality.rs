@@ -9,6 +9,8 @@ use super::runtime::mark_encrypted;
 pub struct Sev;
 
 impl Personality for Sev {
+    const ENCRYPTED: bool = true;
+
     fn add_memory(vm: &VmFd, region: &KvmUserspaceMemoryRegion) {
         mark_encrypted(vm, region).expect("SEV memory pinning failed");
     }
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A software model of how the SGX instructions calculate MRENCLAVE
+//!
+//! See the descriptions of ECREATE, EADD and EEXTEND in the Intel SDM.
+
+use openssl::sha::Sha256;
+use primordial::Page;
+use sgx::enclave::Segment;
+use sgx::types::page::SecInfo;
+
+use std::mem::size_of;
+
+/// The number of bytes measured by a single EEXTEND
+const CHUNK: usize = 256;
+
+/// The number of bytes of a SECINFO measured by EADD
+const SECINFO: usize = 48;
+
+/// Calculates MRENCLAVE like the SGX instructions that build an enclave
+pub struct Hasher(Sha256);
+
+impl Hasher {
+    /// Starts the measurement like ECREATE
+    pub fn new(size: usize, ssa_frame_pages: u32) -> Self {
+        let mut block = [0u8; 64];
+        block[..8].copy_from_slice(b"ECREATE\0");
+        block[8..12].copy_from_slice(&ssa_frame_pages.to_le_bytes());
+        block[12..20].copy_from_slice(&(size as u64).to_le_bytes());
+
+        let mut sha = Sha256::new();
+        sha.update(&block);
+        Self(sha)
+    }
+
+    /// Measures all pages of a segment like EADD followed by EEXTEND
+    ///
+    /// The `base` is the start address of the enclave.
+    pub fn load(&mut self, base: usize, segment: &Segment) {
+        // The SECINFO is passed to the hardware as is, so it has its layout.
+        assert_eq!(size_of::<SecInfo>(), 64);
+        let si = &segment.si as *const SecInfo as *const u8;
+        let si = unsafe { std::slice::from_raw_parts(si, SECINFO) };
+
+        for (i, page) in segment.src.iter().enumerate() {
+            let offset = (segment.dst + i * Page::size() - base) as u64;
+
+            let mut block = [0u8; 64];
+            block[..8].copy_from_slice(b"EADD\0\0\0\0");
+            block[8..16].copy_from_slice(&offset.to_le_bytes());
+            block[16..].copy_from_slice(si);
+            self.0.update(&block);
+
+            let page = page as *const Page as *const u8;
+            let bytes = unsafe { std::slice::from_raw_parts(page, Page::size()) };
+            for (j, chunk) in bytes.chunks(CHUNK).enumerate() {
+                let mut block = [0u8; 64];
+                block[..8].copy_from_slice(b"EEXTEND\0");
+                block[8..16].copy_from_slice(&(offset + (j * CHUNK) as u64).to_le_bytes());
+                self.0.update(&block);
+                self.0.update(chunk);
+            }
+        }
+    }
+
+    /// Finishes the measurement like EINIT and returns MRENCLAVE
+    pub fn finish(self) -> [u8; 32] {
+        self.0.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::hex;
+    use sgx::types::page::Flags;
+
+    #[test]
+    fn known_answer() {
+        let base = 0x10000;
+        let segment = Segment {
+            si: SecInfo::reg(Flags::R | Flags::W),
+            dst: base + Page::size(),
+            src: vec![Page::copy([0xaau8; 64]), Page::default()],
+        };
+
+        let mut hasher = Hasher::new(0x8000, 1);
+        hasher.load(base, &segment);
+
+        // Calculated independently from the ECREATE, EADD and EEXTEND layouts in the SDM
+        assert_eq!(
+            hex(&hasher.finish()),
+            "4625ee7742e88dfdb025f982d5616b89e8394a5bb4ba02f4b11469f10c16f689"
+        );
+    }
+}
@@ -1,12 +1,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::backend::sgx::attestation::get_attestation;
-use crate::backend::{Command, Datum, Keep};
+use crate::backend::{hex, signals, Args, Command, Datum, DebugOptions, Keep, Report, Shadow};
 use crate::binary::Component;
 use crate::sallyport;
-use crate::syscall::{SYS_ENARX_CPUID, SYS_ENARX_ERESUME, SYS_ENARX_GETATT};
+use crate::syscall::{
+    SYS_ENARX_CPUID, SYS_ENARX_ERESUME, SYS_ENARX_GETATT, SYS_ENARX_SIGNALS, SYS_ENARX_SPAWN,
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use lset::Span;
 use primordial::Page;
 use sgx::enclave::{Builder, Enclave, Entry, Registers, Segment};
@@ -23,8 +25,18 @@ use std::sync::{Arc, RwLock};
 
 mod attestation;
 mod data;
+mod hasher;
 mod shim;
 
+use hasher::Hasher;
+use shim::{Layout, MAX_THREADS, SIZE};
+
+/// The size of a single SSA frame in pages
+const SSA_FRAME_PAGES: u32 = 1;
+
+/// The number of SSA frames of each thread
+const SSA_FRAMES: usize = 2;
+
 const SHIM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/bin/shim-sgx"));
 
 impl From<crate::binary::Segment> for Segment {
@@ -73,93 +85,215 @@ impl crate::backend::Backend for Backend {
     }
 
     /// Create a keep instance on this backend
-    fn build(&self, mut code: Component, _sock: Option<&Path>) -> Result<Arc<dyn Keep>> {
-        let mut shim = Component::from_bytes(SHIM)?;
+    fn build(
+        &self,
+        code: Component,
+        args: &Args,
+        _debug: &DebugOptions,
+        _sock: Option<&Path>,
+    ) -> Result<Arc<dyn Keep>> {
+        let shim = Component::from_bytes(SHIM)?;
+        let (layout, segments) = load(shim, code, args)?;
+
+        // Initiate the enclave building process.
+        let mut builder = Builder::new(layout.enclave).expect("Unable to create builder");
+        builder.load(&segments)?;
+        Ok(builder.build()?)
+    }
 
-        // Calculate the memory layout for the enclave.
-        let layout = crate::backend::sgx::shim::Layout::calculate(shim.region(), code.region());
+    /// Calculate MRENCLAVE in software, without any SGX hardware
+    fn measure(&self, code: Component, args: &Args) -> Result<Report> {
+        let shim = Component::from_bytes(SHIM)?;
+        let mut report = Report::new("sgx", &shim, &code)?;
 
-        // Relocate the shim binary.
-        shim.entry += layout.shim.start;
-        for seg in shim.segments.iter_mut() {
-            seg.dst += layout.shim.start;
-        }
+        let (layout, segments) = load(shim, code, args)?;
 
-        // Relocate the code binary.
-        code.entry += layout.code.start;
-        for seg in code.segments.iter_mut() {
-            seg.dst += layout.code.start;
+        let mut hasher = Hasher::new(Span::from(layout.enclave).count, SSA_FRAME_PAGES);
+        for segment in &segments {
+            hasher.load(layout.enclave.start, segment);
         }
 
-        // Create SSAs and TCS.
-        let ssas = vec![Page::default(); 2];
+        let regions = [
+            ("enclave", layout.enclave),
+            ("prefix", layout.prefix),
+            ("code", layout.code),
+            ("args", layout.args),
+            ("manifest", layout.manifest),
+            ("heap", layout.heap),
+            ("stack", layout.stack),
+            ("shim", layout.shim),
+        ];
+
+        report
+            .digests
+            .insert("sha256".into(), hex(&hasher.finish()));
+        report.layout = regions
+            .iter()
+            .map(|(name, line)| (name.to_string(), (*line).into()))
+            .collect();
+        Ok(report)
+    }
+}
+
+/// Lays out the enclave and assembles all of its segments in loading order
+fn load(mut shim: Component, mut code: Component, args: &Args) -> Result<(Layout, Vec<Segment>)> {
+    let argv = args.encode()?;
+
+    // Calculate the memory layout for the enclave.
+    let manifest = args.manifest();
+    let threads = args.threads();
+    if threads.map_or(false, |n| n == 0 || n > MAX_THREADS) {
+        bail!("SGX keeps run 1 to {} threads", MAX_THREADS);
+    }
+
+    // Bounded by the enclave, the sizes cannot overflow the layout.
+    let (heap, stack) = (args.heap(), args.stack());
+    if heap.map_or(false, |n| n > SIZE) || stack.map_or(false, |n| n > SIZE) {
+        bail!(
+            "The heap and stack of SGX keeps must fit into the {} byte enclave",
+            SIZE
+        );
+    }
+
+    let mut layout = Layout::calculate(
+        shim.region(),
+        code.region(),
+        argv.len(),
+        manifest.len(),
+        heap,
+        stack,
+        threads,
+    );
+    if layout.heap.end > layout.stack.start {
+        bail!("The heap and the stacks of all threads do not fit into the SGX enclave");
+    }
+
+    layout.argc = args.argv.len();
+    layout.envc = args.envp.len();
+    layout.tmpfs = args.tmpfs()?;
+    layout.sealed = args.sealed()?;
+
+    // Relocate the shim binary.
+    shim.entry += layout.shim.start;
+    for seg in shim.segments.iter_mut() {
+        seg.dst += layout.shim.start;
+    }
+
+    // Relocate the code binary.
+    code.entry += layout.code.start;
+    for seg in code.segments.iter_mut() {
+        seg.dst += layout.code.start;
+    }
+
+    // Each thread has a TCS followed by its copy of the layout and its SSAs,
+    // and a stack of its own.
+    let mut segments = Vec::new();
+    for index in 0..layout.threads {
+        let thread = layout.thread(index);
+        let dst = layout.tcs(index);
+
+        let ssas = vec![Page::default(); SSA_FRAMES];
         let tcs = Tcs::new(
             shim.entry - layout.enclave.start,
-            Page::size() * 2, // SSAs after Layout (see below)
+            dst + Page::size() * 2 - layout.enclave.start, // SSAs after Layout (see below)
             ssas.len() as _,
         );
 
-        let internal = vec![
-            // TCS
-            Segment {
-                si: SecInfo::tcs(),
-                dst: layout.prefix.start,
-                src: vec![Page::copy(tcs)],
-            },
-            // Layout
-            Segment {
-                si: SecInfo::reg(Flags::R),
-                dst: layout.prefix.start + Page::size(),
-                src: vec![Page::copy(layout)],
-            },
-            // SSAs
-            Segment {
-                si: SecInfo::reg(Flags::R | Flags::W),
-                dst: layout.prefix.start + Page::size() * 2,
-                src: ssas,
-            },
-            // Heap
-            Segment {
-                si: SecInfo::reg(Flags::R | Flags::W | Flags::X),
-                dst: layout.heap.start,
-                src: vec![Page::default(); Span::from(layout.heap).count / Page::size()],
-            },
-            // Stack
-            Segment {
-                si: SecInfo::reg(Flags::R | Flags::W),
-                dst: layout.stack.start,
-                src: vec![Page::default(); Span::from(layout.stack).count / Page::size()],
-            },
-        ];
-
-        let shim_segs: Vec<_> = shim.segments.into_iter().map(Segment::from).collect();
-        let code_segs: Vec<_> = code.segments.into_iter().map(Segment::from).collect();
-
-        // Initiate the enclave building process.
-        let mut builder = Builder::new(layout.enclave).expect("Unable to create builder");
-        builder.load(&internal)?;
-        builder.load(&shim_segs)?;
-        builder.load(&code_segs)?;
-        Ok(builder.build()?)
+        // TCS
+        segments.push(Segment {
+            si: SecInfo::tcs(),
+            dst,
+            src: vec![Page::copy(tcs)],
+        });
+
+        // Layout
+        segments.push(Segment {
+            si: SecInfo::reg(Flags::R),
+            dst: dst + Page::size(),
+            src: vec![Page::copy(thread)],
+        });
+
+        // SSAs
+        segments.push(Segment {
+            si: SecInfo::reg(Flags::R | Flags::W),
+            dst: dst + Page::size() * 2,
+            src: ssas,
+        });
+
+        // Stack
+        segments.push(Segment {
+            si: SecInfo::reg(Flags::R | Flags::W),
+            dst: thread.stack.start,
+            src: vec![Page::default(); Span::from(thread.stack).count / Page::size()],
+        });
     }
 
-    fn measure(&self, mut _code: Component) -> Result<String> {
-        unimplemented!()
+    // Heap
+    segments.push(Segment {
+        si: SecInfo::reg(Flags::R | Flags::W | Flags::X),
+        dst: layout.heap.start,
+        src: vec![Page::default(); Span::from(layout.heap).count / Page::size()],
+    });
+
+    // Arguments and environment, and the manifest
+    for (dst, bytes) in &[
+        (layout.args.start, &argv[..]),
+        (layout.manifest.start, manifest),
+    ] {
+        if !bytes.is_empty() {
+            let mut src = vec![Page::default(); (bytes.len() + Page::size() - 1) / Page::size()];
+            unsafe { src.align_to_mut::<u8>() }.1[..bytes.len()].copy_from_slice(bytes);
+
+            segments.push(Segment {
+                si: SecInfo::reg(Flags::R),
+                dst: *dst,
+                src,
+            });
+        }
     }
+
+    segments.extend(shim.segments.into_iter().map(Segment::from));
+    segments.extend(code.segments.into_iter().map(Segment::from));
+
+    Ok((layout, segments))
 }
 
 impl super::Keep for RwLock<Enclave> {
     fn add_thread(self: Arc<Self>) -> Result<Box<dyn crate::backend::Thread>> {
-        Ok(Box::new(Thread {
-            thread: sgx::enclave::Thread::new(self).ok_or_else(|| anyhow!("out of threads"))?,
-            block: Default::default(),
-        }))
+        Ok(Box::new(Thread::new(self)?))
     }
 }
 
 struct Thread {
+    keep: Arc<RwLock<Enclave>>,
     thread: sgx::enclave::Thread,
     block: sallyport::Block,
+    shadow: Shadow,
+}
+
+// SAFETY: `sgx::enclave::Thread` is not `Send` because of the raw pointer
+// to its TCS, but a TCS is not bound to the OS thread that took it. The CPU
+// only refuses to enter one TCS on two logical processors at once. `enter`
+// takes `&mut self`, so the one `Thread` owning a TCS enters it from a
+// single OS thread at a time. The enclave only writes the `Block` while its
+// `Thread` is inside `enter`, and the `Block` and the `Shadow` move with the
+// `Thread` that owns them. A TCS is taken and returned through the
+// `RwLock` of the `Enclave`.
+unsafe impl Send for Thread {}
+
+impl Thread {
+    /// Takes the next free TCS of the enclave
+    fn new(keep: Arc<RwLock<Enclave>>) -> Result<Self> {
+        let thread =
+            sgx::enclave::Thread::new(keep.clone()).ok_or_else(|| anyhow!("out of threads"))?;
+
+        Ok(Self {
+            keep,
+            thread,
+            block: Default::default(),
+            shadow: Shadow::default(),
+        })
+    }
 }
 
 impl super::Thread for Thread {
@@ -167,6 +301,8 @@ impl super::Thread for Thread {
         let mut registers = Registers::default();
         let mut how = Entry::Enter;
 
+        self.shadow.restore();
+
         // The main loop event handles different types of enclave exits and
         // re-enters the enclave with specific parameters.
         //
@@ -174,6 +310,7 @@ impl super::Thread for Thread {
         //      that a syscall should be performed. Execution continues in
         //      the enclave with EENTER[CSSA = 1]. The syscall
         //      is proxied and potentially passed back out to the host.
+        //      Those with a divide error raise SIGFPE in the enclave.
         //
         //   2. OK with a syscall number other than SYS_ERESUME indicates the syscall
         //      to be performed. The syscall is performed here and enclave
@@ -184,11 +321,23 @@ impl super::Thread for Thread {
         //      and normal enclave execution should resume
         //      with ERESUME[CSSA = 0].
         //
-        //   4. Asynchronous exits other than invalid opcode will panic.
+        //   4. OK with a syscall number of SYS_ENARX_SPAWN asks for another
+        //      thread of the enclave. The next free TCS is returned to the
+        //      host to enter, and enclave execution resumes with
+        //      EENTER[CSSA = 1].
+        //
+        //   5. OK with a syscall number of SYS_ENARX_SIGNALS fetches the
+        //      signals the host forwards to the enclave. Enclave execution
+        //      resumes with EENTER[CSSA = 1].
+        //
+        //   6. Asynchronous exits other than invalid opcode or divide error
+        //      will panic.
         loop {
             registers.rdx = (&mut self.block).into();
             how = match self.thread.enter(how, &mut registers) {
-                Err(ei) if ei.trap == Exception::InvalidOpcode => Entry::Enter,
+                Err(ei) if matches!(ei.trap, Exception::InvalidOpcode | Exception::DivideError) => {
+                    Entry::Enter
+                }
                 Ok(_) => match unsafe { self.block.msg.req }.num.into() {
                     SYS_ENARX_CPUID => unsafe {
                         let cpuid = core::arch::x86_64::__cpuid_count(
@@ -203,22 +352,46 @@ impl super::Thread for Thread {
 
                         Entry::Enter
                     },
-                    SYS_ENARX_GETATT => {
-                        let result = unsafe {
-                            get_attestation(
-                                self.block.msg.req.arg[0].into(),
-                                self.block.msg.req.arg[1].into(),
-                                self.block.msg.req.arg[2].into(),
-                                self.block.msg.req.arg[3].into(),
-                            )?
-                        };
-
-                        self.block.msg.rep = Ok([result.into(), 0.into()]).into();
-
+                    SYS_ENARX_ERESUME => Entry::Resume,
+                    SYS_ENARX_SIGNALS => {
+                        let set = signals::take() as usize;
+                        self.block.msg.rep = Ok([set.into(), 0.into()]).into();
                         Entry::Enter
                     }
-                    SYS_ENARX_ERESUME => Entry::Resume,
-                    _ => return Ok(Command::SysCall(&mut self.block)),
+                    SYS_ENARX_SPAWN => match Thread::new(self.keep.clone()) {
+                        Ok(thread) => {
+                            self.block.msg.rep = Ok([0.into(), 0.into()]).into();
+                            return Ok(Command::Spawn(Box::new(thread)));
+                        }
+                        Err(_) => {
+                            self.block.msg.rep = Err(libc::EAGAIN).into();
+                            Entry::Enter
+                        }
+                    },
+                    // The enclave may change the block at any time, so the host
+                    // only works on a copy of it.
+                    num => match unsafe { self.shadow.copy(&self.block) } {
+                        Err(errno) => {
+                            self.block.msg.rep = Err(errno).into();
+                            Entry::Enter
+                        }
+                        Ok(block) if num == SYS_ENARX_GETATT => {
+                            let result = unsafe {
+                                get_attestation(
+                                    block.msg.req.arg[0].into(),
+                                    block.msg.req.arg[1].into(),
+                                    block.msg.req.arg[2].into(),
+                                    block.msg.req.arg[3].into(),
+                                )?
+                            };
+
+                            block.msg.rep = Ok([result.into(), 0.into()]).into();
+                            self.shadow.restore();
+
+                            Entry::Enter
+                        }
+                        Ok(block) => return Ok(Command::SysCall(block)),
+                    },
                 },
                 e => panic!("Unexpected AEX: {:?}", e),
             }
@@ -2,12 +2,18 @@
 
 use lset::{Contains as _, Span};
 use nbytes::bytes;
+use primordial::Page;
 
 const PREFIX: usize = bytes![4; MiB];
 const ALIGN: usize = bytes![2; MiB];
 const STACK: usize = bytes![8; MiB];
 const HEAP: usize = bytes![128; MiB];
-const SIZE: usize = bytes![64; GiB];
+pub const SIZE: usize = bytes![64; GiB];
+const THREADS: usize = 1;
+
+/// The pages of each thread in the prefix: its TCS, its copy of the layout
+/// and two SSA frames
+const THREAD_PAGES: usize = 4;
 
 const fn lower(value: usize, boundary: usize) -> usize {
     value / boundary * boundary
@@ -35,9 +41,26 @@ include!("../../../internal/shim-sgx/src/hostlib.rs");
 
 impl Layout {
     /// Calculate the memory layout of the SGX keep
-    pub fn calculate(shim: Line<usize>, code: Line<usize>) -> Self {
+    ///
+    /// The `heap` and `stack` sizes default to `HEAP` and `STACK`, the
+    /// number of `threads` to `THREADS`. Each thread gets a stack of its
+    /// own, below an unmapped guard page, so only keeps whose manifest asks
+    /// for more threads reserve room for them.
+    pub fn calculate(
+        shim: Line<usize>,
+        code: Line<usize>,
+        args: usize,
+        manifest: usize,
+        heap: Option<usize>,
+        stack: Option<usize>,
+        threads: Option<usize>,
+    ) -> Self {
         assert_eq!(shim.start, 0);
 
+        let threads = threads.unwrap_or(THREADS);
+        assert!(threads > 0 && threads <= MAX_THREADS);
+        assert!(threads * THREAD_PAGES * Page::size() <= PREFIX);
+
         let shim: Line<usize> = above(
             Span {
                 start: bytes!(32; TiB) + bytes!(63;GiB),
@@ -62,8 +85,11 @@ impl Layout {
         }
         .into();
         let code = above(prefix, Span::from(code).count);
-        let heap = above(code, HEAP);
-        let stack = below(shim, STACK);
+        let args = above(code, args);
+        let manifest = above(args, manifest);
+        let heap = above(manifest, raise(heap.unwrap_or(HEAP), Page::size()));
+        let stride = raise(stack.unwrap_or(STACK), Page::size()) + Page::size();
+        let stack = below(shim, stride * threads);
 
         Self {
             enclave,
@@ -73,6 +99,40 @@ impl Layout {
             heap: heap.into(),
             stack: stack.into(),
             shim,
+
+            args: args.into(),
+            argc: 0,
+            envc: 0,
+
+            manifest: manifest.into(),
+
+            tmpfs: [0; 32],
+            sealed: [0; 32],
+
+            threads,
+            thread: 0,
         }
     }
+
+    /// The copy of the layout after the TCS of thread `index`
+    pub fn thread(&self, index: usize) -> Self {
+        assert!(index < self.threads);
+
+        let stride = Span::from(self.stack).count / self.threads;
+        let stack = Span {
+            start: self.stack.start + stride * index + Page::size(),
+            count: stride - Page::size(),
+        };
+
+        Self {
+            stack: stack.into(),
+            thread: index,
+            ..*self
+        }
+    }
+
+    /// The address of the TCS of thread `index`
+    pub fn tcs(&self, index: usize) -> usize {
+        self.prefix.start + index * THREAD_PAGES * Page::size()
+    }
 }
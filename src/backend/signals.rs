@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host signals forwarded to the keep
+//!
+//! The loader catches SIGINT, SIGPIPE and SIGTERM instead of dying from
+//! them and leaves them for the shim, which fetches them with
+//! `SYS_ENARX_SIGNALS`, when one of its syscalls fails with `EINTR` or
+//! `EPIPE`. A signal arriving outside of a syscall fails the next blocking
+//! syscall of the keep with `EINTR` instead.
+//!
+//! A second SIGINT or SIGTERM, before the shim fetched the first, ends the
+//! loader, so a keep ignoring them can still be stopped.
+
+use crate::sallyport::Request;
+
+use std::io::Error;
+use std::mem::zeroed;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use libc::c_int;
+
+/// The signals forwarded to the keep
+const FORWARDED: [c_int; 3] = [libc::SIGINT, libc::SIGPIPE, libc::SIGTERM];
+
+/// The syscalls a pending signal interrupts, before they block
+const BLOCKING: &[i64] = &[
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_recvfrom,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_poll,
+    libc::SYS_epoll_wait,
+    libc::SYS_epoll_pwait,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_sched_yield,
+];
+
+/// The forwarded signals caught, but not fetched by the shim yet
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// The signal set with `sig`
+fn bit(sig: c_int) -> u64 {
+    1 << (sig - 1)
+}
+
+/// Marks the caught signal pending for the keep
+extern "C" fn pend(sig: c_int) {
+    let pending = PENDING.fetch_or(bit(sig), Ordering::SeqCst);
+
+    if pending & bit(sig) != 0 && sig != libc::SIGPIPE {
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+    }
+}
+
+/// Forwards SIGINT, SIGPIPE and SIGTERM of the loader to its keeps
+///
+/// Without, they end the loader and its keeps like any other process.
+pub fn forward_signals() -> Result<()> {
+    for sig in FORWARDED.iter().copied() {
+        unsafe {
+            let mut action: libc::sigaction = zeroed();
+            action.sa_sigaction = pend as usize;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            // Without SA_RESTART, the signal interrupts a blocking syscall
+            // of the keep.
+            if libc::sigaction(sig, &action, null_mut()) < 0 {
+                return Err(Error::last_os_error().into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Takes the signals pending for the keep
+pub(crate) fn take() -> u64 {
+    PENDING.swap(0, Ordering::SeqCst)
+}
+
+/// Whether a pending signal interrupts `req`, before it is executed
+pub(crate) fn interrupts(req: &Request) -> bool {
+    let num = usize::from(req.num) as i64;
+    PENDING.load(Ordering::SeqCst) != 0 && BLOCKING.contains(&num)
+}
@@ -10,12 +10,21 @@ use primordial::Page;
 use std::cmp::{max, min};
 use std::path::Path;
 
-use super::Segment;
+use super::{Segment, Symbols};
 
+/// A loadable ELF binary, such as the shim or the payload
 pub struct Component {
+    /// The loadable segments of the binary
     pub segments: Vec<Segment>,
+
+    /// The entry point of the binary
     pub entry: usize,
+
+    /// Whether the binary is position independent
     pub pie: bool,
+
+    /// The symbols of the binary, to symbolize backtraces with
+    pub symbols: Symbols,
 }
 
 impl Component {
@@ -75,6 +84,7 @@ impl Component {
 
         Ok(Self {
             entry: elf.entry as _,
+            symbols: Symbols::new(&elf, bytes.as_ref()),
             segments,
             pie,
         })
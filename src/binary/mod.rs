@@ -2,9 +2,11 @@
 
 mod component;
 mod segment;
+mod symbols;
 
 pub use component::Component;
 pub use segment::Segment;
+pub use symbols::Symbols;
 
 /// Permissions ascribed to a particular program header
 pub struct Permissions {
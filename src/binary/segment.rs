@@ -14,8 +14,13 @@ use super::Permissions;
 
 /// A loadable segment of code
 pub struct Segment {
+    /// The page aligned contents of the segment
     pub src: Vec<Page>,
+
+    /// The page aligned destination address of the segment
     pub dst: usize,
+
+    /// The permissions of the segment
     pub perms: Permissions,
 }
 
@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Symbolization of the addresses of a binary, for backtraces
+
+use addr2line::gimli::{EndianRcSlice, RunTimeEndian};
+use addr2line::object::File;
+use addr2line::{Context, Location};
+use goblin::elf::Elf;
+
+use std::sync::Arc;
+
+/// The sections holding the symbol table or the DWARF line information
+const SECTIONS: &[&str] = &[".symtab", ".debug_info"];
+
+/// The symbol table and debug information of a binary
+///
+/// They are kept in their raw form and are only parsed when addresses
+/// are looked up, which usually only happens when a keep crashes.
+#[derive(Clone, Default)]
+pub struct Symbols(Option<Arc<[u8]>>);
+
+impl Symbols {
+    /// Keeps the bytes of a binary, if it has any symbols
+    pub(crate) fn new(elf: &Elf, bytes: &[u8]) -> Self {
+        let symbols = elf
+            .section_headers
+            .iter()
+            .filter_map(|sh| elf.shdr_strtab.get(sh.sh_name)?.ok())
+            .any(|name| SECTIONS.contains(&name));
+
+        Self(match symbols {
+            true => Some(bytes.into()),
+            false => None,
+        })
+    }
+
+    /// Describes the code at each of `addrs`, virtual addresses of the binary
+    ///
+    /// Every address is described by the functions it belongs to, with the
+    /// innermost inlined function first. With debug information, each of
+    /// them is followed by its source location. Without any symbols, the
+    /// description is empty.
+    pub fn lookup(&self, addrs: &[u64]) -> Vec<Vec<String>> {
+        let bytes = match self.0.as_ref() {
+            Some(bytes) => bytes,
+            None => return vec![Vec::new(); addrs.len()],
+        };
+
+        let elf = Elf::parse(&bytes[..]).ok();
+        let file = File::parse(&bytes[..]).ok();
+        let dwarf = file.as_ref().and_then(|file| Context::new(file).ok());
+
+        addrs
+            .iter()
+            .map(|addr| {
+                let mut names = dwarf
+                    .as_ref()
+                    .map(|d| functions(d, *addr))
+                    .unwrap_or_default();
+
+                if names.is_empty() {
+                    names.extend(elf.as_ref().and_then(|elf| symbol(elf, *addr)));
+                }
+
+                names
+            })
+            .collect()
+    }
+}
+
+/// Finds the functions at `addr` in the DWARF debug information
+fn functions(dwarf: &Context<EndianRcSlice<RunTimeEndian>>, addr: u64) -> Vec<String> {
+    let mut names = Vec::new();
+
+    let mut frames = match dwarf.find_frames(addr) {
+        Ok(frames) => frames,
+        Err(_) => return names,
+    };
+
+    while let Ok(Some(frame)) = frames.next() {
+        let function = frame
+            .function
+            .and_then(|f| f.demangle().ok().map(|name| name.into_owned()))
+            .unwrap_or_else(|| "??".into());
+
+        names.push(match frame.location {
+            Some(Location {
+                file: Some(file),
+                line: Some(line),
+                ..
+            }) => format!("{} at {}:{}", function, file, line),
+            _ => function,
+        });
+    }
+
+    names
+}
+
+/// Finds the function at `addr` in the symbol table
+fn symbol(elf: &Elf, addr: u64) -> Option<String> {
+    let sym = elf.syms.iter().find(|sym| {
+        sym.is_function() && addr >= sym.st_value && addr - sym.st_value < sym.st_size
+    })?;
+
+    let name = elf.strtab.get(sym.st_name)?.ok()?;
+    let offset = addr - sym.st_value;
+
+    Some(format!(
+        "{:#}+{:#x}",
+        rustc_demangle::demangle(name),
+        offset
+    ))
+}
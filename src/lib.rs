@@ -0,0 +1,329 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! This crate provides the `enarx-keepldr` executable which loads `static-pie`
+//! binaries into an Enarx Keep - that is a hardware isolated environment using
+//! technologies such as Intel SGX or AMD SEV.
+//!
+//! The loader is also available as a library, so that host programs can
+//! create and drive keeps without shelling out to the executable.
+//!
+//! # Building
+//!
+//! Please see **BUILD.md** for instructions.
+//!
+//! # Run Tests
+//!
+//!     $ cargo test
+//!
+//! # Build and Run an Application
+//!
+//!     $ cat > test.c <<EOF
+//!     #include <stdio.h>
+//!
+//!     int main() {
+//!         printf("Hello World!\n");
+//!         return 0;
+//!     }
+//!     EOF
+//!
+//!     $ musl-gcc -static-pie -fPIC -o test test.c
+//!     $ target/debug/enarx-keepldr exec ./test
+//!     Hello World!
+//!
+//! Arguments following the payload are passed on to it, and its environment
+//! is given with `--env` (it defaults to `LANG=C`):
+//!
+//!     $ target/debug/enarx-keepldr exec --env HOME=/ ./test -- --verbose
+//!
+//! # Describe a Keep with a Manifest
+//!
+//! Instead of the command line, a keep can be described by a TOML (or
+//! CBOR) manifest naming the payload, its arguments and environment, the
+//! preferred backends, memory limits and the host files to open for it:
+//!
+//!     $ cat > keep.toml <<EOF
+//!     code = "test"
+//!     args = ["--verbose"]
+//!     backend = ["sgx", "sev"]
+//!
+//!     [memory]
+//!     stack = 16777216
+//!
+//!     [[files]]
+//!     fd = 3
+//!     path = "config.toml"
+//!     EOF
+//!
+//!     $ target/debug/enarx-keepldr exec --manifest keep.toml
+//!
+//! The manifest is loaded into the keep, so it is part of the measurement
+//! output by `report --manifest keep.toml`.
+//!
+//! # Trace the Syscalls of a Keep
+//!
+//! The syscalls a keep proxies to the host can be logged strace-style, to
+//! stderr or to a file, along with their results and the time the host took
+//! to handle them:
+//!
+//!     $ target/debug/enarx-keepldr exec --trace ./test
+//!     1602756000.123456 write(0x1, 0x7fff0000, 0xd, 0x0, 0x0, 0x0) = 13 <0.000012>
+//!
+//! Syscalls the host never returns from, like `exit_group`, are logged before
+//! they are executed, with `?` as their result.
+//!
+//! `--trace-format json` logs one JSON object per syscall instead:
+//!
+//!     $ target/debug/enarx-keepldr exec --trace=trace.log --trace-format json ./test
+//!
+//! # Record and Replay the Syscalls of a Keep
+//!
+//! `--record` writes every syscall a keep proxies to the host to a file, along
+//! with its reply and all bytes the host wrote to the shared block for it.
+//! `--replay` feeds a recording back to a keep running the same payload, so it
+//! sees the same replies without the host executing anything but its exit:
+//!
+//!     $ target/debug/enarx-keepldr exec --record trace.cbor ./test < input
+//!     $ target/debug/enarx-keepldr exec --replay trace.cbor ./test
+//!
+//! The recording is a sequence of CBOR records. Replaying fails as soon as the
+//! keep issues a different syscall than the recorded one, or passes other
+//! arguments or buffers to it.
+//!
+//! # Constrain the Syscalls of a Keep
+//!
+//! A policy restricts the syscalls a keep may proxy to the host by number and
+//! arguments. Syscalls it does not allow fail with `EPERM`, or with
+//! `action = "log"` are only reported on stderr:
+//!
+//!     $ cat > policy.toml <<EOF
+//!     action = "deny"
+//!
+//!     [[allow]]
+//!     syscall = "socket"
+//!     family = ["inet"]
+//!
+//!     [[allow]]
+//!     syscall = "connect"
+//!     address = ["127.0.0.1:8080"]
+//!
+//!     [[allow]]
+//!     syscall = "write"
+//!
+//!     [[allow]]
+//!     syscall = "exit_group"
+//!     EOF
+//!
+//!     $ target/debug/enarx-keepldr exec --policy policy.toml ./test
+//!
+//! The same rules can be given as the `policy` table of a manifest.
+//!
+//! # Keep Scratch Files Inside a Keep
+//!
+//! With `--tmpfs`, the files below an absolute path are served from the
+//! encrypted memory of the keep instead of the host. Opening, reading,
+//! writing, seeking, listing and unlinking them never leaves the keep, and
+//! their contents are lost when it exits:
+//!
+//!     $ target/debug/enarx-keepldr exec --tmpfs /tmp ./test
+//!
+//! The mount point can also be given as `tmpfs = "/tmp"` in a manifest. It
+//! must be shorter than 32 bytes.
+//!
+//! # Persist Sealed Files
+//!
+//! With `--sealed`, the files below an absolute path are stored on the host,
+//! but sealed to the keep. The shim encrypts and authenticates every block
+//! of them with a key derived from the secret of the keep, which is the
+//! secret injected into an SEV keep or the seal key of an SGX enclave. The
+//! payload uses them like any other file, while the host only ever sees
+//! ciphertext:
+//!
+//!     $ target/debug/enarx-keepldr exec --sealed /data ./test
+//!
+//! The prefix can also be given as `sealed = "/data"` in a manifest. It must
+//! be shorter than 32 bytes. Without a secret, opening a sealed file fails
+//! with `EACCES`. Only absolute paths in canonical form are sealed, and the
+//! host still sees the names and sizes of the files.
+//!
+//! # Select a Different Backend
+//!
+//! `enarx-keepldr exec` will probe the machine it is running on
+//! in an attempt to deduce an appropriate deployment backend unless
+//! that target is already specified with the `--backend` option or
+//! in an environment variable called `ENARX_BACKEND`.
+//!
+//! To see what backends are supported on your system, run:
+//!
+//!     $ target/debug/enarx-keepldr info
+//!
+//! Scripts can request the same information with `--format json` or
+//! `--format cbor`, or test whether a single backend is usable from
+//! the exit status of:
+//!
+//!     $ target/debug/enarx-keepldr info --check sgx
+//!
+//! To manually select a backend, pass its name with `--backend` or
+//! set the `ENARX_BACKEND` environment variable:
+//!
+//!     $ target/debug/enarx-keepldr exec --backend sgx ./test
+//!     $ ENARX_BACKEND=sgx target/debug/enarx-keepldr exec ./test
+//!
+//! Several backends may be given in order of preference, such as
+//! `--backend sgx,sev,nil`. The first usable one is chosen. If none
+//! of them is usable, the failed checks of each one are reported.
+//!
+//! Note that some backends are conditionally compiled. They can all
+//! be compiled in like so:
+//!
+//!     $ cargo build --all-features
+//!
+//! Or specific backends can be compiled in:
+//!
+//!     $ cargo build --features=backend-sgx,backend-kvm
+//!
+//! # Emulate a Keep
+//!
+//! The `emulated` backend runs the payload in an ordinary process instead
+//! of a hardware keep. A seccomp filter traps its syscalls, which a shim
+//! handles with the same code as the SEV and SGX shims before proxying them
+//! to the host. It needs no special hardware, so it exercises the syscall
+//! path of a keep on any Linux machine:
+//!
+//!     $ cargo build --features=backend-emulated
+//!     $ target/debug/enarx-keepldr exec --backend emulated ./test
+//!
+//! An emulated keep isolates nothing from the host. It cannot be measured
+//! or attested and has no secret to seal files to.
+//!
+//! # Measure a Keep Offline
+//!
+//! The `report --offline` subcommand measures a keep in software, so it
+//! works on any machine, even without the hardware of the backend:
+//!
+//!     $ target/debug/enarx-keepldr report --offline --backend sgx ./test
+//!
+//! The SEV launch measurement additionally depends on the guest policy,
+//! the firmware build, the transport integrity key (TIK) of the launch
+//! session and the nonce returned along with the measurement:
+//!
+//!     $ target/debug/enarx-keepldr report --offline --backend sev --policy 1 \
+//!           --build 0.24.15 --tik session_tik.bin \
+//!           --nonce 00112233445566778899aabbccddeeff ./test
+//!
+//! # Verify a Keep Measurement
+//!
+//! The `verify` subcommand measures a keep like `report` and compares the
+//! result with a previously saved report, field by field, or with a list of
+//! allowed digests. It exits unsuccessfully on any mismatch:
+//!
+//!     $ target/debug/enarx-keepldr report --backend sgx ./test > expected.json
+//!     $ target/debug/enarx-keepldr verify --backend sgx --expected expected.json ./test
+//!
+//! Only an offline SEV report has a launch measurement. The one of a keep
+//! launched on the hardware depends on the random session of its launch,
+//! which only the owner of the session knows, so `verify` rejects an
+//! expected launch measurement unless it measures offline, too:
+//!
+//!     $ target/debug/enarx-keepldr verify --offline --backend sev --expected expected.json \
+//!           --policy 1 --build 0.24.15 --tik session_tik.bin \
+//!           --nonce 00112233445566778899aabbccddeeff ./test
+//!
+//! # Use as a Library
+//!
+//! The backends compiled into the crate are listed in [`BACKENDS`]. A host
+//! program picks one of them, builds a keep from a payload and then drives
+//! the keep's threads itself, servicing the syscalls the keep proxies out
+//! with a [`SyscallProxy`]. Besides executing them on the host with
+//! [`Passthrough`], a [`Chain`] of [`Layer`]s can trace, filter, emulate or
+//! rewrite them:
+//!
+//! ```no_run
+//! use enarx_keepldr::{Args, Command, Component, Passthrough, SyscallProxy, Thread, BACKENDS};
+//!
+//! fn run(mut thread: Box<dyn Thread>) -> anyhow::Result<()> {
+//!     let mut proxy = Passthrough;
+//!     loop {
+//!         match thread.enter()? {
+//!             Command::SysCall(block) => {
+//!                 let req = unsafe { block.msg.req };
+//!                 block.msg.rep = unsafe { proxy.proxy(&req)? };
+//!             }
+//!             Command::Continue => (),
+//!             Command::Spawn(thread) => {
+//!                 std::thread::spawn(move || {
+//!                     if let Err(e) = run(thread) {
+//!                         eprintln!("Error: {:?}", e);
+//!                         std::process::exit(1);
+//!                     }
+//!                 });
+//!             }
+//!             _ => anyhow::bail!("The keep returned an unknown command."),
+//!         }
+//!     }
+//! }
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let backend = BACKENDS.iter().find(|b| b.have()).expect("no backend");
+//!
+//! let code = Component::from_bytes(std::fs::read("./test")?)?;
+//! let args = Args {
+//!     argv: vec!["--verbose".into()],
+//!     envp: vec!["LANG=C".into()],
+//!     ..Default::default()
+//! };
+//! let keep = backend.build(code, &args, &Default::default(), None)?;
+//!
+//! run(keep.add_thread()?)
+//! # }
+//! ```
+//!
+//! Keeps with several threads report each new one with `Command::Spawn`, to
+//! be entered in parallel to the others, usually on an OS thread of its own.
+//!
+//! SIGINT, SIGPIPE and SIGTERM end the host program like any other process,
+//! unless it calls [`forward_signals`] to have them delivered to the payload
+//! inside its keeps instead.
+
+#![deny(clippy::all)]
+#![deny(missing_docs)]
+#![feature(asm)]
+
+mod backend;
+mod binary;
+mod manifest;
+mod names;
+mod policy;
+mod protobuf;
+mod record;
+/// Shared structures for the communication between the loader and the shims
+pub mod sallyport;
+mod syscall;
+mod trace;
+
+// workaround for sallyport tests, until we have internal crates
+pub use sallyport::Request;
+
+pub use backend::{
+    forward_signals, Args, Backend, Chain, Command, Datum, DebugOptions, Keep, Layer, Passthrough,
+    Region, Report, SyscallProxy, Thread,
+};
+pub use binary::{Component, Permissions, Segment, Symbols};
+pub use manifest::{Manifest, Memory, Preopen};
+pub use policy::{Action, Address, ArgValues, Family, Policy, Rule, Syscall};
+pub use record::{Patch, Record, Recorder, Replayer};
+pub use trace::{TraceFormat, Tracer};
+
+#[cfg(feature = "backend-sev")]
+pub use backend::sev::offline as sev_offline;
+
+/// All backends compiled into this crate, in order of preference
+pub const BACKENDS: &[&dyn Backend] = &[
+    #[cfg(feature = "backend-sev")]
+    &backend::sev::Backend,
+    #[cfg(feature = "backend-sgx")]
+    &backend::sgx::Backend,
+    #[cfg(feature = "backend-kvm")]
+    &backend::kvm::Backend,
+    #[cfg(feature = "backend-emulated")]
+    &backend::emulated::Backend,
+];
@@ -1,106 +1,250 @@
 // SPDX-License-Identifier: Apache-2.0
 
-//! This crate provides the `enarx-keepldr` executable which loads `static-pie`
-//! binaries into an Enarx Keep - that is a hardware isolated environment using
-//! technologies such as Intel SGX or AMD SEV.
+//! The `enarx-keepldr` executable
 //!
-//! # Building
-//!
-//! Please see **BUILD.md** for instructions.
-//!
-//! # Run Tests
-//!
-//!     $ cargo test
-//!
-//! # Build and Run an Application
-//!
-//!     $ cat > test.c <<EOF
-//!     #include <stdio.h>
-//!
-//!     int main() {
-//!         printf("Hello World!\n");
-//!         return 0;
-//!     }
-//!     EOF
-//!
-//!     $ musl-gcc -static-pie -fPIC -o test test.c
-//!     $ target/debug/enarx-keepldr exec ./test
-//!     Hello World!
-//!
-//! # Select a Different Backend
-//!
-//! `enarx-keepldr exec` will probe the machine it is running on
-//! in an attempt to deduce an appropriate deployment backend unless
-//! that target is already specified in an environment variable
-//! called `ENARX_BACKEND`.
-//!
-//! To see what backends are supported on your system, run:
-//!
-//!     $ target/debug/enarx-keepldr info
-//!
-//! To manually select a backend, set the `ENARX_BACKEND` environment
-//! variable:
-//!
-//!     $ ENARX_BACKEND=sgx target/debug/enarx-keepldr exec ./test
-//!
-//! Note that some backends are conditionally compiled. They can all
-//! be compiled in like so:
-//!
-//!     $ cargo build --all-features
-//!
-//! Or specific backends can be compiled in:
-//!
-//!     $ cargo build --features=backend-sgx,backend-kvm
+//! This is a thin command line interface over the `enarx_keepldr` library.
+//! See the library documentation for details.
 
 #![deny(clippy::all)]
 #![deny(missing_docs)]
-#![feature(asm)]
-
-mod backend;
-mod binary;
-mod protobuf;
-mod sallyport;
-mod syscall;
 
-// workaround for sallyport tests, until we have internal crates
-pub use sallyport::Request;
-
-use backend::{Backend, Command};
-use binary::Component;
+use enarx_keepldr::sallyport::Reply;
+use enarx_keepldr::{
+    forward_signals, Args, Backend, Chain, Command, Component, Datum, DebugOptions, Manifest,
+    Passthrough, Policy, Preopen, Recorder, Replayer, Report as Measurement, Request, SyscallProxy,
+    Thread, TraceFormat, Tracer, BACKENDS,
+};
 
 use anyhow::Result;
+use primordial::Register;
+use serde::Serialize;
+use serde_json::Value;
 use structopt::StructOpt;
 
 use std::ffi::CString;
+use std::fs::File;
 use std::io::Error;
 use std::os::raw::c_char;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::IntoRawFd;
 use std::path::PathBuf;
 use std::ptr::null;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 
 /// Prints information about your current platform
 #[derive(StructOpt)]
-struct Info {}
+struct Info {
+    /// The output format: text, json or cbor
+    #[structopt(short, long, default_value = "text")]
+    format: Format,
+
+    /// Only report on the named backend and exit unsuccessfully if it is unusable
+    #[structopt(short, long)]
+    check: Option<String>,
+}
+
+/// The output formats of the `info` subcommand
+enum Format {
+    Text,
+    Json,
+    Cbor,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "cbor" => Ok(Self::Cbor),
+            _ => Err(format!("unknown format '{}'", s)),
+        }
+    }
+}
+
+/// The machine-readable platform information of a single backend
+#[derive(Serialize)]
+struct BackendInfo {
+    name: &'static str,
+    have: bool,
+    data: Vec<Datum>,
+}
 
 /// Executes a keep
 #[derive(StructOpt)]
 struct Exec {
+    /// The backends to try, in order of preference (e.g. `sgx,sev,nil`)
+    #[structopt(short, long, env = "ENARX_BACKEND", use_delimiter = true)]
+    backend: Vec<String>,
+
     /// The socket to use for preattestation
     #[structopt(short, long)]
     sock: Option<PathBuf>,
 
-    /// The payload to run inside the keep
-    code: PathBuf,
+    /// Trace the syscalls proxied to the host, to the given file or else to stderr
+    #[structopt(long, require_equals = true)]
+    trace: Option<Option<PathBuf>>,
+
+    /// The format of the trace: text or json (one object per line)
+    #[structopt(long, default_value = "text")]
+    trace_format: TraceFormat,
+
+    /// The policy for the syscalls proxied to the host (overrides the one of the manifest)
+    #[structopt(long)]
+    policy: Option<PathBuf>,
+
+    /// Record the syscalls proxied to the host and their replies to the given file
+    #[structopt(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Replay the replies of a recording instead of proxying syscalls to the host
+    #[structopt(long)]
+    replay: Option<PathBuf>,
+
+    /// Wait for gdb to attach to the given unix socket before starting the keep (kvm only)
+    #[structopt(long)]
+    gdb: Option<PathBuf>,
+
+    /// Write an ELF core dump of the keep to the given file if it fails (kvm only, one vCPU)
+    #[structopt(long)]
+    core_dump: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    payload: Payload,
 }
 
 /// Get a report from a keep
 #[derive(StructOpt)]
 struct Report {
-    /// The payload to run inside the keep
-    code: PathBuf,
+    /// Calculate the measurement of the given backend in software, without any hardware
+    #[structopt(long)]
+    offline: bool,
+
+    #[structopt(flatten)]
+    launch: Launch,
+
+    /// The backends to try, in order of preference (e.g. `sgx,sev,nil`)
+    #[structopt(short, long, env = "ENARX_BACKEND", use_delimiter = true)]
+    backend: Vec<String>,
+
+    #[structopt(flatten)]
+    payload: Payload,
+}
+
+/// The payload of a keep, optionally described by a manifest
+#[derive(StructOpt)]
+struct Payload {
+    /// The manifest describing the keep, which is loaded into the keep
+    #[structopt(short, long)]
+    manifest: Option<PathBuf>,
+
+    /// An environment variable for the payload in KEY=VALUE form (defaults to LANG=C)
+    #[structopt(short, long, number_of_values = 1)]
+    env: Vec<String>,
+
+    /// The payload to run inside the keep (defaults to the one of the manifest)
+    #[structopt(required_unless = "manifest")]
+    code: Option<PathBuf>,
+
+    /// The arguments for the payload (use `--` before arguments starting with `-`)
+    args: Vec<String>,
+
+    /// Serve the files below this absolute path from memory inside the keep
+    #[structopt(long)]
+    tmpfs: Option<String>,
+
+    /// Encrypt the files below this absolute path with a key only the keep has
+    #[structopt(long)]
+    sealed: Option<String>,
+}
+
+impl Payload {
+    /// Merges the command line with the manifest
+    ///
+    /// The command line takes precedence. Without any environment on
+    /// either, the payload gets `LANG=C`, whatever the backend.
+    fn load(self) -> Result<(PathBuf, Args)> {
+        let manifest = self.manifest.map(Manifest::from_path).transpose()?;
+        let defaults = manifest.clone().unwrap_or_default();
+
+        let code = match self.code.or(defaults.code) {
+            Some(code) => code,
+            None => anyhow::bail!("the manifest does not name a payload"),
+        };
+
+        let argv = match self.args.is_empty() {
+            true => defaults.args,
+            false => self.args,
+        };
+
+        let envp = match (self.env.is_empty(), defaults.env.is_empty()) {
+            (false, _) => self.env,
+            (true, false) => defaults.env,
+            (true, true) => vec!["LANG=C".into()],
+        };
+
+        let tmpfs = self.tmpfs.or(defaults.tmpfs);
+        let sealed = self.sealed.or(defaults.sealed);
+
+        Ok((
+            code,
+            Args {
+                argv,
+                envp,
+                manifest,
+                tmpfs,
+                sealed,
+            },
+        ))
+    }
+}
+
+/// Verifies the measurement of a keep
+#[derive(StructOpt)]
+struct Verify {
+    /// A previously output report the measurement must match
+    #[structopt(long)]
+    expected: Option<PathBuf>,
+
+    /// An allowed digest in hexadecimal, one of which the measurement must match
+    #[structopt(long = "digest", number_of_values = 1)]
+    digests: Vec<String>,
+
+    #[structopt(flatten)]
+    report: Report,
+}
+
+/// The SEV launch parameters of an offline measurement
+#[derive(StructOpt)]
+struct Launch {
+    /// The SEV guest policy in hexadecimal
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_policy))]
+    policy: u32,
+
+    /// The SEV firmware API version and build ID, such as 0.24.15
+    #[structopt(long)]
+    build: Option<String>,
+
+    /// The location of the C-bit in guest physical addresses
+    #[structopt(long, default_value = "47")]
+    c_bit: u32,
+
+    /// The file holding the transport integrity key of the launch session
+    #[structopt(long)]
+    tik: Option<PathBuf>,
+
+    /// The nonce returned along with the launch measurement in hexadecimal
+    #[structopt(long)]
+    nonce: Option<String>,
+}
+
+fn parse_policy(s: &str) -> std::result::Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16)
 }
 
 #[derive(StructOpt)]
@@ -109,36 +253,60 @@ enum Options {
     Info(Info),
     Exec(Exec),
     Report(Report),
+    Verify(Verify),
 }
 
 #[allow(clippy::unnecessary_wraps)]
 fn main() -> Result<()> {
-    let backends: &[Box<dyn Backend>] = &[
-        #[cfg(feature = "backend-sev")]
-        Box::new(backend::sev::Backend),
-        #[cfg(feature = "backend-sgx")]
-        Box::new(backend::sgx::Backend),
-        #[cfg(feature = "backend-kvm")]
-        Box::new(backend::kvm::Backend),
-    ];
-
     match Options::from_args() {
-        Options::Info(_) => info(backends),
-        Options::Exec(e) => exec(backends, e),
-        Options::Report(e) => measure(backends, e),
+        Options::Info(i) => info(BACKENDS, i),
+        Options::Exec(e) => exec(BACKENDS, e),
+        Options::Report(e) => report(BACKENDS, e),
+        Options::Verify(e) => verify(BACKENDS, e),
     }
 }
 
-#[allow(clippy::unnecessary_wraps)]
-fn info(backends: &[Box<dyn Backend>]) -> Result<()> {
+fn info(backends: &[&dyn Backend], opts: Info) -> Result<()> {
+    let backends: Vec<_> = match opts.check.as_ref() {
+        None => backends.to_vec(),
+        Some(name) => match backends.iter().find(|b| b.name() == name.as_str()) {
+            Some(backend) => vec![*backend],
+            None => anyhow::bail!("Keep backend '{}' is unsupported.", name),
+        },
+    };
+
+    let infos: Vec<_> = backends
+        .iter()
+        .map(|b| BackendInfo {
+            name: b.name(),
+            have: b.have(),
+            data: b.data(),
+        })
+        .collect();
+
+    match opts.format {
+        Format::Text => print_info(&infos),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&infos)?),
+        Format::Cbor => ciborium::ser::into_writer(&infos, std::io::stdout())?,
+    }
+
+    if opts.check.is_some() && !infos.iter().all(|i| i.have) {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Prints the platform information for humans
+fn print_info(infos: &[BackendInfo]) {
     use colorful::*;
 
-    for backend in backends {
-        println!("Backend: {}", backend.name());
+    for backend in infos {
+        println!("Backend: {}", backend.name);
 
-        let data = backend.data();
+        let data = &backend.data;
 
-        for datum in &data {
+        for datum in data {
             let icon = match datum.pass {
                 true => "✔".green(),
                 false => "✗".red(),
@@ -151,82 +319,487 @@ fn info(backends: &[Box<dyn Backend>]) -> Result<()> {
             }
         }
 
-        for datum in &data {
+        for datum in data {
             if let Some(mesg) = datum.mesg.as_ref() {
                 println!("\n{}\n", mesg);
             }
         }
     }
+}
+
+/// The failed checks of the backends that could not be selected
+#[derive(Debug)]
+struct Unsupported(Vec<(String, Vec<Datum>)>);
+
+impl std::error::Error for Unsupported {}
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "no usable keep backend was found")?;
+
+        for (name, failed) in &self.0 {
+            write!(f, "\n\nBackend: {}", name)?;
+
+            if failed.is_empty() {
+                write!(f, "\n ✗ not compiled into this build")?;
+            }
+
+            for datum in failed {
+                match datum.info.as_ref() {
+                    Some(info) => write!(f, "\n ✗ {}: {}", datum.name, info)?,
+                    None => write!(f, "\n ✗ {}", datum.name)?,
+                }
+
+                if let Some(mesg) = datum.mesg.as_ref() {
+                    write!(f, "\n\n{}", mesg)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Selects the first usable backend from the list of preferred ones
+///
+/// Without any preference, the first usable backend compiled in is chosen.
+/// `None` stands for the `nil` backend, which runs the payload directly.
+fn select<'a>(
+    backends: &[&'a dyn Backend],
+    preferred: &[String],
+) -> Result<Option<&'a dyn Backend>> {
+    if preferred.is_empty() {
+        return Ok(backends.iter().find(|b| b.have()).copied());
+    }
+
+    let mut failures = Vec::new();
+
+    for name in preferred {
+        if name == "nil" {
+            return Ok(None);
+        }
+
+        match backends.iter().find(|b| b.name() == name.as_str()) {
+            Some(backend) if backend.have() => return Ok(Some(*backend)),
+            Some(backend) => {
+                let failed = backend.data().into_iter().filter(|d| !d.pass).collect();
+                failures.push((name.clone(), failed));
+            }
+            None => failures.push((name.clone(), Vec::new())),
+        }
+    }
+
+    Err(Unsupported(failures).into())
+}
 
+fn report(backends: &[&dyn Backend], opts: Report) -> Result<()> {
+    let report = measure(backends, opts)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
     Ok(())
 }
 
-#[allow(unreachable_code)]
-#[allow(clippy::unnecessary_wraps)]
-fn measure(backends: &[Box<dyn Backend>], opts: Report) -> Result<()> {
-    let keep = std::env::var_os("ENARX_BACKEND").map(|x| x.into_string().unwrap());
+/// Measures a keep with the selected backend
+fn measure(backends: &[&dyn Backend], opts: Report) -> Result<Measurement> {
+    let (path, args) = opts.payload.load()?;
+    let preferred = preferred(opts.backend, &args);
 
-    let backend = backends
-        .iter()
-        .filter(|b| keep.is_none() || keep == Some(b.name().into()))
-        .find(|b| b.have());
+    if opts.offline {
+        return measure_offline(backends, &preferred, opts.launch, path, args);
+    }
 
-    if let Some(backend) = backend {
-        let code = Component::from_path(&opts.code)?;
-        let json = backend.measure(code)?;
-        println!("{}", json);
-    } else {
-        panic!(
-            "Keep backend '{}' is unsupported.",
-            keep.unwrap_or_else(|| String::from("nil"))
+    // The nil backend has no measurement, so only real backends qualify.
+    let preferred = match preferred.is_empty() {
+        true => backends.iter().map(|b| b.name().into()).collect(),
+        false => preferred,
+    };
+
+    match select(backends, &preferred)? {
+        Some(backend) => backend.measure(Component::from_path(&path)?, &args),
+        None => anyhow::bail!("Keep backend 'nil' cannot be measured."),
+    }
+}
+
+/// Measures a keep in software, even if its backend is unusable on this machine
+#[cfg_attr(not(feature = "backend-sev"), allow(unused_variables))]
+fn measure_offline(
+    backends: &[&dyn Backend],
+    preferred: &[String],
+    launch: Launch,
+    path: PathBuf,
+    args: Args,
+) -> Result<Measurement> {
+    let name = match preferred {
+        [name] => name.as_str(),
+        _ => anyhow::bail!("--offline requires a single backend"),
+    };
+
+    match name {
+        #[cfg(feature = "backend-sev")]
+        "sev" => measure_sev_offline(launch, path, args),
+
+        name => match backends.iter().find(|b| b.name() == name) {
+            Some(backend) => backend.measure(Component::from_path(&path)?, &args),
+            None => anyhow::bail!("Keep backend '{}' is unsupported.", name),
+        },
+    }
+}
+
+fn verify(backends: &[&dyn Backend], opts: Verify) -> Result<()> {
+    if opts.expected.is_none() && opts.digests.is_empty() {
+        anyhow::bail!("verify requires --expected or --digest");
+    }
+
+    let actual = measure(backends, opts.report)?;
+    let mut mismatches = Vec::new();
+
+    if let Some(path) = opts.expected {
+        let expected = std::fs::read(&path)?;
+        let expected: Measurement = serde_json::from_slice(&expected)?;
+
+        // The launch measurement of a keep on the hardware depends on the
+        // session of its launch, so only an offline one can be compared.
+        if expected.launch.is_some() && actual.launch.is_none() {
+            anyhow::bail!("the expected launch measurement can only be verified with --offline");
+        }
+
+        let expected = serde_json::to_value(&expected)?;
+        let actual = serde_json::to_value(&actual)?;
+        diff("", &expected, &actual, &mut mismatches);
+    }
+
+    if !opts.digests.is_empty() {
+        let allowed = |d: &String| opts.digests.iter().any(|a| a.eq_ignore_ascii_case(d));
+
+        if !actual
+            .digests
+            .values()
+            .chain(actual.launch.iter())
+            .any(allowed)
+        {
+            mismatches.push(format!(
+                "digests: none of the allowed digests matches {:?}",
+                actual.digests
+            ));
+        }
+    }
+
+    if !mismatches.is_empty() {
+        anyhow::bail!(
+            "the measurement does not match:\n  {}",
+            mismatches.join("\n  ")
         );
     }
 
+    println!("The measurement matches.");
     Ok(())
 }
 
+/// Collects the differences between two json values, field by field
+fn diff(path: &str, expected: &Value, actual: &Value, mismatches: &mut Vec<String>) {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            let mut keys: Vec<_> = expected.keys().chain(actual.keys()).collect();
+            keys.sort();
+            keys.dedup();
+
+            for key in keys {
+                let path = match path {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", path, key),
+                };
+
+                let expected = expected.get(key).unwrap_or(&Value::Null);
+                let actual = actual.get(key).unwrap_or(&Value::Null);
+                diff(&path, expected, actual, mismatches);
+            }
+        }
+
+        (expected, actual) if expected != actual => {
+            mismatches.push(format!("{}: expected {}, found {}", path, expected, actual))
+        }
+
+        _ => (),
+    }
+}
+
+#[cfg(feature = "backend-sev")]
+fn measure_sev_offline(launch: Launch, path: PathBuf, args: Args) -> Result<Measurement> {
+    use anyhow::Context;
+    use enarx_keepldr::sev_offline;
+    use std::convert::TryInto;
+
+    let build = launch
+        .build
+        .context("an offline SEV measurement requires --build")?;
+    let build = match build.split('.').map(str::parse::<u8>).collect::<Vec<_>>()[..] {
+        [Ok(major), Ok(minor), Ok(id)] => (major, minor, id),
+        _ => anyhow::bail!("invalid SEV firmware build '{}'", build),
+    };
+
+    let tik = std::fs::read(
+        launch
+            .tik
+            .context("an offline SEV measurement requires --tik")?,
+    )?;
+    let tik = tik[..]
+        .try_into()
+        .context("the transport integrity key must be 16 bytes")?;
+
+    let nonce = from_hex(
+        &launch
+            .nonce
+            .context("an offline SEV measurement requires --nonce")?,
+    )?;
+    let nonce = nonce[..].try_into().context("the nonce must be 16 bytes")?;
+
+    let launch = sev_offline::Launch {
+        policy: launch.policy,
+        build,
+        c_bit: launch.c_bit,
+        tik,
+        nonce,
+    };
+
+    sev_offline::measure(Component::from_path(&path)?, &args, &launch)
+}
+
+/// Decodes a string of hexadecimal digits
+#[cfg(feature = "backend-sev")]
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.trim_start_matches("0x");
+
+    if !hex.is_ascii() || hex.len() % 2 != 0 {
+        anyhow::bail!("invalid hexadecimal string '{}'", hex);
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
 #[allow(unreachable_code)]
-#[allow(clippy::unnecessary_wraps)]
-fn exec(backends: &[Box<dyn Backend>], opts: Exec) -> Result<()> {
-    let keep = std::env::var_os("ENARX_BACKEND").map(|x| x.into_string().unwrap());
+fn exec(backends: &[&dyn Backend], opts: Exec) -> Result<()> {
+    let (path, args) = opts.payload.load()?;
+    let backend = select(backends, &preferred(opts.backend, &args))?;
 
-    let backend = backends
-        .iter()
-        .filter(|b| keep.is_none() || keep == Some(b.name().into()))
-        .find(|b| b.have());
+    let debug = DebugOptions {
+        gdb: opts.gdb,
+        core_dump: opts.core_dump,
+    };
+
+    if let Some(backend) = backend.filter(|b| debug.gdb.is_some() && b.name() != "kvm") {
+        anyhow::bail!(
+            "Keep backend '{}' cannot be debugged with gdb.",
+            backend.name()
+        );
+    }
+
+    if let Some(backend) = backend.filter(|b| debug.core_dump.is_some() && b.name() != "kvm") {
+        anyhow::bail!("Keep backend '{}' cannot dump core.", backend.name());
+    }
+
+    let policy = match (opts.policy, args.manifest.as_ref()) {
+        (Some(path), _) => Some(Policy::from_path(path)?),
+        (None, Some(manifest)) => Policy::from_manifest(manifest)?,
+        (None, None) => None,
+    };
+
+    if let Some(manifest) = args.manifest.as_ref() {
+        preopen(&manifest.files)?;
+    }
+
+    let trace = match opts.trace {
+        None => None,
+        Some(None) => Some((None, opts.trace_format)),
+        Some(Some(path)) => Some((Some(File::create(path)?), opts.trace_format)),
+    };
+
+    let recorder = opts.record.map(Recorder::create).transpose()?;
+    let replayer = opts.replay.map(Replayer::open).transpose()?;
 
     if let Some(backend) = backend {
-        let code = Component::from_path(&opts.code)?;
-        let keep = backend.build(code, opts.sock.as_deref())?;
+        let code = Component::from_path(&path)?;
+        let keep = backend.build(code, &args, &debug, opts.sock.as_deref())?;
+
+        let proxies = Arc::new(Proxies {
+            trace,
+            policy,
+            threads: AtomicUsize::new(1),
+        });
+        forward_signals()?;
+        run(keep.clone().add_thread()?, proxies, recorder, replayer)?;
 
-        let mut thread = keep.clone().add_thread()?;
+        // The main thread of the payload exited, the last of the others ends the process.
         loop {
-            match thread.enter()? {
-                Command::SysCall(block) => unsafe {
-                    block.msg.rep = block.msg.req.syscall();
-                },
-                Command::Continue => (),
-            }
+            std::thread::park();
         }
+    } else if trace.is_some() {
+        anyhow::bail!("Keep backend 'nil' cannot be traced.");
+    } else if policy.is_some() {
+        anyhow::bail!("Keep backend 'nil' cannot enforce a syscall policy.");
+    } else if recorder.is_some() || replayer.is_some() {
+        anyhow::bail!("Keep backend 'nil' cannot record or replay syscalls.");
+    } else if args.tmpfs.is_some() {
+        anyhow::bail!("Keep backend 'nil' cannot serve a tmpfs.");
+    } else if args.sealed.is_some() {
+        anyhow::bail!("Keep backend 'nil' cannot seal files.");
+    } else if debug.gdb.is_some() {
+        anyhow::bail!("Keep backend 'nil' cannot be debugged with gdb.");
+    } else if debug.core_dump.is_some() {
+        anyhow::bail!("Keep backend 'nil' cannot dump core.");
     } else {
-        match keep {
-            Some(name) if name != "nil" => panic!("Keep backend '{}' is unsupported.", name),
-            _ => {
-                use std::env::args_os;
-                let cstr = CString::new(opts.code.as_os_str().as_bytes()).unwrap();
-                let name = CString::new(args_os().next().unwrap().as_os_str().as_bytes()).unwrap();
-                unsafe {
-                    libc::execl(
-                        cstr.as_ptr(),
-                        name.as_ptr(),
-                        cstr.as_ptr(),
-                        null::<c_char>(),
-                    )
-                };
+        let cstr = CString::new(path.as_os_str().as_bytes())?;
+
+        let argv = std::iter::once(Ok(cstr.clone()))
+            .chain(args.argv.into_iter().map(CString::new))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let envp = args
+            .envp
+            .into_iter()
+            .map(CString::new)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let mut argv: Vec<_> = argv.iter().map(|x| x.as_ptr()).collect();
+        argv.push(null::<c_char>());
+        let mut envp: Vec<_> = envp.iter().map(|x| x.as_ptr()).collect();
+        envp.push(null::<c_char>());
+
+        unsafe { libc::execve(cstr.as_ptr(), argv.as_ptr(), envp.as_ptr()) };
+        return Err(Error::last_os_error().into());
+    }
+
+    unreachable!();
+}
+
+/// The proxies for the syscalls of a keep
+struct Proxies {
+    trace: Option<(Option<File>, TraceFormat)>,
+    policy: Option<Policy>,
+    threads: AtomicUsize,
+}
+
+impl Proxies {
+    /// Creates the chain of proxies for a thread of the keep
+    fn chain(&self) -> Result<Chain> {
+        let mut chain = Chain::new(Host);
+
+        match self.trace.as_ref() {
+            None => (),
+            Some((None, format)) => chain = chain.layer(Tracer::new(std::io::stderr(), *format)),
+            Some((Some(file), format)) => {
+                let file = std::io::LineWriter::new(file.try_clone()?);
+                chain = chain.layer(Tracer::new(file, *format));
+            }
+        }
+
+        if let Some(policy) = self.policy.clone() {
+            chain = chain.layer(policy);
+        }
+
+        Ok(chain)
+    }
+}
+
+/// Executes the syscalls of the keep on the host, besides `exit`
+///
+/// On the host, `exit` would end the OS thread without unwinding it, so the
+/// `Thread` of the keep would never be dropped. `run` returns instead.
+struct Host;
+
+impl SyscallProxy for Host {
+    unsafe fn proxy(&mut self, req: &Request) -> Result<Reply> {
+        match usize::from(req.num) as i64 {
+            libc::SYS_exit => {
+                let ok_result: [Register<usize>; 2] = [0.into(), 0.into()];
+                Ok(Reply::from(Ok(ok_result)))
+            }
+            _ => Passthrough.proxy(req),
+        }
+    }
+}
+
+/// Enters a thread of the keep and proxies its syscalls, until it exits
+///
+/// Every thread the keep starts runs the same way, on an OS thread and with
+/// a chain of proxies of its own. The last thread to exit ends the process.
+fn run(
+    mut thread: Box<dyn Thread>,
+    proxies: Arc<Proxies>,
+    mut recorder: Option<Recorder>,
+    mut replayer: Option<Replayer>,
+) -> Result<()> {
+    let mut proxy = proxies.chain()?;
+
+    loop {
+        match thread.enter()? {
+            Command::SysCall(block) => {
+                let req = unsafe { block.msg.req };
+
+                if let Some(replayer) = replayer.as_mut() {
+                    replayer.handle(block, &mut proxy)?;
+                } else if let Some(recorder) = recorder.as_mut() {
+                    unsafe { recorder.handle(block, &mut proxy)? };
+                } else {
+                    block.msg.rep = unsafe { proxy.proxy(&req)? };
+                }
+
+                // Only the thread exits, while the others of the keep run on.
+                if usize::from(req.num) as i64 == libc::SYS_exit {
+                    if proxies.threads.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        std::process::exit(usize::from(req.arg[0]) as i32);
+                    }
+
+                    return Ok(());
+                }
+            }
+            Command::Continue => (),
+            Command::Spawn(thread) => {
+                // The order of the syscalls of several threads is not recorded.
+                if recorder.is_some() || replayer.is_some() {
+                    anyhow::bail!("Keeps with several threads cannot be recorded or replayed.");
+                }
+
+                proxies.threads.fetch_add(1, Ordering::SeqCst);
+
+                let proxies = proxies.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = run(thread, proxies, None, None) {
+                        eprintln!("Error: {:?}", e);
+                        std::process::exit(1);
+                    }
+                });
+            }
+            _ => anyhow::bail!("The keep returned an unknown command."),
+        }
+    }
+}
+
+/// Opens the host files of the manifest at the file descriptors the payload expects
+fn preopen(files: &[Preopen]) -> Result<()> {
+    for file in files {
+        let fd = std::fs::OpenOptions::new()
+            .read(true)
+            .write(file.write)
+            .open(&file.path)?
+            .into_raw_fd();
+
+        if fd != file.fd {
+            if unsafe { libc::dup2(fd, file.fd) } < 0 {
                 return Err(Error::last_os_error().into());
             }
+
+            unsafe { libc::close(fd) };
         }
     }
 
-    unreachable!();
+    Ok(())
+}
+
+/// The backends to try, from the command line or else from the manifest
+fn preferred(backend: Vec<String>, args: &Args) -> Vec<String> {
+    match (backend.is_empty(), args.manifest.as_ref()) {
+        (true, Some(manifest)) => manifest.backend.clone(),
+        _ => backend,
+    }
 }
@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The declarative description of a keep
+//!
+//! A manifest is written in TOML or, if its file name ends in `.cbor`,
+//! in CBOR. For example:
+//!
+//! ```toml
+//! code = "app"
+//! args = ["--verbose"]
+//! env = ["LANG=C"]
+//! backend = ["sgx", "sev"]
+//! tmpfs = "/tmp"
+//! sealed = "/data"
+//!
+//! [memory]
+//! heap = 134217728
+//! stack = 8388608
+//! threads = 8
+//!
+//! [[files]]
+//! fd = 3
+//! path = "config.toml"
+//!
+//! [policy]
+//! action = "deny"
+//! allow = [{ syscall = "write" }, { syscall = "exit_group" }]
+//! ```
+//!
+//! The unmodified bytes of the manifest are loaded into the keep, so they
+//! are part of its measurement.
+
+use crate::policy::Policy;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use std::path::{Path, PathBuf};
+
+/// The maximum size of a manifest
+const MAX_SIZE: usize = nbytes::bytes![64; KiB];
+
+/// The declarative description of a keep
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    /// The payload to run inside the keep, relative to the manifest
+    pub code: Option<PathBuf>,
+
+    /// The payload arguments, not including `argv[0]`
+    pub args: Vec<String>,
+
+    /// The payload environment variables in `KEY=VALUE` form
+    pub env: Vec<String>,
+
+    /// The backends to try, in order of preference
+    pub backend: Vec<String>,
+
+    /// The memory limits of the keep
+    pub memory: Memory,
+
+    /// The names of the syscalls the keep may proxy to the host, or all if unset
+    ///
+    /// This is a shorthand for `allow` rules of the policy.
+    pub syscalls: Option<Vec<String>>,
+
+    /// The policy for the syscalls the keep proxies to the host
+    pub policy: Option<Policy>,
+
+    /// The host files opened for the payload before it starts
+    pub files: Vec<Preopen>,
+
+    /// The absolute path of an in-memory filesystem served inside the keep
+    pub tmpfs: Option<String>,
+
+    /// The absolute path below which files are sealed to the keep
+    pub sealed: Option<String>,
+
+    /// The unmodified bytes of the manifest
+    #[serde(skip)]
+    pub raw: Vec<u8>,
+}
+
+/// The memory limits of a keep
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Memory {
+    /// The size of the heap in bytes (SGX and emulated only)
+    pub heap: Option<usize>,
+
+    /// The size of the initial payload stack in bytes (at most 1 GiB on kvm and sev)
+    pub stack: Option<usize>,
+
+    /// The number of threads the keep can run at once, each with a stack of its own (SGX
+    /// only, defaults to 1)
+    pub threads: Option<usize>,
+}
+
+/// A host file opened for the payload before it starts
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preopen {
+    /// The file descriptor number the payload finds the file at
+    pub fd: i32,
+
+    /// The path of the file on the host, relative to the manifest
+    pub path: PathBuf,
+
+    /// Whether the file is opened for writing as well
+    #[serde(default)]
+    pub write: bool,
+}
+
+impl Manifest {
+    /// Loads a manifest from a file
+    ///
+    /// All relative paths of the manifest are resolved against the
+    /// directory of the file.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let raw = std::fs::read(path)?;
+
+        let mut manifest = match path.extension().and_then(|e| e.to_str()) {
+            Some("cbor") => Self::from_cbor(raw)?,
+            _ => Self::from_toml(raw)?,
+        };
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        manifest.code = manifest.code.map(|code| dir.join(code));
+        for file in &mut manifest.files {
+            file.path = dir.join(&file.path);
+        }
+
+        Ok(manifest)
+    }
+
+    /// Parses a manifest in TOML format
+    pub fn from_toml(raw: Vec<u8>) -> Result<Self> {
+        Self::check(&raw)?;
+        let manifest = toml::from_slice(&raw)?;
+        Ok(Self { raw, ..manifest })
+    }
+
+    /// Parses a manifest in CBOR format
+    pub fn from_cbor(raw: Vec<u8>) -> Result<Self> {
+        Self::check(&raw)?;
+        let manifest = ciborium::de::from_reader(&raw[..])?;
+        Ok(Self { raw, ..manifest })
+    }
+
+    fn check(raw: &[u8]) -> Result<()> {
+        if raw.len() > MAX_SIZE {
+            bail!("the manifest is larger than {} bytes", MAX_SIZE);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The policy for the syscalls a keep proxies to the host
+//!
+//! A policy is read from a TOML file given to `exec --policy` or from the
+//! `policy` table of a manifest. For example:
+//!
+//! ```toml
+//! action = "deny"
+//!
+//! [[allow]]
+//! syscall = "write"
+//! args = [{ index = 0, values = [1, 2] }]
+//!
+//! [[allow]]
+//! syscall = "socket"
+//! family = ["inet", "inet6"]
+//!
+//! [[allow]]
+//! syscall = "connect"
+//! address = ["127.0.0.1:8080", "::1"]
+//!
+//! [[deny]]
+//! syscall = "listen"
+//! ```
+//!
+//! A syscall is allowed if it matches none of the `deny` rules and, if there
+//! is an `allow` list, at least one of the `allow` rules.
+
+use crate::backend::{Layer, SyscallProxy};
+use crate::manifest::Manifest;
+use crate::names::{name, number};
+use crate::sallyport::{Reply, Request};
+
+use anyhow::{anyhow, Result};
+use libc::c_int;
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+
+/// What happens to the syscalls a policy does not allow
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// The syscall fails with `EPERM`
+    Deny,
+
+    /// The syscall is logged to stderr, but proxied anyway
+    Log,
+}
+
+impl Default for Action {
+    fn default() -> Self {
+        Self::Deny
+    }
+}
+
+/// The policy for the syscalls a keep proxies to the host
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// What happens to the syscalls the policy does not allow
+    pub action: Action,
+
+    /// The syscalls that are allowed, or all if unset
+    pub allow: Option<Vec<Rule>>,
+
+    /// The syscalls that are not allowed, even if they match `allow`
+    pub deny: Vec<Rule>,
+}
+
+/// A rule matching syscalls by number and arguments
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// The name or number of the syscall
+    pub syscall: Syscall,
+
+    /// The values the arguments must have
+    #[serde(default)]
+    pub args: Vec<ArgValues>,
+
+    /// The address families the socket may have (`socket` only)
+    pub family: Option<Vec<Family>>,
+
+    /// The addresses that may be used (`bind`, `connect` and `sendto` only)
+    pub address: Option<Vec<Address>>,
+}
+
+/// The values a syscall argument must have to match a rule
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArgValues {
+    /// The index of the argument, starting at 0
+    pub index: usize,
+
+    /// The values the argument may have
+    pub values: Vec<usize>,
+}
+
+/// A syscall number, which is given by name or number
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "SyscallId", into = "SyscallId")]
+pub struct Syscall(pub i64);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SyscallId {
+    Number(i64),
+    Name(String),
+}
+
+impl TryFrom<SyscallId> for Syscall {
+    type Error = String;
+
+    fn try_from(id: SyscallId) -> std::result::Result<Self, Self::Error> {
+        match id {
+            SyscallId::Number(num) => Ok(Self(num)),
+            SyscallId::Name(name) => match number(&name) {
+                Some(num) => Ok(Self(num)),
+                None => Err(format!("unknown syscall '{}'", name)),
+            },
+        }
+    }
+}
+
+impl From<Syscall> for SyscallId {
+    fn from(syscall: Syscall) -> Self {
+        match name(syscall.0) {
+            Some(name) => Self::Name(name.into()),
+            None => Self::Number(syscall.0),
+        }
+    }
+}
+
+/// A socket address family
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    /// `AF_UNIX`
+    Unix,
+
+    /// `AF_INET`
+    Inet,
+
+    /// `AF_INET6`
+    Inet6,
+}
+
+impl Family {
+    fn value(self) -> c_int {
+        match self {
+            Self::Unix => libc::AF_UNIX,
+            Self::Inet => libc::AF_INET,
+            Self::Inet6 => libc::AF_INET6,
+        }
+    }
+}
+
+/// An IP address with an optional port, e.g. `127.0.0.1:8080` or `::1`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Address {
+    /// The IP address
+    pub ip: IpAddr,
+
+    /// The port, or any if unset
+    pub port: Option<u16>,
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Self {
+                ip: addr.ip(),
+                port: Some(addr.port()),
+            });
+        }
+
+        match s.parse() {
+            Ok(ip) => Ok(Self { ip, port: None }),
+            Err(_) => Err(format!("invalid address '{}'", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Address> for String {
+    fn from(addr: Address) -> Self {
+        match addr.port {
+            Some(port) => SocketAddr::new(addr.ip, port).to_string(),
+            None => addr.ip.to_string(),
+        }
+    }
+}
+
+impl Address {
+    /// Checks whether `addr` is this address
+    ///
+    /// An IPv4-mapped IPv6 address like `::ffff:127.0.0.1` reaches the same
+    /// host as its IPv4 address, so both are compared in canonical form.
+    fn matches(&self, addr: SocketAddr) -> bool {
+        self.ip.to_canonical() == addr.ip().to_canonical()
+            && self.port.map_or(true, |port| port == addr.port())
+    }
+}
+
+impl Policy {
+    /// Loads a policy from a TOML file
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(toml::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// Returns the policy of a manifest, if it has one
+    ///
+    /// The `syscalls` of the manifest are added to the `allow` rules.
+    pub fn from_manifest(manifest: &Manifest) -> Result<Option<Self>> {
+        let mut policy = manifest.policy.clone();
+
+        if let Some(syscalls) = manifest.syscalls.as_ref() {
+            let allow = policy
+                .get_or_insert_with(Default::default)
+                .allow
+                .get_or_insert_with(Vec::new);
+
+            for syscall in syscalls {
+                let num =
+                    number(syscall).ok_or_else(|| anyhow!("unknown syscall '{}'", syscall))?;
+
+                allow.push(Rule {
+                    syscall: Syscall(num),
+                    args: Vec::new(),
+                    family: None,
+                    address: None,
+                });
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Checks whether a request may be proxied to the host
+    ///
+    /// Returns `EPERM` if the policy does not allow the request.
+    ///
+    /// # Safety
+    ///
+    /// The socket addresses of the request are read, so its pointer
+    /// arguments must point to memory the host can read. They should have
+    /// been validated to point into the checked copy of the syscall block,
+    /// which the keep cannot change, so the request that is executed is the
+    /// one that was checked.
+    pub unsafe fn check(&self, req: &Request) -> std::result::Result<(), c_int> {
+        let allowed = match self.allow.as_ref() {
+            Some(allow) => allow.iter().any(|rule| rule.matches(req)),
+            None => true,
+        };
+
+        if allowed && !self.deny.iter().any(|rule| rule.matches(req)) {
+            return Ok(());
+        }
+
+        match self.action {
+            Action::Deny => Err(libc::EPERM),
+            Action::Log => {
+                let num = usize::from(req.num) as i64;
+                match name(num) {
+                    Some(name) => eprintln!("policy violation: {}", name),
+                    None => eprintln!("policy violation: syscall {}", num),
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Layer for Policy {
+    unsafe fn handle(&mut self, req: &Request, next: &mut dyn SyscallProxy) -> Result<Reply> {
+        match self.check(req) {
+            Ok(()) => next.proxy(req),
+            Err(errno) => Ok(Reply::from(Err(errno))),
+        }
+    }
+}
+
+impl Rule {
+    /// Whether the rule matches `req`, whose pointers the host can read
+    unsafe fn matches(&self, req: &Request) -> bool {
+        let num = usize::from(req.num) as i64;
+        let arg = |i: usize| usize::from(req.arg[i]);
+
+        if num != self.syscall.0 {
+            return false;
+        }
+
+        for values in &self.args {
+            match req.arg.get(values.index) {
+                Some(value) if values.values.contains(&usize::from(*value)) => (),
+                _ => return false,
+            }
+        }
+
+        if let (libc::SYS_socket, Some(families)) = (num, self.family.as_ref()) {
+            if !families.iter().any(|f| f.value() == arg(0) as c_int) {
+                return false;
+            }
+        }
+
+        if let Some(addresses) = self.address.as_ref() {
+            let addr = match num {
+                libc::SYS_bind | libc::SYS_connect => sockaddr(arg(1), arg(2)),
+                libc::SYS_sendto if arg(4) != 0 => sockaddr(arg(4), arg(5)),
+                _ => return true,
+            };
+
+            return addr.map_or(false, |addr| addresses.iter().any(|a| a.matches(addr)));
+        }
+
+        true
+    }
+}
+
+/// Reads an `AF_INET` or `AF_INET6` socket address of the request
+///
+/// `ptr` has to point to `len` bytes the host can read.
+unsafe fn sockaddr(ptr: usize, len: usize) -> Option<SocketAddr> {
+    if len < size_of::<libc::sa_family_t>() {
+        return None;
+    }
+
+    let family = (ptr as *const libc::sa_family_t).read_unaligned();
+
+    match family as c_int {
+        libc::AF_INET if len >= size_of::<libc::sockaddr_in>() => {
+            let sin = (ptr as *const libc::sockaddr_in).read_unaligned();
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(SocketAddr::new(ip.into(), u16::from_be(sin.sin_port)))
+        }
+
+        libc::AF_INET6 if len >= size_of::<libc::sockaddr_in6>() => {
+            let sin6 = (ptr as *const libc::sockaddr_in6).read_unaligned();
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Some(SocketAddr::new(ip.into(), u16::from_be(sin6.sin6_port)))
+        }
+
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request;
+
+    use primordial::Register;
+
+    fn connect(addr: &libc::sockaddr_in6) -> Request {
+        let ptr = addr as *const libc::sockaddr_in6 as usize;
+        request!(libc::SYS_connect => 3, ptr, size_of::<libc::sockaddr_in6>())
+    }
+
+    #[test]
+    fn ipv4_mapped() {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[deny]]
+            syscall = "connect"
+            address = ["127.0.0.1"]
+            "#,
+        )
+        .unwrap();
+
+        let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+        addr.sin6_family = libc::AF_INET6 as _;
+        addr.sin6_port = 8080u16.to_be();
+
+        addr.sin6_addr.s6_addr = Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets();
+        assert_eq!(unsafe { policy.check(&connect(&addr)) }, Err(libc::EPERM));
+
+        addr.sin6_addr.s6_addr = Ipv6Addr::LOCALHOST.octets();
+        assert_eq!(unsafe { policy.check(&connect(&addr)) }, Ok(()));
+    }
+}
@@ -0,0 +1,454 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Recording and replaying of the syscalls a keep proxies to the host
+//!
+//! A recording is a sequence of CBOR records, one per syscall. Each holds
+//! the request with the buffers it passes to the host, the reply and every
+//! byte of the `Block` the host changed while handling the request.
+//! Replaying a recording checks that the keep issues the same requests and
+//! writes the same bytes back to the block instead of executing them, so
+//! the keep sees exactly the replies it saw while it was recorded.
+
+use crate::backend::SyscallProxy;
+use crate::names::name;
+use crate::sallyport::{Block, Request, Result as SysResult};
+use crate::syscall::SYS_ENARX_GETATT;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::mem::size_of;
+use std::path::Path;
+
+/// Changed bytes separated by at most this many unchanged ones are merged
+/// into one patch
+const GAP: usize = 8;
+
+/// A syscall of a recording
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// The syscall number
+    pub num: usize,
+
+    /// The syscall arguments
+    ///
+    /// Arguments pointing into the block are recorded as offsets from its
+    /// start, because the block is at another address in every run.
+    pub args: [usize; 7],
+
+    /// The buffers the request passes to the host, before the syscall
+    pub inputs: Vec<Patch>,
+
+    /// The return values of the syscall or its error number
+    pub reply: std::result::Result<[usize; 2], i32>,
+
+    /// The bytes of the block the host changed, including the reply
+    pub patches: Vec<Patch>,
+}
+
+/// A run of bytes in a `Block`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Patch {
+    /// The offset of the bytes from the start of the block
+    pub offset: usize,
+
+    /// The bytes at the offset
+    #[serde(with = "serde_bytes")]
+    pub bytes: Vec<u8>,
+}
+
+/// Records the syscalls of a keep while a proxy handles them
+pub struct Recorder {
+    out: Box<dyn Write + Send>,
+}
+
+impl Recorder {
+    /// Creates a recorder writing to `out`
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self { out: Box::new(out) }
+    }
+
+    /// Creates a recorder writing to a new file
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Hands the request in `block` to `proxy` and records the outcome
+    ///
+    /// Every record is flushed, because the keep may end the process while
+    /// the next request is handled.
+    ///
+    /// # Safety
+    ///
+    /// `block` must be the checked copy of a `Command::SysCall`, whose
+    /// request only points into it, because `proxy` may execute it.
+    pub unsafe fn handle(&mut self, block: &mut Block, proxy: &mut dyn SyscallProxy) -> Result<()> {
+        let req = block.msg.req;
+        let args = args(block, &req);
+        let inputs = inputs(block, &req);
+        let before = bytes(block).to_vec();
+
+        let rep = proxy.proxy(&req)?;
+        block.msg.rep = rep;
+
+        let record = Record {
+            num: req.num.into(),
+            args,
+            inputs,
+            reply: SysResult::from(rep).map(|ret| [ret[0].into(), ret[1].into()]),
+            patches: diff(&before, bytes(block)),
+        };
+
+        ciborium::ser::into_writer(&record, &mut self.out)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Replays a recording to a keep instead of executing its syscalls
+pub struct Replayer {
+    input: Box<dyn BufRead + Send>,
+
+    /// The number of syscalls replayed so far
+    count: usize,
+}
+
+impl Replayer {
+    /// Creates a replayer reading a recording from `input`
+    pub fn new(input: impl BufRead + Send + 'static) -> Self {
+        Self {
+            input: Box::new(input),
+            count: 0,
+        }
+    }
+
+    /// Creates a replayer reading a recording from a file
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+
+    /// Writes the recorded outcome of the request in `block` to the block
+    ///
+    /// `exit` and `exit_group` are handed to `proxy` instead, so the keep
+    /// ends like it did while recording. Fails if the keep issues a
+    /// different syscall than the recorded one, with other arguments or
+    /// buffers, or more syscalls.
+    pub fn handle(&mut self, block: &mut Block, proxy: &mut dyn SyscallProxy) -> Result<()> {
+        let req = unsafe { block.msg.req };
+        let num = usize::from(req.num);
+
+        // Neither syscall takes a pointer.
+        if num == libc::SYS_exit as usize || num == libc::SYS_exit_group as usize {
+            block.msg.rep = unsafe { proxy.proxy(&req)? };
+            return Ok(());
+        }
+
+        let record = match self.next()? {
+            Some(record) => record,
+            None => bail!("the recording ended after {} syscalls", self.count),
+        };
+
+        if record.num != num {
+            bail!(
+                "the keep diverged from the recording at syscall {}: expected {}, found {}",
+                self.count,
+                describe(record.num),
+                describe(num)
+            );
+        }
+
+        if record.args != args(block, &req) {
+            bail!(
+                "the keep diverged from the recording at syscall {}: {} with other arguments",
+                self.count,
+                describe(num)
+            );
+        }
+
+        if record.inputs != inputs(block, &req) {
+            bail!(
+                "the keep diverged from the recording at syscall {}: {} with other buffers",
+                self.count,
+                describe(num)
+            );
+        }
+
+        let bytes = bytes_mut(block);
+        for patch in record.patches {
+            let end = patch.offset.saturating_add(patch.bytes.len());
+            let range = bytes
+                .get_mut(patch.offset..end)
+                .ok_or_else(|| anyhow!("the recording patches beyond the block"))?;
+            range.copy_from_slice(&patch.bytes);
+        }
+
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Reads the next record, if any
+    fn next(&mut self) -> Result<Option<Record>> {
+        if self.input.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(ciborium::de::from_reader(&mut self.input)?))
+    }
+}
+
+/// Returns the name of a syscall, or its number if it has none
+fn describe(num: usize) -> String {
+    match name(num as i64) {
+        Some(name) => name.into(),
+        None => format!("syscall {}", num as i64),
+    }
+}
+
+/// Returns the arguments of a request in `block`, as recorded
+fn args(block: &Block, req: &Request) -> [usize; 7] {
+    let start = block as *const Block as usize;
+    let mut args = [0usize; 7];
+
+    for (arg, reg) in args.iter_mut().zip(req.arg.iter()) {
+        let value = usize::from(*reg);
+        *arg = match value.checked_sub(start) {
+            Some(offset) if offset < size_of::<Block>() => offset,
+            _ => value,
+        };
+    }
+
+    args
+}
+
+/// Collects the buffers a request in `block` passes to the host
+///
+/// `iovec` arrays are left out, because they hold addresses. The request
+/// must have been checked to only point into `block`.
+fn inputs(block: &Block, req: &Request) -> Vec<Patch> {
+    let bytes = bytes(block);
+    let start = block as *const Block as usize;
+    let arg = |i: usize| usize::from(req.arg[i]);
+
+    let buf = |ptr: usize, len: usize| -> Option<Patch> {
+        let offset = ptr.checked_sub(start)?;
+        let bytes = bytes.get(offset..offset.checked_add(len)?)?;
+        Some(Patch {
+            offset,
+            bytes: bytes.to_vec(),
+        })
+    };
+
+    let cstr = |ptr: usize| -> Option<Patch> {
+        let offset = ptr.checked_sub(start)?;
+        let len = bytes.get(offset..)?.iter().position(|b| *b == 0)?;
+        buf(ptr, len + 1)
+    };
+
+    let socklen = size_of::<libc::socklen_t>();
+    let mut inputs = Vec::new();
+
+    match usize::from(req.num) as i64 {
+        libc::SYS_write | libc::SYS_pwrite64 => inputs.extend(buf(arg(1), arg(2))),
+        libc::SYS_writev => {
+            let iovecs = buf(arg(1), arg(2).saturating_mul(size_of::<libc::iovec>()));
+            for iov in iovecs
+                .iter()
+                .flat_map(|p| p.bytes.chunks(size_of::<libc::iovec>()))
+            {
+                let base = usize::from_ne_bytes(iov[..8].try_into().unwrap());
+                let len = usize::from_ne_bytes(iov[8..].try_into().unwrap());
+                inputs.extend(buf(base, len));
+            }
+        }
+        libc::SYS_openat | libc::SYS_newfstatat => inputs.extend(cstr(arg(1))),
+        libc::SYS_unlink | libc::SYS_mkdir => inputs.extend(cstr(arg(0))),
+        libc::SYS_poll => inputs.extend(buf(
+            arg(0),
+            arg(1).saturating_mul(size_of::<libc::pollfd>()),
+        )),
+        libc::SYS_ioctl if arg(1) == libc::FIONBIO as usize => {
+            inputs.extend(buf(arg(2), size_of::<libc::c_int>()))
+        }
+        libc::SYS_epoll_ctl => inputs.extend(buf(arg(3), size_of::<libc::epoll_event>())),
+        libc::SYS_bind | libc::SYS_connect => inputs.extend(buf(arg(1), arg(2))),
+        libc::SYS_accept | libc::SYS_accept4 | libc::SYS_getsockname => {
+            inputs.extend(buf(arg(2), socklen))
+        }
+        libc::SYS_setsockopt => inputs.extend(buf(arg(3), arg(4))),
+        libc::SYS_recvfrom => inputs.extend(buf(arg(5), socklen)),
+        libc::SYS_sendto => {
+            inputs.extend(buf(arg(1), arg(2)));
+            inputs.extend(buf(arg(4), arg(5)));
+        }
+        SYS_ENARX_GETATT => inputs.extend(buf(arg(0), arg(1))),
+        _ => (),
+    }
+
+    inputs
+}
+
+/// Collects the runs of bytes which differ between two blocks
+fn diff(before: &[u8], after: &[u8]) -> Vec<Patch> {
+    let mut patches: Vec<Patch> = Vec::new();
+
+    for (offset, (old, new)) in before.iter().zip(after).enumerate() {
+        if old == new {
+            continue;
+        }
+
+        match patches.last_mut() {
+            Some(patch) if offset - (patch.offset + patch.bytes.len()) <= GAP => {
+                let end = patch.offset + patch.bytes.len();
+                patch.bytes.extend_from_slice(&after[end..=offset]);
+            }
+
+            _ => patches.push(Patch {
+                offset,
+                bytes: vec![*new],
+            }),
+        }
+    }
+
+    patches
+}
+
+fn bytes(block: &Block) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(block as *const Block as *const u8, size_of::<Block>()) }
+}
+
+fn bytes_mut(block: &mut Block) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(block as *mut Block as *mut u8, size_of::<Block>()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request;
+    use crate::sallyport::Reply;
+
+    use primordial::Register;
+    use std::io::Cursor;
+
+    /// A proxy for syscalls a replay must not execute
+    struct Unreachable;
+
+    impl SyscallProxy for Unreachable {
+        unsafe fn proxy(&mut self, _: &Request) -> Result<Reply> {
+            unreachable!()
+        }
+    }
+
+    /// Places `bytes` at `offset` in the buffer of `block` and returns their address
+    fn place(block: &mut Block, offset: usize, bytes: &[u8]) -> usize {
+        let (c, _) = block.cursor().alloc::<u8>(offset).unwrap();
+        let (_, dst) = c.copy_from_slice(bytes).unwrap();
+        dst.as_ptr() as usize
+    }
+
+    /// Issues a `write` of `bytes` to `fd` in `block`
+    fn write(block: &mut Block, fd: usize, bytes: &[u8]) {
+        let ptr = place(block, 0, bytes);
+        block.msg.req = request!(libc::SYS_write => fd, ptr, bytes.len());
+    }
+
+    /// Records the request in `block` as if the host changed `patches`
+    fn record(block: &Block, patches: Vec<Patch>) -> Record {
+        let req = unsafe { block.msg.req };
+        Record {
+            num: req.num.into(),
+            args: args(block, &req),
+            inputs: inputs(block, &req),
+            reply: Ok([3, 0]),
+            patches,
+        }
+    }
+
+    fn replayer(records: &[Record]) -> Replayer {
+        let mut recording = Vec::new();
+        for record in records {
+            ciborium::ser::into_writer(record, &mut recording).unwrap();
+        }
+
+        Replayer::new(Cursor::new(recording))
+    }
+
+    #[test]
+    fn diff_gap() {
+        let before = [0u8; 32];
+        let mut after = before;
+        after[2] = 1;
+        after[3] = 2;
+        after[12] = 3;
+        after[22] = 4;
+
+        // 8 unchanged bytes are merged into the patch, 9 are not.
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                Patch {
+                    offset: 2,
+                    bytes: vec![1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 3],
+                },
+                Patch {
+                    offset: 22,
+                    bytes: vec![4],
+                },
+            ]
+        );
+
+        assert_eq!(diff(&before, &before), vec![]);
+    }
+
+    #[test]
+    fn replay() {
+        let mut block = Box::new(Block::default());
+        write(&mut block, 1, b"foo");
+        let patch = Patch {
+            offset: 1024,
+            bytes: vec![1, 2, 3],
+        };
+
+        let mut replayer = replayer(&[record(&block, vec![patch.clone()])]);
+        replayer.handle(&mut block, &mut Unreachable).unwrap();
+        assert_eq!(bytes(&block)[1024..1027], patch.bytes[..]);
+
+        // The recording has ended.
+        assert!(replayer.handle(&mut block, &mut Unreachable).is_err());
+    }
+
+    #[test]
+    fn diverged_num() {
+        let mut block = Box::new(Block::default());
+        write(&mut block, 1, b"foo");
+        let mut replayer = replayer(&[record(&block, vec![])]);
+
+        block.msg.req.num = (libc::SYS_read as usize).into();
+        let err = replayer.handle(&mut block, &mut Unreachable).unwrap_err();
+        assert!(err.to_string().contains("expected write, found read"));
+    }
+
+    #[test]
+    fn diverged_args() {
+        let mut block = Box::new(Block::default());
+        write(&mut block, 1, b"foo");
+        let mut replayer = replayer(&[record(&block, vec![])]);
+
+        write(&mut block, 2, b"foo");
+        let err = replayer.handle(&mut block, &mut Unreachable).unwrap_err();
+        assert!(err.to_string().contains("other arguments"));
+    }
+
+    #[test]
+    fn diverged_inputs() {
+        let mut block = Box::new(Block::default());
+        write(&mut block, 1, b"foo");
+        let mut replayer = replayer(&[record(&block, vec![])]);
+
+        write(&mut block, 1, b"bar");
+        let err = replayer.handle(&mut block, &mut Unreachable).unwrap_err();
+        assert!(err.to_string().contains("other buffers"));
+    }
+}
@@ -18,11 +18,31 @@ pub const SYS_ENARX_BALLOON_MEMORY: i64 = 0xEA03;
 #[allow(dead_code)]
 pub const SYS_ENARX_CPUID: i64 = 0xEA04;
 
+/// Enarx syscall extension: report the frames of a backtrace to the host
+#[allow(dead_code)]
+pub const SYS_ENARX_BACKTRACE: i64 = 0xEA05;
+
+/// Enarx syscall extension: start another vCPU or enclave thread of the keep
+#[allow(dead_code)]
+pub const SYS_ENARX_SPAWN: i64 = 0xEA06;
+
+/// Enarx syscall extension: fetch the host signals forwarded to the keep
+#[allow(dead_code)]
+pub const SYS_ENARX_SIGNALS: i64 = 0xEA07;
+
 /// Enarx syscall extension: Resume an enclave after an asynchronous exit
 // Keep in sync with shim-sgx/src/start.S
 #[allow(dead_code)]
 pub const SYS_ENARX_ERESUME: i64 = -1;
 
+/// The size of the NUL padded mount point of the in-keep tmpfs
+#[allow(dead_code)]
+pub const TMPFS_PATH_MAX: usize = 32;
+
+/// The size of the NUL padded prefix of the sealed files
+#[allow(dead_code)]
+pub const SEALED_PATH_MAX: usize = 32;
+
 /// `get_attestation` technology return value
 ///
 /// See https://github.com/enarx/enarx-keepldr/issues/31
@@ -0,0 +1,156 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tracing of the syscalls a keep proxies to the host
+
+use crate::backend::{Layer, SyscallProxy};
+use crate::names::name;
+use crate::sallyport::{Reply, Request, Result as SysResult};
+
+use serde::Serialize;
+
+use std::io::{Error, Result, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The syscalls the host never returns from, which are logged before
+const NORETURN: &[i64] = &[libc::SYS_exit, libc::SYS_exit_group];
+
+/// The output formats of a `Tracer`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One strace-like line per syscall
+    Text,
+
+    /// One JSON object per line and syscall
+    Json,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown trace format '{}'", s)),
+        }
+    }
+}
+
+/// A single traced syscall in JSON format
+#[derive(Serialize)]
+struct Record<'a> {
+    /// The time the syscall was issued, in seconds since the UNIX epoch
+    time: f64,
+
+    /// The time the host took to handle the syscall, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency: Option<f64>,
+
+    num: usize,
+    name: Option<&'a str>,
+    args: [usize; 6],
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ret: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errno: Option<i32>,
+}
+
+/// A `Layer` logging every syscall a keep proxies to the host along with its reply
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+}
+
+impl Tracer {
+    /// Creates a tracer writing to `out`
+    pub fn new(out: impl Write + Send + 'static, format: TraceFormat) -> Self {
+        Self {
+            out: Box::new(out),
+            format,
+        }
+    }
+
+    /// Logs `req` with its reply and latency, or without for a syscall never returning
+    fn log(&mut self, req: &Request, rep: Option<(Reply, Duration)>, time: Duration) -> Result<()> {
+        let num = usize::from(req.num);
+        let name = name(num as i64);
+
+        let mut args = [0usize; 6];
+        for (arg, reg) in args.iter_mut().zip(req.arg.iter()) {
+            *arg = usize::from(*reg);
+        }
+
+        let res = rep.map(|(rep, latency)| (SysResult::from(rep), latency));
+
+        match self.format {
+            TraceFormat::Json => {
+                let record = Record {
+                    time: time.as_secs_f64(),
+                    latency: res.map(|(_, latency)| latency.as_secs_f64()),
+                    num,
+                    name,
+                    args,
+                    ret: res.and_then(|(res, _)| res.ok()).map(|ret| ret[0].into()),
+                    errno: res.and_then(|(res, _)| res.err()),
+                };
+
+                serde_json::to_writer(&mut self.out, &record)?;
+                writeln!(self.out)
+            }
+
+            TraceFormat::Text => {
+                let args: Vec<_> = args.iter().map(|a| format!("{:#x}", a)).collect();
+                let args = args.join(", ");
+
+                let result = match res {
+                    Some((Ok(ret), latency)) => format!(
+                        "{} <{:.6}>",
+                        usize::from(ret[0]) as isize,
+                        latency.as_secs_f64()
+                    ),
+                    Some((Err(errno), latency)) => format!(
+                        "-1 ({}) <{:.6}>",
+                        Error::from_raw_os_error(errno),
+                        latency.as_secs_f64()
+                    ),
+                    None => "?".into(),
+                };
+
+                write!(self.out, "{}.{:06} ", time.as_secs(), time.subsec_micros())?;
+                match name {
+                    Some(name) => write!(self.out, "{}({})", name, args)?,
+                    None => write!(self.out, "syscall_{:#x}({})", num, args)?,
+                }
+                writeln!(self.out, " = {}", result)
+            }
+        }
+    }
+}
+
+impl Layer for Tracer {
+    unsafe fn handle(
+        &mut self,
+        req: &Request,
+        next: &mut dyn SyscallProxy,
+    ) -> anyhow::Result<Reply> {
+        let time = SystemTime::now();
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        // Like strace, log the syscalls never returning without a result.
+        if NORETURN.contains(&(usize::from(req.num) as i64)) {
+            self.log(req, None, since)?;
+            self.out.flush()?;
+            return next.proxy(req);
+        }
+
+        let start = Instant::now();
+        let rep = next.proxy(req)?;
+        let latency = start.elapsed();
+
+        self.log(req, Some((rep, latency)), since)?;
+        Ok(rep)
+    }
+}
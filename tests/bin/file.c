@@ -0,0 +1,81 @@
+#include "libc.h"
+#include <fcntl.h>
+
+#define DIR "/tmp/enarx-keepldr-file-test"
+#define FILE_PATH DIR "/file"
+
+/* Not defined by glibc without _GNU_SOURCE */
+struct linux_dirent64 {
+    ino_t d_ino;
+    off_t d_off;
+    unsigned short d_reclen;
+    unsigned char d_type;
+    char d_name[];
+};
+
+int equal(const char *a, const char *b, size_t len) {
+    for (size_t i = 0; i < len; i++)
+        if (a[i] != b[i])
+            return 0;
+
+    return 1;
+}
+
+int main(void) {
+    const char data[] = "hello, world";
+    char buf[256];
+    struct stat st;
+
+    if (mkdir(DIR, 0700) < 0)
+        return 1;
+
+    int fd = openat(AT_FDCWD, FILE_PATH, O_CREAT | O_EXCL | O_RDWR, 0600);
+    if (fd < 0)
+        return 2;
+
+    if (pwrite(fd, data, sizeof(data) - 1, 0) != sizeof(data) - 1)
+        return 3;
+
+    if (lseek(fd, 0, SEEK_END) != sizeof(data) - 1)
+        return 4;
+
+    if (pread(fd, buf, 5, 7) != 5 || !equal(buf, "world", 5))
+        return 5;
+
+    if (fstatat(AT_FDCWD, FILE_PATH, &st, 0) < 0)
+        return 6;
+
+    if (!S_ISREG(st.st_mode) || st.st_size != sizeof(data) - 1)
+        return 7;
+
+    if (close(fd) < 0)
+        return 8;
+
+    int dir = openat(AT_FDCWD, DIR, O_RDONLY | O_DIRECTORY);
+    if (dir < 0)
+        return 9;
+
+    ssize_t len = getdents64(dir, buf, sizeof(buf));
+    if (len <= 0)
+        return 10;
+
+    int found = 0;
+    for (ssize_t off = 0; off < len;) {
+        struct linux_dirent64 *d = (struct linux_dirent64 *) (buf + off);
+        found |= equal(d->d_name, "file", 5);
+        off += d->d_reclen;
+    }
+
+    if (!found)
+        return 11;
+
+    close(dir);
+
+    if (unlink(FILE_PATH) < 0)
+        return 12;
+
+    if (fstatat(AT_FDCWD, FILE_PATH, &st, 0) == 0 || errno != ENOENT)
+        return 13;
+
+    return 0;
+}
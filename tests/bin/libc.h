@@ -5,6 +5,8 @@
 #include <time.h>
 #include <sys/types.h>
 #include <sys/socket.h>
+#include <sys/stat.h>
+#include <stdarg.h>
 
 int *__errno_location(void) {
     static int errnum = 0;
@@ -331,3 +333,155 @@ ssize_t recvfrom(int sockfd, void *buf, size_t len, int flags,
 
     return rax;
 }
+
+int openat(int dirfd, const char *pathname, int flags, ...) {
+    int rax;
+    va_list ap;
+
+    va_start(ap, flags);
+    register mode_t r10 __asm__("r10") = va_arg(ap, mode_t);
+    va_end(ap);
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_openat), "D" (dirfd), "S" (pathname), "d" (flags), "r" (r10)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int fstatat(int dirfd, const char *pathname, struct stat *statbuf, int flags) {
+    int rax;
+    register int r10 __asm__("r10") = flags;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_newfstatat), "D" (dirfd), "S" (pathname), "d" (statbuf), "r" (r10)
+    : "%rcx", "%r11", "memory"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+off_t lseek(int fd, off_t offset, int whence) {
+    off_t rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_lseek), "D" (fd), "S" (offset), "d" (whence)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+ssize_t pread(int fd, void *buf, size_t count, off_t offset) {
+    ssize_t rax;
+    register off_t r10 __asm__("r10") = offset;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_pread64), "D" (fd), "S" (buf), "d" (count), "r" (r10)
+    : "%rcx", "%r11", "memory"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+ssize_t pwrite(int fd, const void *buf, size_t count, off_t offset) {
+    ssize_t rax;
+    register off_t r10 __asm__("r10") = offset;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_pwrite64), "D" (fd), "S" (buf), "d" (count), "r" (r10)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+ssize_t getdents64(int fd, void *dirp, size_t count) {
+    ssize_t rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_getdents64), "D" (fd), "S" (dirp), "d" (count)
+    : "%rcx", "%r11", "memory"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int unlink(const char *pathname) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_unlink), "D" (pathname)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int mkdir(const char *pathname, mode_t mode) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_mkdir), "D" (pathname), "S" (mode)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
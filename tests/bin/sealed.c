@@ -0,0 +1,83 @@
+#include "libc.h"
+#include <fcntl.h>
+
+/* Sealed with `--sealed`, so the host only sees ciphertext */
+#define PREFIX "/tmp/enarx-keepldr-sealed-test"
+#define FILE_PATH PREFIX "/file"
+
+/* Other spellings of FILE_PATH, which must be sealed as well */
+#define UP "../../../../../../../../../../../../../../../../../../../../"
+const char *const SPELLINGS[] = {
+    "/" FILE_PATH,
+    "/." FILE_PATH,
+    "/var/.." FILE_PATH,
+    UP UP FILE_PATH,
+};
+
+int equal(const char *a, const char *b, size_t len) {
+    for (size_t i = 0; i < len; i++)
+        if (a[i] != b[i])
+            return 0;
+
+    return 1;
+}
+
+int main(void) {
+    const char data[] = "sealed secret";
+    char buf[256];
+    struct stat st;
+
+    int fd = openat(AT_FDCWD, FILE_PATH, O_CREAT | O_TRUNC | O_WRONLY, 0600);
+    if (fd < 0)
+        return 1;
+
+    if (write(fd, data, sizeof(data) - 1) != sizeof(data) - 1)
+        return 2;
+
+    if (read(fd, buf, sizeof(buf)) >= 0 || errno != EBADF)
+        return 3;
+
+    if (close(fd) < 0)
+        return 4;
+
+    fd = openat(AT_FDCWD, FILE_PATH, O_RDWR);
+    if (fd < 0)
+        return 5;
+
+    if (read(fd, buf, sizeof(buf)) != sizeof(data) - 1 || !equal(buf, data, sizeof(data) - 1))
+        return 6;
+
+    /* Overwrite "secret" in the middle of the sealed block */
+    if (pwrite(fd, "SECRET", 6, 7) != 6)
+        return 7;
+
+    if (pread(fd, buf, 6, 7) != 6 || !equal(buf, "SECRET", 6))
+        return 8;
+
+    if (lseek(fd, 0, SEEK_END) != sizeof(data) - 1)
+        return 9;
+
+    if (fstatat(AT_FDCWD, FILE_PATH, &st, 0) < 0)
+        return 10;
+
+    if (!S_ISREG(st.st_mode) || st.st_size != sizeof(data) - 1)
+        return 11;
+
+    if (close(fd) < 0)
+        return 12;
+
+    /* The host would return the ciphertext instead */
+    for (size_t i = 0; i < sizeof(SPELLINGS) / sizeof(SPELLINGS[0]); i++) {
+        fd = openat(AT_FDCWD, SPELLINGS[i], O_RDONLY);
+        if (fd < 0)
+            return 13;
+
+        if (read(fd, buf, sizeof(buf)) != sizeof(data) - 1 || !equal(buf, "sealed SECRET", 13))
+            return 14;
+
+        if (close(fd) < 0)
+            return 15;
+    }
+
+    return 0;
+}
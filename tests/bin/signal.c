@@ -0,0 +1,103 @@
+#include "libc.h"
+#include <signal.h>
+
+/* missing in the libc headers */
+#define SA_RESTORER 0x04000000
+
+/* The kernel `struct sigaction` */
+struct kernel_sigaction {
+    void (*handler)(int, void *, void *);
+    unsigned long flags;
+    void (*restorer)(void);
+    unsigned long mask;
+};
+
+/* The `ucontext_t` up to the general purpose registers */
+struct context {
+    unsigned long flags;
+    void *link;
+    stack_t stack;
+    unsigned long gregs[18];
+};
+
+/* The index of `rip` in `gregs` */
+#define RIP 16
+
+static long sys4(long nr, long a, long b, long c, long d) {
+    long rax;
+    register long r10 __asm__("r10") = d;
+
+    asm volatile(
+        "syscall"
+        : "=a" (rax)
+        : "a" (nr), "D" (a), "S" (b), "d" (c), "r" (r10)
+        : "%rcx", "%r11", "memory"
+    );
+
+    return rax;
+}
+
+/* Returns from a handler */
+void restore(void);
+asm(
+    ".text\n"
+    "restore:\n"
+    "    mov $15, %eax\n" /* SYS_rt_sigreturn */
+    "    syscall\n"
+);
+
+static volatile int usr1 = 0;
+static volatile int ill = 0;
+
+static void handler(int sig, void *info, void *uc) {
+    struct context *ctx = uc;
+
+    if (*(int *) info != sig)
+        return;
+
+    switch (sig) {
+    case SIGUSR1:
+        usr1++;
+        break;
+
+    case SIGILL:
+        /* skip the `ud2` */
+        ctx->gregs[RIP] += 2;
+        ill++;
+        break;
+    }
+}
+
+int main(void) {
+    struct kernel_sigaction act = { handler, SA_SIGINFO | SA_RESTORER, restore, 0 };
+    unsigned long set = 1UL << (SIGUSR1 - 1);
+    long pid = sys4(SYS_getpid, 0, 0, 0, 0);
+    long tid = sys4(SYS_gettid, 0, 0, 0, 0);
+
+    if (sys4(SYS_rt_sigaction, SIGUSR1, (long) &act, 0, sizeof(set)) != 0)
+        return 1;
+
+    if (sys4(SYS_rt_sigaction, SIGILL, (long) &act, 0, sizeof(set)) != 0)
+        return 2;
+
+    /* A signal for the calling thread arrives, before tgkill() returns. */
+    if (sys4(SYS_tgkill, pid, tid, SIGUSR1, 0) != 0 || usr1 != 1)
+        return 3;
+
+    /* A blocked signal stays pending, until it is unblocked. */
+    if (sys4(SYS_rt_sigprocmask, SIG_BLOCK, (long) &set, 0, sizeof(set)) != 0)
+        return 4;
+
+    if (sys4(SYS_tgkill, pid, tid, SIGUSR1, 0) != 0 || usr1 != 1)
+        return 5;
+
+    if (sys4(SYS_rt_sigprocmask, SIG_UNBLOCK, (long) &set, 0, sizeof(set)) != 0 || usr1 != 2)
+        return 6;
+
+    /* An invalid opcode raises SIGILL. */
+    asm volatile("ud2");
+    if (ill != 1)
+        return 7;
+
+    return 0;
+}
@@ -0,0 +1,38 @@
+//! Starts threads, which meet at a barrier and send their numbers back
+//! over a channel, so the keep has to run them in parallel.
+
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread;
+
+const THREADS: usize = 4;
+
+fn main() {
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let counter = Arc::new(Mutex::new(0));
+    let (tx, rx) = mpsc::channel();
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let barrier = barrier.clone();
+            let counter = counter.clone();
+            let tx = tx.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+                *counter.lock().unwrap() += 1;
+                tx.send(i).unwrap();
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(tx);
+    let mut numbers: Vec<_> = rx.iter().collect();
+    numbers.sort_unstable();
+
+    assert_eq!(numbers, (0..THREADS).collect::<Vec<_>>());
+    assert_eq!(*counter.lock().unwrap(), THREADS);
+}
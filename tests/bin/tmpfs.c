@@ -0,0 +1,106 @@
+#include "libc.h"
+#include <fcntl.h>
+
+/* Mounted with `--tmpfs`, so it never exists on the host */
+#define MOUNT "/tmp/enarx-keepldr-tmpfs-test"
+#define FILE_PATH MOUNT "/file"
+#define SUBDIR MOUNT "/dir"
+
+/* Other spellings of FILE_PATH, which must not reach the host either */
+#define UP "../../../../../../../../../../../../../../../../../../../../"
+const char *const SPELLINGS[] = {
+    "/" FILE_PATH,
+    "/." FILE_PATH,
+    "/var/.." FILE_PATH,
+    SUBDIR "/../file",
+    UP UP FILE_PATH,
+};
+
+/* Not defined by glibc without _GNU_SOURCE */
+struct linux_dirent64 {
+    ino_t d_ino;
+    off_t d_off;
+    unsigned short d_reclen;
+    unsigned char d_type;
+    char d_name[];
+};
+
+int equal(const char *a, const char *b, size_t len) {
+    for (size_t i = 0; i < len; i++)
+        if (a[i] != b[i])
+            return 0;
+
+    return 1;
+}
+
+int main(void) {
+    const char data[] = "hello, world";
+    char buf[256];
+    struct stat st;
+
+    if (mkdir(SUBDIR, 0700) < 0)
+        return 1;
+
+    int fd = openat(AT_FDCWD, FILE_PATH, O_CREAT | O_EXCL | O_RDWR, 0600);
+    if (fd < 0)
+        return 2;
+
+    if (write(fd, data, sizeof(data) - 1) != sizeof(data) - 1)
+        return 3;
+
+    if (lseek(fd, 0, SEEK_SET) != 0)
+        return 4;
+
+    if (read(fd, buf, sizeof(buf)) != sizeof(data) - 1 || !equal(buf, data, sizeof(data) - 1))
+        return 5;
+
+    if (read(fd, buf, sizeof(buf)) != 0)
+        return 6;
+
+    if (fstatat(AT_FDCWD, FILE_PATH, &st, 0) < 0)
+        return 7;
+
+    if (!S_ISREG(st.st_mode) || st.st_size != sizeof(data) - 1)
+        return 8;
+
+    for (size_t i = 0; i < sizeof(SPELLINGS) / sizeof(SPELLINGS[0]); i++) {
+        if (fstatat(AT_FDCWD, SPELLINGS[i], &st, 0) < 0 || st.st_size != sizeof(data) - 1)
+            return 16;
+    }
+
+    int dir = openat(AT_FDCWD, MOUNT, O_RDONLY | O_DIRECTORY);
+    if (dir < 0)
+        return 9;
+
+    ssize_t len = getdents64(dir, buf, sizeof(buf));
+    if (len <= 0)
+        return 10;
+
+    int found = 0;
+    for (ssize_t off = 0; off < len;) {
+        struct linux_dirent64 *d = (struct linux_dirent64 *) (buf + off);
+        found |= equal(d->d_name, "file", 5);
+        found |= equal(d->d_name, "dir", 4) << 1;
+        off += d->d_reclen;
+    }
+
+    if (found != 3)
+        return 11;
+
+    close(dir);
+
+    /* The data stays readable until the unlinked file is closed */
+    if (unlink(FILE_PATH) < 0)
+        return 12;
+
+    if (fstatat(AT_FDCWD, FILE_PATH, &st, 0) == 0 || errno != ENOENT)
+        return 13;
+
+    if (pread(fd, buf, 5, 7) != 5 || !equal(buf, "world", 5))
+        return 14;
+
+    if (close(fd) < 0)
+        return 15;
+
+    return 0;
+}
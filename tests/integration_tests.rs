@@ -54,6 +54,18 @@ fn run_test<'a>(
     input: impl Into<Option<&'a [u8]>>,
     expected_stdout: impl Into<Option<&'a [u8]>>,
     expected_stderr: impl Into<Option<&'a [u8]>>,
+) -> Output {
+    run_test_with_options(bin, &[], status, input, expected_stdout, expected_stderr)
+}
+
+/// Like `run_test`, but passes additional options to `exec`
+fn run_test_with_options<'a>(
+    bin: &str,
+    options: &[&str],
+    status: i32,
+    input: impl Into<Option<&'a [u8]>>,
+    expected_stdout: impl Into<Option<&'a [u8]>>,
+    expected_stderr: impl Into<Option<&'a [u8]>>,
 ) -> Output {
     let expected_stdout = expected_stdout.into();
     let expected_stderr = expected_stderr.into();
@@ -62,6 +74,7 @@ fn run_test<'a>(
     let mut child = Command::new(&String::from(KEEP_BIN))
         .current_dir(CRATE)
         .arg("exec")
+        .args(options)
         .arg(bin_path)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
@@ -372,6 +385,112 @@ fn listen() {
     run_test("listen", 0, None, None, None);
 }
 
+#[test]
+#[serial]
+fn file() {
+    let dir = Path::new("/tmp/enarx-keepldr-file-test");
+    let _ = fs::remove_dir_all(dir);
+
+    run_test("file", 0, None, None, None);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+// The nil backend runs the payload on the host, without proxying its syscalls.
+#[cfg(any(
+    feature = "backend-kvm",
+    feature = "backend-sgx",
+    feature = "backend-emulated"
+))]
+#[test]
+#[serial]
+fn record_replay() {
+    const INPUT: &[u8; 12] = b"hello world\n";
+    let tmpdir = TempDir::new("record").unwrap();
+    let recording = tmpdir.path().join("read.cbor");
+    let recording = recording.to_str().unwrap();
+
+    run_test_with_options(
+        "read",
+        &["--record", recording],
+        0,
+        &INPUT[..],
+        &INPUT[..],
+        None,
+    );
+
+    // The replies are replayed, but the writes to stdout are not executed.
+    run_test_with_options("read", &["--replay", recording], 0, None, &b""[..], None);
+}
+
+// The nil backend runs the payload on the host, without a shim to serve a tmpfs.
+#[cfg(any(
+    feature = "backend-kvm",
+    feature = "backend-sgx",
+    feature = "backend-emulated"
+))]
+#[test]
+#[serial]
+fn tmpfs() {
+    let mount = "/tmp/enarx-keepldr-tmpfs-test";
+    let _ = fs::remove_dir_all(mount);
+
+    run_test_with_options("tmpfs", &["--tmpfs", mount], 0, None, None, None);
+
+    // None of the files may have reached the host.
+    assert!(!Path::new(mount).exists());
+}
+
+// Only SGX keeps always have a secret to derive the key of the sealed files from.
+#[cfg(feature = "backend-sgx")]
+#[test]
+#[serial]
+fn sealed() {
+    let prefix = "/tmp/enarx-keepldr-sealed-test";
+    let _ = fs::remove_dir_all(prefix);
+    fs::create_dir(prefix).unwrap();
+
+    let options = ["--backend=sgx", "--sealed", prefix];
+    run_test_with_options("sealed", &options, 0, None, None, None);
+
+    // One record: nonce, the 13 bytes of ciphertext and the tag
+    let sealed = fs::read(Path::new(prefix).join("file")).unwrap();
+    assert_eq!(sealed.len(), 12 + 13 + 16);
+    assert!(!sealed.windows(6).any(|w| w == b"sealed" || w == b"SECRET"));
+
+    fs::remove_dir_all(prefix).unwrap();
+}
+
+// The other tests run on the best backend of the machine, which is rarely the emulated one.
+#[cfg(feature = "backend-emulated")]
+#[test]
+#[serial]
+fn emulated() {
+    const INPUT: &[u8; 12] = b"hello world\n";
+    let options = ["--backend=emulated"];
+    run_test_with_options("read", &options, 0, &INPUT[..], &INPUT[..], None);
+}
+
+#[test]
+#[serial]
+fn threads() {
+    // SGX keeps run a single thread, unless the manifest asks for more.
+    let tmpdir = TempDir::new("threads").unwrap();
+    let manifest = tmpdir.path().join("threads.toml");
+    fs::write(&manifest, "[memory]\nthreads = 5\n").unwrap();
+    let manifest = manifest.to_str().unwrap();
+
+    // Emulated keeps run a single thread, so far.
+    let options = ["--backend=sgx,sev,kvm,nil", "--manifest", manifest];
+    run_test_with_options("threads", &options, 0, None, None, None);
+}
+
+#[test]
+#[serial]
+fn signal() {
+    run_test("signal", 0, None, None, None);
+}
+
 #[test]
 #[serial]
 fn memspike() {